@@ -1,66 +1,364 @@
 use anyhow::{Context, Result};
-use bcrypt::{hash, verify, DEFAULT_COST};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm as Argon2Algorithm, Argon2, Params, Version};
 use chrono::{Duration, Utc};
-use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, Ed25519KeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
 use serde::{Deserialize, Serialize};
-use std::sync::OnceLock;
+use sha2::{Digest, Sha256};
+use std::str::FromStr;
+use std::sync::RwLock;
+use uuid::Uuid;
 
 const TOKEN_EXPIRATION_HOURS: i64 = 24 * 7; // 7 days
 
-/// Per-installation JWT secret, initialized once at startup from the DB.
-static JWT_SECRET: OnceLock<String> = OnceLock::new();
+/// Consecutive failed password attempts before an account is locked
+pub const LOCKOUT_THRESHOLD: u32 = 10;
+pub const LOCKOUT_MINUTES: i64 = 15;
+/// Failed attempts allowed before backoff kicks in
+const BACKOFF_FREE_ATTEMPTS: u32 = 3;
+const BACKOFF_MAX_SECS: i64 = 60;
 
-/// Initialize the JWT secret from the database. If none exists, generates
-/// a cryptographically random 64-char hex string and persists it.
+/// Prefix that distinguishes personal access tokens from session JWTs
+pub const API_TOKEN_PREFIX: &str = "ctn_";
+/// Prefix of guest invite tokens, which are shared as links
+pub const GUEST_INVITE_PREFIX: &str = "ctninv_";
+
+// Scopes that can be granted to a personal access token
+pub const SCOPE_FILES_READ: &str = "files:read";
+pub const SCOPE_FILES_WRITE: &str = "files:write";
+pub const SCOPE_MESSAGES_READ: &str = "messages:read";
+pub const SCOPE_MESSAGES_SEND: &str = "messages:send";
+pub const SCOPE_ADMIN: &str = "admin";
+pub const API_TOKEN_SCOPES: &[&str] = &[
+    SCOPE_FILES_READ, SCOPE_FILES_WRITE, SCOPE_MESSAGES_READ, SCOPE_MESSAGES_SEND, SCOPE_ADMIN,
+];
+/// Longest lifetime a personal access token can be given
+pub const MAX_API_TOKEN_DAYS: u32 = 3650;
+
+/// Key ID given to the pre-rotation `app_secrets.jwt_secret`. Tokens issued
+/// before key IDs existed carry no `kid` header and verify against it.
+const LEGACY_KID: &str = "legacy";
+
+/// Signing keys loaded from the `jwt_keys` table at startup and after each
+/// rotation. The active key signs new tokens; retired keys keep verifying
+/// tokens until their grace period ends.
+static KEYRING: RwLock<Option<KeyRing>> = RwLock::new(None);
+
+struct KeyRing {
+    active_kid: String,
+    keys: Vec<SigningKey>,
+}
+
+struct SigningKey {
+    kid: String,
+    algorithm: Algorithm,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    /// Raw public key for asymmetric keys, published through the JWKS endpoint
+    public_key: Option<Vec<u8>>,
+    /// Unix timestamp after which a retired key stops verifying tokens
+    expires_at: Option<i64>,
+}
+
+/// Signing key metadata for the admin UI — never includes key material.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SigningKeyInfo {
+    pub kid: String,
+    pub algorithm: String,
+    pub active: bool,
+    pub created_at: String,
+    pub retired_at: Option<String>,
+    pub expires_at: Option<String>,
+}
+
+/// Initialize the signing keyring from the database. On first run after the
+/// upgrade, the old single `jwt_secret` is imported as the active HS256 key so
+/// existing sessions stay valid; on a fresh install a new HS256 key is generated.
 /// Must be called once during app setup before any token operations.
-pub fn init_jwt_secret(db: &rusqlite::Connection) -> Result<()> {
-    // Ensure the table exists
+pub fn init_signing_keys(db: &rusqlite::Connection) -> Result<()> {
+    // Ensure the tables exist
     db.execute_batch(
         "CREATE TABLE IF NOT EXISTS app_secrets (
             key TEXT PRIMARY KEY,
             value TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS jwt_keys (
+            kid TEXT PRIMARY KEY,
+            algorithm TEXT NOT NULL,
+            private_key TEXT NOT NULL,
+            public_key TEXT,
+            created_at TEXT NOT NULL,
+            retired_at TEXT,
+            expires_at TEXT
         );"
-    ).context("Failed to create app_secrets table")?;
+    ).context("Failed to create signing key tables")?;
 
-    // Try to load existing secret
-    let existing: Option<String> = db.prepare("SELECT value FROM app_secrets WHERE key = 'jwt_secret'")
-        .ok()
-        .and_then(|mut stmt| stmt.query_row([], |row| row.get(0)).ok());
-
-    let secret = match existing {
-        Some(s) => s,
-        None => {
-            // Generate a random 32-byte (64 hex char) secret
-            let random_bytes: [u8; 32] = {
-                let mut buf = [0u8; 32];
-                getrandom::fill(&mut buf).context("Failed to generate random bytes")?;
-                buf
-            };
-            let secret = hex::encode(random_bytes);
-            db.execute(
-                "INSERT INTO app_secrets (key, value) VALUES ('jwt_secret', ?1)",
-                [&secret],
-            ).context("Failed to persist JWT secret")?;
-            log::info!("Generated new per-installation JWT secret");
-            secret
+    // Drop retired keys whose grace period has ended
+    db.execute(
+        "DELETE FROM jwt_keys WHERE expires_at IS NOT NULL AND expires_at <= ?1",
+        [Utc::now().to_rfc3339()],
+    ).context("Failed to prune expired signing keys")?;
+
+    let has_active = db.prepare("SELECT 1 FROM jwt_keys WHERE retired_at IS NULL")?
+        .exists([])?;
+
+    if !has_active {
+        let legacy: Option<String> = db.prepare("SELECT value FROM app_secrets WHERE key = 'jwt_secret'")
+            .ok()
+            .and_then(|mut stmt| stmt.query_row([], |row| row.get(0)).ok());
+
+        match legacy {
+            Some(secret) => {
+                // The old code used the hex string itself as the HMAC secret
+                db.execute(
+                    "INSERT INTO jwt_keys (kid, algorithm, private_key, public_key, created_at)
+                     VALUES (?1, 'HS256', ?2, NULL, ?3)",
+                    rusqlite::params![LEGACY_KID, hex::encode(secret.as_bytes()), Utc::now().to_rfc3339()],
+                ).context("Failed to import legacy JWT secret")?;
+                db.execute("DELETE FROM app_secrets WHERE key = 'jwt_secret'", [])?;
+                log::info!("Imported legacy JWT secret as signing key '{}'", LEGACY_KID);
+            }
+            None => {
+                insert_signing_key(db, Algorithm::HS256)?;
+                log::info!("Generated new per-installation JWT signing key");
+            }
         }
+    }
+
+    load_keyring(db)
+}
+
+/// Replace the active signing key with a freshly generated one using
+/// `algorithm` ("HS256", "EdDSA" or "ES256"). The previous key keeps verifying
+/// tokens for `grace_hours` — by default the full token lifetime, so no one is
+/// logged out; 0 revokes all existing sessions immediately. A longer grace
+/// period than the token lifetime is rejected since no token outlives it.
+pub fn rotate_signing_key(
+    db: &rusqlite::Connection,
+    algorithm: &str,
+    grace_hours: Option<i64>,
+) -> Result<SigningKeyInfo> {
+    let algorithm = match Algorithm::from_str(algorithm) {
+        Ok(alg @ (Algorithm::HS256 | Algorithm::EdDSA | Algorithm::ES256)) => alg,
+        _ => anyhow::bail!("Unsupported signing algorithm: {}", algorithm),
     };
+    let grace_hours = grace_hours.unwrap_or(TOKEN_EXPIRATION_HOURS);
+    if !(0..=TOKEN_EXPIRATION_HOURS).contains(&grace_hours) {
+        anyhow::bail!("Grace period must be between 0 and {} hours", TOKEN_EXPIRATION_HOURS);
+    }
+    let now = Utc::now();
+    let expires_at = (now + Duration::hours(grace_hours)).to_rfc3339();
 
-    JWT_SECRET.set(secret).ok(); // Ignore if already set (e.g. tests)
-    Ok(())
+    // Keys retired earlier never outlive the new grace period either
+    db.execute(
+        "UPDATE jwt_keys SET retired_at = COALESCE(retired_at, ?1), expires_at = ?2
+         WHERE retired_at IS NULL OR expires_at IS NULL OR expires_at > ?2",
+        rusqlite::params![now.to_rfc3339(), expires_at],
+    ).context("Failed to retire active signing key")?;
+    let kid = insert_signing_key(db, algorithm)?;
+
+    load_keyring(db)?;
+    log::info!("Rotated JWT signing key to '{}' ({:?})", kid, algorithm);
+
+    list_signing_keys(db)?
+        .into_iter()
+        .find(|k| k.kid == kid)
+        .context("Rotated signing key not found")
 }
 
-fn get_secret() -> &'static str {
-    JWT_SECRET.get().expect("JWT secret not initialized — call auth::init_jwt_secret() during setup")
+pub fn list_signing_keys(db: &rusqlite::Connection) -> Result<Vec<SigningKeyInfo>> {
+    let mut stmt = db.prepare(
+        "SELECT kid, algorithm, created_at, retired_at, expires_at
+         FROM jwt_keys ORDER BY created_at DESC"
+    ).context("Failed to prepare query")?;
+
+    let keys = stmt.query_map([], |row| {
+        let retired_at: Option<String> = row.get(3)?;
+        Ok(SigningKeyInfo {
+            kid: row.get(0)?,
+            algorithm: row.get(1)?,
+            active: retired_at.is_none(),
+            created_at: row.get(2)?,
+            retired_at,
+            expires_at: row.get(4)?,
+        })
+    }).context("Failed to query signing keys")?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(keys)
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Public keys of all asymmetric signing keys as a JWK Set, so other services
+/// can verify hub tokens. HS256 keys are shared secrets and never published.
+pub fn jwks() -> serde_json::Value {
+    let keyring = KEYRING.read().unwrap_or_else(|e| e.into_inner());
+    let keys: Vec<serde_json::Value> = keyring.as_ref()
+        .map(|ring| ring.keys.iter().filter_map(public_jwk).collect())
+        .unwrap_or_default();
+    serde_json::json!({ "keys": keys })
+}
+
+fn public_jwk(key: &SigningKey) -> Option<serde_json::Value> {
+    let public = key.public_key.as_ref()?;
+    match key.algorithm {
+        Algorithm::EdDSA => Some(serde_json::json!({
+            "kty": "OKP",
+            "crv": "Ed25519",
+            "kid": key.kid,
+            "alg": "EdDSA",
+            "use": "sig",
+            "x": URL_SAFE_NO_PAD.encode(public),
+        })),
+        Algorithm::ES256 => Some(serde_json::json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": key.kid,
+            "alg": "ES256",
+            "use": "sig",
+            // Uncompressed point: 0x04 || x || y
+            "x": URL_SAFE_NO_PAD.encode(&public[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&public[33..65]),
+        })),
+        _ => None,
+    }
+}
+
+/// Generate key material for `algorithm`, persist it as the active key and return its kid.
+fn insert_signing_key(db: &rusqlite::Connection, algorithm: Algorithm) -> Result<String> {
+    let (private_key, public_key) = generate_key_material(algorithm)?;
+    let kid = Uuid::new_v4().simple().to_string();
+    db.execute(
+        "INSERT INTO jwt_keys (kid, algorithm, private_key, public_key, created_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        rusqlite::params![
+            kid,
+            format!("{:?}", algorithm),
+            hex::encode(private_key),
+            public_key.map(hex::encode),
+            Utc::now().to_rfc3339()
+        ],
+    ).context("Failed to persist signing key")?;
+    Ok(kid)
+}
+
+/// Returns (private key, public key). HS256 keys are a 32-byte secret; asymmetric
+/// keys are a PKCS#8 document plus the raw public key.
+fn generate_key_material(algorithm: Algorithm) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+    let rng = SystemRandom::new();
+    match algorithm {
+        Algorithm::HS256 => {
+            let mut buf = [0u8; 32];
+            getrandom::fill(&mut buf).context("Failed to generate random bytes")?;
+            Ok((buf.to_vec(), None))
+        }
+        Algorithm::EdDSA => {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng)
+                .map_err(|_| anyhow::anyhow!("Failed to generate Ed25519 key"))?;
+            let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref())
+                .map_err(|_| anyhow::anyhow!("Failed to parse Ed25519 key"))?;
+            Ok((pkcs8.as_ref().to_vec(), Some(pair.public_key().as_ref().to_vec())))
+        }
+        Algorithm::ES256 => {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .map_err(|_| anyhow::anyhow!("Failed to generate P-256 key"))?;
+            let pair = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .map_err(|_| anyhow::anyhow!("Failed to parse P-256 key"))?;
+            Ok((pkcs8.as_ref().to_vec(), Some(pair.public_key().as_ref().to_vec())))
+        }
+        other => anyhow::bail!("Unsupported signing algorithm: {:?}", other),
+    }
+}
+
+fn signing_key_from_material(
+    kid: String,
+    algorithm: Algorithm,
+    private_key: &[u8],
+    public_key: Option<&[u8]>,
+    expires_at: Option<i64>,
+) -> Result<SigningKey> {
+    let (encoding, decoding) = match (algorithm, public_key) {
+        (Algorithm::HS256, _) => (EncodingKey::from_secret(private_key), DecodingKey::from_secret(private_key)),
+        (Algorithm::EdDSA, Some(public)) => (EncodingKey::from_ed_der(private_key), DecodingKey::from_ed_der(public)),
+        (Algorithm::ES256, Some(public)) => (EncodingKey::from_ec_der(private_key), DecodingKey::from_ec_der(public)),
+        _ => anyhow::bail!("Signing key '{}' is missing its public key", kid),
+    };
+    Ok(SigningKey {
+        kid,
+        algorithm,
+        encoding,
+        decoding,
+        public_key: public_key.map(|p| p.to_vec()),
+        expires_at,
+    })
+}
+
+fn load_keyring(db: &rusqlite::Connection) -> Result<()> {
+    let mut stmt = db.prepare(
+        "SELECT kid, algorithm, private_key, public_key, retired_at, expires_at FROM jwt_keys"
+    ).context("Failed to prepare query")?;
+
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, String>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, Option<String>>(3)?,
+            row.get::<_, Option<String>>(4)?,
+            row.get::<_, Option<String>>(5)?,
+        ))
+    }).context("Failed to query signing keys")?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut active_kid = None;
+    let mut keys = Vec::with_capacity(rows.len());
+    for (kid, algorithm, private_key, public_key, retired_at, expires_at) in rows {
+        let algorithm = Algorithm::from_str(&algorithm)
+            .with_context(|| format!("Unknown algorithm for signing key '{}'", kid))?;
+        let private_key = hex::decode(&private_key).context("Corrupt signing key")?;
+        let public_key = public_key.map(hex::decode).transpose().context("Corrupt public key")?;
+        let expires_at = expires_at
+            .and_then(|e| chrono::DateTime::parse_from_rfc3339(&e).ok())
+            .map(|e| e.timestamp());
+        if retired_at.is_none() {
+            active_kid = Some(kid.clone());
+        }
+        keys.push(signing_key_from_material(kid, algorithm, &private_key, public_key.as_deref(), expires_at)?);
+    }
+
+    let active_kid = active_kid.context("No active JWT signing key")?;
+    *KEYRING.write().unwrap_or_else(|e| e.into_inner()) = Some(KeyRing { active_kid, keys });
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,      // user_id
     pub username: String,
     pub is_admin: bool,
     pub exp: i64,         // Expiration timestamp
     pub iat: i64,         // Issued at timestamp
+    /// Scopes of the personal access token used to authenticate.
+    /// `None` for session JWTs, which carry the user's full access.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scopes: Option<Vec<String>>,
+}
+
+impl Claims {
+    /// Whether these claims grant the given scope. Session JWTs grant every
+    /// scope; the `admin` scope implies all others, and `messages:send`
+    /// implies `messages:read` (tokens made before it existed could read).
+    pub fn has_scope(&self, scope: &str) -> bool {
+        match &self.scopes {
+            None => true,
+            Some(scopes) => scopes.iter().any(|s| {
+                s == scope || s == SCOPE_ADMIN || (scope == SCOPE_MESSAGES_READ && s == SCOPE_MESSAGES_SEND)
+            }),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -69,22 +367,204 @@ pub struct AuthToken {
     pub expires_at: String,
 }
 
-/// Hash a password using bcrypt
+// --- Password hashing ---
+// New hashes are Argon2id (PHC string format). bcrypt hashes from earlier
+// versions still verify and are replaced on the next successful login.
+
+/// Argon2id cost parameters, tunable per hub
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PasswordHashParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+/// OWASP's baseline for Argon2id: 19 MiB, 2 passes, 1 lane. Takes well
+/// under a second on a Raspberry Pi 4.
+pub const DEFAULT_HASH_PARAMS: PasswordHashParams = PasswordHashParams {
+    memory_kib: 19 * 1024,
+    iterations: 2,
+    parallelism: 1,
+};
+
+/// hub_settings key holding the parameters as JSON
+const HASH_PARAMS_SETTING: &str = "password_hash_params";
+
+static HASH_PARAMS: RwLock<PasswordHashParams> = RwLock::new(DEFAULT_HASH_PARAMS);
+
+pub const MIN_PASSWORD_LENGTH: usize = 10;
+/// Longer passwords are refused so hashing cost stays bounded
+pub const MAX_PASSWORD_BYTES: usize = 1024;
+
+/// Passwords long enough to pass the length check that are still among
+/// the first tried by guessers
+const COMMON_PASSWORDS: &[&str] = &[
+    "1234567890", "0123456789", "0987654321", "1111111111", "1234512345",
+    "123456789a", "a123456789", "1q2w3e4r5t", "qwertyuiop", "qwerty1234",
+    "qwerty12345", "asdfghjkl1", "password12", "password123", "password1234",
+    "passw0rd123", "iloveyou12", "letmein123", "welcome123", "changeme123",
+    "administrator", "abcdefghij", "abc1234567", "citinet123",
+];
+
+/// Load the hub's hashing parameters, if an admin has changed them. Call
+/// once during app setup, like `init_signing_keys`.
+pub fn init_password_hashing(db: &rusqlite::Connection) -> Result<()> {
+    let stored: Option<String> = db.prepare("SELECT value FROM hub_settings WHERE key = ?1")
+        .ok()
+        .and_then(|mut stmt| stmt.query_row([HASH_PARAMS_SETTING], |row| row.get(0)).ok());
+    let params = match stored {
+        Some(value) => serde_json::from_str(&value).context("Stored password hash parameters are invalid")?,
+        None => DEFAULT_HASH_PARAMS,
+    };
+    argon2_params(&params)?;
+    *HASH_PARAMS.write().unwrap_or_else(|e| e.into_inner()) = params;
+    Ok(())
+}
+
+pub fn password_hash_params() -> PasswordHashParams {
+    *HASH_PARAMS.read().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Change the parameters used for new hashes. Existing passwords are
+/// rehashed with them as members sign in.
+pub fn set_password_hash_params(db: &rusqlite::Connection, params: PasswordHashParams) -> Result<()> {
+    argon2_params(&params)?;
+    db.execute(
+        "INSERT INTO hub_settings (key, value) VALUES (?1, ?2)
+         ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        rusqlite::params![HASH_PARAMS_SETTING, serde_json::to_string(&params)?],
+    ).context("Failed to save password hash parameters")?;
+    *HASH_PARAMS.write().unwrap_or_else(|e| e.into_inner()) = params;
+    Ok(())
+}
+
+/// Validate parameters. The bounds keep a typo from making every login
+/// take minutes or exhaust memory on a small device.
+fn argon2_params(params: &PasswordHashParams) -> Result<Params> {
+    if !(8 * 1024..=1024 * 1024).contains(&params.memory_kib) {
+        anyhow::bail!("Memory must be between 8 MiB and 1 GiB");
+    }
+    if !(1..=10).contains(&params.iterations) {
+        anyhow::bail!("Iterations must be between 1 and 10");
+    }
+    if !(1..=8).contains(&params.parallelism) {
+        anyhow::bail!("Parallelism must be between 1 and 8");
+    }
+    Params::new(params.memory_kib, params.iterations, params.parallelism, None)
+        .map_err(|e| anyhow::anyhow!("Invalid Argon2 parameters: {}", e))
+}
+
+/// Hash a password with Argon2id and the hub's current parameters
 pub fn hash_password(password: &str) -> Result<String> {
-    hash(password, DEFAULT_COST)
-        .context("Failed to hash password")
+    let params = argon2_params(&password_hash_params())?;
+    let mut salt = [0u8; 16];
+    getrandom::fill(&mut salt).context("Failed to generate random bytes")?;
+    let salt = SaltString::encode_b64(&salt)
+        .map_err(|e| anyhow::anyhow!("Failed to encode salt: {}", e))?;
+    let hash = Argon2::new(Argon2Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("Failed to hash password: {}", e))?;
+    Ok(hash.to_string())
 }
 
-/// Verify a password against a bcrypt hash
+/// Verify a password against an Argon2 or (legacy) bcrypt hash
 pub fn verify_password(password: &str, hash: &str) -> Result<bool> {
-    verify(password, hash)
-        .context("Failed to verify password")
+    // Accounts without a password (awaiting setup, or anonymized) never match
+    if hash.is_empty() {
+        return Ok(false);
+    }
+    if !hash.starts_with("$argon2") {
+        return bcrypt::verify(password, hash).context("Failed to verify password");
+    }
+    let parsed = PasswordHash::new(hash)
+        .map_err(|e| anyhow::anyhow!("Invalid password hash: {}", e))?;
+    // The algorithm and parameters are read from the hash itself
+    match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+        Ok(()) => Ok(true),
+        Err(argon2::password_hash::Error::Password) => Ok(false),
+        Err(e) => Err(anyhow::anyhow!("Failed to verify password: {}", e)),
+    }
+}
+
+/// Whether a hash that just verified should be replaced: it's bcrypt, or
+/// Argon2 with other parameters than the hub's current ones.
+pub fn needs_rehash(hash: &str) -> bool {
+    let Ok(parsed) = PasswordHash::new(hash) else {
+        return !hash.is_empty();
+    };
+    if parsed.algorithm != Argon2Algorithm::Argon2id.ident() {
+        return true;
+    }
+    let Ok(params) = Params::try_from(&parsed) else {
+        return true;
+    };
+    let current = password_hash_params();
+    (params.m_cost(), params.t_cost(), params.p_cost())
+        != (current.memory_kib, current.iterations, current.parallelism)
+}
+
+/// Minimum strength for a password a member chooses: long enough, not
+/// absurdly long, not one repeated character, not containing the username
+/// (pass "" when it isn't known) and not a well-known common password.
+pub fn check_password_strength(password: &str, username: &str) -> Result<()> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        anyhow::bail!("Password must be at least {} characters", MIN_PASSWORD_LENGTH);
+    }
+    if password.len() > MAX_PASSWORD_BYTES {
+        anyhow::bail!("Password must be at most {} bytes", MAX_PASSWORD_BYTES);
+    }
+    let mut chars = password.chars();
+    if let Some(first) = chars.next() {
+        if chars.all(|c| c == first) {
+            anyhow::bail!("Password can't be a single repeated character");
+        }
+    }
+    let lower = password.to_lowercase();
+    let username = username.trim().to_lowercase();
+    if username.chars().count() >= 3 && lower.contains(&username) {
+        anyhow::bail!("Password can't contain the username");
+    }
+    if COMMON_PASSWORDS.contains(&lower.as_str()) {
+        anyhow::bail!("Password is too common");
+    }
+    Ok(())
+}
+
+/// Seconds an account must wait after its last failed login before the next
+/// attempt is checked: none for the first few failures, then doubling up to a minute.
+pub fn login_backoff_secs(failed_logins: u32) -> i64 {
+    if failed_logins < BACKOFF_FREE_ATTEMPTS {
+        return 0;
+    }
+    let exponent = (failed_logins - BACKOFF_FREE_ATTEMPTS).min(6);
+    (1i64 << exponent).min(BACKOFF_MAX_SECS)
 }
 
 /// Generate a JWT token for a user
 pub fn generate_token(user_id: &str, username: &str, is_admin: bool) -> Result<AuthToken> {
+    issue_token(user_id, username, is_admin, None, TOKEN_EXPIRATION_HOURS)
+}
+
+/// Generate a short-lived JWT limited to `scopes`, e.g. an OIDC access token.
+/// Scoped tokens never carry admin rights.
+pub fn generate_scoped_token(
+    user_id: &str,
+    username: &str,
+    scopes: Vec<String>,
+    ttl_hours: i64,
+) -> Result<AuthToken> {
+    issue_token(user_id, username, false, Some(scopes), ttl_hours)
+}
+
+fn issue_token(
+    user_id: &str,
+    username: &str,
+    is_admin: bool,
+    scopes: Option<Vec<String>>,
+    ttl_hours: i64,
+) -> Result<AuthToken> {
     let now = Utc::now();
-    let expiration = now + Duration::hours(TOKEN_EXPIRATION_HOURS);
+    let expiration = now + Duration::hours(ttl_hours);
 
     let claims = Claims {
         sub: user_id.to_string(),
@@ -92,14 +572,11 @@ pub fn generate_token(user_id: &str, username: &str, is_admin: bool) -> Result<A
         is_admin,
         exp: expiration.timestamp(),
         iat: now.timestamp(),
+        scopes,
     };
 
-    let token = encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(get_secret().as_ref()),
-    )
-    .context("Failed to generate JWT token")?;
+    let token = sign_with_active_key(&claims, false)
+        .context("Failed to generate JWT token")?;
 
     Ok(AuthToken {
         token,
@@ -107,47 +584,158 @@ pub fn generate_token(user_id: &str, username: &str, is_admin: bool) -> Result<A
     })
 }
 
-/// Validate a JWT token and extract claims
+/// Sign an OpenID Connect ID token. Relying parties verify it through the JWKS
+/// endpoint, so this fails unless the active key is asymmetric (EdDSA/ES256).
+pub fn sign_id_token<T: Serialize>(claims: &T) -> Result<String> {
+    sign_with_active_key(claims, true)
+}
+
+/// Algorithm of the active signing key if it can sign ID tokens (i.e. is asymmetric).
+pub fn id_token_algorithm() -> Option<String> {
+    let keyring = KEYRING.read().unwrap_or_else(|e| e.into_inner());
+    let ring = keyring.as_ref()?;
+    ring.keys.iter()
+        .find(|k| k.kid == ring.active_kid && k.public_key.is_some())
+        .map(|k| format!("{:?}", k.algorithm))
+}
+
+fn sign_with_active_key<T: Serialize>(claims: &T, require_asymmetric: bool) -> Result<String> {
+    let keyring = KEYRING.read().unwrap_or_else(|e| e.into_inner());
+    let keyring = keyring.as_ref()
+        .context("JWT signing keys not initialized — call auth::init_signing_keys() during setup")?;
+    let key = keyring.keys.iter()
+        .find(|k| k.kid == keyring.active_kid)
+        .context("Active JWT signing key missing")?;
+
+    if require_asymmetric && key.public_key.is_none() {
+        anyhow::bail!("The active signing key is HS256 — rotate to EdDSA or ES256 to sign ID tokens");
+    }
+
+    let mut header = Header::new(key.algorithm);
+    header.kid = Some(key.kid.clone());
+
+    Ok(encode(&header, claims, &key.encoding)?)
+}
+
+/// Validate a JWT token against the signing key named by its `kid` header and extract claims
 pub fn validate_token(token: &str) -> Result<Claims> {
+    let header = decode_header(token).context("Invalid token")?;
+    let kid = header.kid.as_deref().unwrap_or(LEGACY_KID);
+
+    let keyring = KEYRING.read().unwrap_or_else(|e| e.into_inner());
+    let key = keyring.as_ref()
+        .and_then(|ring| ring.keys.iter().find(|k| k.kid == kid))
+        .context("Unknown signing key")?;
+
+    if header.alg != key.algorithm {
+        anyhow::bail!("Token algorithm does not match signing key");
+    }
+    if key.expires_at.is_some_and(|exp| exp <= Utc::now().timestamp()) {
+        anyhow::bail!("Signing key has expired");
+    }
+
     let token_data = decode::<Claims>(
         token,
-        &DecodingKey::from_secret(get_secret().as_ref()),
-        &Validation::default(),
+        &key.decoding,
+        &Validation::new(key.algorithm),
     )
     .context("Invalid or expired token")?;
 
     Ok(token_data.claims)
 }
 
+/// Generate a random secret: `prefix` followed by 64 random hex chars. Used for
+/// personal access tokens, OIDC client secrets and authorization codes. Only
+/// the hash is persisted, so the plaintext is shown exactly once.
+pub fn generate_secret(prefix: &str) -> Result<String> {
+    let mut buf = [0u8; 32];
+    getrandom::fill(&mut buf).context("Failed to generate random bytes")?;
+    Ok(format!("{}{}", prefix, hex::encode(buf)))
+}
+
+/// Generate a new personal access token
+pub fn generate_api_token() -> Result<String> {
+    generate_secret(API_TOKEN_PREFIX)
+}
+
+/// Generate a password or setup code for an account an admin sets up:
+/// 16 hex chars, short enough to read out, 64 bits of entropy
+pub fn generate_temporary_password() -> Result<String> {
+    let secret = generate_secret("")?;
+    Ok(secret[..16].to_string())
+}
+
+/// Hash a generated secret for storage and lookup (SHA-256, hex).
+/// Secrets are high-entropy, so a fast unsalted hash is sufficient.
+pub fn hash_secret(secret: &str) -> String {
+    hex::encode(Sha256::digest(secret.as_bytes()))
+}
+
+/// Verify a PKCE `code_verifier` against an S256 `code_challenge` (RFC 7636).
+pub fn verify_pkce(code_verifier: &str, code_challenge: &str) -> bool {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes())) == code_challenge
+}
+
+pub fn is_api_token(token: &str) -> bool {
+    token.starts_with(API_TOKEN_PREFIX)
+}
+
 /// Extract the token from an Authorization header
 pub fn extract_bearer_token(auth_header: &str) -> Option<&str> {
-    if auth_header.starts_with("Bearer ") {
-        Some(&auth_header[7..])
-    } else {
-        None
-    }
+    auth_header.strip_prefix("Bearer ")
+}
+
+/// Tests share the global keyring, so hold this guard while using it.
+#[cfg(test)]
+pub(crate) fn ensure_test_keys() -> std::sync::MutexGuard<'static, ()> {
+    static LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    static INIT: std::sync::Once = std::sync::Once::new();
+    let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    INIT.call_once(|| {
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        init_signing_keys(&db).unwrap();
+    });
+    guard
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    fn ensure_test_secret() {
-        // For tests, set a deterministic secret
-        JWT_SECRET.set("test-secret-for-unit-tests-only".to_string()).ok();
-    }
-
     #[test]
     fn test_password_hashing() {
         let password = "test_password_123";
         let hash = hash_password(password).unwrap();
         assert!(verify_password(password, &hash).unwrap());
         assert!(!verify_password("wrong_password", &hash).unwrap());
+        assert!(!verify_password("", "").unwrap());
+    }
+
+    #[test]
+    fn test_legacy_bcrypt_hashes() {
+        let legacy = bcrypt::hash("test_password_123", 4).unwrap();
+        assert!(verify_password("test_password_123", &legacy).unwrap());
+        assert!(!verify_password("wrong_password", &legacy).unwrap());
+        assert!(needs_rehash(&legacy));
+
+        let hash = hash_password("test_password_123").unwrap();
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(!needs_rehash(&hash));
+    }
+
+    #[test]
+    fn test_password_strength() {
+        assert!(check_password_strength("short", "").is_err());
+        assert!(check_password_strength("aaaaaaaaaaaa", "").is_err());
+        assert!(check_password_strength("Password123", "").is_err());
+        assert!(check_password_strength("alice-rocks-2024", "Alice").is_err());
+        assert!(check_password_strength(&"x1".repeat(600), "").is_err());
+        assert!(check_password_strength("correct horse battery", "alice").is_ok());
     }
 
     #[test]
     fn test_token_generation_and_validation() {
-        ensure_test_secret();
+        let _keys = ensure_test_keys();
         let token = generate_token("user123", "testuser", false).unwrap();
         let claims = validate_token(&token.token).unwrap();
 
@@ -156,6 +744,112 @@ mod tests {
         assert!(!claims.is_admin);
     }
 
+    #[test]
+    fn test_signing_key_rotation() {
+        let _keys = ensure_test_keys();
+        let db = rusqlite::Connection::open_in_memory().unwrap();
+        init_signing_keys(&db).unwrap();
+        let old = generate_token("user123", "testuser", false).unwrap();
+
+        assert!(rotate_signing_key(&db, "RS256", None).is_err());
+        assert!(rotate_signing_key(&db, "EdDSA", Some(-1)).is_err());
+        assert!(rotate_signing_key(&db, "EdDSA", Some(i64::MAX)).is_err());
+        assert_eq!(list_signing_keys(&db).unwrap().len(), 1);
+
+        // The retired key keeps verifying during the grace period
+        let key = rotate_signing_key(&db, "EdDSA", Some(1)).unwrap();
+        assert!(key.active);
+        assert_eq!(key.algorithm, "EdDSA");
+        assert!(validate_token(&old.token).is_ok());
+        let new = generate_token("user123", "testuser", false).unwrap();
+        assert_eq!(decode_header(&new.token).unwrap().kid.as_deref(), Some(key.kid.as_str()));
+
+        // A zero grace period retires it immediately
+        rotate_signing_key(&db, "ES256", Some(0)).unwrap();
+        assert!(validate_token(&old.token).is_err());
+        assert!(validate_token(&new.token).is_err());
+        assert!(validate_token(&generate_token("user123", "testuser", false).unwrap().token).is_ok());
+
+        let keys = list_signing_keys(&db).unwrap();
+        assert_eq!(keys.len(), 3);
+        assert_eq!(keys.iter().filter(|k| k.active).count(), 1);
+    }
+
+    #[test]
+    fn test_signing_key_algorithms() {
+        let claims = Claims {
+            sub: "user123".to_string(),
+            username: "testuser".to_string(),
+            is_admin: false,
+            exp: Utc::now().timestamp() + 60,
+            iat: Utc::now().timestamp(),
+            scopes: None,
+        };
+
+        for algorithm in [Algorithm::HS256, Algorithm::EdDSA, Algorithm::ES256] {
+            let (private_key, public_key) = generate_key_material(algorithm).unwrap();
+            let key = signing_key_from_material(
+                "test".to_string(), algorithm, &private_key, public_key.as_deref(), None,
+            ).unwrap();
+
+            let token = encode(&Header::new(algorithm), &claims, &key.encoding).unwrap();
+            let decoded = decode::<Claims>(&token, &key.decoding, &Validation::new(algorithm)).unwrap();
+            assert_eq!(decoded.claims.sub, "user123");
+            assert_eq!(public_jwk(&key).is_some(), algorithm != Algorithm::HS256);
+        }
+    }
+
+    #[test]
+    fn test_api_token_generation_and_scopes() {
+        let token = generate_api_token().unwrap();
+        assert!(is_api_token(&token));
+        assert_eq!(token.len(), API_TOKEN_PREFIX.len() + 64);
+        assert_eq!(hash_secret(&token), hash_secret(&token));
+        assert_ne!(hash_secret(&token), hash_secret(&generate_api_token().unwrap()));
+
+        let mut claims = Claims {
+            sub: "user123".to_string(),
+            username: "testuser".to_string(),
+            is_admin: false,
+            exp: 0,
+            iat: 0,
+            scopes: None,
+        };
+        assert!(claims.has_scope(SCOPE_FILES_WRITE));
+
+        claims.scopes = Some(vec![SCOPE_FILES_READ.to_string()]);
+        assert!(claims.has_scope(SCOPE_FILES_READ));
+        assert!(!claims.has_scope(SCOPE_FILES_WRITE));
+
+        claims.scopes = Some(vec![SCOPE_ADMIN.to_string()]);
+        assert!(claims.has_scope(SCOPE_MESSAGES_SEND));
+
+        claims.scopes = Some(vec![SCOPE_MESSAGES_READ.to_string()]);
+        assert!(claims.has_scope(SCOPE_MESSAGES_READ));
+        assert!(!claims.has_scope(SCOPE_MESSAGES_SEND));
+
+        claims.scopes = Some(vec![SCOPE_MESSAGES_SEND.to_string()]);
+        assert!(claims.has_scope(SCOPE_MESSAGES_READ));
+    }
+
+    #[test]
+    fn test_pkce_verification() {
+        // challenge = base64url(sha256(verifier)), unpadded
+        let verifier = "citinet-pkce-test-verifier-0123456789abcdefghij";
+        let challenge = "UjHJrk2SM89AmBuZSiz6z2JrjATEjBh5bj01ZkRj2JI";
+        assert!(verify_pkce(verifier, challenge));
+        assert!(!verify_pkce("wrong-verifier", challenge));
+    }
+
+    #[test]
+    fn test_login_backoff() {
+        assert_eq!(login_backoff_secs(0), 0);
+        assert_eq!(login_backoff_secs(2), 0);
+        assert_eq!(login_backoff_secs(3), 1);
+        assert_eq!(login_backoff_secs(5), 4);
+        assert_eq!(login_backoff_secs(50), BACKOFF_MAX_SECS);
+    }
+
     #[test]
     fn test_bearer_token_extraction() {
         assert_eq!(
@@ -0,0 +1,214 @@
+use anyhow::{Context, Result};
+use chrono::{Duration, Utc};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::storage_manager::StorageManager;
+
+/// How long a finished archive stays available for download
+pub const EXPORT_TTL_HOURS: i64 = 48;
+
+/// Bumped whenever the manifest layout changes
+const MANIFEST_VERSION: u32 = 1;
+
+/// Messages read per storage lock while gathering an export
+const MESSAGE_BATCH: i64 = 500;
+
+type Storage = Arc<Mutex<Option<StorageManager>>>;
+
+/// Everything needed to write the archive, gathered in short reads under
+/// the storage lock so the (slow) compression can run without it.
+struct Snapshot {
+    manifest: Value,
+    /// (path inside the archive, path on disk)
+    files: Vec<(String, PathBuf)>,
+    archive_path: PathBuf,
+}
+
+/// Build a member's export archive and record the outcome on the job.
+/// Runs on a blocking thread; the storage lock is only held for short
+/// reads and to update the job.
+pub fn run_export_job(storage: Storage, user_id: String, job_id: String) {
+    let result = snapshot(&storage, &user_id, &job_id).and_then(|s| write_archive(&s));
+
+    let sm_lock = storage.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(sm) = sm_lock.as_ref() else { return };
+    let update = match result {
+        Ok(size) => {
+            let expires_at = (Utc::now() + Duration::hours(EXPORT_TTL_HOURS)).to_rfc3339();
+            sm.finish_export_job(&job_id, size, &expires_at)
+        }
+        Err(e) => {
+            log::error!("Data export {} failed: {:#}", job_id, e);
+            sm.fail_export_job(&job_id, "Export failed")
+        }
+    };
+    if let Err(e) = update {
+        log::error!("Failed to record data export {}: {}", job_id, e);
+    }
+}
+
+/// Run a read against storage, holding the lock only for its duration
+fn with_storage<T>(storage: &Storage, f: impl FnOnce(&StorageManager) -> Result<T>) -> Result<T> {
+    let sm_lock = storage.lock().unwrap_or_else(|e| e.into_inner());
+    f(sm_lock.as_ref().context("Storage is not available")?)
+}
+
+fn snapshot(storage: &Storage, user_id: &str, job_id: &str) -> Result<Snapshot> {
+    let (mut snapshot, member_of) = with_storage(storage, |sm| {
+        Ok((account_snapshot(sm, user_id, job_id)?, sm.list_conversations(user_id)?))
+    })?;
+
+    // Messages are read in pages so a long history doesn't hold the lock
+    let mut conversations = Vec::new();
+    for conv in member_of {
+        let conversation_id = &conv.conversation.conversation_id;
+        let mut messages = Vec::new();
+        loop {
+            let batch = with_storage(storage, |sm| {
+                sm.list_all_messages(conversation_id, messages.last(), MESSAGE_BATCH)
+            })?;
+            let done = (batch.len() as i64) < MESSAGE_BATCH;
+            messages.extend(batch);
+            if done {
+                break;
+            }
+        }
+        conversations.push(json!({
+            "conversation_id": conv.conversation.conversation_id,
+            "kind": conv.conversation.kind,
+            "name": conv.conversation.name,
+            "created_at": conv.conversation.created_at,
+            "members": conv.members,
+            "messages": messages,
+        }));
+    }
+    snapshot.manifest["conversations"] = Value::Array(conversations);
+
+    Ok(snapshot)
+}
+
+/// The account, profile and files part of the export
+fn account_snapshot(sm: &StorageManager, user_id: &str, job_id: &str) -> Result<Snapshot> {
+    let user = sm.get_user_by_id(user_id)?
+        .context("User not found")?;
+    let profile = sm.get_profile(user_id)?;
+    let storage_dir = sm.install_path().join("storage");
+
+    let own_files: Vec<_> = sm.list_files(Some(user_id))?
+        .into_iter()
+        .filter(|f| f.user_id == user_id)
+        .collect();
+    let mut files = Vec::with_capacity(own_files.len());
+    let file_entries: Vec<Value> = own_files.iter().map(|f| {
+        let path = format!("files/{}", f.file_name);
+        files.push((path.clone(), storage_dir.join(&f.file_name)));
+        json!({
+            "file_id": f.file_id,
+            "file_name": f.file_name,
+            "size_bytes": f.size_bytes,
+            "is_public": f.is_public,
+            "created_at": f.created_at,
+            "path": path,
+        })
+    }).collect();
+
+    // Avatars are kept apart from the member's files
+    let avatar = profile.avatar_updated_at.is_some().then(|| {
+        files.push(("avatar.png".to_string(), sm.avatar_path(user_id)));
+        "avatar.png"
+    });
+
+    let hub_name = sm.get_node_config()?.map(|c| c.node_name);
+    let manifest = json!({
+        "format": "citinet-export",
+        "version": MANIFEST_VERSION,
+        "generated_at": Utc::now().to_rfc3339(),
+        "hub": hub_name,
+        "account": {
+            "user_id": user.user_id,
+            "username": user.username,
+            "email": user.email,
+            "role": user.role,
+            "created_at": user.created_at,
+        },
+        "profile": profile,
+        "avatar": avatar,
+        "files": file_entries,
+    });
+
+    Ok(Snapshot {
+        manifest,
+        files,
+        archive_path: sm.export_archive_path(job_id),
+    })
+}
+
+/// Write the archive next to its final path and move it into place, so a
+/// half-written file is never served. Returns the archive size.
+fn write_archive(snapshot: &Snapshot) -> Result<u64> {
+    let dir = snapshot.archive_path.parent().context("Invalid export path")?;
+    fs::create_dir_all(dir).context("Failed to create exports directory")?;
+    let partial = snapshot.archive_path.with_extension("zip.partial");
+
+    let file = fs::File::create(&partial).context("Failed to create export archive")?;
+    let mut zip = ZipWriter::new(file);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+    zip.start_file("manifest.json", options)?;
+    zip.write_all(serde_json::to_string_pretty(&snapshot.manifest)?.as_bytes())?;
+
+    for (name, path) in &snapshot.files {
+        // A file missing on disk is still listed in the manifest
+        let Ok(data) = fs::read(path) else {
+            log::warn!("Data export skipped missing file {}", path.display());
+            continue;
+        };
+        zip.start_file(name.as_str(), options)?;
+        zip.write_all(&data)?;
+    }
+    zip.finish().context("Failed to finish export archive")?;
+
+    fs::rename(&partial, &snapshot.archive_path).context("Failed to store export archive")?;
+    Ok(fs::metadata(&snapshot.archive_path)?.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_export_archive() {
+        let dir = std::env::temp_dir().join(format!("citinet-test-{}", uuid::Uuid::new_v4()));
+        let sm = StorageManager::initialize(dir.to_str().unwrap()).unwrap();
+        let alice = sm.create_user("alice", "alice@example.org", "hash", false).unwrap();
+        let conv = sm.create_group_conversation(&alice.user_id, "Team", &[]).unwrap();
+        sm.create_message(&conv.conversation_id, &alice.user_id, "hello", &[], None, None).unwrap();
+        let (job, _) = sm.create_export_job(&alice.user_id).unwrap();
+        let archive_path = sm.export_archive_path(&job.job_id);
+
+        let storage = Arc::new(Mutex::new(Some(sm)));
+        run_export_job(storage.clone(), alice.user_id.clone(), job.job_id.clone());
+
+        let sm_lock = storage.lock().unwrap();
+        let sm = sm_lock.as_ref().unwrap();
+        let job = sm.get_export_job(&alice.user_id, &job.job_id).unwrap().unwrap();
+        assert_eq!(job.status, "ready");
+        assert_eq!(job.size_bytes, Some(fs::metadata(&archive_path).unwrap().len()));
+
+        let mut zip = zip::ZipArchive::new(fs::File::open(&archive_path).unwrap()).unwrap();
+        let mut manifest = String::new();
+        zip.by_name("manifest.json").unwrap().read_to_string(&mut manifest).unwrap();
+        let manifest: Value = serde_json::from_str(&manifest).unwrap();
+        assert_eq!(manifest["account"]["username"], "alice");
+        assert_eq!(manifest["conversations"][0]["messages"][0]["body"], "hello");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
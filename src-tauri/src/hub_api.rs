@@ -1,23 +1,37 @@
 use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
 use axum::{
     Router,
-    extract::{DefaultBodyLimit, Multipart, Path, Query, State, ws::{WebSocket, WebSocketUpgrade, Message as WsMessage}},
+    body::Body,
+    extract::{ConnectInfo, DefaultBodyLimit, Form, Multipart, Path, Query, State, ws::{WebSocket, WebSocketUpgrade, Message as WsMessage}},
     http::{StatusCode, header, HeaderMap, Method},
     middleware::{self, Next},
-    response::{IntoResponse, Response},
-    routing::{get, patch, post},
+    response::{Html, IntoResponse, Redirect, Response},
+    routing::{delete, get, patch, post, put},
     Json,
 };
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use tokio::sync::broadcast;
+use tokio_util::io::ReaderStream;
 
-use crate::storage_manager::StorageManager;
+use base64::Engine;
+use ipnet::IpNet;
+
+use crate::storage_manager::{
+    AuditFilter, Conversation, ConversationMember, FieldVisibility, Message, OidcAuthCode, OidcClient, Profile, ProfileVisibility, StorageManager, User,
+    DEFAULT_TRUSTED_PROXIES, MAX_AVATAR_BYTES, MAX_CONVERSATION_NAME_LEN, MAX_FILE_DESCRIPTION_LEN, MAX_SEARCH_QUERY_LEN, utc_timestamp,
+};
+use crate::data_export;
+use crate::mailer::{self, SmtpConfig};
+use crate::member_csv;
+use crate::webauthn::{self, AuthenticationCredential, Ceremony, ChallengeStore, RegistrationCredential};
 use crate::tunnel_manager::TunnelManager;
 use crate::auth;
+use crate::roles;
 
 // --- Rate limiter ---
 
@@ -58,15 +72,153 @@ impl RateLimiter {
     }
 }
 
-/// Extract real client IP from Cloudflare headers, falling back to "direct"
-fn get_client_ip(headers: &HeaderMap) -> String {
-    headers.get("cf-connecting-ip")
-        .or_else(|| headers.get("x-forwarded-for"))
+// --- Presence ---
+
+/// Whether a member is around, judged from their open WebSockets
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Presence {
+    Online,
+    Away,
+    Offline,
+}
+
+/// How long a connected member can go without activity before they're away
+const PRESENCE_AWAY_SECS: u64 = 300;
+
+struct PresenceEntry {
+    connections: u32,
+    last_active: Instant,
+    away: bool,
+}
+
+/// Open WebSockets and last activity per user. The transition methods
+/// return the new presence when it changed, so callers know to announce it.
+#[derive(Clone)]
+pub struct PresenceTracker {
+    users: Arc<Mutex<HashMap<String, PresenceEntry>>>,
+}
+
+impl PresenceTracker {
+    pub fn new() -> Self {
+        Self { users: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    fn status(&self, user_id: &str) -> Presence {
+        let users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        match users.get(user_id) {
+            Some(entry) if entry.away || entry.last_active.elapsed().as_secs() >= PRESENCE_AWAY_SECS => Presence::Away,
+            Some(_) => Presence::Online,
+            None => Presence::Offline,
+        }
+    }
+
+    fn connect(&self, user_id: &str) -> Option<Presence> {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        match users.get_mut(user_id) {
+            Some(entry) => {
+                entry.connections += 1;
+                entry.last_active = Instant::now();
+                std::mem::take(&mut entry.away).then_some(Presence::Online)
+            }
+            None => {
+                users.insert(user_id.to_string(), PresenceEntry {
+                    connections: 1,
+                    last_active: Instant::now(),
+                    away: false,
+                });
+                Some(Presence::Online)
+            }
+        }
+    }
+
+    fn disconnect(&self, user_id: &str) -> Option<Presence> {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = users.get_mut(user_id)?;
+        entry.connections = entry.connections.saturating_sub(1);
+        if entry.connections > 0 {
+            return None;
+        }
+        users.remove(user_id);
+        Some(Presence::Offline)
+    }
+
+    /// The user did something on one of their connections
+    fn touch(&self, user_id: &str) -> Option<Presence> {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = users.get_mut(user_id)?;
+        entry.last_active = Instant::now();
+        std::mem::take(&mut entry.away).then_some(Presence::Online)
+    }
+
+    /// Mark the user away once they've been idle long enough
+    fn check_idle(&self, user_id: &str) -> Option<Presence> {
+        let mut users = self.users.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = users.get_mut(user_id)?;
+        if entry.away || entry.last_active.elapsed().as_secs() < PRESENCE_AWAY_SECS {
+            return None;
+        }
+        entry.away = true;
+        Some(Presence::Away)
+    }
+}
+
+/// Extract the real client IP. Forwarding headers are only honored when the
+/// connection comes from a trusted proxy; X-Forwarded-For is read right to left,
+/// skipping trusted hops, so clients can't pick their own address.
+fn get_client_ip(state: &ApiState, headers: &HeaderMap, peer: SocketAddr) -> String {
+    let peer_ip = peer.ip().to_canonical();
+    let trusted = trusted_proxies(state);
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer_ip) {
+        return peer_ip.to_string();
+    }
+
+    // Only cloudflared sets CF-Connecting-IP; without a Cloudflare tunnel
+    // the header comes from the client and can't be trusted
+    if behind_cloudflare(state) {
+        let cf_ip = headers.get("cf-connecting-ip")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<IpAddr>().ok());
+        if let Some(ip) = cf_ip {
+            return ip.to_string();
+        }
+    }
+
+    let forwarded = headers.get("x-forwarded-for")
         .and_then(|v| v.to_str().ok())
-        .unwrap_or("direct")
-        .split(',').next().unwrap_or("direct")
-        .trim()
-        .to_string()
+        .unwrap_or("");
+    for hop in forwarded.split(',').rev() {
+        match hop.trim().parse::<IpAddr>() {
+            Ok(ip) if is_trusted(&ip) => continue,
+            Ok(ip) => return ip.to_string(),
+            Err(_) => break,
+        }
+    }
+    peer_ip.to_string()
+}
+
+/// Whether a Cloudflare tunnel (quick or named, not Tailscale) is configured
+fn behind_cloudflare(state: &ApiState) -> bool {
+    state.tunnel_manager.lock().ok()
+        .and_then(|tm| tm.as_ref().and_then(|tm| tm.get_config().map(|c| c.mode != "tailscale")))
+        .unwrap_or(false)
+}
+
+fn trusted_proxies(state: &ApiState) -> Vec<IpNet> {
+    let configured = state.storage_manager.lock().ok()
+        .and_then(|sm| sm.as_ref().and_then(|sm| sm.get_trusted_proxies().ok()));
+    match configured {
+        Some(proxies) => proxies.iter().filter_map(|p| parse_trusted_proxy(p)).collect(),
+        None => DEFAULT_TRUSTED_PROXIES.iter().filter_map(|p| parse_trusted_proxy(p)).collect(),
+    }
+}
+
+/// Parse a trusted proxy entry: a single address or a CIDR range
+pub fn parse_trusted_proxy(entry: &str) -> Option<IpNet> {
+    let entry = entry.trim();
+    entry.parse::<IpNet>().ok()
+        .or_else(|| entry.parse::<IpAddr>().ok().map(IpNet::from))
 }
 
 #[derive(Deserialize)]
@@ -92,10 +244,99 @@ pub struct AuthResponse {
     pub expires_at: String,
 }
 
+/// Realtime event fanned out to WebSocket subscribers of a conversation
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BroadcastMessage {
+    /// One of the `EVENT_*` constants
+    #[serde(rename = "type")]
+    pub event: String,
+    /// The recipient's event sequence number, for resuming after a
+    /// reconnect. Absent on ephemeral events such as typing.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub seq: Option<u64>,
+    /// Empty on events addressed by `audience` instead
+    #[serde(skip_serializing_if = "String::is_empty", default)]
     pub conversation_id: String,
+    /// Set on events about a thread: replies and the thread's parent
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub thread_id: Option<String>,
     pub message: Value,
+    /// Every recipient's sequence number; each socket copies its own into `seq`
+    #[serde(skip)]
+    pub seqs: HashMap<String, u64>,
+    /// Deliver to these users, whatever conversations they're in
+    #[serde(skip)]
+    pub audience: Option<Vec<String>>,
+}
+
+pub const EVENT_MESSAGE_CREATED: &str = "message.created";
+pub const EVENT_MESSAGE_EDITED: &str = "message.edited";
+pub const EVENT_MESSAGE_DELETED: &str = "message.deleted";
+pub const EVENT_REACTION_ADDED: &str = "reaction.added";
+pub const EVENT_REACTION_REMOVED: &str = "reaction.removed";
+/// A thread gained or lost a reply; carries the parent with its new counts
+pub const EVENT_THREAD_UPDATED: &str = "thread.updated";
+/// A member's read cursor moved; carries the member
+pub const EVENT_CONVERSATION_READ: &str = "conversation.read";
+/// A member is typing; carries their `user_id` and `username`
+pub const EVENT_TYPING: &str = "typing";
+/// Members joined or left; carries their `user_ids` and the new `members`
+pub const EVENT_MEMBERS_ADDED: &str = "conversation.members_added";
+pub const EVENT_MEMBERS_REMOVED: &str = "conversation.members_removed";
+/// Sent only to the member who was added, with the whole conversation
+pub const EVENT_CONVERSATION_ADDED: &str = "conversation.added";
+/// Sent only to the member who was removed; no more events follow for it
+pub const EVENT_CONVERSATION_REMOVED: &str = "conversation.removed";
+/// A member came online, went away or went offline; sent to everyone who
+/// shares a conversation with them
+pub const EVENT_PRESENCE: &str = "presence";
+
+/// Version of the WebSocket protocol, announced in the `hello` frame.
+/// Bumped when an existing frame changes shape; new frame types don't.
+pub const WS_PROTOCOL_VERSION: u32 = 1;
+
+/// Frames a client can send over `/ws`
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+pub enum WsClientFrame {
+    #[serde(rename = "message.send")]
+    SendMessage {
+        conversation_id: String,
+        #[serde(flatten)]
+        message: SendMessageRequest,
+    },
+    #[serde(rename = "typing")]
+    Typing {
+        conversation_id: String,
+        thread_id: Option<String>,
+    },
+    #[serde(rename = "ping")]
+    Ping {
+        #[serde(default)]
+        nonce: Option<Value>,
+    },
+}
+
+/// Frames sent to one client only, in reply to what it sent
+#[derive(Serialize)]
+#[serde(tag = "type")]
+pub enum WsReplyFrame {
+    /// `seq` is the user's latest event sequence number
+    #[serde(rename = "hello")]
+    Hello { protocol: u32, user_id: String, seq: u64 },
+    /// Events since the requested `since` are gone; reload state over REST
+    /// and continue from `seq`
+    #[serde(rename = "resync_required")]
+    ResyncRequired { seq: u64 },
+    #[serde(rename = "ack")]
+    Ack { client_id: Option<String>, message: Value },
+    #[serde(rename = "error")]
+    Error { client_id: Option<String>, status: u16, error: String },
+    #[serde(rename = "pong")]
+    Pong {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        nonce: Option<Value>,
+    },
 }
 
 #[derive(Deserialize)]
@@ -104,6 +345,22 @@ pub struct CreateConversationRequest {
     pub peer_user_id: Option<String>,
     pub name: Option<String>,
     pub member_ids: Option<Vec<String>>,
+    pub announcement_only: Option<bool>,
+    /// Joined automatically by members who join the hub from now on
+    pub is_default: Option<bool>,
+}
+
+/// Partial profile update; omitted fields are left alone and an empty
+/// string clears a text field
+#[derive(Deserialize)]
+pub struct UpdateProfileRequest {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub pronouns: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub visibility: Option<ProfileVisibility>,
+    pub allow_direct_messages: Option<bool>,
+    pub show_presence: Option<bool>,
 }
 
 #[derive(Deserialize)]
@@ -111,11 +368,15 @@ pub struct UpdateConversationRequest {
     pub name: Option<String>,
     pub add_members: Option<Vec<String>>,
     pub remove_members: Option<Vec<String>>,
+    pub announcement_only: Option<bool>,
+    /// Joined automatically by members who join the hub from now on
+    pub is_default: Option<bool>,
 }
 
 #[derive(Deserialize)]
 pub struct UpdateFileRequest {
-    pub is_public: bool,
+    pub is_public: Option<bool>,
+    pub description: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -123,6 +384,22 @@ pub struct SendMessageRequest {
     pub body: String,
     #[serde(default)]
     pub attachment_ids: Vec<String>,
+    /// Reply in this message's thread
+    pub parent_id: Option<String>,
+    /// Sender-chosen ID; sending again with the same one returns the
+    /// original message instead of posting twice
+    pub client_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct MarkReadRequest {
+    /// Defaults to the newest message
+    pub message_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct EditMessageRequest {
+    pub body: String,
 }
 
 #[derive(Deserialize)]
@@ -131,9 +408,181 @@ pub struct MessagesQuery {
     pub before: Option<String>,
 }
 
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+    pub limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateApiTokenRequest {
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_in_days: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct RoleRequest {
+    pub name: String,
+    pub permissions: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SetUserRoleRequest {
+    pub role: String,
+}
+
+#[derive(Deserialize)]
+pub struct UserSearchQuery {
+    pub q: Option<String>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub target: Option<String>,
+    pub since: Option<String>,
+    pub until: Option<String>,
+    pub limit: Option<u32>,
+    pub offset: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct SuspendUserRequest {
+    pub reason: Option<String>,
+    /// RFC 3339 end of the suspension; omit to suspend indefinitely
+    pub until: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    /// New password; omit to have the hub generate a temporary one
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateGuestRequest {
+    pub username: String,
+    /// RFC 3339 time the account expires
+    pub expires_at: String,
+    /// Group conversations the guest joins
+    #[serde(default)]
+    pub conversation_ids: Vec<String>,
+    /// Omit to have the hub generate a temporary password
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateGuestInviteRequest {
+    pub expires_at: String,
+    #[serde(default)]
+    pub conversation_ids: Vec<String>,
+    pub max_uses: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub struct GuestSignupRequest {
+    pub invite: String,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct AccountSetupRequest {
+    pub username: String,
+    pub code: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordResetRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordResetConfirmRequest {
+    pub code: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailQuery {
+    pub token: String,
+}
+
+#[derive(Deserialize)]
+pub struct EmailSettingsRequest {
+    /// Email me when someone sends a message in one of my conversations
+    pub notifications: bool,
+}
+
+#[derive(Deserialize)]
+pub struct TestEmailRequest {
+    pub to: String,
+}
+
+#[derive(Deserialize)]
+pub struct RegisterPasskeyRequest {
+    pub ceremony_id: String,
+    pub name: Option<String>,
+    pub credential: RegistrationCredential,
+}
+
+#[derive(Deserialize)]
+pub struct UpdatePasskeySettingsRequest {
+    pub passkey_required: bool,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLoginOptionsRequest {
+    pub username: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLoginRequest {
+    pub ceremony_id: String,
+    pub credential: AuthenticationCredential,
+}
+
+#[derive(Deserialize)]
+pub struct AuthorizeParams {
+    pub response_type: String,
+    pub client_id: String,
+    pub redirect_uri: String,
+    #[serde(default)]
+    pub scope: String,
+    pub state: Option<String>,
+    pub nonce: Option<String>,
+    pub code_challenge: Option<String>,
+    pub code_challenge_method: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AuthorizeForm {
+    #[serde(flatten)]
+    pub params: AuthorizeParams,
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Deserialize)]
+pub struct TokenRequest {
+    pub grant_type: String,
+    pub code: Option<String>,
+    pub redirect_uri: Option<String>,
+    pub client_id: Option<String>,
+    pub client_secret: Option<String>,
+    pub code_verifier: Option<String>,
+}
+
 #[derive(Deserialize)]
 pub struct WsQuery {
     pub token: String,
+    /// Replay events after this sequence number
+    pub since: Option<u64>,
 }
 
 #[derive(Clone)]
@@ -143,10 +592,32 @@ pub struct ApiState {
     pub started_at: Instant,
     pub msg_tx: broadcast::Sender<BroadcastMessage>,
     pub auth_limiter: RateLimiter,
+    pub passkey_ceremonies: ChallengeStore,
+    pub presence: PresenceTracker,
 }
 
 pub const HUB_API_PORT: u16 = 9090;
 
+/// How often an open WebSocket re-checks that its user hasn't been suspended
+/// and, for guests, hasn't expired
+const WS_SUSPENSION_CHECK_SECS: u64 = 30;
+
+/// Typing indicators are forwarded at most this often per conversation
+const WS_TYPING_THROTTLE_SECS: u64 = 3;
+
+const MAX_CLIENT_ID_LEN: usize = 64;
+
+/// Realtime events are kept this long for replay after a reconnect
+pub const EVENT_RETENTION_HOURS: i64 = 24;
+
+/// A reconnecting client that missed more events than this has to resync
+const MAX_REPLAY_EVENTS: u32 = 1000;
+
+/// Scopes the OIDC provider understands; anything else requested is dropped
+const OIDC_SCOPES: &[&str] = &["openid", "profile", "email"];
+const AUTH_CODE_TTL_SECS: i64 = 300;
+const OIDC_TOKEN_TTL_HOURS: i64 = 1;
+
 /// Manual CORS middleware — injects headers on every response unconditionally.
 /// More robust than tower_http CorsLayer because it also covers error responses,
 /// proxy-stripped headers, and ensures OPTIONS preflight always succeeds.
@@ -176,24 +647,78 @@ async fn cors_middleware(req: axum::http::Request<axum::body::Body>, next: Next)
 pub async fn start_hub_api(state: ApiState, port: u16) -> anyhow::Result<()> {
     let app = Router::new()
         .route("/api/health", get(health))
+        .route("/.well-known/jwks.json", get(jwks))
+        .route("/.well-known/openid-configuration", get(openid_configuration))
+        .route("/oauth/authorize", get(oauth_authorize_page).post(oauth_authorize_submit))
+        .route("/oauth/token", post(oauth_token))
+        .route("/oauth/userinfo", get(oauth_userinfo).post(oauth_userinfo))
         .route("/api/info", get(hub_info))
         .route("/api/status", get(hub_status))
         .route("/api/auth/register", post(register))
         .route("/api/auth/login", post(login))
+        .route("/api/auth/guest", post(guest_signup))
+        .route("/api/auth/setup", post(complete_account_setup))
+        .route("/api/auth/verify-email", get(verify_email))
+        .route("/api/auth/password-reset", post(request_password_reset))
+        .route("/api/auth/password-reset/confirm", post(confirm_password_reset))
         .route("/api/members", get(list_members))
+        .route("/api/members/{id}", get(get_member_profile))
+        .route("/api/members/{id}/avatar", get(get_member_avatar))
+        .route("/api/me/profile", get(get_my_profile).patch(update_my_profile))
+        .route("/api/me/profile/avatar", post(upload_avatar).delete(delete_avatar))
+        .route("/api/me/email", get(get_email_settings).patch(update_email_settings))
+        .route("/api/me/email/verify", post(resend_verification))
+        .route("/api/me/export", get(list_exports).post(start_export))
+        .route("/api/me/export/{id}", get(get_export))
+        .route("/api/me/export/{id}/download", get(download_export))
+        .route("/api/me/tokens", get(list_api_tokens).post(create_api_token))
+        .route("/api/me/tokens/{id}", delete(delete_api_token))
+        .route("/api/me/passkeys", get(list_passkeys).post(register_passkey).patch(update_passkey_settings))
+        .route("/api/me/passkeys/options", post(passkey_registration_options))
+        .route("/api/me/passkeys/{id}", delete(delete_passkey))
+        .route("/api/auth/passkey/options", post(passkey_login_options))
+        .route("/api/auth/passkey/verify", post(passkey_login))
+        .route("/api/admin/locked-accounts", get(list_locked_accounts))
+        .route("/api/admin/audit", get(list_audit_events))
+        .route("/api/admin/users", get(admin_list_users))
+        .route("/api/admin/users/import", post(import_users))
+        .route("/api/admin/users/export", get(export_users))
+        .route("/api/admin/users/{id}", delete(admin_delete_user))
+        .route("/api/admin/users/{id}/suspend", post(suspend_user))
+        .route("/api/admin/users/{id}/unsuspend", post(unsuspend_user))
+        .route("/api/admin/users/{id}/reset-password", post(reset_user_password))
+        .route("/api/admin/users/{id}/unlock", post(unlock_user))
+        .route("/api/admin/users/{id}/role", put(set_user_role))
+        .route("/api/admin/guests", post(create_guest))
+        .route("/api/admin/guest-invites", get(list_guest_invites).post(create_guest_invite))
+        .route("/api/admin/guest-invites/{id}", delete(delete_guest_invite))
+        .route("/api/admin/smtp", get(get_smtp_config).put(set_smtp_config).delete(clear_smtp_config))
+        .route("/api/admin/smtp/test", post(send_test_email))
+        .route("/api/admin/email-outbox", get(list_email_outbox))
+        .route("/api/admin/roles", get(list_roles).post(create_role))
+        .route("/api/admin/roles/{id}", patch(update_role).delete(delete_role))
         .route("/api/conversations", get(list_conversations_handler).post(create_conversation))
         .route("/api/conversations/{id}", patch(update_conversation))
+        .route("/api/conversations/{id}/read", post(mark_conversation_read))
+        .route("/api/conversations/{id}/join", post(join_channel))
+        .route("/api/conversations/{id}/leave", post(leave_channel))
+        .route("/api/channels", get(list_channels))
         .route("/api/conversations/{id}/messages", get(get_messages).post(send_message))
+        .route("/api/conversations/{id}/messages/{message_id}", patch(edit_message).delete(delete_message))
+        .route("/api/conversations/{id}/messages/{message_id}/history", get(get_message_history))
+        .route("/api/conversations/{id}/messages/{message_id}/thread", get(get_thread))
+        .route("/api/conversations/{id}/messages/{message_id}/reactions/{emoji}", put(add_reaction).delete(remove_reaction))
         .route("/ws", get(ws_handler))
         .route("/api/files", get(list_files).post(upload_file))
-        .route("/api/files/{name}", get(download_file).delete(delete_file_handler).patch(update_file_visibility_handler))
+        .route("/api/files/{name}", get(download_file).delete(delete_file_handler).patch(update_file_handler))
+        .route("/api/search", get(search))
         .layer(DefaultBodyLimit::max(100 * 1024 * 1024)) // 100 MB upload limit
         .layer(middleware::from_fn(cors_middleware))
         .with_state(state);
 
     let listener = tokio::net::TcpListener::bind(("0.0.0.0", port)).await?;
     log::info!("Hub API listening on 0.0.0.0:{}", port);
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
     Ok(())
 }
 
@@ -202,6 +727,11 @@ async fn health() -> Json<Value> {
     Json(json!({ "ok": true, "version": "0.1.0" }))
 }
 
+// GET /.well-known/jwks.json
+async fn jwks() -> Json<Value> {
+    Json(auth::jwks())
+}
+
 // GET /api/info
 async fn hub_info(State(state): State<ApiState>) -> Result<Json<Value>, StatusCode> {
     let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
@@ -216,6 +746,7 @@ async fn hub_info(State(state): State<ApiState>) -> Result<Json<Value>, StatusCo
         "node_name": config.node_name,
         "node_type": config.node_type,
         "storage_quota_gb": config.disk_quota_gb,
+        "password_min_length": auth::MIN_PASSWORD_LENGTH,
     })))
 }
 
@@ -251,18 +782,33 @@ async fn list_members(
     State(state): State<ApiState>,
     headers: HeaderMap,
 ) -> Result<Json<Value>, StatusCode> {
-    let _claims = validate_auth_header(&headers)?;
+    let claims = validate_auth_header(&state, &headers)?;
 
     let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_MEMBERS_VIEW)?;
 
     let users = sm.list_users().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let last_seen = sm.last_seen_times().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let profiles = sm.list_profiles().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let manages_users = require_permission(sm, &claims, roles::PERM_USERS_MANAGE).is_ok();
 
     let members: Vec<Value> = users.iter().map(|u| {
+        let profile = profiles.get(&u.user_id);
+        let full = manages_users || claims.sub == u.user_id;
+        // Hidden presence reads as offline, with no last seen time
+        let presence_hidden = profile.is_some_and(|p| !p.show_presence);
+        let show_presence = full || !presence_hidden;
         json!({
             "user_id": u.user_id,
             "username": u.username,
+            "display_name": profile.and_then(|p| p.display_name.clone()),
+            "avatar_url": profile.and_then(|p| avatar_url(p, full)),
             "is_admin": u.is_admin,
+            "role": u.role,
+            "suspended": u.suspension.is_some(),
+            "presence": if show_presence { state.presence.status(&u.user_id) } else { Presence::Offline },
+            "last_seen_at": if show_presence { last_seen.get(&u.user_id) } else { None },
             "created_at": u.created_at,
         })
     }).collect();
@@ -270,498 +816,4179 @@ async fn list_members(
     Ok(Json(json!({ "members": members, "total": members.len() })))
 }
 
-// GET /api/files
-async fn list_files(
-    State(state): State<ApiState>,
-    headers: HeaderMap,
-) -> Result<Json<Value>, StatusCode> {
-    // Validate authentication and get user claims
-    let claims = validate_auth_header(&headers)?;
+// --- Profiles ---
 
-    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+/// Members see each other's profile fields according to the owner's
+/// visibility settings; the member themself and user managers see everything.
+fn can_see_private_profile(sm: &StorageManager, claims: &auth::Claims, user_id: &str) -> bool {
+    claims.sub == user_id || require_permission(sm, claims, roles::PERM_USERS_MANAGE).is_ok()
+}
 
-    let files = sm.list_files(Some(&claims.sub)).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+fn avatar_url(profile: &Profile, full: bool) -> Option<String> {
+    let visible = full || profile.visibility.avatar == FieldVisibility::Members;
+    (visible && profile.avatar_updated_at.is_some())
+        .then(|| format!("/api/members/{}/avatar", profile.user_id))
+}
 
-    let file_list: Vec<Value> = files.iter().map(|f| {
-        json!({
-            "file_id": f.file_id,
-            "file_name": f.file_name,
-            "size_bytes": f.size_bytes,
-            "is_public": f.is_public,
-            "owner_id": f.user_id,
-            "created_at": f.created_at,
-        })
-    }).collect();
+fn profile_json(user: &User, profile: &Profile, full: bool) -> Value {
+    let shown = |visibility: FieldVisibility| full || visibility == FieldVisibility::Members;
+    let v = &profile.visibility;
 
-    Ok(Json(json!(file_list)))
+    let mut out = json!({
+        "user_id": user.user_id,
+        "username": user.username,
+        "display_name": profile.display_name,
+        "avatar_url": avatar_url(profile, full),
+        "role": user.role,
+        "suspended": user.suspension.is_some(),
+        "allow_direct_messages": profile.allow_direct_messages,
+        "show_presence": profile.show_presence,
+        "created_at": user.created_at,
+    });
+    if shown(v.email) {
+        out["email"] = json!(user.email);
+    }
+    if shown(v.bio) {
+        out["bio"] = json!(profile.bio);
+    }
+    if shown(v.pronouns) {
+        out["pronouns"] = json!(profile.pronouns);
+    }
+    if shown(v.tags) {
+        out["tags"] = json!(profile.tags);
+    }
+    if full {
+        out["visibility"] = json!(profile.visibility);
+    }
+    out
 }
 
-// POST /api/files (multipart/form-data)
-async fn upload_file(
+/// Trim a submitted text field; an empty value clears it
+fn profile_text(value: String) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
+}
+
+// GET /api/members/:id
+async fn get_member_profile(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    mut multipart: Multipart,
+    Path(user_id): Path<String>,
 ) -> Result<Json<Value>, StatusCode> {
-    // Validate authentication and get user claims
-    let claims = validate_auth_header(&headers)?;
-    
-    let mut file_name = String::new();
-    let mut file_data = Vec::new();
-    let mut is_public = false;
-
-    while let Ok(Some(field)) = multipart.next_field().await {
-        let name = field.name().unwrap_or("").to_string();
-        if name == "file" {
-            file_name = field.file_name().unwrap_or("upload").to_string();
-            if let Ok(bytes) = field.bytes().await {
-                file_data = bytes.to_vec();
-            }
-        } else if name == "is_public" {
-            if let Ok(text) = field.text().await {
-                is_public = text == "true" || text == "1";
-            }
-        }
-    }
-
-    if file_name.is_empty() || file_data.is_empty() {
-        return Err(StatusCode::BAD_REQUEST);
-    }
+    let claims = validate_auth_header(&state, &headers)?;
 
     let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_MEMBERS_VIEW)?;
 
-    let file = sm.upload_file(&claims.sub, &file_name, &file_data, is_public)
+    let user = sm.get_user_by_id(&user_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let profile = sm.get_profile(&user_id)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    Ok(Json(json!({
-        "file_id": file.file_id,
-        "file_name": file.file_name,
-        "size_bytes": file_data.len(),
-    })))
+    Ok(Json(profile_json(&user, &profile, can_see_private_profile(sm, &claims, &user_id))))
 }
 
-// GET /api/files/:name
-async fn download_file(
+// GET /api/members/:id/avatar
+async fn get_member_avatar(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Path(name): Path<String>,
+    Path(user_id): Path<String>,
 ) -> Result<impl IntoResponse, StatusCode> {
-    // Validate authentication and get user claims
-    let claims = validate_auth_header(&headers)?;
+    let claims = validate_auth_header(&state, &headers)?;
+
     let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    if claims.sub != user_id {
+        require_permission(sm, &claims, roles::PERM_MEMBERS_VIEW)?;
+    }
 
-    let data = sm.read_file(&claims.sub, &name).map_err(|_| StatusCode::NOT_FOUND)?;
-
-    let content_type = mime_from_ext(&name);
-    let disposition = if content_type.starts_with("image/") || content_type.starts_with("video/") {
-        format!("inline; filename=\"{}\"", name)
-    } else {
-        format!("attachment; filename=\"{}\"", name)
-    };
+    let profile = sm.get_profile(&user_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if avatar_url(&profile, can_see_private_profile(sm, &claims, &user_id)).is_none() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let data = sm.read_avatar(&user_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
 
-    Ok((
-        [
-            (header::CONTENT_TYPE, content_type),
-            (header::CONTENT_DISPOSITION, disposition),
-        ],
-        data,
-    ))
+    Ok(([(header::CONTENT_TYPE, "image/png")], data))
 }
 
-// DELETE /api/files/:name
-async fn delete_file_handler(
+// GET /api/me/profile
+async fn get_my_profile(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Path(name): Path<String>,
-) -> Result<StatusCode, StatusCode> {
-    // Validate authentication and get user claims
-    let claims = validate_auth_header(&headers)?;
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+
     let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
 
-    sm.delete_file(&claims.sub, &name).map_err(|_| StatusCode::NOT_FOUND)?;
-
-    Ok(StatusCode::NO_CONTENT)
+    let user = sm.get_user_by_id(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let profile = sm.get_profile(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(profile_json(&user, &profile, true)))
 }
 
-// PATCH /api/files/:name
-async fn update_file_visibility_handler(
+// PATCH /api/me/profile
+async fn update_my_profile(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Path(name): Path<String>,
-    Json(body): Json<UpdateFileRequest>,
-) -> Result<StatusCode, StatusCode> {
-    let claims = validate_auth_header(&headers)?;
+    Json(req): Json<UpdateProfileRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_session(&claims)?;
+
     let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
 
-    sm.update_file_visibility(&claims.sub, &name, body.is_public)
-        .map_err(|_| StatusCode::NOT_FOUND)?;
-
-    Ok(StatusCode::OK)
-}
-
-// Helper function to validate JWT from Authorization header
-fn validate_auth_header(headers: &HeaderMap) -> Result<auth::Claims, StatusCode> {
-    let auth_header = headers
-        .get(header::AUTHORIZATION)
-        .and_then(|h| h.to_str().ok())
+    let user = sm.get_user_by_id(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
         .ok_or(StatusCode::UNAUTHORIZED)?;
+    let mut profile = sm.get_profile(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    let token = auth::extract_bearer_token(auth_header)
-        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if let Some(display_name) = req.display_name {
+        profile.display_name = profile_text(display_name);
+    }
+    if let Some(bio) = req.bio {
+        profile.bio = profile_text(bio);
+    }
+    if let Some(pronouns) = req.pronouns {
+        profile.pronouns = profile_text(pronouns);
+    }
+    if let Some(tags) = req.tags {
+        profile.tags.clear();
+        for tag in tags.into_iter().filter_map(profile_text) {
+            if !profile.tags.contains(&tag) {
+                profile.tags.push(tag);
+            }
+        }
+    }
+    if let Some(visibility) = req.visibility {
+        profile.visibility = visibility;
+    }
+    if let Some(allow) = req.allow_direct_messages {
+        profile.allow_direct_messages = allow;
+    }
+    let was_shown = profile.show_presence;
+    if let Some(show) = req.show_presence {
+        profile.show_presence = show;
+    }
 
-    auth::validate_token(token)
-        .map_err(|_| StatusCode::UNAUTHORIZED)
-}
+    let profile = sm.save_profile(&profile)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
 
-fn mime_from_ext(name: &str) -> String {
-    match name.rsplit('.').next().map(|e| e.to_lowercase()).as_deref() {
-        Some("jpg") | Some("jpeg") => "image/jpeg".to_string(),
-        Some("png") => "image/png".to_string(),
-        Some("gif") => "image/gif".to_string(),
-        Some("webp") => "image/webp".to_string(),
-        Some("svg") => "image/svg+xml".to_string(),
-        Some("bmp") => "image/bmp".to_string(),
-        Some("mp4") | Some("m4v") => "video/mp4".to_string(),
-        Some("webm") => "video/webm".to_string(),
-        Some("mov") => "video/quicktime".to_string(),
-        Some("avi") => "video/x-msvideo".to_string(),
-        Some("mkv") => "video/x-matroska".to_string(),
-        Some("ogv") => "video/ogg".to_string(),
-        Some("3gp") => "video/3gpp".to_string(),
-        Some("pdf") => "application/pdf".to_string(),
-        Some("txt") => "text/plain".to_string(),
-        Some("html") | Some("htm") => "text/html".to_string(),
-        Some("json") => "application/json".to_string(),
-        Some("zip") => "application/zip".to_string(),
-        _ => "application/octet-stream".to_string(),
+    // Hiding presence reads as going offline; showing it again reveals
+    // the current state
+    if profile.show_presence != was_shown {
+        if profile.show_presence {
+            let last_seen = sm.last_seen_times().unwrap_or_default().remove(&claims.sub);
+            broadcast_presence(&state, sm, &claims.sub, state.presence.status(&claims.sub), last_seen.as_deref());
+        } else {
+            broadcast_presence(&state, sm, &claims.sub, Presence::Offline, None);
+        }
     }
+
+    Ok(Json(profile_json(&user, &profile, true)))
 }
 
-// POST /api/conversations
-async fn create_conversation(
+// POST /api/me/profile/avatar
+async fn upload_avatar(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Json(req): Json<CreateConversationRequest>,
+    mut multipart: Multipart,
 ) -> Result<Json<Value>, StatusCode> {
-    let claims = validate_auth_header(&headers)?;
+    let claims = validate_auth_header(&state, &headers)?;
+    require_session(&claims)?;
+
+    // The image type is sniffed from the content, not the file name
+    let mut data = Vec::new();
+    while let Ok(Some(field)) = multipart.next_field().await {
+        if field.name() == Some("file") {
+            if let Ok(bytes) = field.bytes().await {
+                data = bytes.to_vec();
+            }
+        }
+    }
+    if data.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if data.len() > MAX_AVATAR_BYTES {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
 
     let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
 
-    match req.kind.as_str() {
-        "dm" => {
-            let peer_id = req.peer_user_id.as_deref().ok_or(StatusCode::BAD_REQUEST)?;
-            let conv = sm.create_dm_conversation(&claims.sub, peer_id)
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-            let members = sm.get_conversation_members(&conv.conversation_id)
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-            Ok(Json(json!({
-                "conversation_id": conv.conversation_id,
-                "kind": conv.kind,
-                "name": conv.name,
-                "members": members,
-                "created_at": conv.created_at,
-            })))
-        }
-        "group" => {
-            let name = req.name.as_deref().ok_or(StatusCode::BAD_REQUEST)?;
-            let member_ids = req.member_ids.as_ref().ok_or(StatusCode::BAD_REQUEST)?;
-            let conv = sm.create_group_conversation(&claims.sub, name, member_ids)
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-            let members = sm.get_conversation_members(&conv.conversation_id)
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-            Ok(Json(json!({
-                "conversation_id": conv.conversation_id,
-                "kind": conv.kind,
-                "name": conv.name,
-                "members": members,
-                "created_at": conv.created_at,
-            })))
-        }
-        _ => Err(StatusCode::BAD_REQUEST),
-    }
+    let profile = sm.set_avatar(&claims.sub, &data)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    Ok(Json(json!({ "avatar_url": avatar_url(&profile, true) })))
 }
 
-// GET /api/conversations
-async fn list_conversations_handler(
+// DELETE /api/me/profile/avatar
+async fn delete_avatar(
     State(state): State<ApiState>,
     headers: HeaderMap,
-) -> Result<Json<Value>, StatusCode> {
-    let claims = validate_auth_header(&headers)?;
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_session(&claims)?;
 
     let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
 
-    let convs = sm.list_conversations(&claims.sub)
+    sm.clear_avatar(&claims.sub)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    Ok(Json(json!({ "conversations": convs })))
+    Ok(StatusCode::NO_CONTENT)
 }
 
-// PATCH /api/conversations/:id
-async fn update_conversation(
+// --- Personal data export ---
+// Exports hold everything about a member, so they need a login session.
+
+// POST /api/me/export
+async fn start_export(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<Value>), StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+    require_session(&claims)?;
+
+    let (job, created) = {
+        let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+        let (job, created) = sm.create_export_job(&claims.sub)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if created {
+            sm.audit(Some(&claims.sub), "export.request", Some(&job.job_id), Some(&ip), None);
+        }
+        (job, created)
+    };
+
+    if created {
+        let storage = state.storage_manager.clone();
+        let (user_id, job_id) = (claims.sub.clone(), job.job_id.clone());
+        tokio::task::spawn_blocking(move || data_export::run_export_job(storage, user_id, job_id));
+    }
+
+    Ok((StatusCode::ACCEPTED, Json(json!(job))))
+}
+
+// GET /api/me/export
+async fn list_exports(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Path(conversation_id): Path<String>,
-    Json(req): Json<UpdateConversationRequest>,
 ) -> Result<Json<Value>, StatusCode> {
-    let claims = validate_auth_header(&headers)?;
+    let claims = validate_auth_header(&state, &headers)?;
+    require_session(&claims)?;
 
     let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
 
-    // Verify caller is a member
-    let is_member = sm.is_conversation_member(&conversation_id, &claims.sub)
+    sm.purge_expired_exports()
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    if !is_member {
-        return Err(StatusCode::FORBIDDEN);
-    }
-
-    if let Some(name) = &req.name {
-        sm.rename_conversation(&conversation_id, name)
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    }
-    if let Some(add) = &req.add_members {
-        for uid in add {
-            sm.add_group_member(&conversation_id, uid)
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        }
-    }
-    if let Some(remove) = &req.remove_members {
-        for uid in remove {
-            sm.remove_group_member(&conversation_id, uid)
-                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        }
-    }
-
-    let members = sm.get_conversation_members(&conversation_id)
+    let jobs = sm.list_export_jobs(&claims.sub)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    Ok(Json(json!({ "ok": true, "members": members })))
+    Ok(Json(json!({ "exports": jobs })))
 }
 
-// POST /api/conversations/:id/messages
-async fn send_message(
+// GET /api/me/export/:id
+async fn get_export(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Path(conversation_id): Path<String>,
-    Json(req): Json<SendMessageRequest>,
+    Path(job_id): Path<String>,
 ) -> Result<Json<Value>, StatusCode> {
-    let claims = validate_auth_header(&headers)?;
+    let claims = validate_auth_header(&state, &headers)?;
+    require_session(&claims)?;
 
-    if req.body.is_empty() && req.attachment_ids.is_empty() {
-        return Err(StatusCode::BAD_REQUEST);
-    }
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
 
-    let message = {
+    let job = sm.get_export_job(&claims.sub, &job_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(json!(job)))
+}
+
+// GET /api/me/export/:id/download
+async fn download_export(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path(job_id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_session(&claims)?;
+
+    let archive_path = {
         let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
         let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
 
-        sm.create_message(&conversation_id, &claims.sub, &req.body, &req.attachment_ids)
+        sm.purge_expired_exports()
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let job = sm.get_export_job(&claims.sub, &job_id)
             .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::NOT_FOUND)?;
+        if job.status != "ready" {
+            return Err(StatusCode::CONFLICT);
+        }
+        sm.export_archive_path(&job.job_id)
     };
 
-    let msg_json = json!({
-        "message_id": message.message_id,
-        "conversation_id": message.conversation_id,
-        "sender_id": message.sender_id,
-        "sender_username": message.sender_username,
-        "body": message.body,
-        "attachments": message.attachments,
-        "created_at": message.created_at,
-    });
-
-    // Broadcast to WebSocket subscribers
-    let _ = state.msg_tx.send(BroadcastMessage {
-        conversation_id: conversation_id.clone(),
-        message: msg_json.clone(),
-    });
+    let file = tokio::fs::File::open(&archive_path).await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+    let size = file.metadata().await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .len();
+    let disposition = format!(
+        "attachment; filename=\"citinet-export-{}-{}.zip\"",
+        claims.username,
+        chrono::Utc::now().format("%Y%m%d"),
+    );
 
-    Ok(Json(msg_json))
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_LENGTH, size.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        // Archives can be large, so stream them rather than buffering
+        Body::from_stream(ReaderStream::new(file)),
+    ))
 }
 
-// GET /api/conversations/:id/messages
-async fn get_messages(
+// GET /api/me/tokens
+async fn list_api_tokens(
     State(state): State<ApiState>,
     headers: HeaderMap,
-    Path(conversation_id): Path<String>,
-    Query(query): Query<MessagesQuery>,
 ) -> Result<Json<Value>, StatusCode> {
-    let claims = validate_auth_header(&headers)?;
+    let claims = validate_auth_header(&state, &headers)?;
+    require_session(&claims)?;
 
     let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
 
-    let is_member = sm.is_conversation_member(&conversation_id, &claims.sub)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-    if !is_member {
-        return Err(StatusCode::FORBIDDEN);
-    }
-
-    let limit = query.limit.unwrap_or(50).min(100);
-    let messages = sm.list_messages(&conversation_id, limit, query.before.as_deref())
+    let tokens = sm.list_api_tokens(&claims.sub)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    Ok(Json(json!({ "messages": messages })))
+    Ok(Json(json!({ "tokens": tokens })))
 }
 
-// GET /ws?token=JWT
-async fn ws_handler(
-    ws: WebSocketUpgrade,
+// POST /api/me/tokens
+async fn create_api_token(
     State(state): State<ApiState>,
-    Query(query): Query<WsQuery>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let claims = auth::validate_token(&query.token)
-        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<CreateApiTokenRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+    require_session(&claims)?;
 
-    Ok(ws.on_upgrade(move |socket| handle_ws(socket, state, claims)))
-}
+    let name = req.name.trim();
+    if name.is_empty() || req.scopes.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if req.scopes.iter().any(|s| !auth::API_TOKEN_SCOPES.contains(&s.as_str())) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
 
-async fn handle_ws(
-    mut socket: WebSocket,
-    state: ApiState,
-    claims: auth::Claims,
-) {
-    let mut rx = state.msg_tx.subscribe();
-    let user_id = claims.sub.clone();
+    let mut scopes = req.scopes.clone();
+    scopes.sort();
+    scopes.dedup();
 
-    // Load user's conversation IDs for filtering
-    let conversation_ids: Vec<String> = {
-        let sm_lock = state.storage_manager.lock().ok();
-        match sm_lock.as_ref().and_then(|l| l.as_ref()) {
-            Some(sm) => sm.list_conversations(&user_id)
-                .map(|convs| convs.into_iter().map(|c| c.conversation.conversation_id).collect())
-                .unwrap_or_default(),
-            None => vec![],
-        }
-    };
+    if req.expires_in_days.is_some_and(|days| days == 0 || days > auth::MAX_API_TOKEN_DAYS) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let expires_at = req.expires_in_days
+        .map(|days| (chrono::Utc::now() + chrono::Duration::days(days as i64)).to_rfc3339());
 
-    loop {
-        tokio::select! {
-            msg = rx.recv() => {
-                match msg {
-                    Ok(broadcast_msg) => {
-                        if conversation_ids.contains(&broadcast_msg.conversation_id) {
-                            let payload = serde_json::to_string(&broadcast_msg).unwrap_or_default();
-                            if socket.send(WsMessage::Text(payload.into())).await.is_err() {
-                                break;
-                            }
-                        }
-                    }
-                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
-                    Err(_) => break,
-                }
-            }
-            ws_msg = socket.recv() => {
-                match ws_msg {
-                    Some(Ok(_)) => {} // Ignore client messages; sends go through REST
-                    _ => break,
-                }
-            }
-        }
+    let token = auth::generate_api_token()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    // The admin scope unlocks the admin permissions the user's role holds,
+    // so it's only useful, and only granted, if it holds one
+    if scopes.iter().any(|s| s == auth::SCOPE_ADMIN)
+        && !roles::ADMIN_PERMISSIONS.iter().any(|p| require_permission(sm, &claims, p).is_ok())
+    {
+        return Err(StatusCode::FORBIDDEN);
     }
+
+    let api_token = sm.create_api_token(
+        &claims.sub, name, &auth::hash_secret(&token), &scopes, expires_at.as_deref(),
+    ).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sm.audit(Some(&claims.sub), "token.create", Some(&api_token.token_id), Some(&ip), Some(&scopes.join(" ")));
+
+    // The plaintext token is only ever returned here
+    Ok(Json(json!({
+        "token_id": api_token.token_id,
+        "name": api_token.name,
+        "scopes": api_token.scopes,
+        "expires_at": api_token.expires_at,
+        "created_at": api_token.created_at,
+        "token": token,
+    })))
 }
 
-// POST /api/auth/register
-async fn register(
+// DELETE /api/me/tokens/:id
+async fn delete_api_token(
     State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
-    Json(req): Json<RegisterRequest>,
-) -> Result<Json<AuthResponse>, StatusCode> {
-    // Rate limit
-    let ip = get_client_ip(&headers);
-    if !state.auth_limiter.check(&ip) {
-        return Err(StatusCode::TOO_MANY_REQUESTS);
-    }
+    Path(token_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+    require_session(&claims)?;
 
     let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
 
-    // Validate input
-    if req.username.is_empty() || req.email.is_empty() || req.password.is_empty() {
-        return Err(StatusCode::BAD_REQUEST);
+    let deleted = sm.delete_api_token(&claims.sub, &token_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND);
     }
+    sm.audit(Some(&claims.sub), "token.delete", Some(&token_id), Some(&ip), None);
 
-    // Check if username already exists
-    if let Ok(Some(_)) = sm.get_user_by_username(&req.username) {
-        return Err(StatusCode::CONFLICT);
-    }
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    // Hash password
-    let password_hash = auth::hash_password(&req.password)
+// GET /api/admin/audit?actor=&action=&target=&since=&until=&limit=&offset=
+async fn list_audit_events(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Query(query): Query<AuditQuery>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_HUB_MANAGE)?;
+
+    // Bounds are compared as strings, so normalize them to UTC first
+    let bound = |v: Option<String>| v
+        .filter(|v| !v.trim().is_empty())
+        .map(|v| utc_timestamp(&v))
+        .transpose()
+        .map_err(|_| StatusCode::BAD_REQUEST);
+    let filter = AuditFilter {
+        actor: query.actor,
+        action: query.action,
+        target: query.target,
+        since: bound(query.since)?,
+        until: bound(query.until)?,
+    };
+    let limit = query.limit.unwrap_or(100).clamp(1, 500);
+    let (events, total) = sm.list_audit_events(&filter, limit, query.offset.unwrap_or(0))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({ "events": events, "total": total })))
+}
+
+// GET /api/admin/locked-accounts
+async fn list_locked_accounts(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    let accounts = sm.list_locked_accounts()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({ "accounts": accounts })))
+}
+
+// GET /api/admin/users?q=&limit=&offset=
+async fn admin_list_users(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Query(query): Query<UserSearchQuery>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    let limit = query.limit.unwrap_or(50).clamp(1, 200);
+    let (users, total) = sm.search_users(query.q.as_deref(), limit, query.offset.unwrap_or(0))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({ "users": users, "total": total })))
+}
+
+/// Look up the target of an admin action and check the caller may act on it.
+fn admin_target(sm: &StorageManager, claims: &auth::Claims, user_id: &str) -> Result<User, StatusCode> {
+    let user = sm.get_user_by_id(user_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    sm.ensure_can_manage_user(&claims.sub, user_id)
+        .map_err(|_| StatusCode::FORBIDDEN)?;
+    Ok(user)
+}
+
+// POST /api/admin/users/import (text/csv body)
+async fn import_users(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Json<member_csv::ImportReport>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    let report = member_csv::import_members(sm, &claims.sub, &body)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    for member in &report.created {
+        sm.audit(Some(&claims.sub), "user.import", Some(&member.user_id), Some(&ip), Some(&member.role));
+        if member.role != roles::ROLE_GUEST {
+            if let Err(e) = sm.join_default_channels(&member.user_id) {
+                log::error!("Failed to join {} to default channels: {}", member.user_id, e);
+            }
+            broadcast_joined(&state, sm, &member.user_id);
+        }
+    }
+
+    Ok(Json(report))
+}
+
+// GET /api/admin/users/export
+async fn export_users(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    let data = member_csv::export_members(sm)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sm.audit(Some(&claims.sub), "user.export", None, Some(&ip), None);
+
+    let disposition = format!(
+        "attachment; filename=\"citinet-members-{}.csv\"",
+        chrono::Utc::now().format("%Y%m%d"),
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        data,
+    ))
+}
+
+// DELETE /api/admin/users/:id
+async fn admin_delete_user(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+    if claims.sub == user_id {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    let user = admin_target(sm, &claims, &user_id)?;
+    let conversation_ids = sm.list_conversation_ids(&user_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Remaining failures are the last admin / last owner guards
+    sm.delete_user(&user_id).map_err(|_| StatusCode::CONFLICT)?;
+    sm.audit(Some(&claims.sub), "user.delete", Some(&user_id), Some(&ip), Some(&user.username));
+
+    for conversation_id in conversation_ids {
+        if let Ok(members) = sm.get_conversation_members(&conversation_id) {
+            broadcast_members(&state, sm, &conversation_id, EVENT_MEMBERS_REMOVED, vec![user_id.clone()], &members);
+        }
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// POST /api/admin/users/:id/suspend
+async fn suspend_user(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Json(req): Json<SuspendUserRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+    if claims.sub == user_id {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if let Some(until) = &req.until {
+        let until = chrono::DateTime::parse_from_rfc3339(until).map_err(|_| StatusCode::BAD_REQUEST)?;
+        if until <= chrono::Utc::now() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    admin_target(sm, &claims, &user_id)?;
+    let reason = req.reason.as_deref().map(str::trim).filter(|r| !r.is_empty());
+    sm.suspend_user(&user_id, reason, req.until.as_deref())
+        .map_err(|_| StatusCode::CONFLICT)?;
+    sm.audit(Some(&claims.sub), "user.suspend", Some(&user_id), Some(&ip), reason);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// POST /api/admin/users/:id/unsuspend
+async fn unsuspend_user(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    admin_target(sm, &claims, &user_id)?;
+    sm.unsuspend_user(&user_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sm.audit(Some(&claims.sub), "user.unsuspend", Some(&user_id), Some(&ip), None);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// POST /api/admin/users/:id/reset-password
+async fn reset_user_password(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Json(req): Json<ResetPasswordRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let (password, generated) = match req.password {
+        Some(p) if p.is_empty() => return Err(StatusCode::BAD_REQUEST),
+        Some(p) => {
+            auth::check_password_strength(&p, "").map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+            (p, false)
+        }
+        None => {
+            let password = auth::generate_temporary_password()
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            (password, true)
+        }
+    };
+    // Checked before hashing so callers without the permission can't make
+    // the hub do the work
+    {
+        let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+        require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+    }
+    let password_hash = hash_new_password(&password).await?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    let user = admin_target(sm, &claims, &user_id)?;
+    sm.reset_password(&user_id, &password_hash)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    log::info!("Password for '{}' reset by {}", user.username, claims.sub);
+    sm.audit(Some(&claims.sub), "user.password_reset", Some(&user_id), Some(&ip), None);
+
+    // A generated password is returned exactly once
+    if generated {
+        Ok(Json(json!({ "temporary_password": password })))
+    } else {
+        Ok(Json(json!({})))
+    }
+}
+
+// POST /api/admin/users/:id/unlock
+async fn unlock_user(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    sm.get_user_by_id(&user_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    sm.clear_failed_logins(&user_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sm.audit(Some(&claims.sub), "user.unlock", Some(&user_id), Some(&ip), None);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// --- Guests ---
+
+// POST /api/admin/guests
+async fn create_guest(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<CreateGuestRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let username = req.username.trim();
+    if username.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let (password, generated) = match req.password {
+        Some(p) if p.is_empty() => return Err(StatusCode::BAD_REQUEST),
+        Some(p) => {
+            auth::check_password_strength(&p, "").map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+            (p, false)
+        }
+        None => {
+            let password = auth::generate_temporary_password()
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            (password, true)
+        }
+    };
+    // Checked before hashing so callers without the permission can't make
+    // the hub do the work
+    {
+        let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+        require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+    }
+    let password_hash = hash_new_password(&password).await?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    if sm.get_user_by_username(username).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?.is_some() {
+        return Err(StatusCode::CONFLICT);
+    }
+    let guest = sm.create_guest(username, &password_hash, &req.expires_at, &req.conversation_ids)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    sm.audit(Some(&claims.sub), "guest.create", Some(&guest.user_id), Some(&ip), guest.expires_at.as_deref());
+    broadcast_joined(&state, sm, &guest.user_id);
+
+    let mut value = json!(guest);
+    // A generated password is returned exactly once
+    if generated {
+        value["temporary_password"] = json!(password);
+    }
+    Ok(Json(value))
+}
+
+// GET /api/admin/guest-invites
+async fn list_guest_invites(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    let invites = sm.list_guest_invites().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(json!({ "invites": invites })))
+}
+
+// POST /api/admin/guest-invites
+async fn create_guest_invite(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<CreateGuestInviteRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+    if req.max_uses == Some(0) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let token = auth::generate_secret(auth::GUEST_INVITE_PREFIX)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    let invite = sm.create_guest_invite(
+        &claims.sub,
+        &auth::hash_secret(&token),
+        &req.conversation_ids,
+        &req.expires_at,
+        req.max_uses,
+    ).map_err(|_| StatusCode::BAD_REQUEST)?;
+    sm.audit(Some(&claims.sub), "guest_invite.create", Some(&invite.invite_id), Some(&ip), Some(&invite.expires_at));
+
+    // The invite token is only ever shown here
+    let mut value = json!(invite);
+    value["token"] = json!(token);
+    Ok(Json(value))
+}
+
+// DELETE /api/admin/guest-invites/:id
+async fn delete_guest_invite(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(invite_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    if !sm.delete_guest_invite(&invite_id).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    sm.audit(Some(&claims.sub), "guest_invite.delete", Some(&invite_id), Some(&ip), None);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// GET /api/admin/roles
+async fn list_roles(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    // Needed both to edit roles and to assign them
+    require_permission(sm, &claims, roles::PERM_ROLES_MANAGE)
+        .or_else(|_| require_permission(sm, &claims, roles::PERM_USERS_MANAGE))?;
+
+    let all = sm.list_roles().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({ "roles": all, "permissions": roles::ALL_PERMISSIONS })))
+}
+
+// POST /api/admin/roles
+async fn create_role(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<RoleRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_ROLES_MANAGE)?;
+    sm.ensure_can_grant(&claims.sub, &req.permissions)
+        .map_err(|_| StatusCode::FORBIDDEN)?;
+
+    let role = sm.create_role(&claims.sub, req.name.trim(), &req.permissions)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    sm.audit(Some(&claims.sub), "role.create", Some(&role.role_id), Some(&ip), Some(&role.permissions.join(" ")));
+
+    Ok(Json(json!(role)))
+}
+
+// PATCH /api/admin/roles/:id
+async fn update_role(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(role_id): Path<String>,
+    Json(req): Json<RoleRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_ROLES_MANAGE)?;
+
+    let existing = sm.get_role(&role_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    sm.ensure_can_grant(&claims.sub, &existing.permissions)
+        .and_then(|_| sm.ensure_can_grant(&claims.sub, &req.permissions))
+        .map_err(|_| StatusCode::FORBIDDEN)?;
+    let role = sm.update_role(&claims.sub, &role_id, req.name.trim(), &req.permissions)
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    sm.audit(Some(&claims.sub), "role.update", Some(&role_id), Some(&ip), Some(&role.permissions.join(" ")));
+
+    Ok(Json(json!(role)))
+}
+
+// DELETE /api/admin/roles/:id
+async fn delete_role(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(role_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_ROLES_MANAGE)?;
+
+    // Built-in roles and roles still in use can't be deleted
+    let deleted = sm.delete_role(&role_id).map_err(|_| StatusCode::CONFLICT)?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    sm.audit(Some(&claims.sub), "role.delete", Some(&role_id), Some(&ip), None);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// PUT /api/admin/users/:id/role
+async fn set_user_role(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(user_id): Path<String>,
+    Json(req): Json<SetUserRoleRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_USERS_MANAGE)?;
+
+    sm.get_user_by_id(&user_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    sm.get_role(&req.role)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    // Remaining failures are the owner-role and permission-subset rules
+    sm.set_user_role(&claims.sub, &user_id, &req.role)
+        .map_err(|_| StatusCode::FORBIDDEN)?;
+    sm.audit(Some(&claims.sub), "user.role", Some(&user_id), Some(&ip), Some(&req.role));
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// GET /api/files
+async fn list_files(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    // Validate authentication and get user claims
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_FILES_READ)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_FILES_READ)?;
+
+    let files = sm.list_files(Some(&claims.sub)).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let file_list: Vec<Value> = files.iter().map(|f| {
+        json!({
+            "file_id": f.file_id,
+            "file_name": f.file_name,
+            "size_bytes": f.size_bytes,
+            "is_public": f.is_public,
+            "description": f.description,
+            "owner_id": f.user_id,
+            "created_at": f.created_at,
+        })
+    }).collect();
+
+    Ok(Json(json!(file_list)))
+}
+
+// POST /api/files (multipart/form-data)
+async fn upload_file(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Result<Json<Value>, StatusCode> {
+    // Validate authentication and get user claims
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_FILES_WRITE)?;
+    {
+        let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+        require_permission(sm, &claims, roles::PERM_FILES_UPLOAD)?;
+    }
+
+    let mut file_name = String::new();
+    let mut file_data = Vec::new();
+    let mut is_public = false;
+
+    while let Ok(Some(field)) = multipart.next_field().await {
+        let name = field.name().unwrap_or("").to_string();
+        if name == "file" {
+            file_name = field.file_name().unwrap_or("upload").to_string();
+            if let Ok(bytes) = field.bytes().await {
+                file_data = bytes.to_vec();
+            }
+        } else if name == "is_public" {
+            if let Ok(text) = field.text().await {
+                is_public = text == "true" || text == "1";
+            }
+        }
+    }
+
+    if file_name.is_empty() || file_data.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let file = sm.upload_file(&claims.sub, &file_name, &file_data, is_public)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "file_id": file.file_id,
+        "file_name": file.file_name,
+        "size_bytes": file_data.len(),
+    })))
+}
+
+// GET /api/files/:name
+async fn download_file(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
+    // Validate authentication and get user claims
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_FILES_READ)?;
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_FILES_READ)?;
+
+    let data = sm.read_file(&claims.sub, &name).map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let content_type = mime_from_ext(&name);
+    let disposition = if content_type.starts_with("image/") || content_type.starts_with("video/") {
+        format!("inline; filename=\"{}\"", name)
+    } else {
+        format!("attachment; filename=\"{}\"", name)
+    };
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        data,
+    ))
+}
+
+// DELETE /api/files/:name
+async fn delete_file_handler(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    // Validate authentication and get user claims
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+    require_scope(&claims, auth::SCOPE_FILES_WRITE)?;
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    sm.delete_file(&claims.sub, &name).map_err(|_| StatusCode::NOT_FOUND)?;
+    sm.audit(Some(&claims.sub), "file.delete", Some(&name), Some(&ip), None);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// PATCH /api/files/:name
+async fn update_file_handler(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    Json(body): Json<UpdateFileRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+    require_scope(&claims, auth::SCOPE_FILES_WRITE)?;
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    if body.description.as_ref()
+        .is_some_and(|d| d.trim().chars().count() > MAX_FILE_DESCRIPTION_LEN)
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if let Some(is_public) = body.is_public {
+        sm.update_file_visibility(&claims.sub, &name, is_public)
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+        let visibility = if is_public { "public" } else { "private" };
+        sm.audit(Some(&claims.sub), "file.visibility", Some(&name), Some(&ip), Some(visibility));
+    }
+    if let Some(description) = &body.description {
+        sm.update_file_description(&claims.sub, &name, description)
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+        sm.audit(Some(&claims.sub), "file.description", Some(&name), Some(&ip), None);
+    }
+
+    Ok(StatusCode::OK)
+}
+
+// Helper function to validate a JWT or personal access token from Authorization header
+fn validate_auth_header(state: &ApiState, headers: &HeaderMap) -> Result<auth::Claims, StatusCode> {
+    let auth_header = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let token = auth::extract_bearer_token(auth_header)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    validate_bearer(state, token)
+}
+
+/// Like `resolve_bearer`, but refuses OIDC access tokens: those are issued to
+/// third-party apps and only grant the userinfo endpoint.
+fn validate_bearer(state: &ApiState, token: &str) -> Result<auth::Claims, StatusCode> {
+    let claims = resolve_bearer(state, token)?;
+    if claims.scopes.as_ref().is_some_and(|scopes| scopes.iter().any(|s| s == "openid")) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(claims)
+}
+
+/// Resolve a bearer credential to claims. Session JWTs are verified by
+/// signature; personal access tokens are looked up by hash and carry their
+/// granted scopes. Either is refused while the user is suspended or, for
+/// guests, once their account has expired.
+fn resolve_bearer(state: &ApiState, token: &str) -> Result<auth::Claims, StatusCode> {
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    if !auth::is_api_token(token) {
+        let claims = auth::validate_token(token)
+            .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        // Revoked by a password reset
+        if sm.is_session_revoked(&claims.sub, claims.iat).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        // JWTs issued before a suspension stay cryptographically valid
+        if sm.is_suspended(&claims.sub).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        // ...and so do those of guests whose account has run out
+        if sm.is_expired(&claims.sub).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+            return Err(StatusCode::UNAUTHORIZED);
+        }
+        return Ok(claims);
+    }
+
+    let api_token = sm.use_api_token(&auth::hash_secret(token))
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let user = sm.get_user_by_id(&api_token.user_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if user.suspension.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if user.is_expired() {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let exp = api_token.expires_at.as_deref()
+        .and_then(|e| chrono::DateTime::parse_from_rfc3339(e).ok())
+        .map(|e| e.timestamp())
+        .unwrap_or(0); // 0 = never expires
+    let iat = chrono::DateTime::parse_from_rfc3339(&api_token.created_at)
+        .map(|c| c.timestamp())
+        .unwrap_or(0);
+
+    Ok(auth::Claims {
+        sub: user.user_id,
+        username: user.username,
+        is_admin: user.is_admin && api_token.scopes.iter().any(|s| s == auth::SCOPE_ADMIN),
+        exp,
+        iat,
+        scopes: Some(api_token.scopes),
+    })
+}
+
+/// Token management requires an interactive session, so a leaked token
+/// cannot be used to mint further tokens.
+fn require_session(claims: &auth::Claims) -> Result<(), StatusCode> {
+    if claims.scopes.is_none() {
+        Ok(())
+    } else {
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+/// Shared authorization check: the caller's role must grant the permission,
+/// and a scoped token needs the admin scope for administrative permissions.
+/// Roles are read per request, so role changes apply to existing tokens.
+fn require_permission(sm: &StorageManager, claims: &auth::Claims, permission: &str) -> Result<(), StatusCode> {
+    if roles::ADMIN_PERMISSIONS.contains(&permission) && !claims.has_scope(auth::SCOPE_ADMIN) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    sm.require_permission(&claims.sub, permission)
+        .map_err(|_| StatusCode::FORBIDDEN)
+}
+
+fn require_scope(claims: &auth::Claims, scope: &str) -> Result<(), StatusCode> {
+    if claims.has_scope(scope) {
+        Ok(())
+    } else {
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+fn mime_from_ext(name: &str) -> String {
+    match name.rsplit('.').next().map(|e| e.to_lowercase()).as_deref() {
+        Some("jpg") | Some("jpeg") => "image/jpeg".to_string(),
+        Some("png") => "image/png".to_string(),
+        Some("gif") => "image/gif".to_string(),
+        Some("webp") => "image/webp".to_string(),
+        Some("svg") => "image/svg+xml".to_string(),
+        Some("bmp") => "image/bmp".to_string(),
+        Some("mp4") | Some("m4v") => "video/mp4".to_string(),
+        Some("webm") => "video/webm".to_string(),
+        Some("mov") => "video/quicktime".to_string(),
+        Some("avi") => "video/x-msvideo".to_string(),
+        Some("mkv") => "video/x-matroska".to_string(),
+        Some("ogv") => "video/ogg".to_string(),
+        Some("3gp") => "video/3gpp".to_string(),
+        Some("pdf") => "application/pdf".to_string(),
+        Some("txt") => "text/plain".to_string(),
+        Some("html") | Some("htm") => "text/html".to_string(),
+        Some("json") => "application/json".to_string(),
+        Some("zip") => "application/zip".to_string(),
+        _ => "application/octet-stream".to_string(),
+    }
+}
+
+// POST /api/conversations
+async fn create_conversation(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(req): Json<CreateConversationRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_MESSAGES_SEND)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_MESSAGES_SEND)?;
+    // Choosing who to talk to needs the member directory, so guests only
+    // take part in the conversations they were added to
+    require_permission(sm, &claims, roles::PERM_MEMBERS_VIEW)?;
+
+    match req.kind.as_str() {
+        "dm" => {
+            let peer_id = req.peer_user_id.as_deref().ok_or(StatusCode::BAD_REQUEST)?;
+            if peer_id == claims.sub {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            sm.get_user_by_id(peer_id)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .ok_or(StatusCode::NOT_FOUND)?;
+            // Respect the peer's contact preference; moderators can still reach them
+            let peer_profile = sm.get_profile(peer_id)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            if !peer_profile.allow_direct_messages
+                && require_permission(sm, &claims, roles::PERM_CONTENT_MODERATE).is_err()
+            {
+                return Err(StatusCode::FORBIDDEN);
+            }
+            let (conv, created) = sm.create_dm_conversation(&claims.sub, peer_id)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let members = sm.get_conversation_members(&conv.conversation_id)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            // Reopening an existing DM changes nothing, so it isn't logged
+            if created {
+                broadcast_members(&state, sm, &conv.conversation_id, EVENT_MEMBERS_ADDED,
+                    members.iter().map(|m| m.user_id.clone()).collect(), &members);
+            }
+            Ok(Json(json!({
+                "conversation_id": conv.conversation_id,
+                "kind": conv.kind,
+                "name": conv.name,
+                "members": members,
+                "created_at": conv.created_at,
+            })))
+        }
+        "group" => {
+            let name = req.name.as_deref().ok_or(StatusCode::BAD_REQUEST)?;
+            check_conversation_name(name)?;
+            let member_ids = req.member_ids.as_ref().ok_or(StatusCode::BAD_REQUEST)?;
+            let conv = sm.create_group_conversation(&claims.sub, name, member_ids)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let members = sm.get_conversation_members(&conv.conversation_id)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            broadcast_members(&state, sm, &conv.conversation_id, EVENT_MEMBERS_ADDED,
+                members.iter().map(|m| m.user_id.clone()).collect(), &members);
+            Ok(Json(json!({
+                "conversation_id": conv.conversation_id,
+                "kind": conv.kind,
+                "name": conv.name,
+                "members": members,
+                "created_at": conv.created_at,
+            })))
+        }
+        "channel" => {
+            let name = req.name.as_deref().map(str::trim).filter(|n| !n.is_empty())
+                .ok_or(StatusCode::BAD_REQUEST)?;
+            check_conversation_name(name)?;
+            let is_default = req.is_default.unwrap_or(false);
+            if is_default {
+                require_permission(sm, &claims, roles::PERM_HUB_MANAGE)?;
+            }
+            let conv = sm.create_channel(&claims.sub, name, req.announcement_only.unwrap_or(false), is_default)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            let members = sm.get_conversation_members(&conv.conversation_id)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            broadcast_members(&state, sm, &conv.conversation_id, EVENT_MEMBERS_ADDED,
+                vec![claims.sub.clone()], &members);
+            Ok(Json(json!({
+                "conversation_id": conv.conversation_id,
+                "kind": conv.kind,
+                "name": conv.name,
+                "announcement_only": conv.announcement_only,
+                "is_default": conv.is_default,
+                "members": members,
+                "created_at": conv.created_at,
+            })))
+        }
+        _ => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+// GET /api/channels
+async fn list_channels(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_MESSAGES_READ)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_MESSAGES_SEND)?;
+    // Like starting a conversation, browsing channels is for full members
+    require_permission(sm, &claims, roles::PERM_MEMBERS_VIEW)?;
+
+    let channels = sm.list_channels(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({ "channels": channels })))
+}
+
+fn check_conversation_name(name: &str) -> Result<(), StatusCode> {
+    if name.chars().count() > MAX_CONVERSATION_NAME_LEN {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(())
+}
+
+/// Look up a channel by ID; other kinds of conversation aren't found
+fn find_channel(sm: &StorageManager, conversation_id: &str) -> Result<Conversation, StatusCode> {
+    sm.get_conversation(conversation_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|conv| conv.kind == "channel")
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+/// The creator and moderators manage a channel and can always post in it
+fn can_manage_channel(sm: &StorageManager, claims: &auth::Claims, conv: &Conversation) -> bool {
+    conv.created_by == claims.sub || require_permission(sm, claims, roles::PERM_CONTENT_MODERATE).is_ok()
+}
+
+// POST /api/conversations/:id/join
+async fn join_channel(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path(conversation_id): Path<String>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_MESSAGES_SEND)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_MESSAGES_SEND)?;
+    require_permission(sm, &claims, roles::PERM_MEMBERS_VIEW)?;
+
+    find_channel(sm, &conversation_id)?;
+    if sm.add_group_member(&conversation_id, &claims.sub).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        let members = sm.get_conversation_members(&conversation_id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        broadcast_members(&state, sm, &conversation_id, EVENT_MEMBERS_ADDED, vec![claims.sub.clone()], &members);
+    }
+
+    let conv = sm.get_member_conversation(&conversation_id, &claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(json!(conv)))
+}
+
+// POST /api/conversations/:id/leave
+async fn leave_channel(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path(conversation_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_MESSAGES_SEND)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_MESSAGES_SEND)?;
+
+    find_channel(sm, &conversation_id)?;
+    if sm.remove_group_member(&conversation_id, &claims.sub).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        let members = sm.get_conversation_members(&conversation_id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        broadcast_members(&state, sm, &conversation_id, EVENT_MEMBERS_REMOVED, vec![claims.sub.clone()], &members);
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// GET /api/conversations
+async fn list_conversations_handler(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_MESSAGES_READ)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_MESSAGES_SEND)?;
+
+    let convs = sm.list_conversations(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({ "conversations": convs })))
+}
+
+// PATCH /api/conversations/:id
+async fn update_conversation(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path(conversation_id): Path<String>,
+    Json(req): Json<UpdateConversationRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_MESSAGES_SEND)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_MESSAGES_SEND)?;
+
+    // Verify caller is a member
+    let is_member = sm.is_conversation_member(&conversation_id, &claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !is_member {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let conv = sm.get_conversation(&conversation_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if let Some(name) = &req.name {
+        check_conversation_name(name)?;
+    }
+    if conv.kind == "channel" {
+        // Any member can invite others, but only those who manage the
+        // channel can change it or remove someone else
+        let removes_others = req.remove_members.iter().flatten().any(|uid| *uid != claims.sub);
+        if (req.name.is_some() || req.announcement_only.is_some() || removes_others)
+            && !can_manage_channel(sm, &claims, &conv)
+        {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        if req.is_default.is_some() {
+            require_permission(sm, &claims, roles::PERM_HUB_MANAGE)?;
+        }
+        if req.announcement_only.is_some() || req.is_default.is_some() {
+            sm.update_channel(&conversation_id, req.announcement_only, req.is_default)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        }
+    } else if req.announcement_only.is_some() || req.is_default.is_some() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if let Some(name) = &req.name {
+        sm.rename_conversation(&conversation_id, name)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    if req.add_members.is_some() || req.remove_members.is_some() {
+        require_permission(sm, &claims, roles::PERM_MEMBERS_VIEW)?;
+    }
+    let mut added = Vec::new();
+    for uid in req.add_members.iter().flatten() {
+        if sm.add_group_member(&conversation_id, uid).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+            added.push(uid.clone());
+        }
+    }
+    let mut removed = Vec::new();
+    for uid in req.remove_members.iter().flatten() {
+        if sm.remove_group_member(&conversation_id, uid).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+            removed.push(uid.clone());
+        }
+    }
+
+    let members = sm.get_conversation_members(&conversation_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !added.is_empty() {
+        broadcast_members(&state, sm, &conversation_id, EVENT_MEMBERS_ADDED, added, &members);
+    }
+    if !removed.is_empty() {
+        broadcast_members(&state, sm, &conversation_id, EVENT_MEMBERS_REMOVED, removed, &members);
+    }
+
+    Ok(Json(json!({ "ok": true, "members": members })))
+}
+
+// POST /api/conversations/:id/read
+async fn mark_conversation_read(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path(conversation_id): Path<String>,
+    Json(req): Json<MarkReadRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    // Moving a read cursor only needs read access
+    require_scope(&claims, auth::SCOPE_MESSAGES_READ)?;
+
+    let unread_count = {
+        let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+        let is_member = sm.is_conversation_member(&conversation_id, &claims.sub)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if !is_member {
+            return Err(StatusCode::FORBIDDEN);
+        }
+
+        let member = sm.mark_conversation_read(&conversation_id, &claims.sub, req.message_id.as_deref())
+            .map_err(|_| StatusCode::NOT_FOUND)?;
+        let unread_count = sm.unread_count(&conversation_id, &claims.sub)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        // Only announce cursors that moved, so repeated calls stay quiet
+        if let Some(member) = &member {
+            broadcast_event(&state, sm, EVENT_CONVERSATION_READ, &conversation_id, None, json!(member));
+        }
+        unread_count
+    };
+
+    Ok(Json(json!({ "ok": true, "unread_count": unread_count })))
+}
+
+// POST /api/conversations/:id/messages
+async fn send_message(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path(conversation_id): Path<String>,
+    Json(req): Json<SendMessageRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+
+    let message = post_message(&state, &claims, &conversation_id, &req)?;
+    Ok(Json(json!(message)))
+}
+
+/// Post a message for the caller and broadcast it. Shared by REST and the
+/// WebSocket. A repeated `client_id` returns the original message without
+/// posting or broadcasting again.
+fn post_message(
+    state: &ApiState,
+    claims: &auth::Claims,
+    conversation_id: &str,
+    req: &SendMessageRequest,
+) -> Result<Message, StatusCode> {
+    require_scope(claims, auth::SCOPE_MESSAGES_SEND)?;
+
+    if req.body.is_empty() && req.attachment_ids.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if req.client_id.as_ref().is_some_and(|id| id.is_empty() || id.len() > MAX_CLIENT_ID_LEN) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let tunnel_url = tunnel_url(state);
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, claims, roles::PERM_MESSAGES_SEND)?;
+
+    let is_member = sm.is_conversation_member(conversation_id, &claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !is_member {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let conv = sm.get_conversation(conversation_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if conv.announcement_only && !can_manage_channel(sm, claims, &conv) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    if let Some(client_id) = &req.client_id {
+        let existing = sm.find_message_by_client_id(&claims.sub, client_id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if let Some(existing) = existing {
+            if existing.conversation_id != conversation_id {
+                return Err(StatusCode::CONFLICT);
+            }
+            return Ok(existing);
+        }
+    }
+
+    if let Some(parent_id) = &req.parent_id {
+        let parent = conversation_message(sm, claims, conversation_id, parent_id)?;
+        if parent.deleted_at.is_some() {
+            return Err(StatusCode::CONFLICT);
+        }
+    }
+
+    let message = sm.create_message(
+        conversation_id,
+        &claims.sub,
+        &req.body,
+        &req.attachment_ids,
+        req.parent_id.as_deref(),
+        req.client_id.as_deref(),
+    ).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if let Err(e) = mailer::notify_new_message(sm, &hub_name(sm), conversation_id, &claims.sub, &message.sender_username, tunnel_url.as_deref()) {
+        log::error!("Failed to queue message notifications: {}", e);
+    }
+    let parent = thread_parent(sm, &message)?;
+
+    // Broadcast to WebSocket subscribers
+    broadcast_event(state, sm, EVENT_MESSAGE_CREATED, conversation_id, message.parent_id.as_deref(), json!(message));
+    if let Some(parent) = parent {
+        broadcast_event(state, sm, EVENT_THREAD_UPDATED, conversation_id, Some(&parent.message_id), json!(parent));
+    }
+
+    Ok(message)
+}
+
+/// Log an event for the conversation's members, and anyone named in its
+/// `user_ids`, then push it to their WebSockets. Called with the storage
+/// lock held, so sequence numbers reach each socket in order.
+fn broadcast_event(
+    state: &ApiState,
+    sm: &StorageManager,
+    event: &str,
+    conversation_id: &str,
+    thread_id: Option<&str>,
+    message: Value,
+) {
+    let mut recipients: Vec<String> = sm.get_conversation_members(conversation_id)
+        .map(|members| members.into_iter().map(|m| m.user_id).collect())
+        .unwrap_or_default();
+    if let Some(user_ids) = message["user_ids"].as_array() {
+        recipients.extend(user_ids.iter().filter_map(|id| id.as_str().map(str::to_string)));
+    }
+
+    let mut broadcast_msg = BroadcastMessage {
+        event: event.to_string(),
+        seq: None,
+        conversation_id: conversation_id.to_string(),
+        thread_id: thread_id.map(str::to_string),
+        message,
+        seqs: HashMap::new(),
+        audience: None,
+    };
+    let payload = serde_json::to_string(&broadcast_msg).unwrap_or_default();
+    match sm.record_event(&recipients, &payload) {
+        Ok(seqs) => broadcast_msg.seqs = seqs,
+        Err(e) => log::error!("Failed to record event: {}", e),
+    }
+    let _ = state.msg_tx.send(broadcast_msg);
+}
+
+/// Push an event that isn't worth replaying, such as typing
+fn broadcast_ephemeral(state: &ApiState, event: &str, conversation_id: &str, thread_id: Option<&str>, message: Value) {
+    let _ = state.msg_tx.send(BroadcastMessage {
+        event: event.to_string(),
+        seq: None,
+        conversation_id: conversation_id.to_string(),
+        thread_id: thread_id.map(str::to_string),
+        message,
+        seqs: HashMap::new(),
+        audience: None,
+    });
+}
+
+/// Record a change in the user's presence and tell everyone who shares a
+/// conversation with them, unless they've chosen to hide it
+fn announce_presence(state: &ApiState, user_id: &str, presence: Presence) {
+    let Ok(sm_lock) = state.storage_manager.lock() else { return };
+    let Some(sm) = sm_lock.as_ref() else { return };
+
+    // Away keeps the time they were last active
+    let now = chrono::Utc::now().to_rfc3339();
+    if presence != Presence::Away {
+        if let Err(e) = sm.set_last_seen(user_id, &now) {
+            log::error!("Failed to record last seen: {}", e);
+        }
+    }
+    if !sm.get_profile(user_id).map(|p| p.show_presence).unwrap_or(false) {
+        return;
+    }
+    broadcast_presence(state, sm, user_id, presence, Some(&now));
+}
+
+/// Send a presence change to everyone sharing a conversation with the
+/// user, whatever their presence setting
+fn broadcast_presence(state: &ApiState, sm: &StorageManager, user_id: &str, presence: Presence, last_seen_at: Option<&str>) {
+    let peers = sm.conversation_peers(user_id).unwrap_or_default();
+    if peers.is_empty() {
+        return;
+    }
+    let _ = state.msg_tx.send(BroadcastMessage {
+        event: EVENT_PRESENCE.to_string(),
+        seq: None,
+        conversation_id: String::new(),
+        thread_id: None,
+        message: json!({
+            "user_id": user_id,
+            "presence": presence,
+            "last_seen_at": last_seen_at,
+        }),
+        seqs: HashMap::new(),
+        audience: Some(peers),
+    });
+}
+
+/// Tell a conversation that members joined or left. Each socket also
+/// (un)subscribes itself when its own user is among `user_ids`.
+fn broadcast_members(
+    state: &ApiState,
+    sm: &StorageManager,
+    conversation_id: &str,
+    event: &str,
+    user_ids: Vec<String>,
+    members: &[ConversationMember],
+) {
+    broadcast_event(state, sm, event, conversation_id, None, json!({
+        "user_ids": user_ids,
+        "members": members,
+    }));
+}
+
+/// Announce a new account's conversation memberships, e.g. a guest's
+/// groups or a new member's default channels
+fn broadcast_joined(state: &ApiState, sm: &StorageManager, user_id: &str) {
+    for conversation_id in sm.list_conversation_ids(user_id).unwrap_or_default() {
+        if let Ok(members) = sm.get_conversation_members(&conversation_id) {
+            broadcast_members(state, sm, &conversation_id, EVENT_MEMBERS_ADDED, vec![user_id.to_string()], &members);
+        }
+    }
+}
+
+/// The thread a reply belongs to, reloaded for its current reply count
+fn thread_parent(sm: &StorageManager, message: &Message) -> Result<Option<Message>, StatusCode> {
+    match &message.parent_id {
+        Some(parent_id) => sm.get_message(parent_id).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR),
+        None => Ok(None),
+    }
+}
+
+/// Look up a message in a conversation the caller belongs to
+fn conversation_message(
+    sm: &StorageManager,
+    claims: &auth::Claims,
+    conversation_id: &str,
+    message_id: &str,
+) -> Result<Message, StatusCode> {
+    let is_member = sm.is_conversation_member(conversation_id, &claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !is_member {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    sm.get_message(message_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .filter(|m| m.conversation_id == conversation_id)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+// PATCH /api/conversations/:id/messages/:message_id
+async fn edit_message(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path((conversation_id, message_id)): Path<(String, String)>,
+    Json(req): Json<EditMessageRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_MESSAGES_SEND)?;
+
+    let message = {
+        let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+        require_permission(sm, &claims, roles::PERM_MESSAGES_SEND)?;
+
+        let existing = conversation_message(sm, &claims, &conversation_id, &message_id)?;
+        if existing.sender_id != claims.sub {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        if existing.deleted_at.is_some() {
+            return Err(StatusCode::CONFLICT);
+        }
+        if req.body.is_empty() && existing.attachments.is_empty() {
+            return Err(StatusCode::BAD_REQUEST);
+        }
+
+        let message = sm.edit_message(&message_id, &claims.sub, &req.body)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        broadcast_event(&state, sm, EVENT_MESSAGE_EDITED, &conversation_id, message.parent_id.as_deref(), json!(message));
+        message
+    };
+
+    Ok(Json(json!(message)))
+}
+
+// DELETE /api/conversations/:id/messages/:message_id
+async fn delete_message(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path((conversation_id, message_id)): Path<(String, String)>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_MESSAGES_SEND)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let message = {
+        let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+        require_permission(sm, &claims, roles::PERM_MESSAGES_SEND)?;
+
+        // Senders can delete their own messages; moderators anyone's
+        let existing = conversation_message(sm, &claims, &conversation_id, &message_id)?;
+        let is_sender = existing.sender_id == claims.sub;
+        if !is_sender {
+            require_permission(sm, &claims, roles::PERM_CONTENT_MODERATE)?;
+        }
+
+        let message = sm.delete_message(&message_id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if !is_sender {
+            sm.audit(Some(&claims.sub), "message.delete", Some(&message_id), Some(&ip),
+                Some(&format!("sender={} conversation={}", existing.sender_username, conversation_id)));
+        }
+        broadcast_event(&state, sm, EVENT_MESSAGE_DELETED, &conversation_id, message.parent_id.as_deref(), json!(message));
+        if let Some(parent) = thread_parent(sm, &message)? {
+            broadcast_event(&state, sm, EVENT_THREAD_UPDATED, &conversation_id, Some(&parent.message_id), json!(parent));
+        }
+        message
+    };
+
+    Ok(Json(json!(message)))
+}
+
+// PUT /api/conversations/:id/messages/:message_id/reactions/:emoji
+async fn add_reaction(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path((conversation_id, message_id, emoji)): Path<(String, String, String)>,
+) -> Result<Json<Value>, StatusCode> {
+    update_reaction(state, headers, conversation_id, message_id, emoji, true).await
+}
+
+// DELETE /api/conversations/:id/messages/:message_id/reactions/:emoji
+async fn remove_reaction(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path((conversation_id, message_id, emoji)): Path<(String, String, String)>,
+) -> Result<Json<Value>, StatusCode> {
+    update_reaction(state, headers, conversation_id, message_id, emoji, false).await
+}
+
+/// Add or remove the caller's reaction and tell the conversation when
+/// anything changed. Both directions are idempotent.
+async fn update_reaction(
+    state: ApiState,
+    headers: HeaderMap,
+    conversation_id: String,
+    message_id: String,
+    emoji: String,
+    add: bool,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_MESSAGES_SEND)?;
+
+    let reaction_json = {
+        let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+        require_permission(sm, &claims, roles::PERM_MESSAGES_SEND)?;
+
+        let message = conversation_message(sm, &claims, &conversation_id, &message_id)?;
+        if message.deleted_at.is_some() {
+            return Err(StatusCode::CONFLICT);
+        }
+
+        let changed = if add {
+            sm.add_reaction(&message_id, &claims.sub, &emoji)
+                .map_err(|_| StatusCode::BAD_REQUEST)?
+        } else {
+            sm.remove_reaction(&message_id, &claims.sub, &emoji)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        };
+        let reactions = sm.list_reactions(&message_id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        let reaction_json = json!({
+            "message_id": message_id,
+            "user_id": claims.sub,
+            "emoji": emoji,
+            "reactions": reactions,
+        });
+        if changed {
+            let event = if add { EVENT_REACTION_ADDED } else { EVENT_REACTION_REMOVED };
+            broadcast_event(&state, sm, event, &conversation_id, message.parent_id.as_deref(), reaction_json.clone());
+        }
+        reaction_json
+    };
+
+    Ok(Json(reaction_json))
+}
+
+// GET /api/conversations/:id/messages/:message_id/thread
+async fn get_thread(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path((conversation_id, message_id)): Path<(String, String)>,
+    Query(query): Query<MessagesQuery>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_MESSAGES_READ)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_MESSAGES_SEND)?;
+
+    let parent = conversation_message(sm, &claims, &conversation_id, &message_id)?;
+    if parent.parent_id.is_some() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+
+    let limit = query.limit.unwrap_or(50).min(100);
+    let replies = sm.list_thread(&message_id, limit, query.before.as_deref())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({ "parent": parent, "replies": replies })))
+}
+
+// GET /api/conversations/:id/messages/:message_id/history
+async fn get_message_history(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path((conversation_id, message_id)): Path<(String, String)>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_MESSAGES_READ)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_MESSAGES_SEND)?;
+
+    let message = conversation_message(sm, &claims, &conversation_id, &message_id)?;
+    let edits = sm.list_message_edits(&message_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({ "message": message, "edits": edits })))
+}
+
+// GET /api/conversations/:id/messages
+async fn get_messages(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Path(conversation_id): Path<String>,
+    Query(query): Query<MessagesQuery>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_scope(&claims, auth::SCOPE_MESSAGES_READ)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_MESSAGES_SEND)?;
+
+    let is_member = sm.is_conversation_member(&conversation_id, &claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !is_member {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let limit = query.limit.unwrap_or(50).min(100);
+    let messages = sm.list_messages(&conversation_id, limit, query.before.as_deref())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({ "messages": messages })))
+}
+
+// GET /api/search?q=...[&limit=N]
+// Messages come from the caller's conversations and files from those they
+// can read; each half needs its token scope and role permission.
+async fn search(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    if query.q.chars().count() > MAX_SEARCH_QUERY_LEN {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    let can_search_messages = require_scope(&claims, auth::SCOPE_MESSAGES_READ).is_ok()
+        && require_permission(sm, &claims, roles::PERM_MESSAGES_SEND).is_ok();
+    let can_search_files = require_scope(&claims, auth::SCOPE_FILES_READ).is_ok()
+        && require_permission(sm, &claims, roles::PERM_FILES_READ).is_ok();
+    if !can_search_messages && !can_search_files {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let limit = query.limit.unwrap_or(20).min(50);
+    let messages = if can_search_messages {
+        sm.search_messages(&claims.sub, &query.q, limit)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    } else {
+        Vec::new()
+    };
+    let files = if can_search_files {
+        sm.search_files(&claims.sub, &query.q, limit)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    } else {
+        Vec::new()
+    };
+
+    let files: Vec<Value> = files.iter().map(|f| {
+        json!({
+            "file_id": f.file_id,
+            "file_name": f.file_name,
+            "size_bytes": f.size_bytes,
+            "is_public": f.is_public,
+            "description": f.description,
+            "owner_id": f.user_id,
+            "created_at": f.created_at,
+        })
+    }).collect();
+
+    Ok(Json(json!({ "messages": messages, "files": files })))
+}
+
+// GET /ws?token=JWT[&since=SEQ]
+async fn ws_handler(
+    ws: WebSocketUpgrade,
+    State(state): State<ApiState>,
+    Query(query): Query<WsQuery>,
+) -> Result<impl IntoResponse, StatusCode> {
+    let claims = validate_bearer(&state, &query.token)?;
+    require_scope(&claims, auth::SCOPE_MESSAGES_READ)?;
+
+    Ok(ws.on_upgrade(move |socket| handle_ws(socket, state, claims, query.since)))
+}
+
+/// What a socket knows about its user's realtime stream
+struct WsSubscription {
+    user_id: String,
+    conversation_ids: Vec<String>,
+    /// Highest sequence number delivered, to skip live events that a
+    /// replay already covered
+    last_seq: u64,
+}
+
+async fn handle_ws(
+    mut socket: WebSocket,
+    state: ApiState,
+    claims: auth::Claims,
+    since: Option<u64>,
+) {
+    let user_id = claims.sub.clone();
+
+    // A fresh client starts from the sequence number at connect time. It's
+    // read before subscribing, and the replay below covers anything
+    // recorded in between.
+    let start_seq = match since {
+        Some(since) => since,
+        None => {
+            let sm_lock = state.storage_manager.lock().ok();
+            sm_lock.as_ref().and_then(|l| l.as_ref())
+                .and_then(|sm| sm.event_seq(&user_id).ok())
+                .unwrap_or(0)
+        }
+    };
+    let mut rx = state.msg_tx.subscribe();
+
+    // Load user's conversation IDs for filtering; membership events keep
+    // them current
+    let (conversation_ids, latest_seq) = {
+        let sm_lock = state.storage_manager.lock().ok();
+        match sm_lock.as_ref().and_then(|l| l.as_ref()) {
+            Some(sm) => (
+                sm.list_conversation_ids(&user_id).unwrap_or_default(),
+                sm.event_seq(&user_id).unwrap_or(0),
+            ),
+            None => (vec![], 0),
+        }
+    };
+    let mut sub = WsSubscription { user_id: user_id.clone(), conversation_ids, last_seq: start_seq };
+
+    let hello_seq = if since.is_some() { latest_seq } else { start_seq };
+    let hello = WsReplyFrame::Hello { protocol: WS_PROTOCOL_VERSION, user_id: user_id.clone(), seq: hello_seq };
+    if send_ws_frame(&mut socket, &hello).await.is_err() {
+        return;
+    }
+    if replay_events(&mut socket, &state, &mut sub).await.is_err() {
+        return;
+    }
+
+    if let Some(presence) = state.presence.connect(&user_id) {
+        announce_presence(&state, &user_id, presence);
+    }
+
+    let mut suspension_check = tokio::time::interval(std::time::Duration::from_secs(WS_SUSPENSION_CHECK_SECS));
+    let mut last_typing: HashMap<String, Instant> = HashMap::new();
+
+    loop {
+        tokio::select! {
+            _ = suspension_check.tick() => {
+                let blocked = {
+                    let sm_lock = state.storage_manager.lock().ok();
+                    sm_lock.as_ref().and_then(|l| l.as_ref()).is_some_and(|sm| {
+                        sm.is_suspended(&user_id).unwrap_or(false) || sm.is_expired(&user_id).unwrap_or(false)
+                    })
+                };
+                if blocked {
+                    let _ = socket.send(WsMessage::Close(None)).await;
+                    break;
+                }
+                if let Some(presence) = state.presence.check_idle(&user_id) {
+                    announce_presence(&state, &user_id, presence);
+                }
+            }
+            msg = rx.recv() => {
+                let delivered = match msg {
+                    Ok(broadcast_msg) => deliver_event(&mut socket, &state, &mut sub, broadcast_msg).await,
+                    // Missed events are in the log; catch up from there
+                    Err(broadcast::error::RecvError::Lagged(_)) => replay_events(&mut socket, &state, &mut sub).await,
+                    Err(_) => break,
+                };
+                if delivered.is_err() {
+                    break;
+                }
+            }
+            ws_msg = socket.recv() => {
+                let text = match ws_msg {
+                    Some(Ok(WsMessage::Text(text))) => text,
+                    Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let reply = match serde_json::from_str::<WsClientFrame>(text.as_str()) {
+                    Ok(frame) => {
+                        // Pings are keepalives, not the user doing anything
+                        if !matches!(frame, WsClientFrame::Ping { .. }) {
+                            if let Some(presence) = state.presence.touch(&user_id) {
+                                announce_presence(&state, &user_id, presence);
+                            }
+                        }
+                        handle_ws_frame(&state, &claims, &sub.conversation_ids, &mut last_typing, frame).await
+                    }
+                    Err(e) => Some(WsReplyFrame::Error {
+                        client_id: None,
+                        status: StatusCode::BAD_REQUEST.as_u16(),
+                        error: format!("Invalid frame: {}", e),
+                    }),
+                };
+                if let Some(reply) = reply {
+                    if send_ws_frame(&mut socket, &reply).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some(presence) = state.presence.disconnect(&user_id) {
+        announce_presence(&state, &user_id, presence);
+    }
+}
+
+/// Send the user's logged events after `sub.last_seq`, or `resync_required`
+/// if some are no longer available
+async fn replay_events(socket: &mut WebSocket, state: &ApiState, sub: &mut WsSubscription) -> Result<(), axum::Error> {
+    match replay_frames(state, sub) {
+        Ok(frames) => {
+            for frame in frames {
+                send_ws_frame(socket, &frame).await?;
+            }
+            Ok(())
+        }
+        Err(seq) => send_ws_frame(socket, &WsReplyFrame::ResyncRequired { seq }).await,
+    }
+}
+
+/// The frames replaying the user's events after `sub.last_seq`, or the
+/// latest sequence number to resync from if some are no longer available
+fn replay_frames(state: &ApiState, sub: &mut WsSubscription) -> Result<Vec<BroadcastMessage>, u64> {
+    let (events, latest_seq) = {
+        let sm_lock = state.storage_manager.lock().ok();
+        match sm_lock.as_ref().and_then(|l| l.as_ref()) {
+            Some(sm) => (
+                sm.events_since(&sub.user_id, sub.last_seq, MAX_REPLAY_EVENTS).ok().flatten(),
+                sm.event_seq(&sub.user_id).unwrap_or(0),
+            ),
+            None => (None, 0),
+        }
+    };
+
+    let Some(events) = events else {
+        sub.last_seq = latest_seq;
+        return Err(latest_seq);
+    };
+    let mut frames = Vec::new();
+    for (seq, payload) in events {
+        let Ok(mut broadcast_msg) = serde_json::from_str::<BroadcastMessage>(&payload) else { continue };
+        broadcast_msg.seqs.insert(sub.user_id.clone(), seq);
+        frames.extend(route_event(state, sub, broadcast_msg));
+    }
+    Ok(frames)
+}
+
+/// Forward one event if it concerns this socket's user
+async fn deliver_event(
+    socket: &mut WebSocket,
+    state: &ApiState,
+    sub: &mut WsSubscription,
+    broadcast_msg: BroadcastMessage,
+) -> Result<(), axum::Error> {
+    match route_event(state, sub, broadcast_msg) {
+        Some(frame) => send_ws_frame(socket, &frame).await,
+        None => Ok(()),
+    }
+}
+
+/// The frame to send this socket for an event, if any, updating the
+/// subscriptions when the user joins or leaves a conversation
+fn route_event(
+    state: &ApiState,
+    sub: &mut WsSubscription,
+    mut broadcast_msg: BroadcastMessage,
+) -> Option<BroadcastMessage> {
+    if let Some(audience) = &broadcast_msg.audience {
+        return audience.contains(&sub.user_id).then_some(broadcast_msg);
+    }
+
+    broadcast_msg.seq = broadcast_msg.seqs.get(&sub.user_id).copied();
+    if let Some(seq) = broadcast_msg.seq {
+        if seq <= sub.last_seq {
+            return None;
+        }
+        sub.last_seq = seq;
+    }
+
+    let about_me = broadcast_msg.message["user_ids"].as_array()
+        .is_some_and(|ids| ids.iter().any(|id| id.as_str() == Some(sub.user_id.as_str())));
+    let subscribed = sub.conversation_ids.contains(&broadcast_msg.conversation_id);
+    if about_me && broadcast_msg.event == EVENT_MEMBERS_ADDED {
+        if subscribed {
+            return None;
+        }
+        sub.conversation_ids.push(broadcast_msg.conversation_id.clone());
+        let conversation = {
+            let sm_lock = state.storage_manager.lock().ok();
+            sm_lock.as_ref().and_then(|l| l.as_ref())
+                .and_then(|sm| sm.get_member_conversation(&broadcast_msg.conversation_id, &sub.user_id).ok().flatten())
+        };
+        return Some(BroadcastMessage {
+            event: EVENT_CONVERSATION_ADDED.to_string(),
+            message: json!(conversation),
+            ..broadcast_msg
+        });
+    }
+    if about_me && broadcast_msg.event == EVENT_MEMBERS_REMOVED {
+        if !subscribed {
+            return None;
+        }
+        sub.conversation_ids.retain(|id| *id != broadcast_msg.conversation_id);
+        return Some(BroadcastMessage {
+            event: EVENT_CONVERSATION_REMOVED.to_string(),
+            message: json!({}),
+            ..broadcast_msg
+        });
+    }
+    if !subscribed {
+        return None;
+    }
+    // Don't echo typing indicators back to the typist
+    if broadcast_msg.event == EVENT_TYPING
+        && broadcast_msg.message["user_id"].as_str() == Some(sub.user_id.as_str())
+    {
+        return None;
+    }
+    Some(broadcast_msg)
+}
+
+async fn send_ws_frame<T: Serialize>(socket: &mut WebSocket, frame: &T) -> Result<(), axum::Error> {
+    let payload = serde_json::to_string(frame).unwrap_or_default();
+    socket.send(WsMessage::Text(payload.into())).await
+}
+
+/// Act on one client frame; returns the reply for that client, if any
+async fn handle_ws_frame(
+    state: &ApiState,
+    claims: &auth::Claims,
+    conversation_ids: &[String],
+    last_typing: &mut HashMap<String, Instant>,
+    frame: WsClientFrame,
+) -> Option<WsReplyFrame> {
+    match frame {
+        WsClientFrame::SendMessage { conversation_id, message } => {
+            let client_id = message.client_id.clone();
+            let state = state.clone();
+            let claims = claims.clone();
+            // Storage calls block, so keep them off the socket's task
+            let result = tokio::task::spawn_blocking(move || {
+                post_message(&state, &claims, &conversation_id, &message)
+            }).await.unwrap_or(Err(StatusCode::INTERNAL_SERVER_ERROR));
+            Some(match result {
+                Ok(message) => WsReplyFrame::Ack { client_id, message: json!(message) },
+                Err(status) => WsReplyFrame::Error {
+                    client_id,
+                    status: status.as_u16(),
+                    error: status.canonical_reason().unwrap_or("Error").to_string(),
+                },
+            })
+        }
+        WsClientFrame::Typing { conversation_id, thread_id } => {
+            if !claims.has_scope(auth::SCOPE_MESSAGES_SEND) {
+                return Some(WsReplyFrame::Error {
+                    client_id: None,
+                    status: StatusCode::FORBIDDEN.as_u16(),
+                    error: "Token can't send messages".to_string(),
+                });
+            }
+            if !conversation_ids.contains(&conversation_id) {
+                return Some(WsReplyFrame::Error {
+                    client_id: None,
+                    status: StatusCode::FORBIDDEN.as_u16(),
+                    error: "Not a member of this conversation".to_string(),
+                });
+            }
+            let key = format!("{}:{}", conversation_id, thread_id.as_deref().unwrap_or(""));
+            let now = Instant::now();
+            let throttled = last_typing.get(&key)
+                .is_some_and(|t| now.duration_since(*t).as_secs() < WS_TYPING_THROTTLE_SECS);
+            if !throttled {
+                last_typing.insert(key, now);
+                broadcast_ephemeral(state, EVENT_TYPING, &conversation_id, thread_id.as_deref(), json!({
+                    "user_id": claims.sub,
+                    "username": claims.username,
+                }));
+            }
+            None
+        }
+        WsClientFrame::Ping { nonce } => Some(WsReplyFrame::Pong { nonce }),
+    }
+}
+
+// POST /api/auth/register
+async fn register(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<RegisterRequest>,
+) -> Result<Json<AuthResponse>, StatusCode> {
+    // Rate limit
+    let ip = get_client_ip(&state, &headers, peer);
+    if !state.auth_limiter.check(&ip) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+    let tunnel_url = tunnel_url(&state);
+
+    // Validate input
+    if req.username.is_empty() || req.email.is_empty() || req.password.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    auth::check_password_strength(&req.password, &req.username)
+        .map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+
+    // Hash password
+    let password_hash = hash_new_password(&req.password).await?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    // Check if username already exists
+    if let Ok(Some(_)) = sm.get_user_by_username(&req.username) {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    // First user is admin by default
+    let user_count = sm.list_users()
+        .map(|users| users.len())
+        .unwrap_or(0);
+    let is_admin = user_count == 0;
+
+    // Create user
+    let user = sm.create_user(&req.username, &req.email, &password_hash, is_admin)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sm.audit(Some(&user.user_id), "auth.register", Some(&user.user_id), Some(&ip), None);
+    if let Err(e) = sm.join_default_channels(&user.user_id) {
+        log::error!("Failed to join {} to default channels: {}", user.user_id, e);
+    }
+    broadcast_joined(&state, sm, &user.user_id);
+    if let Err(e) = mailer::send_verification(sm, &hub_name(sm), &user, tunnel_url.as_deref()) {
+        log::error!("Failed to queue verification email for {}: {}", user.user_id, e);
+    }
+
+    // Generate JWT token
+    let auth_token = auth::generate_token(&user.user_id, &user.username, user.is_admin)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(AuthResponse {
+        user_id: user.user_id,
+        username: user.username,
+        email: user.email,
+        is_admin: user.is_admin,
+        token: auth_token.token,
+        expires_at: auth_token.expires_at,
+    }))
+}
+
+// POST /api/auth/guest
+async fn guest_signup(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<GuestSignupRequest>,
+) -> Result<Json<AuthResponse>, StatusCode> {
+    // Rate limit
+    let ip = get_client_ip(&state, &headers, peer);
+    if !state.auth_limiter.check(&ip) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let username = req.username.trim();
+    if username.is_empty() || req.password.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    auth::check_password_strength(&req.password, username)
+        .map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+    let password_hash = hash_new_password(&req.password).await?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    // Checked before redeeming so a taken name doesn't use up the invite
+    if sm.get_user_by_username(username).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?.is_some() {
+        return Err(StatusCode::CONFLICT);
+    }
+    let (invite, guest) = sm.sign_up_guest(&auth::hash_secret(req.invite.trim()), username, &password_hash)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    sm.audit(Some(&guest.user_id), "auth.register", Some(&guest.user_id), Some(&ip), Some(&format!("guest:{}", invite.invite_id)));
+    broadcast_joined(&state, sm, &guest.user_id);
+
+    Ok(Json(auth_response(guest)?))
+}
+
+// POST /api/auth/setup
+async fn complete_account_setup(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<AccountSetupRequest>,
+) -> Result<Json<AuthResponse>, StatusCode> {
+    // Rate limit
+    let ip = get_client_ip(&state, &headers, peer);
+    if !state.auth_limiter.check(&ip) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    if req.password.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    auth::check_password_strength(&req.password, &req.username)
+        .map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+    let password_hash = hash_new_password(&req.password).await?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let user = sm.complete_account_setup(req.username.trim(), &auth::hash_secret(req.code.trim()), &password_hash)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if user.suspension.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    sm.audit(Some(&user.user_id), "auth.setup", Some(&user.user_id), Some(&ip), None);
+
+    Ok(Json(auth_response(user)?))
+}
+
+/// Hash a new password on a blocking thread, since hashing is slow on purpose
+async fn hash_new_password(password: &str) -> Result<String, StatusCode> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || auth::hash_password(&password)).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+// POST /api/auth/login
+async fn login(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<LoginRequest>,
+) -> Result<Response, StatusCode> {
+    // Rate limit
+    let ip = get_client_ip(&state, &headers, peer);
+    if !state.auth_limiter.check(&ip) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let rp_id = passkey_rp_id(&state);
+    let user = verify_credentials(&state, &req.username, &req.password, &ip).await?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    // Passkey as second factor: no token yet, the client finishes the sign-in
+    // through /api/auth/passkey/verify with the returned ceremony
+    if sm.is_passkey_required(&user.user_id).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? {
+        let rp_id = rp_id?;
+        let allowed: Vec<String> = sm.list_passkeys(&user.user_id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .into_iter()
+            .map(|p| p.credential_id)
+            .collect();
+        let (ceremony_id, challenge) = state.passkey_ceremonies
+            .start(Ceremony::Authenticate { user_id: Some(user.user_id) }, &rp_id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+        return Ok(Json(json!({
+            "passkey_required": true,
+            "ceremony_id": ceremony_id,
+            "publicKey": webauthn::request_options(&challenge, &rp_id, &allowed),
+        })).into_response());
+    }
+
+    sm.audit(Some(&user.user_id), "auth.login", Some(&user.user_id), Some(&ip), Some("password"));
+    Ok(Json(auth_response(user)?).into_response())
+}
+
+fn auth_response(user: User) -> Result<AuthResponse, StatusCode> {
+    // Generate JWT token
+    let auth_token = auth::generate_token(&user.user_id, &user.username, user.is_admin)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(AuthResponse {
+        user_id: user.user_id,
+        username: user.username,
+        email: user.email,
+        is_admin: user.is_admin,
+        token: auth_token.token,
+        expires_at: auth_token.expires_at,
+    })
+}
+
+/// Verify a username/password pair and return the user.
+/// Shared by API login and the OIDC sign-in form.
+///
+/// Failures are counted per account: after a few, further attempts must wait
+/// out a growing delay, and at `LOCKOUT_THRESHOLD` the account is locked until
+/// the lockout expires or an admin unlocks it. Both are answered with the same
+/// 401 as an unknown username, so they don't reveal which accounts exist.
+/// Suspended users get 403 and expired guests 401. Failures are recorded in
+/// the audit log.
+async fn verify_credentials(state: &ApiState, username: &str, password: &str, ip: &str) -> Result<User, StatusCode> {
+    // Look the account up and check its throttle under the lock
+    let (user, failed_logins, password_hash) = {
+        let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+        let Some(user) = sm.get_user_by_username(username)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)? else {
+            // Audited too, so username guessing shows up in the log
+            let tried: String = username.chars().take(64).collect();
+            sm.audit(None, "auth.login_failed", None, Some(ip), Some(&format!("unknown user: {}", tried)));
+            return Err(StatusCode::UNAUTHORIZED);
+        };
+
+        let throttle = sm.get_login_throttle(&user.user_id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let now = chrono::Utc::now();
+        let parse = |t: &str| chrono::DateTime::parse_from_rfc3339(t).ok();
+        if let Some(locked_until) = throttle.locked_until.as_deref().and_then(parse) {
+            if locked_until > now {
+                sm.audit(None, "auth.login_failed", Some(&user.user_id), Some(ip), Some("locked"));
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+            // Lockout has expired — start counting afresh
+            sm.clear_failed_logins(&user.user_id)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        } else if let Some(last_failed) = throttle.last_failed_at.as_deref().and_then(parse) {
+            let wait = chrono::Duration::seconds(auth::login_backoff_secs(throttle.failed_logins));
+            if now < last_failed + wait {
+                sm.audit(None, "auth.login_failed", Some(&user.user_id), Some(ip), Some("backoff"));
+                return Err(StatusCode::UNAUTHORIZED);
+            }
+        }
+
+        let password_hash = sm.get_password_hash(username)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::UNAUTHORIZED)?;
+        (user, throttle.failed_logins, password_hash)
+    };
+
+    // Hashing is slow on purpose, so it runs on a blocking thread without
+    // the storage lock. Accounts that can't sign in aren't rehashed.
+    let can_sign_in = user.suspension.is_none() && !user.is_expired();
+    let (valid, new_hash) = {
+        let password = password.to_string();
+        let password_hash = password_hash.clone();
+        tokio::task::spawn_blocking(move || {
+            let valid = auth::verify_password(&password, &password_hash)?;
+            let new_hash = if valid && can_sign_in && auth::needs_rehash(&password_hash) {
+                match auth::hash_password(&password) {
+                    Ok(new_hash) => Some(new_hash),
+                    Err(e) => {
+                        log::error!("Failed to rehash password: {}", e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            anyhow::Ok((valid, new_hash))
+        }).await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    };
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    // The password was changed while this attempt was being checked
+    let current_hash = sm.get_password_hash(username)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if valid && current_hash.as_deref() != Some(password_hash.as_str()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    if !valid {
+        let failed = sm.record_failed_login(&user.user_id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if failed >= auth::LOCKOUT_THRESHOLD {
+            let until = chrono::Utc::now() + chrono::Duration::minutes(auth::LOCKOUT_MINUTES);
+            sm.lock_account(&user.user_id, &until.to_rfc3339())
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            log::warn!("Locked account '{}' after {} failed logins", user.username, failed);
+            sm.audit(None, "auth.lockout", Some(&user.user_id), Some(ip), Some(&failed.to_string()));
+        }
+        sm.audit(None, "auth.login_failed", Some(&user.user_id), Some(ip), None);
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    if failed_logins > 0 {
+        sm.clear_failed_logins(&user.user_id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    // Checked after the password so suspension status isn't disclosed to guessers
+    if user.suspension.is_some() {
+        sm.audit(None, "auth.login_blocked", Some(&user.user_id), Some(ip), Some("suspended"));
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if user.is_expired() {
+        sm.audit(None, "auth.login_blocked", Some(&user.user_id), Some(ip), Some("expired"));
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    // Only replaces the hash it was computed from, so a password changed
+    // in the meantime is kept
+    if let Some(new_hash) = new_hash {
+        if let Err(e) = sm.rehash_password(&user.user_id, &password_hash, &new_hash) {
+            log::error!("Failed to store rehashed password for {}: {}", user.user_id, e);
+        }
+    }
+
+    Ok(user)
+}
+
+pub fn hub_name(sm: &StorageManager) -> String {
+    sm.get_node_config().ok().flatten()
+        .map(|c| c.node_name)
+        .unwrap_or_else(|| "Citinet".to_string())
+}
+
+// --- Email ---
+// Outgoing mail goes through the outbox (see mailer.rs). Everything here
+// degrades gracefully when the admin hasn't configured SMTP: verification
+// and notifications are skipped, and password reset requests do nothing.
+
+// GET /api/auth/verify-email?token= (the link in the verification email)
+async fn verify_email(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<VerifyEmailQuery>,
+) -> Result<Html<String>, StatusCode> {
+    let ip = get_client_ip(&state, &headers, peer);
+    if !state.auth_limiter.check(&ip) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let consumed = sm.consume_email_token(&auth::hash_secret(query.token.trim()), mailer::KIND_VERIFY)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let verified = match consumed {
+        Some((user_id, email)) => {
+            let verified = sm.mark_email_verified(&user_id, &email)
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            if verified {
+                sm.audit(Some(&user_id), "auth.email_verified", Some(&user_id), Some(&ip), None);
+            }
+            verified
+        }
+        None => false,
+    };
+
+    let message = if verified {
+        "Your email address is confirmed. You can close this page."
+    } else {
+        "This link is invalid or has expired. Request a new one from the app."
+    };
+    Ok(render_notice_page(&hub_name(sm), message))
+}
+
+// POST /api/auth/password-reset
+// Always 202, so the response doesn't reveal which addresses have accounts.
+async fn request_password_reset(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<PasswordResetRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let ip = get_client_ip(&state, &headers, peer);
+    if !state.auth_limiter.check(&ip) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    let email = req.email.trim();
+    if email.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let users = sm.find_users_by_email(email).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let hub = hub_name(sm);
+    for user in users.iter().filter(|u| u.suspension.is_none() && u.expires_at.is_none()) {
+        match mailer::send_password_reset(sm, &hub, user) {
+            Ok(true) => sm.audit(None, "auth.password_reset_request", Some(&user.user_id), Some(&ip), None),
+            Ok(false) => {}
+            Err(e) => log::error!("Failed to queue password reset for {}: {}", user.user_id, e),
+        }
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+// POST /api/auth/password-reset/confirm
+async fn confirm_password_reset(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<PasswordResetConfirmRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let ip = get_client_ip(&state, &headers, peer);
+    if !state.auth_limiter.check(&ip) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    if req.password.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    // Checked before the code is used up; the username isn't known yet
+    auth::check_password_strength(&req.password, "")
+        .map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+    let password_hash = hash_new_password(&req.password).await?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let (user_id, email) = sm.consume_email_token(&auth::hash_secret(req.code.trim()), mailer::KIND_PASSWORD_RESET)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let user = sm.get_user_by_id(&user_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if user.suspension.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    // Also signs the account out everywhere and lifts any lockout
+    sm.reset_password(&user_id, &password_hash)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Receiving the code proves the member controls the address
+    let _ = sm.mark_email_verified(&user_id, &email);
+    sm.audit(Some(&user_id), "auth.password_reset", Some(&user_id), Some(&ip), None);
+
+    // No session is issued: the member signs in normally, so a required
+    // passkey still applies
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// GET /api/me/email
+async fn get_email_settings(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_session(&claims)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let user = sm.get_user_by_id(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let notifications = sm.get_email_notifications(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "email": user.email,
+        "verified_at": user.email_verified_at,
+        "notifications": notifications,
+        "email_enabled": mailer::is_configured(sm),
+    })))
+}
+
+// PATCH /api/me/email
+async fn update_email_settings(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(req): Json<EmailSettingsRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_session(&claims)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    sm.set_email_notifications(&claims.sub, req.notifications)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// POST /api/me/email/verify (send the verification email again)
+async fn resend_verification(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+    require_session(&claims)?;
+    if !state.auth_limiter.check(&ip) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
+    let tunnel_url = tunnel_url(&state);
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let user = sm.get_user_by_id(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    // Guests only have a placeholder address
+    if user.email_verified_at.is_some() || user.expires_at.is_some() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let queued = mailer::send_verification(sm, &hub_name(sm), &user, tunnel_url.as_deref())
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !queued {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    Ok(StatusCode::ACCEPTED)
+}
+
+// GET /api/admin/smtp (null when outgoing email is off)
+async fn get_smtp_config(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_HUB_MANAGE)?;
+
+    let config = mailer::load_config(sm).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(config.map(|c| c.redacted()).unwrap_or(Value::Null)))
+}
+
+// PUT /api/admin/smtp
+async fn set_smtp_config(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(config): Json<SmtpConfig>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_HUB_MANAGE)?;
+
+    let saved = mailer::save_config(sm, config).map_err(|_| StatusCode::BAD_REQUEST)?;
+    sm.audit(Some(&claims.sub), "settings.smtp", None, Some(&ip), Some(&saved.host));
+
+    Ok(Json(saved.redacted()))
+}
+
+// DELETE /api/admin/smtp
+async fn clear_smtp_config(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_HUB_MANAGE)?;
+
+    mailer::clear_config(sm).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sm.audit(Some(&claims.sub), "settings.smtp", None, Some(&ip), Some("disabled"));
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// POST /api/admin/smtp/test
+// Sends straight away rather than through the outbox, so the admin sees
+// whether the settings work.
+async fn send_test_email(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(req): Json<TestEmailRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+
+    let (config, email) = {
+        let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+        require_permission(sm, &claims, roles::PERM_HUB_MANAGE)?;
+        let config = mailer::load_config(sm)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .ok_or(StatusCode::CONFLICT)?;
+        (config, mailer::test_email(&hub_name(sm)))
+    };
+
+    let to = req.to.trim().to_string();
+    tokio::task::spawn_blocking(move || mailer::send(&config, &to, &email.subject, &email.body))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            log::warn!("Test email failed: {:#}", e);
+            StatusCode::BAD_GATEWAY
+        })?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// GET /api/admin/email-outbox
+async fn list_email_outbox(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+    require_permission(sm, &claims, roles::PERM_HUB_MANAGE)?;
+
+    let emails = sm.list_outbox(100).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(json!(emails)))
+}
+
+/// Bare page for links opened from an email
+fn render_notice_page(hub_name: &str, message: &str) -> Html<String> {
+    Html(format!(r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{hub}</title>
+<style>
+body {{ font-family: system-ui, sans-serif; background: #f4f4f5; display: flex; justify-content: center; padding-top: 10vh; }}
+main {{ background: #fff; padding: 2rem; border-radius: 12px; width: 320px; box-shadow: 0 2px 12px rgba(0,0,0,.08); }}
+</style>
+</head>
+<body>
+<main>
+<h2>{hub}</h2>
+<p>{message}</p>
+</main>
+</body>
+</html>"#,
+        hub = html_escape(hub_name),
+        message = html_escape(message),
+    ))
+}
+
+// --- Passkeys (WebAuthn) ---
+// Passkeys can replace the password entirely (/api/auth/passkey/*) or be
+// required after it (passkey_required, enforced in login).
+
+/// WebAuthn relying-party ID: the tunnel hostname. Passkeys are bound to it,
+/// so they are unavailable until a tunnel is configured.
+fn passkey_rp_id(state: &ApiState) -> Result<String, StatusCode> {
+    public_hostname(state).ok_or(StatusCode::SERVICE_UNAVAILABLE)
+}
+
+// GET /api/me/passkeys
+async fn list_passkeys(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_session(&claims)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let passkeys = sm.list_passkeys(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let passkey_required = sm.is_passkey_required(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({ "passkeys": passkeys, "passkey_required": passkey_required })))
+}
+
+// POST /api/me/passkeys/options
+async fn passkey_registration_options(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    require_session(&claims)?;
+
+    let rp_id = passkey_rp_id(&state)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let user = sm.get_user_by_id(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    // Stop the authenticator from registering a second passkey for this account
+    let existing: Vec<String> = sm.list_passkeys(&user.user_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .into_iter()
+        .map(|p| p.credential_id)
+        .collect();
+
+    let (ceremony_id, challenge) = state.passkey_ceremonies
+        .start(Ceremony::Register { user_id: user.user_id.clone() }, &rp_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(json!({
+        "ceremony_id": ceremony_id,
+        "publicKey": webauthn::creation_options(
+            &challenge, &rp_id, &hub_name(sm), &user.user_id, &user.username, &existing,
+        ),
+    })))
+}
+
+// POST /api/me/passkeys
+async fn register_passkey(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<RegisterPasskeyRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+    require_session(&claims)?;
+
+    let pending = state.passkey_ceremonies.take(&req.ceremony_id)
+        .ok_or(StatusCode::BAD_REQUEST)?;
+    if pending.ceremony != (Ceremony::Register { user_id: claims.sub.clone() }) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let credential = webauthn::verify_registration(&req.credential, &pending.challenge, &pending.rp_id)
+        .map_err(|e| {
+            log::warn!("Passkey registration rejected: {}", e);
+            StatusCode::BAD_REQUEST
+        })?;
+
+    let name = req.name.as_deref().map(str::trim).filter(|n| !n.is_empty()).unwrap_or("Passkey");
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    if sm.get_passkey(&credential.credential_id).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?.is_some() {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let passkey = sm.create_passkey(
+        &claims.sub, name, &credential.credential_id, &credential.public_key,
+        credential.algorithm, credential.sign_count,
+    ).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    sm.audit(Some(&claims.sub), "passkey.add", Some(&passkey.credential_id), Some(&ip), Some(&passkey.name));
+
+    Ok(Json(json!(passkey)))
+}
+
+// PATCH /api/me/passkeys
+async fn update_passkey_settings(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<UpdatePasskeySettingsRequest>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+    require_session(&claims)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    // Requiring a passkey without having one would lock the account out
+    if req.passkey_required
+        && sm.list_passkeys(&claims.sub).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?.is_empty()
+    {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    sm.set_passkey_required(&claims.sub, req.passkey_required)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let setting = if req.passkey_required { "required" } else { "optional" };
+    sm.audit(Some(&claims.sub), "passkey.required", Some(&claims.sub), Some(&ip), Some(setting));
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// DELETE /api/me/passkeys/:id
+async fn delete_passkey(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(credential_id): Path<String>,
+) -> Result<StatusCode, StatusCode> {
+    let claims = validate_auth_header(&state, &headers)?;
+    let ip = get_client_ip(&state, &headers, peer);
+    require_session(&claims)?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    // Keep at least one passkey while one is required at login
+    let passkeys = sm.list_passkeys(&claims.sub).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let required = sm.is_passkey_required(&claims.sub).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if required && passkeys.len() == 1 && passkeys[0].credential_id == credential_id {
+        return Err(StatusCode::CONFLICT);
+    }
+
+    let deleted = sm.delete_passkey(&claims.sub, &credential_id)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    sm.audit(Some(&claims.sub), "passkey.remove", Some(&credential_id), Some(&ip), None);
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// POST /api/auth/passkey/options
+async fn passkey_login_options(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(req): Json<PasskeyLoginOptionsRequest>,
+) -> Result<Json<Value>, StatusCode> {
+    let ip = get_client_ip(&state, &headers, peer);
+    if !state.auth_limiter.check(&ip) {
+        return Err(StatusCode::TOO_MANY_REQUESTS);
+    }
 
-    // First user is admin by default
-    let user_count = sm.list_users()
-        .map(|users| users.len())
-        .unwrap_or(0);
-    let is_admin = user_count == 0;
+    let rp_id = passkey_rp_id(&state)?;
 
-    // Create user
-    let user = sm.create_user(&req.username, &req.email, &password_hash, is_admin)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
 
-    // Generate JWT token
-    let auth_token = auth::generate_token(&user.user_id, &user.username, user.is_admin)
+    // With a username, offer only that account's passkeys. Without one (or for
+    // an unknown username) the browser picks from its discoverable passkeys.
+    let user = match req.username.as_deref() {
+        Some(username) => sm.get_user_by_username(username)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?,
+        None => None,
+    };
+    let allowed: Vec<String> = match &user {
+        Some(user) => sm.list_passkeys(&user.user_id)
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+            .into_iter()
+            .map(|p| p.credential_id)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let (ceremony_id, challenge) = state.passkey_ceremonies
+        .start(Ceremony::Authenticate { user_id: user.map(|u| u.user_id) }, &rp_id)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    Ok(Json(AuthResponse {
-        user_id: user.user_id,
-        username: user.username,
-        email: user.email,
-        is_admin: user.is_admin,
-        token: auth_token.token,
-        expires_at: auth_token.expires_at,
-    }))
+    Ok(Json(json!({
+        "ceremony_id": ceremony_id,
+        "publicKey": webauthn::request_options(&challenge, &rp_id, &allowed),
+    })))
 }
 
-// POST /api/auth/login
-async fn login(
+// POST /api/auth/passkey/verify
+async fn passkey_login(
     State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
-    Json(req): Json<LoginRequest>,
+    Json(req): Json<PasskeyLoginRequest>,
 ) -> Result<Json<AuthResponse>, StatusCode> {
-    // Rate limit
-    let ip = get_client_ip(&headers);
+    let ip = get_client_ip(&state, &headers, peer);
     if !state.auth_limiter.check(&ip) {
         return Err(StatusCode::TOO_MANY_REQUESTS);
     }
 
+    let pending = state.passkey_ceremonies.take(&req.ceremony_id)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let Ceremony::Authenticate { user_id: expected_user } = &pending.ceremony else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+
     let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
 
-    // Get user by username
-    let user = sm.get_user_by_username(&req.username)
+    let passkey = sm.get_passkey(req.credential.id.trim_end_matches('='))
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
         .ok_or(StatusCode::UNAUTHORIZED)?;
+    if expected_user.as_ref().is_some_and(|id| *id != passkey.user_id) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let sign_count = webauthn::verify_authentication(
+        &req.credential, &pending.challenge, &pending.rp_id, &passkey.public_key, passkey.sign_count,
+    ).map_err(|e| {
+        log::warn!("Passkey sign-in rejected: {}", e);
+        StatusCode::UNAUTHORIZED
+    })?;
+    sm.update_passkey_usage(&passkey.credential_id, sign_count)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
-    // Get password hash
-    let password_hash = sm.get_password_hash(&req.username)
+    let user = sm.get_user_by_id(&passkey.user_id)
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
         .ok_or(StatusCode::UNAUTHORIZED)?;
+    if user.suspension.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if user.is_expired() {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    sm.audit(Some(&user.user_id), "auth.login", Some(&user.user_id), Some(&ip), Some("passkey"));
 
-    // Verify password
-    let valid = auth::verify_password(&req.password, &password_hash)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(auth_response(user)?))
+}
 
-    if !valid {
-        return Err(StatusCode::UNAUTHORIZED);
+// --- OpenID Connect provider ---
+// Community apps (wikis, forums) sign members in with their hub account using
+// the authorization code flow with PKCE. Clients are registered by the admin
+// from the desktop app; ID tokens require an EdDSA or ES256 signing key.
+
+/// Public hostname of the hub from the tunnel config, if a tunnel or funnel is set up.
+fn public_hostname(state: &ApiState) -> Option<String> {
+    let tm_lock = state.tunnel_manager.lock().ok()?;
+    let hostname = tm_lock.as_ref()?.get_config()?.hostname
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_end_matches('/')
+        .to_string();
+    if hostname.is_empty() { None } else { Some(hostname) }
+}
+
+/// Issuer of the OIDC provider. Taken from the tunnel configuration only, so
+/// a forged Host header cannot change it; without a tunnel there is none.
+fn oidc_issuer(state: &ApiState) -> Option<String> {
+    tunnel_url(state)
+}
+
+/// The hub's public address from the tunnel config, if there is one
+fn tunnel_url(state: &ApiState) -> Option<String> {
+    public_hostname(state).map(|hostname| format!("https://{}", hostname))
+}
+
+// GET /.well-known/openid-configuration
+async fn openid_configuration(
+    State(state): State<ApiState>,
+) -> Result<Json<Value>, StatusCode> {
+    let issuer = oidc_issuer(&state).ok_or(StatusCode::NOT_FOUND)?;
+    let signing_algs: Vec<String> = auth::id_token_algorithm().into_iter().collect();
+
+    Ok(Json(json!({
+        "issuer": issuer,
+        "authorization_endpoint": format!("{}/oauth/authorize", issuer),
+        "token_endpoint": format!("{}/oauth/token", issuer),
+        "userinfo_endpoint": format!("{}/oauth/userinfo", issuer),
+        "jwks_uri": format!("{}/.well-known/jwks.json", issuer),
+        "response_types_supported": ["code"],
+        "grant_types_supported": ["authorization_code"],
+        "subject_types_supported": ["public"],
+        "id_token_signing_alg_values_supported": signing_algs,
+        "scopes_supported": OIDC_SCOPES,
+        "token_endpoint_auth_methods_supported": ["client_secret_basic", "client_secret_post", "none"],
+        "code_challenge_methods_supported": ["S256"],
+        "claims_supported": ["sub", "iss", "aud", "exp", "iat", "auth_time", "nonce", "preferred_username", "name", "email"],
+    })))
+}
+
+/// Check the client and redirect URI of an authorization request. Failures here
+/// are shown to the user instead of redirected, since the redirect target is untrusted.
+fn check_authorize_client(sm: &StorageManager, params: &AuthorizeParams) -> Result<OidcClient, (StatusCode, &'static str)> {
+    let client = sm.get_oidc_client(&params.client_id)
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Internal error"))?
+        .ok_or((StatusCode::BAD_REQUEST, "Unknown client_id"))?;
+    if !client.redirect_uris.contains(&params.redirect_uri) {
+        return Err((StatusCode::BAD_REQUEST, "redirect_uri is not registered for this client"));
     }
+    Ok(client)
+}
 
-    // Generate JWT token
-    let auth_token = auth::generate_token(&user.user_id, &user.username, user.is_admin)
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+/// Protocol-level checks on an authorization request. Returns the OAuth error
+/// code to send back to the client's redirect URI.
+fn check_authorize_request(params: &AuthorizeParams) -> Result<(), &'static str> {
+    if params.response_type != "code" {
+        return Err("unsupported_response_type");
+    }
+    if !params.scope.split_whitespace().any(|s| s == "openid") {
+        return Err("invalid_scope");
+    }
+    if params.code_challenge.as_deref().unwrap_or("").is_empty()
+        || params.code_challenge_method.as_deref() != Some("S256")
+    {
+        return Err("invalid_request");
+    }
+    if auth::id_token_algorithm().is_none() {
+        return Err("server_error");
+    }
+    Ok(())
+}
 
-    Ok(Json(AuthResponse {
+/// CSRF check for the sign-in form: the browser must report that the form
+/// was posted from the hub's own origin.
+fn is_same_origin(headers: &HeaderMap, issuer: &str) -> bool {
+    if let Some(origin) = headers.get(header::ORIGIN) {
+        return origin.to_str().is_ok_and(|o| o == issuer);
+    }
+    headers.get(header::REFERER)
+        .and_then(|r| r.to_str().ok())
+        .and_then(|r| r.strip_prefix(issuer))
+        .is_some_and(|path| path.starts_with('/'))
+}
+
+fn redirect_with_params(redirect_uri: &str, params: &[(&str, &str)]) -> Response {
+    match reqwest::Url::parse(redirect_uri) {
+        Ok(mut url) => {
+            url.query_pairs_mut().extend_pairs(params);
+            Redirect::to(url.as_str()).into_response()
+        }
+        Err(_) => StatusCode::BAD_REQUEST.into_response(),
+    }
+}
+
+fn authorize_error(params: &AuthorizeParams, error: &str) -> Response {
+    let mut pairs = vec![("error", error)];
+    if let Some(state) = params.state.as_deref() {
+        pairs.push(("state", state));
+    }
+    redirect_with_params(&params.redirect_uri, &pairs)
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Minimal self-contained sign-in page for the authorization endpoint.
+/// The authorization request is carried through as hidden form fields.
+fn render_authorize_page(hub_name: &str, client_name: &str, params: &AuthorizeParams, error: Option<&str>) -> Html<String> {
+    let hidden: String = [
+        ("response_type", Some(params.response_type.as_str())),
+        ("client_id", Some(params.client_id.as_str())),
+        ("redirect_uri", Some(params.redirect_uri.as_str())),
+        ("scope", Some(params.scope.as_str())),
+        ("state", params.state.as_deref()),
+        ("nonce", params.nonce.as_deref()),
+        ("code_challenge", params.code_challenge.as_deref()),
+        ("code_challenge_method", params.code_challenge_method.as_deref()),
+    ].iter()
+        .filter_map(|(name, value)| value.map(|v| format!(
+            "<input type=\"hidden\" name=\"{}\" value=\"{}\">", name, html_escape(v)
+        )))
+        .collect();
+
+    let error_html = error
+        .map(|e| format!("<p class=\"error\">{}</p>", html_escape(e)))
+        .unwrap_or_default();
+
+    Html(format!(r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Sign in — {hub}</title>
+<style>
+body {{ font-family: system-ui, sans-serif; background: #f4f4f5; display: flex; justify-content: center; padding-top: 10vh; }}
+form {{ background: #fff; padding: 2rem; border-radius: 12px; width: 320px; box-shadow: 0 2px 12px rgba(0,0,0,.08); }}
+input[type=text], input[type=password] {{ width: 100%; padding: .6rem; margin: .3rem 0 1rem; box-sizing: border-box; border: 1px solid #d4d4d8; border-radius: 6px; }}
+button {{ width: 100%; padding: .7rem; background: #2563eb; color: #fff; border: 0; border-radius: 6px; font-size: 1rem; }}
+.error {{ color: #dc2626; }}
+</style>
+</head>
+<body>
+<form method="post" action="/oauth/authorize">
+<h2>{hub}</h2>
+<p><strong>{client}</strong> wants to sign you in with your hub account.</p>
+{error}
+<label>Username<input type="text" name="username" autocomplete="username" required autofocus></label>
+<label>Password<input type="password" name="password" autocomplete="current-password" required></label>
+{hidden}
+<button type="submit">Sign in</button>
+</form>
+</body>
+</html>"#,
+        hub = html_escape(hub_name),
+        client = html_escape(client_name),
+        error = error_html,
+        hidden = hidden,
+    ))
+}
+
+// GET /oauth/authorize
+async fn oauth_authorize_page(
+    State(state): State<ApiState>,
+    Query(params): Query<AuthorizeParams>,
+) -> Response {
+    let issuer = oidc_issuer(&state);
+
+    let sm_lock = match state.storage_manager.lock() {
+        Ok(l) => l,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let sm = match sm_lock.as_ref() {
+        Some(sm) => sm,
+        None => return StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    };
+
+    let client = match check_authorize_client(sm, &params) {
+        Ok(client) => client,
+        Err(rejection) => return rejection.into_response(),
+    };
+    if let Err(error) = check_authorize_request(&params) {
+        return authorize_error(&params, error);
+    }
+    if issuer.is_none() {
+        return authorize_error(&params, "server_error");
+    }
+
+    let hub_name = hub_name(sm);
+
+    render_authorize_page(&hub_name, &client.name, &params, None).into_response()
+}
+
+// POST /oauth/authorize (form submitted from the sign-in page)
+async fn oauth_authorize_submit(
+    State(state): State<ApiState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Form(form): Form<AuthorizeForm>,
+) -> Response {
+    let params = &form.params;
+    let ip = get_client_ip(&state, &headers, peer);
+    let issuer = oidc_issuer(&state);
+
+    // The storage lock isn't held while the password is checked
+    let (client, hub_name) = {
+        let sm_lock = match state.storage_manager.lock() {
+            Ok(l) => l,
+            Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+        let sm = match sm_lock.as_ref() {
+            Some(sm) => sm,
+            None => return StatusCode::SERVICE_UNAVAILABLE.into_response(),
+        };
+
+        let client = match check_authorize_client(sm, params) {
+            Ok(client) => client,
+            Err(rejection) => return rejection.into_response(),
+        };
+        if let Err(error) = check_authorize_request(params) {
+            return authorize_error(params, error);
+        }
+        let issuer = match issuer {
+            Some(issuer) => issuer,
+            None => return authorize_error(params, "server_error"),
+        };
+        if !is_same_origin(&headers, &issuer) {
+            return (StatusCode::FORBIDDEN, "Sign-in form was not submitted from this hub").into_response();
+        }
+
+        let hub_name = hub_name(sm);
+
+        // Rate limit password attempts the same way as API login
+        if !state.auth_limiter.check(&ip) {
+            let page = render_authorize_page(&hub_name, &client.name, params, Some("Too many attempts — please wait and try again."));
+            return (StatusCode::TOO_MANY_REQUESTS, page).into_response();
+        }
+        (client, hub_name)
+    };
+
+    let user = match verify_credentials(&state, &form.username, &form.password, &ip).await {
+        Ok(user) => user,
+        Err(StatusCode::UNAUTHORIZED) => {
+            let page = render_authorize_page(&hub_name, &client.name, params, Some("Incorrect username or password."));
+            return (StatusCode::UNAUTHORIZED, page).into_response();
+        }
+        Err(StatusCode::FORBIDDEN) => {
+            let page = render_authorize_page(&hub_name, &client.name, params, Some("This account is suspended."));
+            return (StatusCode::FORBIDDEN, page).into_response();
+        }
+        Err(status) => return status.into_response(),
+    };
+
+    let sm_lock = match state.storage_manager.lock() {
+        Ok(l) => l,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let sm = match sm_lock.as_ref() {
+        Some(sm) => sm,
+        None => return StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    };
+
+    // The sign-in page has no WebAuthn support, so accounts that need a
+    // passkey after their password can't use it yet
+    if sm.is_passkey_required(&user.user_id).unwrap_or(true) {
+        let page = render_authorize_page(&hub_name, &client.name, params, Some("This account requires a passkey to sign in, which this page does not support yet."));
+        return (StatusCode::FORBIDDEN, page).into_response();
+    }
+
+    let scope: Vec<&str> = params.scope.split_whitespace()
+        .filter(|s| OIDC_SCOPES.contains(s))
+        .collect();
+
+    let code = match auth::generate_secret("") {
+        Ok(code) => code,
+        Err(_) => return authorize_error(params, "server_error"),
+    };
+    let auth_code = OidcAuthCode {
+        client_id: client.client_id,
         user_id: user.user_id,
-        username: user.username,
-        email: user.email,
-        is_admin: user.is_admin,
-        token: auth_token.token,
-        expires_at: auth_token.expires_at,
-    }))
+        redirect_uri: params.redirect_uri.clone(),
+        scope: scope.join(" "),
+        nonce: params.nonce.clone(),
+        code_challenge: params.code_challenge.clone().unwrap_or_default(),
+        auth_time: chrono::Utc::now().to_rfc3339(),
+    };
+    let expires_at = (chrono::Utc::now() + chrono::Duration::seconds(AUTH_CODE_TTL_SECS)).to_rfc3339();
+    if sm.create_oidc_auth_code(&auth::hash_secret(&code), &auth_code, &expires_at).is_err() {
+        return authorize_error(params, "server_error");
+    }
+    let details = format!("oidc:{}", auth_code.client_id);
+    sm.audit(Some(&auth_code.user_id), "auth.login", Some(&auth_code.user_id), Some(&ip), Some(&details));
+
+    let mut pairs = vec![("code", code.as_str())];
+    if let Some(state) = params.state.as_deref() {
+        pairs.push(("state", state));
+    }
+    redirect_with_params(&params.redirect_uri, &pairs)
+}
+
+fn oauth_error(status: StatusCode, error: &str) -> Response {
+    (status, Json(json!({ "error": error }))).into_response()
+}
+
+/// Client credentials from HTTP Basic auth (client_secret_basic) or the form body.
+fn client_credentials(headers: &HeaderMap, req: &TokenRequest) -> Option<(String, Option<String>)> {
+    let basic = headers.get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Basic "))
+        .and_then(|b| base64::engine::general_purpose::STANDARD.decode(b).ok())
+        .and_then(|b| String::from_utf8(b).ok());
+
+    match basic {
+        Some(pair) => {
+            let (id, secret) = pair.split_once(':')?;
+            Some((id.to_string(), Some(secret.to_string())))
+        }
+        None => Some((req.client_id.clone()?, req.client_secret.clone())),
+    }
+}
+
+// POST /oauth/token
+async fn oauth_token(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Form(req): Form<TokenRequest>,
+) -> Response {
+    if req.grant_type != "authorization_code" {
+        return oauth_error(StatusCode::BAD_REQUEST, "unsupported_grant_type");
+    }
+    let (client_id, client_secret) = match client_credentials(&headers, &req) {
+        Some(creds) => creds,
+        None => return oauth_error(StatusCode::UNAUTHORIZED, "invalid_client"),
+    };
+    let (code, redirect_uri, code_verifier) = match (&req.code, &req.redirect_uri, &req.code_verifier) {
+        (Some(c), Some(r), Some(v)) => (c, r, v),
+        _ => return oauth_error(StatusCode::BAD_REQUEST, "invalid_request"),
+    };
+
+    let issuer = match oidc_issuer(&state) {
+        Some(issuer) => issuer,
+        None => return oauth_error(StatusCode::SERVICE_UNAVAILABLE, "server_error"),
+    };
+
+    let sm_lock = match state.storage_manager.lock() {
+        Ok(l) => l,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    let sm = match sm_lock.as_ref() {
+        Some(sm) => sm,
+        None => return StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    };
+
+    let client = match sm.get_oidc_client(&client_id) {
+        Ok(Some(client)) => client,
+        Ok(None) => return oauth_error(StatusCode::UNAUTHORIZED, "invalid_client"),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    if let Some(expected) = &client.client_secret_hash {
+        let provided = client_secret.as_deref().map(auth::hash_secret);
+        if provided.as_ref() != Some(expected) {
+            return oauth_error(StatusCode::UNAUTHORIZED, "invalid_client");
+        }
+    }
+
+    let auth_code = match sm.take_oidc_auth_code(&auth::hash_secret(code)) {
+        Ok(Some(c)) => c,
+        Ok(None) => return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant"),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    if auth_code.client_id != client.client_id
+        || &auth_code.redirect_uri != redirect_uri
+        || !auth::verify_pkce(code_verifier, &auth_code.code_challenge)
+    {
+        return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant");
+    }
+
+    let user = match sm.get_user_by_id(&auth_code.user_id) {
+        Ok(Some(user)) if user.suspension.is_none() && !user.is_expired() => user,
+        Ok(_) => return oauth_error(StatusCode::BAD_REQUEST, "invalid_grant"),
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let scopes: Vec<String> = auth_code.scope.split_whitespace().map(|s| s.to_string()).collect();
+    let access_token = match auth::generate_scoped_token(&user.user_id, &user.username, scopes.clone(), OIDC_TOKEN_TTL_HOURS) {
+        Ok(t) => t,
+        Err(_) => return oauth_error(StatusCode::INTERNAL_SERVER_ERROR, "server_error"),
+    };
+
+    let now = chrono::Utc::now();
+    let auth_time = chrono::DateTime::parse_from_rfc3339(&auth_code.auth_time)
+        .map(|t| t.timestamp())
+        .unwrap_or(now.timestamp());
+    let mut id_claims = json!({
+        "iss": issuer,
+        "sub": user.user_id,
+        "aud": client.client_id,
+        "exp": (now + chrono::Duration::hours(OIDC_TOKEN_TTL_HOURS)).timestamp(),
+        "iat": now.timestamp(),
+        "auth_time": auth_time,
+    });
+    if let Some(nonce) = &auth_code.nonce {
+        id_claims["nonce"] = json!(nonce);
+    }
+    if scopes.iter().any(|s| s == "profile") {
+        id_claims["preferred_username"] = json!(user.username);
+        id_claims["name"] = json!(user.username);
+    }
+    if scopes.iter().any(|s| s == "email") {
+        id_claims["email"] = json!(user.email);
+    }
+    let id_token = match auth::sign_id_token(&id_claims) {
+        Ok(t) => t,
+        Err(e) => {
+            log::error!("Failed to sign ID token: {}", e);
+            return oauth_error(StatusCode::INTERNAL_SERVER_ERROR, "server_error");
+        }
+    };
+
+    (
+        [(header::CACHE_CONTROL, "no-store")],
+        Json(json!({
+            "access_token": access_token.token,
+            "token_type": "Bearer",
+            "expires_in": OIDC_TOKEN_TTL_HOURS * 3600,
+            "id_token": id_token,
+            "scope": auth_code.scope,
+        })),
+    ).into_response()
+}
+
+// GET/POST /oauth/userinfo
+async fn oauth_userinfo(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+) -> Result<Json<Value>, StatusCode> {
+    let token = headers.get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(auth::extract_bearer_token)
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    let claims = resolve_bearer(&state, token)?;
+    require_scope(&claims, "openid")?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let sm = sm_lock.as_ref().ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let user = sm.get_user_by_id(&claims.sub)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let mut info = json!({ "sub": user.user_id });
+    if claims.has_scope("profile") {
+        info["preferred_username"] = json!(user.username);
+        info["name"] = json!(user.username);
+    }
+    if claims.has_scope("email") {
+        info["email"] = json!(user.email);
+    }
+
+    Ok(Json(info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// API state over a fresh hub in a temporary directory
+    fn test_state() -> (ApiState, std::path::PathBuf) {
+        let dir = std::env::temp_dir().join(format!("citinet-test-{}", uuid::Uuid::new_v4()));
+        let sm = StorageManager::initialize(dir.to_str().unwrap()).unwrap();
+        let (msg_tx, _) = broadcast::channel(64);
+        let state = ApiState {
+            storage_manager: Arc::new(Mutex::new(Some(sm))),
+            tunnel_manager: Arc::new(Mutex::new(None)),
+            started_at: Instant::now(),
+            msg_tx,
+            auth_limiter: RateLimiter::new(10, 1.0),
+            passkey_ceremonies: ChallengeStore::new(),
+            presence: PresenceTracker::new(),
+        };
+        (state, dir)
+    }
+
+    fn with_sm<T>(state: &ApiState, f: impl FnOnce(&StorageManager) -> T) -> T {
+        f(state.storage_manager.lock().unwrap().as_ref().unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_verify_credentials_rehashes_only_usable_accounts() {
+        let (state, dir) = test_state();
+        let legacy = bcrypt::hash("correct horse battery", 4).unwrap();
+        let (alice, bob) = with_sm(&state, |sm| {
+            let alice = sm.create_user("alice", "alice@example.org", &legacy, false).unwrap();
+            let bob = sm.create_user("bob", "bob@example.org", &legacy, false).unwrap();
+            sm.suspend_user(&bob.user_id, None, None).unwrap();
+            (alice, bob)
+        });
+
+        assert_eq!(verify_credentials(&state, "alice", "wrong password", "127.0.0.1").await.unwrap_err(), StatusCode::UNAUTHORIZED);
+        assert_eq!(with_sm(&state, |sm| sm.get_login_throttle(&alice.user_id).unwrap().failed_logins), 1);
+        assert_eq!(verify_credentials(&state, "nobody", "whatever", "127.0.0.1").await.unwrap_err(), StatusCode::UNAUTHORIZED);
+
+        // Wait out the backoff from the failed attempt
+        with_sm(&state, |sm| sm.clear_failed_logins(&alice.user_id).unwrap());
+        let user = verify_credentials(&state, "alice", "correct horse battery", "127.0.0.1").await.unwrap();
+        assert_eq!(user.user_id, alice.user_id);
+        let rehashed = with_sm(&state, |sm| sm.get_password_hash("alice").unwrap().unwrap());
+        assert!(!auth::needs_rehash(&rehashed));
+
+        // A suspended account is refused and keeps its old hash
+        assert_eq!(verify_credentials(&state, "bob", "correct horse battery", "127.0.0.1").await.unwrap_err(), StatusCode::FORBIDDEN);
+        assert_eq!(with_sm(&state, |sm| sm.get_password_hash(&bob.username).unwrap().unwrap()), legacy);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn claims_for(user: &User, scopes: Option<Vec<String>>) -> auth::Claims {
+        let now = chrono::Utc::now().timestamp();
+        auth::Claims {
+            sub: user.user_id.clone(),
+            username: user.username.clone(),
+            is_admin: user.is_admin,
+            exp: now + 3600,
+            iat: now,
+            scopes,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ws_frames() {
+        let (state, dir) = test_state();
+        let (alice, bob, conv) = with_sm(&state, |sm| {
+            let alice = sm.create_user("alice", "alice@example.org", "x", false).unwrap();
+            let bob = sm.create_user("bob", "bob@example.org", "x", false).unwrap();
+            let conv = sm.create_group_conversation(&alice.user_id, "Team", std::slice::from_ref(&bob.user_id)).unwrap();
+            (alice, bob, conv)
+        });
+        let claims = claims_for(&alice, None);
+        let conversation_ids = vec![conv.conversation_id.clone()];
+        let mut last_typing = HashMap::new();
+        let send = |client_id: &str| serde_json::from_value::<WsClientFrame>(json!({
+            "type": "message.send",
+            "conversation_id": conv.conversation_id,
+            "body": "hello",
+            "client_id": client_id,
+        })).unwrap();
+
+        // Resending with the same client ID acks the original message
+        let first = match handle_ws_frame(&state, &claims, &conversation_ids, &mut last_typing, send("c1")).await {
+            Some(WsReplyFrame::Ack { client_id, message }) => {
+                assert_eq!(client_id.as_deref(), Some("c1"));
+                message
+            }
+            _ => panic!("expected an ack"),
+        };
+        match handle_ws_frame(&state, &claims, &conversation_ids, &mut last_typing, send("c1")).await {
+            Some(WsReplyFrame::Ack { message, .. }) => assert_eq!(message["message_id"], first["message_id"]),
+            _ => panic!("expected an ack"),
+        }
+        let sent = with_sm(&state, |sm| sm.list_messages(&conv.conversation_id, 50, None).unwrap());
+        assert_eq!(sent.len(), 1);
+
+        // Client IDs belong to their sender
+        assert!(with_sm(&state, |sm| sm.find_message_by_client_id(&bob.user_id, "c1").unwrap()).is_none());
+
+        match handle_ws_frame(&state, &claims, &conversation_ids, &mut last_typing, send("")).await {
+            Some(WsReplyFrame::Error { client_id, status, .. }) => {
+                assert_eq!(client_id.as_deref(), Some(""));
+                assert_eq!(status, 400);
+            }
+            _ => panic!("expected an error"),
+        }
+
+        // Typing needs membership and a token that can send
+        let typing = |conversation_id: &str| serde_json::from_value::<WsClientFrame>(json!({
+            "type": "typing",
+            "conversation_id": conversation_id,
+        })).unwrap();
+        assert!(handle_ws_frame(&state, &claims, &conversation_ids, &mut last_typing, typing(&conv.conversation_id)).await.is_none());
+        assert!(matches!(
+            handle_ws_frame(&state, &claims, &conversation_ids, &mut last_typing, typing("elsewhere")).await,
+            Some(WsReplyFrame::Error { status: 403, .. })
+        ));
+        let read_only = claims_for(&alice, Some(vec![auth::SCOPE_MESSAGES_READ.to_string()]));
+        assert!(matches!(
+            handle_ws_frame(&state, &read_only, &conversation_ids, &mut last_typing, typing(&conv.conversation_id)).await,
+            Some(WsReplyFrame::Error { status: 403, .. })
+        ));
+
+        let ping = serde_json::from_value::<WsClientFrame>(json!({ "type": "ping", "nonce": 7 })).unwrap();
+        match handle_ws_frame(&state, &claims, &conversation_ids, &mut last_typing, ping).await {
+            Some(WsReplyFrame::Pong { nonce }) => assert_eq!(nonce, Some(json!(7))),
+            _ => panic!("expected a pong"),
+        }
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn event(event: &str, conversation_id: &str, message: Value) -> BroadcastMessage {
+        BroadcastMessage {
+            event: event.to_string(),
+            seq: None,
+            conversation_id: conversation_id.to_string(),
+            thread_id: None,
+            message,
+            seqs: HashMap::new(),
+            audience: None,
+        }
+    }
+
+    #[test]
+    fn test_route_event_follows_membership() {
+        let (state, dir) = test_state();
+        let (alice, bob, conv) = with_sm(&state, |sm| {
+            let alice = sm.create_user("alice", "alice@example.org", "x", false).unwrap();
+            let bob = sm.create_user("bob", "bob@example.org", "x", false).unwrap();
+            let conv = sm.create_group_conversation(&alice.user_id, "Team", std::slice::from_ref(&bob.user_id)).unwrap();
+            (alice, bob, conv)
+        });
+        let id = conv.conversation_id.as_str();
+        let mut sub = WsSubscription { user_id: bob.user_id.clone(), conversation_ids: Vec::new(), last_seq: 0 };
+
+        assert!(route_event(&state, &mut sub, event(EVENT_MESSAGE_CREATED, id, json!({}))).is_none());
+
+        // Being added subscribes the socket and sends the conversation
+        let added = route_event(&state, &mut sub, event(EVENT_MEMBERS_ADDED, id, json!({ "user_ids": [bob.user_id] }))).unwrap();
+        assert_eq!(added.event, EVENT_CONVERSATION_ADDED);
+        assert_eq!(added.message["conversation"]["conversation_id"], json!(id));
+        assert_eq!(sub.conversation_ids, vec![id.to_string()]);
+        assert!(route_event(&state, &mut sub, event(EVENT_MEMBERS_ADDED, id, json!({ "user_ids": [bob.user_id] }))).is_none());
+
+        let mut created = event(EVENT_MESSAGE_CREATED, id, json!({}));
+        created.seqs.insert(bob.user_id.clone(), 1);
+        assert_eq!(route_event(&state, &mut sub, created.clone()).unwrap().seq, Some(1));
+        // A replayed event isn't delivered twice
+        assert!(route_event(&state, &mut sub, created).is_none());
+
+        // Typing isn't echoed to the typist
+        assert!(route_event(&state, &mut sub, event(EVENT_TYPING, id, json!({ "user_id": bob.user_id }))).is_none());
+        assert!(route_event(&state, &mut sub, event(EVENT_TYPING, id, json!({ "user_id": alice.user_id }))).is_some());
+
+        // Being removed unsubscribes it
+        let removed = route_event(&state, &mut sub, event(EVENT_MEMBERS_REMOVED, id, json!({ "user_ids": [bob.user_id] }))).unwrap();
+        assert_eq!(removed.event, EVENT_CONVERSATION_REMOVED);
+        assert!(sub.conversation_ids.is_empty());
+        assert!(route_event(&state, &mut sub, event(EVENT_MESSAGE_CREATED, id, json!({}))).is_none());
+
+        // Addressed events ignore subscriptions
+        let mut direct = event(EVENT_MESSAGE_CREATED, "", json!({}));
+        direct.audience = Some(vec![bob.user_id.clone()]);
+        assert!(route_event(&state, &mut sub, direct.clone()).is_some());
+        direct.audience = Some(vec![alice.user_id.clone()]);
+        assert!(route_event(&state, &mut sub, direct).is_none());
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_replay_frames() {
+        let (state, dir) = test_state();
+        let (bob, conv) = with_sm(&state, |sm| {
+            let alice = sm.create_user("alice", "alice@example.org", "x", false).unwrap();
+            let bob = sm.create_user("bob", "bob@example.org", "x", false).unwrap();
+            let conv = sm.create_group_conversation(&alice.user_id, "Team", std::slice::from_ref(&bob.user_id)).unwrap();
+            for i in 0..=MAX_REPLAY_EVENTS {
+                broadcast_event(&state, sm, EVENT_MESSAGE_CREATED, &conv.conversation_id, None, json!({ "n": i }));
+            }
+            (bob, conv)
+        });
+        let latest = u64::from(MAX_REPLAY_EVENTS) + 1;
+        let subscription = |last_seq| WsSubscription {
+            user_id: bob.user_id.clone(),
+            conversation_ids: vec![conv.conversation_id.clone()],
+            last_seq,
+        };
+
+        // A gap of exactly MAX_REPLAY_EVENTS is replayed in order
+        let mut sub = subscription(1);
+        let frames = replay_frames(&state, &mut sub).unwrap();
+        assert_eq!(frames.len(), MAX_REPLAY_EVENTS as usize);
+        assert_eq!(frames[0].seq, Some(2));
+        assert_eq!(frames[0].message["n"], json!(1));
+        assert_eq!(sub.last_seq, latest);
+        assert!(replay_frames(&state, &mut sub).unwrap().is_empty());
+
+        // One more and the client has to resync
+        let mut sub = subscription(0);
+        assert_eq!(replay_frames(&state, &mut sub).unwrap_err(), latest);
+        assert_eq!(sub.last_seq, latest);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    // The keyring guard only keeps other tests from rotating the keys
+    // meanwhile; nothing else on this runtime waits for it
+    #[tokio::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn test_hiding_presence_announces_offline() {
+        let (state, dir) = test_state();
+        let (alice, bob) = with_sm(&state, |sm| {
+            let alice = sm.create_user("alice", "alice@example.org", "x", false).unwrap();
+            let bob = sm.create_user("bob", "bob@example.org", "x", false).unwrap();
+            sm.create_group_conversation(&alice.user_id, "Team", std::slice::from_ref(&bob.user_id)).unwrap();
+            (alice, bob)
+        });
+        let _keys = auth::ensure_test_keys();
+        let headers = bearer(&alice);
+        state.presence.connect(&alice.user_id);
+        let mut rx = state.msg_tx.subscribe();
+        let show = |show: bool| Json(serde_json::from_value::<UpdateProfileRequest>(json!({ "show_presence": show })).unwrap());
+
+        let profile = update_my_profile(State(state.clone()), headers.clone(), show(false)).await.unwrap().0;
+        assert_eq!(profile["show_presence"], json!(false));
+        let event = rx.try_recv().unwrap();
+        assert_eq!(event.event, EVENT_PRESENCE);
+        assert_eq!(event.audience, Some(vec![bob.user_id.clone()]));
+        assert_eq!(event.message["presence"], json!(Presence::Offline));
+        assert!(event.message["last_seen_at"].is_null());
+
+        // Saving without a change announces nothing
+        let _ = update_my_profile(State(state.clone()), headers.clone(), show(false)).await.unwrap();
+        assert!(rx.try_recv().is_err());
+
+        let _ = update_my_profile(State(state.clone()), headers, show(true)).await.unwrap();
+        assert_eq!(rx.try_recv().unwrap().message["presence"], json!(Presence::Online));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_announcement_only_channels() {
+        let (state, dir) = test_state();
+        let (creator, moderator, member, channel) = with_sm(&state, |sm| {
+            let owner = sm.create_user("owner", "owner@example.org", "x", true).unwrap();
+            let creator = sm.create_user("creator", "creator@example.org", "x", false).unwrap();
+            let moderator = sm.create_user("moderator", "moderator@example.org", "x", false).unwrap();
+            let member = sm.create_user("member", "member@example.org", "x", false).unwrap();
+            sm.set_user_role(&owner.user_id, &moderator.user_id, roles::ROLE_MODERATOR).unwrap();
+            let channel = sm.create_channel(&creator.user_id, "news", true, false).unwrap();
+            sm.add_group_member(&channel.conversation_id, &moderator.user_id).unwrap();
+            sm.add_group_member(&channel.conversation_id, &member.user_id).unwrap();
+            (creator, moderator, member, channel)
+        });
+        let post = |user: &User| {
+            let req = SendMessageRequest { body: "hi".to_string(), attachment_ids: Vec::new(), parent_id: None, client_id: None };
+            post_message(&state, &claims_for(user, None), &channel.conversation_id, &req)
+        };
+
+        assert!(post(&creator).is_ok());
+        assert!(post(&moderator).is_ok());
+        assert_eq!(post(&member).unwrap_err(), StatusCode::FORBIDDEN);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    fn bearer(user: &User) -> HeaderMap {
+        let token = auth::generate_token(&user.user_id, &user.username, user.is_admin).unwrap().token;
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    #[tokio::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn test_admin_scope_follows_role() {
+        let _keys = auth::ensure_test_keys();
+        let (state, dir) = test_state();
+        let (moderator, member) = with_sm(&state, |sm| {
+            let owner = sm.create_user("owner", "owner@example.org", "x", true).unwrap();
+            let moderator = sm.create_user("moderator", "moderator@example.org", "x", false).unwrap();
+            let member = sm.create_user("member", "member@example.org", "x", false).unwrap();
+            sm.set_user_role(&owner.user_id, &moderator.user_id, roles::ROLE_MODERATOR).unwrap();
+            (moderator, member)
+        });
+        let peer = ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1)));
+        let request = || Json(CreateApiTokenRequest {
+            name: "bot".to_string(),
+            scopes: vec![auth::SCOPE_ADMIN.to_string()],
+            expires_in_days: None,
+        });
+
+        assert!(create_api_token(State(state.clone()), peer, bearer(&moderator), request()).await.is_ok());
+        assert_eq!(
+            create_api_token(State(state.clone()), peer, bearer(&member), request()).await.unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    #[allow(clippy::await_holding_lock)]
+    async fn test_read_only_tokens_mark_read() {
+        let _keys = auth::ensure_test_keys();
+        let (state, dir) = test_state();
+        let (alice, bob, conv) = with_sm(&state, |sm| {
+            let alice = sm.create_user("alice", "alice@example.org", "x", false).unwrap();
+            let bob = sm.create_user("bob", "bob@example.org", "x", false).unwrap();
+            let conv = sm.create_group_conversation(&alice.user_id, "Team", std::slice::from_ref(&bob.user_id)).unwrap();
+            sm.create_message(&conv.conversation_id, &alice.user_id, "hi", &[], None, None).unwrap();
+            (alice, bob, conv)
+        });
+        let token = auth::generate_scoped_token(&bob.user_id, &bob.username, vec![auth::SCOPE_MESSAGES_READ.to_string()], 1)
+            .unwrap().token;
+        let mut headers = HeaderMap::new();
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        let request = || Json(MarkReadRequest { message_id: None });
+
+        let read = mark_conversation_read(State(state.clone()), headers, Path(conv.conversation_id.clone()), request()).await.unwrap().0;
+        assert_eq!(read["unread_count"], json!(0));
+
+        // Members only
+        let other = with_sm(&state, |sm| sm.create_group_conversation(&alice.user_id, "Private", &[]).unwrap());
+        assert_eq!(
+            mark_conversation_read(State(state.clone()), bearer(&bob), Path(other.conversation_id), request()).await.unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
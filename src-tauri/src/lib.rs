@@ -4,6 +4,11 @@ mod tunnel_manager;
 mod tailscale_manager;
 mod hub_api;
 mod auth;
+mod webauthn;
+mod roles;
+mod data_export;
+mod mailer;
+mod member_csv;
 
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
@@ -12,7 +17,7 @@ use tauri::menu::{Menu, MenuItem};
 use tauri::tray::TrayIconBuilder;
 use tauri_plugin_autostart::ManagerExt;
 use system_monitor::{SystemMetrics, SystemMonitor, HardwareInfo, DriveSpace};
-use storage_manager::{StorageManager, NodeConfig, StorageStatus, NodeStatus, File, User};
+use storage_manager::{StorageManager, NodeConfig, StorageStatus, NodeStatus, File, User, OidcClient, LockedAccount, Role, AuditFilter, GuestInvite, OutgoingEmail};
 use tunnel_manager::TunnelManager;
 use tailscale_manager::TailscaleManager;
 
@@ -97,7 +102,8 @@ fn initialize_node(
     let mut sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
 
     let sm = StorageManager::initialize(&install_path).map_err(|e| e.to_string())?;
-    auth::init_jwt_secret(sm.db()).map_err(|e| e.to_string())?;
+    auth::init_signing_keys(sm.db()).map_err(|e| e.to_string())?;
+    auth::init_password_hashing(sm.db()).map_err(|e| e.to_string())?;
     let config = sm.save_node_config(
         &node_type, &node_name, disk_quota_gb, bandwidth_limit_mbps, cpu_limit_percent, auto_start,
     ).map_err(|e| e.to_string())?;
@@ -186,6 +192,37 @@ fn get_install_drive_space(state: State<AppState>) -> Result<DriveSpace, String>
     }
 }
 
+/// hub_settings key holding the user signed in to the desktop app
+const DESKTOP_SESSION_KEY: &str = "desktop_session_user";
+
+/// Shared permission check for Tauri commands, made against the user signed in
+/// to the desktop app. Returns that user's ID.
+fn require_desktop_permission(sm: &StorageManager, permission: &str) -> Result<String, String> {
+    let user_id = desktop_user_id(sm).ok_or("Not signed in")?;
+    sm.require_permission(&user_id, permission).map_err(|e| e.to_string())?;
+    Ok(user_id)
+}
+
+/// The user signed in to the desktop app, if any
+fn desktop_user_id(sm: &StorageManager) -> Option<String> {
+    sm.get_setting(DESKTOP_SESSION_KEY).ok().flatten().filter(|id| !id.is_empty())
+}
+
+/// Record a desktop command in the audit log. Desktop commands run locally,
+/// so there is no client IP.
+fn desktop_audit(sm: &StorageManager, action: &str, target: Option<&str>, details: Option<&str>) {
+    sm.audit(desktop_user_id(sm).as_deref(), action, target, None, details);
+}
+
+/// `desktop_audit` for commands that don't otherwise hold the storage lock
+fn desktop_audit_unlocked(state: &AppState, action: &str, target: Option<&str>, details: Option<&str>) {
+    if let Ok(sm_lock) = state.storage_manager.lock() {
+        if let Some(sm) = sm_lock.as_ref() {
+            desktop_audit(sm, action, target, details);
+        }
+    }
+}
+
 #[tauri::command]
 fn create_admin_user(
     state: State<AppState>,
@@ -196,11 +233,15 @@ fn create_admin_user(
     let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
     match sm_lock.as_ref() {
         Some(sm) => {
+            auth::check_password_strength(&password, &username).map_err(|e| e.to_string())?;
             // Hash password
             let password_hash = auth::hash_password(&password).map_err(|e| e.to_string())?;
             // Create admin user (is_admin=true)
-            sm.create_user(&username, &email, &password_hash, true)
-                .map_err(|e| e.to_string())
+            let user = sm.create_user(&username, &email, &password_hash, true)
+                .map_err(|e| e.to_string())?;
+            sm.set_setting(DESKTOP_SESSION_KEY, &user.user_id).map_err(|e| e.to_string())?;
+            sm.audit(Some(&user.user_id), "auth.register", Some(&user.user_id), None, Some("desktop"));
+            Ok(user)
         }
         None => Err("Node not initialized".to_string()),
     }
@@ -221,17 +262,46 @@ fn login_user(state: State<AppState>, username: String, password: String) -> Res
 
     let valid = auth::verify_password(&password, &hash).map_err(|e| e.to_string())?;
     if !valid {
+        sm.audit(None, "auth.login_failed", Some(&user.user_id), None, Some("desktop"));
         return Err("Invalid credentials".to_string());
     }
+    if user.suspension.is_some() {
+        sm.audit(None, "auth.login_blocked", Some(&user.user_id), None, Some("suspended"));
+        return Err("This account is suspended".to_string());
+    }
+    if user.is_expired() {
+        sm.audit(None, "auth.login_blocked", Some(&user.user_id), None, Some("expired"));
+        return Err("This guest account has expired".to_string());
+    }
+    if auth::needs_rehash(&hash) {
+        if let Ok(new_hash) = auth::hash_password(&password) {
+            let _ = sm.rehash_password(&user.user_id, &hash, &new_hash);
+        }
+    }
 
+    sm.set_setting(DESKTOP_SESSION_KEY, &user.user_id).map_err(|e| e.to_string())?;
+    sm.audit(Some(&user.user_id), "auth.login", Some(&user.user_id), None, Some("desktop"));
     Ok(user)
 }
 
+#[tauri::command]
+fn logout_user(state: State<AppState>) -> Result<(), String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        // Kept as an empty value so the pre-session fallback no longer applies
+        Some(sm) => sm.set_setting(DESKTOP_SESSION_KEY, "").map_err(|e| e.to_string()),
+        None => Ok(()),
+    }
+}
+
 #[tauri::command]
 fn list_users(state: State<AppState>) -> Result<Vec<User>, String> {
     let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
     match sm_lock.as_ref() {
-        Some(sm) => sm.list_users().map_err(|e| e.to_string()),
+        Some(sm) => {
+            require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            sm.list_users().map_err(|e| e.to_string())
+        },
         None => Err("Node not initialized".to_string()),
     }
 }
@@ -240,7 +310,51 @@ fn list_users(state: State<AppState>) -> Result<Vec<User>, String> {
 fn delete_user(state: State<AppState>, user_id: String) -> Result<(), String> {
     let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
     match sm_lock.as_ref() {
-        Some(sm) => sm.delete_user(&user_id).map_err(|e| e.to_string()),
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            sm.ensure_can_manage_user(&acting, &user_id).map_err(|e| e.to_string())?;
+            let username = sm.get_user_by_id(&user_id).ok().flatten().map(|u| u.username);
+            sm.delete_user(&user_id).map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "user.delete", Some(&user_id), None, username.as_deref());
+            Ok(())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn suspend_user(state: State<AppState>, user_id: String, reason: Option<String>, until: Option<String>) -> Result<(), String> {
+    if let Some(until) = &until {
+        chrono::DateTime::parse_from_rfc3339(until).map_err(|_| "Invalid suspension end date".to_string())?;
+    }
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            if acting == user_id {
+                return Err("You can't suspend yourself".to_string());
+            }
+            sm.ensure_can_manage_user(&acting, &user_id).map_err(|e| e.to_string())?;
+            let reason = reason.as_deref().map(str::trim).filter(|r| !r.is_empty());
+            sm.suspend_user(&user_id, reason, until.as_deref()).map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "user.suspend", Some(&user_id), None, reason);
+            Ok(())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn unsuspend_user(state: State<AppState>, user_id: String) -> Result<(), String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            sm.ensure_can_manage_user(&acting, &user_id).map_err(|e| e.to_string())?;
+            sm.unsuspend_user(&user_id).map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "user.unsuspend", Some(&user_id), None, None);
+            Ok(())
+        },
         None => Err("Node not initialized".to_string()),
     }
 }
@@ -249,7 +363,506 @@ fn delete_user(state: State<AppState>, user_id: String) -> Result<(), String> {
 fn update_user_role(state: State<AppState>, user_id: String, is_admin: bool) -> Result<(), String> {
     let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
     match sm_lock.as_ref() {
-        Some(sm) => sm.update_user_role(&user_id, is_admin).map_err(|e| e.to_string()),
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            sm.update_user_role(&acting, &user_id, is_admin).map_err(|e| e.to_string())?;
+            let role = if is_admin { roles::ROLE_ADMIN } else { roles::ROLE_MEMBER };
+            sm.audit(Some(&acting), "user.role", Some(&user_id), None, Some(role));
+            Ok(())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn set_user_role(state: State<AppState>, user_id: String, role: String) -> Result<(), String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            sm.set_user_role(&acting, &user_id, &role).map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "user.role", Some(&user_id), None, Some(&role));
+            Ok(())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+// --- Role commands ---
+
+#[tauri::command]
+fn list_roles(state: State<AppState>) -> Result<Vec<Role>, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            require_desktop_permission(sm, roles::PERM_ROLES_MANAGE)
+                .or_else(|_| require_desktop_permission(sm, roles::PERM_USERS_MANAGE))?;
+            sm.list_roles().map_err(|e| e.to_string())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn create_role(state: State<AppState>, name: String, permissions: Vec<String>) -> Result<Role, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_ROLES_MANAGE)?;
+            let role = sm.create_role(&acting, name.trim(), &permissions).map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "role.create", Some(&role.role_id), None, Some(&role.permissions.join(" ")));
+            Ok(role)
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn update_role(state: State<AppState>, role_id: String, name: String, permissions: Vec<String>) -> Result<Role, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_ROLES_MANAGE)?;
+            let role = sm.update_role(&acting, &role_id, name.trim(), &permissions).map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "role.update", Some(&role_id), None, Some(&role.permissions.join(" ")));
+            Ok(role)
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn delete_role(state: State<AppState>, role_id: String) -> Result<(), String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_ROLES_MANAGE)?;
+            if sm.delete_role(&role_id).map_err(|e| e.to_string())? {
+                sm.audit(Some(&acting), "role.delete", Some(&role_id), None, None);
+                Ok(())
+            } else {
+                Err("Role not found".to_string())
+            }
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn list_locked_accounts(state: State<AppState>) -> Result<Vec<LockedAccount>, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            sm.list_locked_accounts().map_err(|e| e.to_string())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn unlock_user(state: State<AppState>, user_id: String) -> Result<(), String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            sm.clear_failed_logins(&user_id).map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "user.unlock", Some(&user_id), None, None);
+            Ok(())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+/// Import members from a `username,email,role` CSV. Each created account
+/// comes with a one-time setup code the member uses to choose a password.
+#[tauri::command]
+fn import_members(state: State<AppState>, csv: String) -> Result<member_csv::ImportReport, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            let report = member_csv::import_members(sm, &acting, &csv).map_err(|e| e.to_string())?;
+            for member in &report.created {
+                sm.audit(Some(&acting), "user.import", Some(&member.user_id), None, Some(&member.role));
+            }
+            Ok(report)
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn export_members(state: State<AppState>) -> Result<String, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            let data = member_csv::export_members(sm).map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "user.export", None, None, None);
+            Ok(data)
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn create_guest(
+    state: State<AppState>,
+    username: String,
+    expires_at: String,
+    conversation_ids: Vec<String>,
+) -> Result<serde_json::Value, String> {
+    let username = username.trim();
+    if username.is_empty() {
+        return Err("Username is required".to_string());
+    }
+    let password = auth::generate_temporary_password().map_err(|e| e.to_string())?;
+    let password_hash = auth::hash_password(&password).map_err(|e| e.to_string())?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            let guest = sm.create_guest(username, &password_hash, &expires_at, &conversation_ids)
+                .map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "guest.create", Some(&guest.user_id), None, guest.expires_at.as_deref());
+
+            let mut value = serde_json::to_value(&guest).map_err(|e| e.to_string())?;
+            value["temporary_password"] = serde_json::json!(password);
+            Ok(value)
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn list_guest_invites(state: State<AppState>) -> Result<Vec<GuestInvite>, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            sm.list_guest_invites().map_err(|e| e.to_string())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+/// Create a guest invite. The returned `token` is shown only once.
+#[tauri::command]
+fn create_guest_invite(
+    state: State<AppState>,
+    expires_at: String,
+    conversation_ids: Vec<String>,
+    max_uses: Option<u32>,
+) -> Result<serde_json::Value, String> {
+    if max_uses == Some(0) {
+        return Err("An invite needs at least one use".to_string());
+    }
+    let token = auth::generate_secret(auth::GUEST_INVITE_PREFIX).map_err(|e| e.to_string())?;
+
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            let invite = sm.create_guest_invite(
+                &acting,
+                &auth::hash_secret(&token),
+                &conversation_ids,
+                &expires_at,
+                max_uses,
+            ).map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "guest_invite.create", Some(&invite.invite_id), None, Some(&invite.expires_at));
+
+            let mut value = serde_json::to_value(&invite).map_err(|e| e.to_string())?;
+            value["token"] = serde_json::json!(token);
+            Ok(value)
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn delete_guest_invite(state: State<AppState>, invite_id: String) -> Result<(), String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_USERS_MANAGE)?;
+            if sm.delete_guest_invite(&invite_id).map_err(|e| e.to_string())? {
+                sm.audit(Some(&acting), "guest_invite.delete", Some(&invite_id), None, None);
+                Ok(())
+            } else {
+                Err("Invite not found".to_string())
+            }
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+/// Search the audit log, newest first
+#[tauri::command]
+fn list_audit_events(
+    state: State<AppState>,
+    filter: AuditFilter,
+    limit: Option<u32>,
+    offset: Option<u32>,
+) -> Result<serde_json::Value, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            let limit = limit.unwrap_or(100).clamp(1, 500);
+            let (events, total) = sm.list_audit_events(&filter, limit, offset.unwrap_or(0))
+                .map_err(|e| e.to_string())?;
+            Ok(serde_json::json!({ "events": events, "total": total }))
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn get_trusted_proxies(state: State<AppState>) -> Result<Vec<String>, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            sm.get_trusted_proxies().map_err(|e| e.to_string())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+/// Set the proxies whose X-Forwarded-For / CF-Connecting-IP headers are believed
+#[tauri::command]
+fn set_trusted_proxies(state: State<AppState>, proxies: Vec<String>) -> Result<(), String> {
+    let proxies: Vec<String> = proxies.iter()
+        .map(|p| p.trim().to_string())
+        .filter(|p| !p.is_empty())
+        .collect();
+    if let Some(invalid) = proxies.iter().find(|p| hub_api::parse_trusted_proxy(p).is_none()) {
+        return Err(format!("Invalid address or CIDR range: {}", invalid));
+    }
+
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            sm.set_trusted_proxies(&proxies).map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "settings.trusted_proxies", None, None, Some(&proxies.join(" ")));
+            Ok(())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+// --- Email commands ---
+
+/// Outgoing mail settings, with the password redacted; null when email is off
+#[tauri::command]
+fn get_smtp_config(state: State<AppState>) -> Result<serde_json::Value, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            let config = mailer::load_config(sm).map_err(|e| e.to_string())?;
+            Ok(config.map(|c| c.redacted()).unwrap_or(serde_json::Value::Null))
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+/// Save outgoing mail settings. Leave `password` out to keep the stored one.
+#[tauri::command]
+fn set_smtp_config(state: State<AppState>, config: mailer::SmtpConfig) -> Result<serde_json::Value, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            let saved = mailer::save_config(sm, config).map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "settings.smtp", None, None, Some(&saved.host));
+            Ok(saved.redacted())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn clear_smtp_config(state: State<AppState>) -> Result<(), String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            mailer::clear_config(sm).map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "settings.smtp", None, None, Some("disabled"));
+            Ok(())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+/// Send a test email with the saved settings and report the SMTP error, if any
+#[tauri::command]
+async fn send_test_email(state: State<'_, AppState>, to: String) -> Result<(), String> {
+    let (config, email) = {
+        let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+        let sm = sm_lock.as_ref().ok_or("Node not initialized")?;
+        require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+        let config = mailer::load_config(sm).map_err(|e| e.to_string())?
+            .ok_or("Outgoing email is not configured")?;
+        (config, mailer::test_email(&hub_api::hub_name(sm)))
+    };
+
+    let to = to.trim().to_string();
+    tauri::async_runtime::spawn_blocking(move || mailer::send(&config, &to, &email.subject, &email.body))
+        .await
+        .map_err(|e| e.to_string())?
+        .map_err(|e| format!("{:#}", e))
+}
+
+#[tauri::command]
+fn list_email_outbox(state: State<AppState>) -> Result<Vec<OutgoingEmail>, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            sm.list_outbox(100).map_err(|e| e.to_string())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+// --- Signing key commands ---
+
+#[tauri::command]
+fn list_signing_keys(state: State<AppState>) -> Result<Vec<auth::SigningKeyInfo>, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            auth::list_signing_keys(sm.db()).map_err(|e| e.to_string())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn rotate_signing_key(
+    state: State<AppState>,
+    algorithm: String,
+    grace_hours: Option<i64>,
+) -> Result<auth::SigningKeyInfo, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            let key = auth::rotate_signing_key(sm.db(), &algorithm, grace_hours)
+                .map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "signing_key.rotate", Some(&key.kid), None, Some(&key.algorithm));
+            Ok(key)
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+// --- Password hashing commands ---
+
+#[tauri::command]
+fn get_password_hash_params(state: State<AppState>) -> Result<auth::PasswordHashParams, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            Ok(auth::password_hash_params())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+/// Change the Argon2id cost. Members' passwords are rehashed as they sign in.
+#[tauri::command]
+fn set_password_hash_params(state: State<AppState>, params: auth::PasswordHashParams) -> Result<(), String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            auth::set_password_hash_params(sm.db(), params).map_err(|e| e.to_string())?;
+            let details = format!("m={} t={} p={}", params.memory_kib, params.iterations, params.parallelism);
+            sm.audit(Some(&acting), "settings.password_hashing", None, None, Some(&details));
+            Ok(())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+// --- OIDC client commands ---
+
+#[tauri::command]
+fn list_oidc_clients(state: State<AppState>) -> Result<Vec<OidcClient>, String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            sm.list_oidc_clients().map_err(|e| e.to_string())
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+/// Register an app that signs members in through the hub. Confidential clients
+/// get a client secret, returned here once alongside the client.
+#[tauri::command]
+fn create_oidc_client(
+    state: State<AppState>,
+    name: String,
+    redirect_uris: Vec<String>,
+    confidential: bool,
+) -> Result<serde_json::Value, String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("Client name is required".to_string());
+    }
+    if redirect_uris.is_empty() {
+        return Err("At least one redirect URI is required".to_string());
+    }
+    for uri in &redirect_uris {
+        reqwest::Url::parse(uri).map_err(|_| format!("Invalid redirect URI: {}", uri))?;
+    }
+
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            let secret = if confidential {
+                Some(auth::generate_secret("ctnsec_").map_err(|e| e.to_string())?)
+            } else {
+                None
+            };
+            let secret_hash = secret.as_deref().map(auth::hash_secret);
+            let client = sm.create_oidc_client(name, &redirect_uris, secret_hash.as_deref())
+                .map_err(|e| e.to_string())?;
+            sm.audit(Some(&acting), "oidc_client.create", Some(&client.client_id), None, Some(&client.name));
+
+            let mut value = serde_json::to_value(&client).map_err(|e| e.to_string())?;
+            value["client_secret"] = serde_json::json!(secret);
+            Ok(value)
+        },
+        None => Err("Node not initialized".to_string()),
+    }
+}
+
+#[tauri::command]
+fn delete_oidc_client(state: State<AppState>, client_id: String) -> Result<(), String> {
+    let sm_lock = state.storage_manager.lock().map_err(|e| e.to_string())?;
+    match sm_lock.as_ref() {
+        Some(sm) => {
+            let acting = require_desktop_permission(sm, roles::PERM_HUB_MANAGE)?;
+            if sm.delete_oidc_client(&client_id).map_err(|e| e.to_string())? {
+                sm.audit(Some(&acting), "oidc_client.delete", Some(&client_id), None, None);
+                Ok(())
+            } else {
+                Err("OIDC client not found".to_string())
+            }
+        },
         None => Err("Node not initialized".to_string()),
     }
 }
@@ -287,7 +900,9 @@ fn delete_file(state: State<AppState>, file_name: String) -> Result<(), String>
             // Get admin user for desktop operations
             let admin = sm.get_first_admin().map_err(|e| e.to_string())?
                 .ok_or("No admin user found")?;
-            sm.delete_file(&admin.user_id, &file_name).map_err(|e| e.to_string())
+            sm.delete_file(&admin.user_id, &file_name).map_err(|e| e.to_string())?;
+            desktop_audit(sm, "file.delete", Some(&file_name), None);
+            Ok(())
         },
         None => Err("Node not initialized".to_string()),
     }
@@ -314,7 +929,10 @@ fn update_file_visibility(state: State<AppState>, file_name: String, is_public:
         Some(sm) => {
             let admin = sm.get_first_admin().map_err(|e| e.to_string())?
                 .ok_or("No admin user found")?;
-            sm.update_file_visibility(&admin.user_id, &file_name, is_public).map_err(|e| e.to_string())
+            sm.update_file_visibility(&admin.user_id, &file_name, is_public).map_err(|e| e.to_string())?;
+            let visibility = if is_public { "public" } else { "private" };
+            desktop_audit(sm, "file.visibility", Some(&file_name), Some(visibility));
+            Ok(())
         },
         None => Err("Node not initialized".to_string()),
     }
@@ -423,6 +1041,7 @@ fn start_quick_tunnel(state: State<AppState>, local_port: u16) -> Result<String,
         let tm = tm_lock.get_or_insert_with(|| TunnelManager::new(&install_path));
         tm.start_quick_tunnel(local_port).map_err(|e| e.to_string())?
     };
+    desktop_audit_unlocked(&state, "tunnel.start", Some(&tunnel_url), Some("quick"));
 
     // Auto-register with registry on every tunnel start (fire-and-forget).
     // This keeps the registry tunnel_url current even when quick tunnel URL rotates.
@@ -484,8 +1103,10 @@ fn setup_tunnel(
     let mut tm_lock = state.tunnel_manager.lock().map_err(|e| e.to_string())?;
     let tm = tm_lock.get_or_insert_with(|| TunnelManager::new(sm.install_path()));
 
-    tm.setup_tunnel(&api_token, &tunnel_name, &hostname, local_port)
-        .map_err(|e| e.to_string())
+    let config = tm.setup_tunnel(&api_token, &tunnel_name, &hostname, local_port)
+        .map_err(|e| e.to_string())?;
+    desktop_audit(sm, "tunnel.setup", Some(&hostname), Some(&tunnel_name));
+    Ok(config)
 }
 
 #[tauri::command]
@@ -507,7 +1128,11 @@ fn start_tunnel(state: State<AppState>) -> Result<(), String> {
                     ));
                 }
             }
-            tm.start_tunnel().map_err(|e| e.to_string())
+            tm.start_tunnel().map_err(|e| e.to_string())?;
+            let hostname = tm.get_config().map(|c| c.hostname.clone());
+            drop(tm_lock);
+            desktop_audit_unlocked(&state, "tunnel.start", hostname.as_deref(), None);
+            Ok(())
         }
         None => Err("Tunnel not configured".to_string()),
     }
@@ -521,7 +1146,12 @@ fn stop_tunnel(state: State<AppState>) -> Result<(), String> {
     }
     let mut tm_lock = state.tunnel_manager.lock().map_err(|e| e.to_string())?;
     match tm_lock.as_mut() {
-        Some(tm) => tm.stop_tunnel().map_err(|e| e.to_string()),
+        Some(tm) => {
+            tm.stop_tunnel().map_err(|e| e.to_string())?;
+            drop(tm_lock);
+            desktop_audit_unlocked(&state, "tunnel.stop", None, None);
+            Ok(())
+        }
         None => Err("Tunnel not configured".to_string()),
     }
 }
@@ -566,6 +1196,8 @@ async fn factory_reset(
     {
         let mut sm = state.storage_manager.lock().map_err(|e| e.to_string())?;
         if let Some(ref mut storage) = *sm {
+            // Recorded first so the actor's name is still known
+            desktop_audit(storage, "hub.factory_reset", None, None);
             storage.factory_reset().map_err(|e| e.to_string())?;
         } else {
             return Err("Storage not initialized".to_string());
@@ -723,6 +1355,8 @@ fn start_tailscale_funnel(state: State<AppState>, port: u16) -> Result<String, S
         }
     }
 
+    desktop_audit_unlocked(&state, "tunnel.start", Some(&tunnel_url), Some("tailscale"));
+
     // Track the active funnel port for the watchdog
     if let Ok(mut fp) = state.tailscale_funnel_port.lock() {
         *fp = Some(port);
@@ -763,6 +1397,8 @@ fn stop_tailscale_funnel(state: State<AppState>) -> Result<(), String> {
         ts.disable_funnel().map_err(|e| e.to_string())?;
     }
 
+    desktop_audit_unlocked(&state, "tunnel.stop", None, Some("tailscale"));
+
     // Clear the funnel port so the watchdog won't try to restart it
     if let Ok(mut fp) = state.tailscale_funnel_port.lock() {
         *fp = None;
@@ -823,6 +1459,8 @@ pub fn run() {
     let autostart_ts_port = tailscale_funnel_port.clone();
     let autostart_stopped_flag = tunnel_stopped_manually.clone();
     let bg_mode_for_close = background_mode.clone();
+    let cleanup_sm = storage_manager.clone();
+    let mail_sm = storage_manager.clone();
 
     tauri::Builder::default()
         .plugin(tauri_plugin_updater::Builder::new().build())
@@ -857,7 +1495,38 @@ pub fn run() {
             login_user,
             list_users,
             delete_user,
+            suspend_user,
+            unsuspend_user,
             update_user_role,
+            set_user_role,
+            logout_user,
+            list_roles,
+            create_role,
+            update_role,
+            delete_role,
+            list_locked_accounts,
+            unlock_user,
+            import_members,
+            export_members,
+            create_guest,
+            list_guest_invites,
+            create_guest_invite,
+            delete_guest_invite,
+            list_audit_events,
+            get_trusted_proxies,
+            set_trusted_proxies,
+            get_smtp_config,
+            set_smtp_config,
+            clear_smtp_config,
+            send_test_email,
+            list_email_outbox,
+            list_signing_keys,
+            rotate_signing_key,
+            get_password_hash_params,
+            set_password_hash_params,
+            list_oidc_clients,
+            create_oidc_client,
+            delete_oidc_client,
             upload_file,
             list_files,
             delete_file,
@@ -920,8 +1589,11 @@ pub fn run() {
                                 .join("citinet.db");
                             if db_path.exists() {
                                 if let Ok(sm) = StorageManager::open(&install_path) {
-                                    if let Err(e) = auth::init_jwt_secret(sm.db()) {
-                                        log::error!("Failed to initialize JWT secret: {}", e);
+                                    if let Err(e) = auth::init_signing_keys(sm.db()) {
+                                        log::error!("Failed to initialize JWT signing keys: {}", e);
+                                    }
+                                    if let Err(e) = auth::init_password_hashing(sm.db()) {
+                                        log::error!("Failed to load password hashing parameters: {}", e);
                                     }
                                     let install = std::path::PathBuf::from(&install_path);
                                     let tm = TunnelManager::new(&install);
@@ -1006,6 +1678,8 @@ pub fn run() {
                 started_at,
                 msg_tx,
                 auth_limiter: hub_api::RateLimiter::new(10, 1.0),
+                passkey_ceremonies: webauthn::ChallengeStore::new(),
+                presence: hub_api::PresenceTracker::new(),
             };
 
             tauri::async_runtime::spawn(async move {
@@ -1170,6 +1844,36 @@ pub fn run() {
                 }
             });
 
+            // Email outbox — SMTP is blocking, so the worker gets its own thread
+            std::thread::spawn(move || mailer::run_outbox_worker(mail_sm));
+
+            // Cleanup — every 5 minutes, anonymize guests whose account has
+            // expired (they're already refused in the meantime) and drop
+            // realtime events too old to replay.
+            tauri::async_runtime::spawn(async move {
+                loop {
+                    tokio::time::sleep(std::time::Duration::from_secs(300)).await;
+
+                    let Ok(sm_lock) = cleanup_sm.lock() else { continue };
+                    let Some(sm) = sm_lock.as_ref() else { continue };
+                    match sm.purge_expired_guests() {
+                        Ok(retired) => {
+                            for user_id in &retired {
+                                sm.audit(None, "guest.expire", Some(user_id), None, None);
+                            }
+                            if !retired.is_empty() {
+                                log::info!("Anonymized {} expired guest account(s)", retired.len());
+                            }
+                        }
+                        Err(e) => log::error!("Guest cleanup failed: {}", e),
+                    }
+                    let cutoff = (chrono::Utc::now() - chrono::Duration::hours(hub_api::EVENT_RETENTION_HOURS)).to_rfc3339();
+                    if let Err(e) = sm.purge_user_events(&cutoff) {
+                        log::error!("Event cleanup failed: {}", e);
+                    }
+                }
+            });
+
             Ok(())
         })
         .run(tauri::generate_context!())
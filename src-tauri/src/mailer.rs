@@ -0,0 +1,594 @@
+use anyhow::{Context, Result};
+use base64::Engine;
+use chrono::{Duration, Utc};
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::auth;
+use crate::storage_manager::{OutgoingEmail, StorageManager, User};
+
+/// hub_settings key holding the SMTP configuration as JSON
+const SETTING_KEY: &str = "smtp_config";
+
+/// Key for the stored SMTP password, kept in the config directory rather
+/// than the database, so a copy of the database alone doesn't reveal it
+const SECRET_KEY_FILE: &str = "smtp.key";
+
+/// Marks a stored password as encrypted; older configs hold it in plain text
+const ENCRYPTED_PREFIX: &str = "enc:";
+
+/// How often the outbox worker looks for mail to send
+pub const OUTBOX_POLL_SECS: u64 = 30;
+
+/// Most emails sent per worker pass
+const OUTBOX_BATCH: u32 = 20;
+
+/// Wait before each retry of a failed delivery. Once these run out the
+/// email is marked failed.
+const RETRY_DELAYS_MINUTES: [i64; 5] = [1, 5, 15, 60, 240];
+
+/// Delivered and failed emails are dropped from the outbox after this long
+const OUTBOX_RETENTION_DAYS: i64 = 7;
+
+const SMTP_TIMEOUT_SECS: u64 = 20;
+
+/// How long an email verification link works
+pub const VERIFY_TOKEN_TTL_HOURS: i64 = 48;
+
+/// How long a password reset code works
+pub const RESET_CODE_TTL_MINUTES: i64 = 60;
+
+pub const KIND_VERIFY: &str = "verify";
+pub const KIND_PASSWORD_RESET: &str = "password_reset";
+pub const KIND_NOTIFICATION: &str = "notification";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    /// Plain connection, e.g. a relay on the local network or a test sink
+    None,
+    /// Upgrade with STARTTLS (usually port 587)
+    Starttls,
+    /// TLS from the start (usually port 465)
+    Tls,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    pub tls: SmtpTls,
+    pub from_address: String,
+    #[serde(default)]
+    pub from_name: Option<String>,
+    /// Address members reach the hub at, for links in emails. Defaults to
+    /// the tunnel hostname.
+    #[serde(default)]
+    pub public_url: Option<String>,
+}
+
+impl SmtpConfig {
+    /// The config as shown to admins: the password is replaced by whether one is set
+    pub fn redacted(&self) -> Value {
+        json!({
+            "host": self.host,
+            "port": self.port,
+            "username": self.username,
+            "has_password": self.password.as_deref().is_some_and(|p| !p.is_empty()),
+            "tls": self.tls,
+            "from_address": self.from_address,
+            "from_name": self.from_name,
+            "public_url": self.public_url,
+        })
+    }
+
+    fn sender(&self) -> Result<Mailbox> {
+        let address = self.from_address.parse().context("Invalid sender address")?;
+        Ok(Mailbox::new(self.from_name.clone().filter(|n| !n.is_empty()), address))
+    }
+}
+
+pub fn load_config(sm: &StorageManager) -> Result<Option<SmtpConfig>> {
+    let Some(value) = sm.get_setting(SETTING_KEY)? else {
+        return Ok(None);
+    };
+    let mut config: SmtpConfig = serde_json::from_str(&value).context("Stored SMTP config is invalid")?;
+    if let Some(sealed) = config.password.as_deref().and_then(|p| p.strip_prefix(ENCRYPTED_PREFIX)) {
+        config.password = Some(decrypt_password(sm, sealed)?);
+    }
+    Ok(Some(config))
+}
+
+/// Validate and store the config. A missing password keeps the stored one,
+/// so admins can change other fields without re-entering it; an empty one
+/// clears it.
+pub fn save_config(sm: &StorageManager, mut config: SmtpConfig) -> Result<SmtpConfig> {
+    config.host = config.host.trim().to_string();
+    config.from_address = config.from_address.trim().to_string();
+    if config.host.is_empty() {
+        anyhow::bail!("SMTP host is required");
+    }
+    if config.port == 0 {
+        anyhow::bail!("SMTP port is required");
+    }
+    config.sender()?;
+    config.username = config.username.filter(|u| !u.trim().is_empty());
+
+    if config.password.is_none() {
+        config.password = load_config(sm)?.and_then(|c| c.password);
+    }
+    config.password = config.password.filter(|p| !p.is_empty());
+    config.public_url = config.public_url
+        .map(|u| u.trim().trim_end_matches('/').to_string())
+        .filter(|u| !u.is_empty());
+    if let Some(url) = &config.public_url {
+        let host = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://"));
+        if !host.is_some_and(|h| !h.is_empty() && !h.contains(char::is_whitespace)) {
+            anyhow::bail!("Public URL must start with https:// or http://");
+        }
+    }
+
+    let mut stored = config.clone();
+    if let Some(password) = &config.password {
+        stored.password = Some(format!("{}{}", ENCRYPTED_PREFIX, encrypt_password(sm, password)?));
+    }
+    sm.set_setting(SETTING_KEY, &serde_json::to_string(&stored)?)?;
+    Ok(config)
+}
+
+/// The password key, created on first use
+fn secret_key(sm: &StorageManager) -> Result<LessSafeKey> {
+    let path = sm.install_path().join("config").join(SECRET_KEY_FILE);
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let mut bytes = vec![0u8; AES_256_GCM.key_len()];
+            SystemRandom::new().fill(&mut bytes)
+                .map_err(|_| anyhow::anyhow!("Failed to generate SMTP key"))?;
+            write_private(&path, &bytes).context("Failed to store SMTP key")?;
+            bytes
+        }
+        Err(e) => return Err(e).context("Failed to read SMTP key"),
+    };
+    let key = UnboundKey::new(&AES_256_GCM, &bytes)
+        .map_err(|_| anyhow::anyhow!("SMTP key is invalid"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+/// Write a file only the hub's user can read
+fn write_private(path: &std::path::Path, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(data)
+}
+
+/// AES-GCM seal the password; returns base64 of nonce and ciphertext
+fn encrypt_password(sm: &StorageManager, password: &str) -> Result<String> {
+    let key = secret_key(sm)?;
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce)
+        .map_err(|_| anyhow::anyhow!("Failed to generate nonce"))?;
+    let mut sealed = password.as_bytes().to_vec();
+    key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::empty(), &mut sealed)
+        .map_err(|_| anyhow::anyhow!("Failed to encrypt SMTP password"))?;
+    Ok(base64::engine::general_purpose::STANDARD.encode([nonce.as_slice(), &sealed].concat()))
+}
+
+fn decrypt_password(sm: &StorageManager, sealed: &str) -> Result<String> {
+    let data = base64::engine::general_purpose::STANDARD.decode(sealed)
+        .context("Stored SMTP password is invalid")?;
+    if data.len() < NONCE_LEN {
+        anyhow::bail!("Stored SMTP password is invalid");
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| anyhow::anyhow!("Stored SMTP password is invalid"))?;
+    let mut ciphertext = ciphertext.to_vec();
+    let plain = secret_key(sm)?.open_in_place(nonce, Aad::empty(), &mut ciphertext)
+        .map_err(|_| anyhow::anyhow!("Stored SMTP password can't be decrypted; enter it again"))?;
+    Ok(String::from_utf8(plain.to_vec())?)
+}
+
+/// Base for links in emails: the configured public URL, else the tunnel's.
+/// Never taken from a request's Host header, which a client controls.
+fn link_base(sm: &StorageManager, tunnel_url: Option<&str>) -> Result<Option<String>> {
+    let configured = load_config(sm)?.and_then(|c| c.public_url);
+    Ok(configured.or_else(|| tunnel_url.map(str::to_string)))
+}
+
+/// Turn email off. Queued emails stay in the outbox until it's configured again.
+pub fn clear_config(sm: &StorageManager) -> Result<()> {
+    sm.delete_setting(SETTING_KEY)
+}
+
+pub fn is_configured(sm: &StorageManager) -> bool {
+    matches!(load_config(sm), Ok(Some(_)))
+}
+
+fn build_transport(config: &SmtpConfig) -> Result<SmtpTransport> {
+    let builder = match config.tls {
+        SmtpTls::None => SmtpTransport::builder_dangerous(&config.host),
+        SmtpTls::Starttls => SmtpTransport::starttls_relay(&config.host)?,
+        SmtpTls::Tls => SmtpTransport::relay(&config.host)?,
+    };
+    let mut builder = builder
+        .port(config.port)
+        .timeout(Some(std::time::Duration::from_secs(SMTP_TIMEOUT_SECS)));
+    if let Some(username) = &config.username {
+        builder = builder.credentials(Credentials::new(
+            username.clone(),
+            config.password.clone().unwrap_or_default(),
+        ));
+    }
+    Ok(builder.build())
+}
+
+/// Send one email right away, bypassing the outbox
+pub fn send(config: &SmtpConfig, to: &str, subject: &str, body: &str) -> Result<()> {
+    let message = Message::builder()
+        .from(config.sender()?)
+        .to(to.parse().context("Invalid recipient address")?)
+        .subject(subject)
+        .header(ContentType::TEXT_PLAIN)
+        .body(body.to_string())
+        .context("Failed to build email")?;
+    build_transport(config)?.send(&message).context("SMTP delivery failed")?;
+    Ok(())
+}
+
+// --- Templates ---
+// Plain text with {placeholder}s. Message contents are never included in
+// notifications; members read them on the hub.
+
+pub struct Email {
+    pub subject: String,
+    pub body: String,
+}
+
+const VERIFY_SUBJECT: &str = "Confirm your email for {hub}";
+const VERIFY_BODY: &str = "Hi {username},
+
+Please confirm that this is your email address by opening the link below:
+
+{link}
+
+The link works for {hours} hours. If you didn't sign up for {hub}, you can ignore this email.
+";
+
+const RESET_SUBJECT: &str = "Your {hub} password reset code";
+const RESET_BODY: &str = "Hi {username},
+
+Someone asked to reset the password of your {hub} account. Enter this code in the app to choose a new password:
+
+{code}
+
+The code works for {minutes} minutes. If you didn't ask for this, you can ignore this email; your password hasn't changed.
+";
+
+const NOTIFICATION_SUBJECT: &str = "New messages on {hub}";
+const NOTIFICATION_BODY: &str = "Hi {username},
+
+{sender} sent you a message on {hub}. Open the hub to read it:
+
+{link}
+
+You get these emails because you turned on message notifications. You can turn them off in your profile settings.
+";
+
+const TEST_SUBJECT: &str = "Test email from {hub}";
+const TEST_BODY: &str = "This is a test email from {hub}. Outgoing email is working.
+";
+
+fn render(template: &str, vars: &[(&str, &str)]) -> String {
+    vars.iter().fold(template.to_string(), |text, (name, value)| {
+        text.replace(&format!("{{{}}}", name), value)
+    })
+}
+
+fn email(subject: &str, body: &str, vars: &[(&str, &str)]) -> Email {
+    Email {
+        subject: render(subject, vars),
+        body: render(body, vars),
+    }
+}
+
+pub fn verification_email(hub: &str, username: &str, link: &str) -> Email {
+    let hours = VERIFY_TOKEN_TTL_HOURS.to_string();
+    email(VERIFY_SUBJECT, VERIFY_BODY, &[("hub", hub), ("username", username), ("link", link), ("hours", &hours)])
+}
+
+pub fn password_reset_email(hub: &str, username: &str, code: &str) -> Email {
+    let minutes = RESET_CODE_TTL_MINUTES.to_string();
+    email(RESET_SUBJECT, RESET_BODY, &[("hub", hub), ("username", username), ("code", code), ("minutes", &minutes)])
+}
+
+pub fn notification_email(hub: &str, username: &str, sender: &str, link: &str) -> Email {
+    email(NOTIFICATION_SUBJECT, NOTIFICATION_BODY, &[("hub", hub), ("username", username), ("sender", sender), ("link", link)])
+}
+
+pub fn test_email(hub: &str) -> Email {
+    email(TEST_SUBJECT, TEST_BODY, &[("hub", hub)])
+}
+
+// --- Outbox ---
+
+/// Put an email in the outbox for the worker to send. Returns false, and
+/// queues nothing, when outgoing email isn't configured.
+pub fn queue(sm: &StorageManager, to: &str, email: &Email, kind: &str) -> Result<bool> {
+    if !is_configured(sm) {
+        return Ok(false);
+    }
+    sm.queue_email(to, &email.subject, &email.body, kind)?;
+    Ok(true)
+}
+
+/// Email the user a link confirming their address. Returns false when
+/// outgoing email isn't configured or the hub has no public URL.
+pub fn send_verification(sm: &StorageManager, hub: &str, user: &User, tunnel_url: Option<&str>) -> Result<bool> {
+    if !is_configured(sm) {
+        return Ok(false);
+    }
+    let Some(base_url) = link_base(sm, tunnel_url)? else {
+        log::warn!("Not sending a verification email: the hub has no public URL");
+        return Ok(false);
+    };
+    let token = auth::generate_secret("")?;
+    let expires_at = (Utc::now() + Duration::hours(VERIFY_TOKEN_TTL_HOURS)).to_rfc3339();
+    sm.create_email_token(&auth::hash_secret(&token), &user.user_id, KIND_VERIFY, &user.email, &expires_at)?;
+    let link = format!("{}/api/auth/verify-email?token={}", base_url, token);
+    queue(sm, &user.email, &verification_email(hub, &user.username, &link), KIND_VERIFY)
+}
+
+/// Email the user a code for choosing a new password. Returns false when
+/// outgoing email isn't configured.
+pub fn send_password_reset(sm: &StorageManager, hub: &str, user: &User) -> Result<bool> {
+    if !is_configured(sm) {
+        return Ok(false);
+    }
+    let code = auth::generate_temporary_password()?;
+    let expires_at = (Utc::now() + Duration::minutes(RESET_CODE_TTL_MINUTES)).to_rfc3339();
+    sm.create_email_token(&auth::hash_secret(&code), &user.user_id, KIND_PASSWORD_RESET, &user.email, &expires_at)?;
+    queue(sm, &user.email, &password_reset_email(hub, &user.username, &code), KIND_PASSWORD_RESET)
+}
+
+/// Tell opted-in members of a conversation about a new message. A member
+/// with a notification still waiting in the outbox isn't sent another, so
+/// a busy conversation doesn't flood their inbox.
+pub fn notify_new_message(
+    sm: &StorageManager,
+    hub: &str,
+    conversation_id: &str,
+    sender_id: &str,
+    sender_name: &str,
+    tunnel_url: Option<&str>,
+) -> Result<()> {
+    if !is_configured(sm) {
+        return Ok(());
+    }
+    // A notification without a working link isn't worth sending
+    let Some(base_url) = link_base(sm, tunnel_url)? else {
+        return Ok(());
+    };
+    for member in sm.notification_recipients(conversation_id, sender_id)? {
+        if sm.has_pending_email(&member.email, KIND_NOTIFICATION)? {
+            continue;
+        }
+        let email = notification_email(hub, &member.username, sender_name, &base_url);
+        sm.queue_email(&member.email, &email.subject, &email.body, KIND_NOTIFICATION)?;
+    }
+    Ok(())
+}
+
+/// When to retry after `attempts` failed deliveries, or None to give up
+fn retry_at(attempts: u32) -> Option<String> {
+    let delay = RETRY_DELAYS_MINUTES.get(attempts.saturating_sub(1) as usize)?;
+    Some((Utc::now() + Duration::minutes(*delay)).to_rfc3339())
+}
+
+/// Send everything that's due. The storage lock is only held to read the
+/// batch and record each outcome, never while talking to the SMTP server.
+pub fn deliver_due(storage: &Arc<Mutex<Option<StorageManager>>>) {
+    let batch: Option<(SmtpConfig, Vec<OutgoingEmail>)> = {
+        let sm_lock = storage.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(sm) = sm_lock.as_ref() else { return };
+        let cutoff = (Utc::now() - Duration::days(OUTBOX_RETENTION_DAYS)).to_rfc3339();
+        if let Err(e) = sm.purge_outbox(&cutoff) {
+            log::error!("Failed to purge email outbox: {}", e);
+        }
+        match (load_config(sm), sm.due_emails(OUTBOX_BATCH)) {
+            (Ok(Some(config)), Ok(due)) if !due.is_empty() => Some((config, due)),
+            (Err(e), _) | (_, Err(e)) => {
+                log::error!("Failed to read email outbox: {}", e);
+                None
+            }
+            _ => None,
+        }
+    };
+    let Some((config, due)) = batch else { return };
+
+    for email in due {
+        let result = send(&config, &email.to_address, &email.subject, &email.body);
+
+        let sm_lock = storage.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(sm) = sm_lock.as_ref() else { return };
+        let update = match result {
+            Ok(()) => sm.mark_email_sent(&email.email_id),
+            Err(e) => {
+                let attempts = email.attempts + 1;
+                let retry = retry_at(attempts);
+                log::warn!(
+                    "Email {} to {} failed (attempt {}): {:#}",
+                    email.email_id, email.to_address, attempts, e
+                );
+                sm.mark_email_failed(&email.email_id, &format!("{:#}", e), retry.as_deref())
+            }
+        };
+        if let Err(e) = update {
+            log::error!("Failed to record delivery of email {}: {}", email.email_id, e);
+        }
+    }
+}
+
+/// Poll the outbox forever. Runs on its own thread since SMTP is blocking.
+pub fn run_outbox_worker(storage: Arc<Mutex<Option<StorageManager>>>) {
+    loop {
+        std::thread::sleep(std::time::Duration::from_secs(OUTBOX_POLL_SECS));
+        deliver_due(&storage);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    /// Minimal SMTP sink: accepts one session and returns the DATA it received
+    fn smtp_sink() -> (u16, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"220 sink ESMTP\r\n").unwrap();
+
+            let mut data = String::new();
+            let mut in_data = false;
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 0 {
+                if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        writer.write_all(b"250 queued\r\n").unwrap();
+                    } else {
+                        data.push_str(&line);
+                    }
+                } else {
+                    let command = line.get(..4).unwrap_or("").to_ascii_uppercase();
+                    let reply: &[u8] = match command.as_str() {
+                        "EHLO" | "HELO" => b"250 sink\r\n",
+                        "DATA" => {
+                            in_data = true;
+                            b"354 go ahead\r\n"
+                        }
+                        "QUIT" => {
+                            writer.write_all(b"221 bye\r\n").unwrap();
+                            break;
+                        }
+                        _ => b"250 ok\r\n",
+                    };
+                    writer.write_all(reply).unwrap();
+                }
+                line.clear();
+            }
+            data
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn test_send_to_local_sink() {
+        let (port, sink) = smtp_sink();
+        let config = SmtpConfig {
+            host: "127.0.0.1".to_string(),
+            port,
+            username: None,
+            password: None,
+            tls: SmtpTls::None,
+            from_address: "hub@example.org".to_string(),
+            from_name: Some("Test Hub".to_string()),
+            public_url: None,
+        };
+
+        let email = password_reset_email("Test Hub", "alice", "0123abcd");
+        assert!(email.body.contains("0123abcd"));
+        assert!(!email.body.contains('{'));
+
+        send(&config, "alice@example.org", &email.subject, &email.body).unwrap();
+        let data = sink.join().unwrap();
+        assert!(data.contains("To: alice@example.org"));
+        assert!(data.contains("Subject: Your Test Hub password reset code"));
+        assert!(data.contains("0123abcd"));
+    }
+
+    #[test]
+    fn test_config_password_is_encrypted() {
+        let dir = std::env::temp_dir().join(format!("citinet-test-{}", uuid::Uuid::new_v4()));
+        let sm = StorageManager::initialize(dir.to_str().unwrap()).unwrap();
+        let config = SmtpConfig {
+            host: "smtp.example.org".to_string(),
+            port: 587,
+            username: Some("hub".to_string()),
+            password: Some("s3cret-password".to_string()),
+            tls: SmtpTls::Starttls,
+            from_address: "hub@example.org".to_string(),
+            from_name: None,
+            public_url: Some("https://hub.example.org/".to_string()),
+        };
+        save_config(&sm, config.clone()).unwrap();
+
+        let raw = sm.get_setting(SETTING_KEY).unwrap().unwrap();
+        assert!(!raw.contains("s3cret-password"));
+        let loaded = load_config(&sm).unwrap().unwrap();
+        assert_eq!(loaded.password.as_deref(), Some("s3cret-password"));
+        assert_eq!(loaded.public_url.as_deref(), Some("https://hub.example.org"));
+
+        // Leaving the password out keeps it
+        save_config(&sm, SmtpConfig { password: None, ..config.clone() }).unwrap();
+        assert_eq!(load_config(&sm).unwrap().unwrap().password.as_deref(), Some("s3cret-password"));
+
+        // Configs saved before encryption still load
+        let mut legacy = config.clone();
+        legacy.password = Some("plain".to_string());
+        sm.set_setting(SETTING_KEY, &serde_json::to_string(&legacy).unwrap()).unwrap();
+        assert_eq!(load_config(&sm).unwrap().unwrap().password.as_deref(), Some("plain"));
+
+        assert!(save_config(&sm, SmtpConfig { public_url: Some("hub.example.org".to_string()), ..config }).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_links_use_configured_url() {
+        let dir = std::env::temp_dir().join(format!("citinet-test-{}", uuid::Uuid::new_v4()));
+        let sm = StorageManager::initialize(dir.to_str().unwrap()).unwrap();
+        let config = SmtpConfig {
+            host: "smtp.example.org".to_string(),
+            port: 587,
+            username: None,
+            password: None,
+            tls: SmtpTls::Starttls,
+            from_address: "hub@example.org".to_string(),
+            from_name: None,
+            public_url: None,
+        };
+        save_config(&sm, config.clone()).unwrap();
+        let user = sm.create_user("alice", "alice@example.org", "hash", false).unwrap();
+
+        // Without a public URL there is nothing safe to link to
+        assert!(!send_verification(&sm, "Hub", &user, None).unwrap());
+        assert!(send_verification(&sm, "Hub", &user, Some("https://tunnel.example.org")).unwrap());
+        assert!(sm.due_emails(10).unwrap()[0].body.contains("https://tunnel.example.org/api/auth/verify-email"));
+
+        save_config(&sm, SmtpConfig { public_url: Some("https://hub.example.org".to_string()), ..config }).unwrap();
+        assert_eq!(link_base(&sm, Some("https://tunnel.example.org")).unwrap().as_deref(), Some("https://hub.example.org"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,269 @@
+use anyhow::{Context, Result};
+use chrono::{Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::auth;
+use crate::roles;
+use crate::storage_manager::StorageManager;
+
+/// Largest member import accepted in one file
+pub const MAX_IMPORT_ROWS: usize = 1000;
+
+/// How long an imported member has to redeem their setup code
+pub const SETUP_CODE_TTL_DAYS: i64 = 14;
+
+/// One data row of an import. `role` defaults to member when the column is
+/// missing or blank.
+#[derive(Debug, Deserialize)]
+struct ImportRow {
+    username: String,
+    email: String,
+    #[serde(default)]
+    role: Option<String>,
+}
+
+/// An account created by an import, with the setup code to hand to the member
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportedMember {
+    /// Record number in the file, counting the header as row 1
+    pub row: usize,
+    pub user_id: String,
+    pub username: String,
+    pub email: String,
+    pub role: String,
+    pub setup_code: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportRowError {
+    pub row: usize,
+    pub username: Option<String>,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ImportReport {
+    pub created: Vec<ImportedMember>,
+    pub errors: Vec<ImportRowError>,
+    pub setup_codes_expire_at: String,
+}
+
+/// Create an account for every valid row of a `username,email,role` CSV.
+/// Rows that fail are reported and skipped; the rest are still imported.
+/// Only problems with the file as a whole (no header, too many rows) are errors.
+pub fn import_members(sm: &StorageManager, acting_user_id: &str, data: &str) -> Result<ImportReport> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(true)
+        .from_reader(data.as_bytes());
+
+    let headers = reader.headers().context("Failed to read CSV header")?.clone();
+    for required in ["username", "email"] {
+        if !headers.iter().any(|h| h == required) {
+            anyhow::bail!("CSV header must include a '{}' column", required);
+        }
+    }
+
+    let records: Vec<_> = reader.records().collect();
+    if records.len() > MAX_IMPORT_ROWS {
+        anyhow::bail!("An import can have at most {} rows", MAX_IMPORT_ROWS);
+    }
+
+    let expires_at = (Utc::now() + Duration::days(SETUP_CODE_TTL_DAYS)).to_rfc3339();
+    let mut report = ImportReport {
+        created: Vec::new(),
+        errors: Vec::new(),
+        setup_codes_expire_at: expires_at.clone(),
+    };
+    let mut seen_usernames = HashSet::new();
+    let mut seen_emails = HashSet::new();
+
+    for (i, record) in records.into_iter().enumerate() {
+        let row = i + 2;
+        let parsed = record
+            .map_err(anyhow::Error::from)
+            .and_then(|r| r.deserialize::<ImportRow>(Some(&headers)).map_err(anyhow::Error::from));
+        let entry = match parsed {
+            Ok(entry) => ImportRow {
+                username: from_spreadsheet(entry.username),
+                email: from_spreadsheet(entry.email),
+                role: entry.role.map(from_spreadsheet),
+            },
+            Err(e) => {
+                report.errors.push(ImportRowError { row, username: None, error: e.to_string() });
+                continue;
+            }
+        };
+        let username = (!entry.username.is_empty()).then(|| entry.username.clone());
+
+        let result = validate_row(&entry, &mut seen_usernames, &mut seen_emails)
+            .and_then(|role| create_member(sm, acting_user_id, &entry, &role, &expires_at));
+        match result {
+            Ok((user_id, role, setup_code)) => report.created.push(ImportedMember {
+                row,
+                user_id,
+                username: entry.username,
+                email: entry.email,
+                role,
+                setup_code,
+            }),
+            Err(e) => report.errors.push(ImportRowError { row, username, error: e.to_string() }),
+        }
+    }
+
+    Ok(report)
+}
+
+/// Check a row on its own and against earlier rows. Returns the role to assign.
+fn validate_row(
+    entry: &ImportRow,
+    seen_usernames: &mut HashSet<String>,
+    seen_emails: &mut HashSet<String>,
+) -> Result<String> {
+    if entry.username.is_empty() {
+        anyhow::bail!("Username is required");
+    }
+    if entry.email.is_empty() || !entry.email.contains('@') {
+        anyhow::bail!("A valid email is required");
+    }
+    if !seen_usernames.insert(entry.username.to_lowercase()) {
+        anyhow::bail!("Username appears more than once in the file");
+    }
+    if !seen_emails.insert(entry.email.to_lowercase()) {
+        anyhow::bail!("Email appears more than once in the file");
+    }
+    let role = entry.role.as_deref().filter(|r| !r.is_empty()).unwrap_or(roles::ROLE_MEMBER);
+    if role == roles::ROLE_GUEST {
+        anyhow::bail!("Guests can't be imported; create them with an expiry instead");
+    }
+    Ok(role.to_string())
+}
+
+fn create_member(
+    sm: &StorageManager,
+    acting_user_id: &str,
+    entry: &ImportRow,
+    role: &str,
+    expires_at: &str,
+) -> Result<(String, String, String)> {
+    let setup_code = auth::generate_temporary_password()?;
+    let user = sm.create_pending_user(
+        acting_user_id,
+        &entry.username,
+        &entry.email,
+        role,
+        &auth::hash_secret(&setup_code),
+        expires_at,
+    )?;
+    Ok((user.user_id, user.role, setup_code))
+}
+
+/// The member list as CSV. The first three columns match the import format,
+/// so an export can be edited and imported into another hub.
+pub fn export_members(sm: &StorageManager) -> Result<String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(["username", "email", "role", "created_at", "suspended", "expires_at"])?;
+    for user in sm.list_users()? {
+        // Guests only have a placeholder address
+        let email = if user.expires_at.is_some() { "" } else { user.email.as_str() };
+        writer.write_record([
+            user.username.as_str(),
+            email,
+            user.role.as_str(),
+            user.created_at.as_str(),
+            if user.suspension.is_some() { "true" } else { "false" },
+            user.expires_at.as_deref().unwrap_or(""),
+        ].map(spreadsheet_safe))?;
+    }
+    let bytes = writer.into_inner().context("Failed to write CSV")?;
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Characters that make a spreadsheet treat a cell as a formula
+const FORMULA_PREFIXES: [char; 6] = ['=', '+', '-', '@', '\t', '\r'];
+
+/// Prefix a cell a spreadsheet would read as a formula with `'`, so opening
+/// an export can't run member-supplied content
+fn spreadsheet_safe(value: &str) -> String {
+    if value.starts_with(FORMULA_PREFIXES) {
+        format!("'{}", value)
+    } else {
+        value.to_string()
+    }
+}
+
+/// Undo `spreadsheet_safe`, so an export imports unchanged
+fn from_spreadsheet(value: String) -> String {
+    match value.strip_prefix('\'') {
+        Some(rest) if rest.starts_with(FORMULA_PREFIXES) => rest.to_string(),
+        _ => value,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(username: &str, email: &str, role: Option<&str>) -> ImportRow {
+        ImportRow {
+            username: username.to_string(),
+            email: email.to_string(),
+            role: role.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_validate_row() {
+        let mut usernames = HashSet::new();
+        let mut emails = HashSet::new();
+        let mut check = |r: ImportRow| validate_row(&r, &mut usernames, &mut emails);
+
+        assert_eq!(check(row("alice", "alice@example.org", None)).unwrap(), roles::ROLE_MEMBER);
+        assert_eq!(check(row("bob", "bob@example.org", Some("moderator"))).unwrap(), roles::ROLE_MODERATOR);
+        assert!(check(row("", "carol@example.org", None)).is_err());
+        assert!(check(row("carol", "not-an-email", None)).is_err());
+        assert!(check(row("Alice", "other@example.org", None)).is_err());
+        assert!(check(row("dave", "BOB@example.org", None)).is_err());
+        assert!(check(row("erin", "erin@example.org", Some("guest"))).is_err());
+    }
+
+    #[test]
+    fn test_import_export_round_trip() {
+        let dir = std::env::temp_dir().join(format!("citinet-test-{}", uuid::Uuid::new_v4()));
+        let sm = StorageManager::initialize(dir.to_str().unwrap()).unwrap();
+        let owner = sm.create_user("owner", "owner@example.org", "hash", true).unwrap();
+
+        // Someone who manages users but can't moderate may not create moderators
+        let perms: Vec<String> = [
+            roles::PERM_USERS_MANAGE, roles::PERM_MEMBERS_VIEW, roles::PERM_FILES_READ,
+            roles::PERM_FILES_UPLOAD, roles::PERM_MESSAGES_SEND,
+        ].iter().map(|p| p.to_string()).collect();
+        let role = sm.create_role(&owner.user_id, "Registrar", &perms).unwrap();
+        let registrar = sm.create_user("registrar", "registrar@example.org", "hash", false).unwrap();
+        sm.set_user_role(&owner.user_id, &registrar.user_id, &role.role_id).unwrap();
+
+        let data = "username,email,role\n=cmd,cmd@example.org,\nmod,mod@example.org,moderator\n";
+        let report = import_members(&sm, &registrar.user_id, data).unwrap();
+        assert_eq!(report.created.len(), 1);
+        assert_eq!(report.created[0].username, "=cmd");
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].row, 3);
+        assert!(report.errors[0].error.contains("Permission denied"));
+
+        // Formula-like cells are neutralized, and import back unchanged
+        let exported = export_members(&sm).unwrap();
+        assert!(exported.contains("'=cmd,cmd@example.org,member"));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let dir = std::env::temp_dir().join(format!("citinet-test-{}", uuid::Uuid::new_v4()));
+        let other = StorageManager::initialize(dir.to_str().unwrap()).unwrap();
+        let owner = other.create_user("admin", "admin@example.org", "hash", true).unwrap();
+        let report = import_members(&other, &owner.user_id, &exported).unwrap();
+        assert!(report.created.iter().any(|m| m.username == "=cmd" && m.role == roles::ROLE_MEMBER));
+        // Custom roles don't carry over between hubs
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.errors[0].username.as_deref(), Some("registrar"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
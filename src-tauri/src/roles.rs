@@ -0,0 +1,76 @@
+// Permissions a role can grant
+pub const PERM_HUB_MANAGE: &str = "hub.manage";
+pub const PERM_USERS_MANAGE: &str = "users.manage";
+pub const PERM_ROLES_MANAGE: &str = "roles.manage";
+pub const PERM_CONTENT_MODERATE: &str = "content.moderate";
+pub const PERM_MEMBERS_VIEW: &str = "members.view";
+pub const PERM_FILES_READ: &str = "files.read";
+pub const PERM_FILES_UPLOAD: &str = "files.upload";
+pub const PERM_MESSAGES_SEND: &str = "messages.send";
+
+pub const ALL_PERMISSIONS: &[&str] = &[
+    PERM_HUB_MANAGE,
+    PERM_USERS_MANAGE,
+    PERM_ROLES_MANAGE,
+    PERM_CONTENT_MODERATE,
+    PERM_MEMBERS_VIEW,
+    PERM_FILES_READ,
+    PERM_FILES_UPLOAD,
+    PERM_MESSAGES_SEND,
+];
+
+/// Permissions that administer the hub rather than use it. A personal access
+/// token needs the `admin` scope to exercise these.
+pub const ADMIN_PERMISSIONS: &[&str] = &[
+    PERM_HUB_MANAGE,
+    PERM_USERS_MANAGE,
+    PERM_ROLES_MANAGE,
+    PERM_CONTENT_MODERATE,
+];
+
+// Built-in roles. Their IDs double as display names.
+pub const ROLE_OWNER: &str = "owner";
+pub const ROLE_ADMIN: &str = "admin";
+pub const ROLE_MODERATOR: &str = "moderator";
+pub const ROLE_MEMBER: &str = "member";
+pub const ROLE_GUEST: &str = "guest";
+
+pub const BUILTIN_ROLES: &[&str] = &[ROLE_OWNER, ROLE_ADMIN, ROLE_MODERATOR, ROLE_MEMBER, ROLE_GUEST];
+
+const MEMBER_PERMISSIONS: &[&str] = &[
+    PERM_MEMBERS_VIEW,
+    PERM_FILES_READ,
+    PERM_FILES_UPLOAD,
+    PERM_MESSAGES_SEND,
+];
+
+const MODERATOR_PERMISSIONS: &[&str] = &[
+    PERM_CONTENT_MODERATE,
+    PERM_MEMBERS_VIEW,
+    PERM_FILES_READ,
+    PERM_FILES_UPLOAD,
+    PERM_MESSAGES_SEND,
+];
+
+const GUEST_PERMISSIONS: &[&str] = &[PERM_FILES_READ, PERM_MESSAGES_SEND];
+
+/// Permissions of a built-in role, or None for a custom role ID.
+/// Owner and admin hold every permission; only an owner can grant or
+/// revoke the owner role.
+pub fn builtin_permissions(role_id: &str) -> Option<&'static [&'static str]> {
+    match role_id {
+        ROLE_OWNER | ROLE_ADMIN => Some(ALL_PERMISSIONS),
+        ROLE_MODERATOR => Some(MODERATOR_PERMISSIONS),
+        ROLE_MEMBER => Some(MEMBER_PERMISSIONS),
+        ROLE_GUEST => Some(GUEST_PERMISSIONS),
+        _ => None,
+    }
+}
+
+pub fn is_builtin(role_id: &str) -> bool {
+    BUILTIN_ROLES.contains(&role_id)
+}
+
+pub fn is_valid_permission(permission: &str) -> bool {
+    ALL_PERMISSIONS.contains(&permission)
+}
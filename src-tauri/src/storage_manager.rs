@@ -1,11 +1,15 @@
 use anyhow::{Context, Result};
 use chrono::Utc;
-use rusqlite::Connection;
+use rusqlite::{Connection, OptionalExtension};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 use std::path::{Path, PathBuf};
+use unicode_segmentation::UnicodeSegmentation;
 use uuid::Uuid;
 
+use crate::roles;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NodeConfig {
     pub node_id: String,
@@ -52,8 +56,254 @@ pub struct User {
     pub username: String,
     pub email: String,
     pub is_admin: bool,
+    pub role: String,
     pub created_at: String,
     pub updated_at: String,
+    /// Set while the user is suspended; suspended users keep their content
+    /// but can't sign in or use existing tokens
+    pub suspension: Option<Suspension>,
+    /// Guest accounts stop working at this time and are then anonymized
+    pub expires_at: Option<String>,
+    /// When the member confirmed their current email address
+    pub email_verified_at: Option<String>,
+}
+
+impl User {
+    /// Whether this is a guest whose account has run out
+    pub fn is_expired(&self) -> bool {
+        has_passed(self.expires_at.as_deref())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Role {
+    pub role_id: String,
+    pub name: String,
+    pub permissions: Vec<String>,
+    pub is_builtin: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Suspension {
+    pub reason: Option<String>,
+    pub suspended_at: String,
+    /// None for an indefinite suspension (ban)
+    pub suspended_until: Option<String>,
+}
+
+/// A shareable link that lets visitors create their own guest account.
+/// Guests created through it expire together with the invite.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuestInvite {
+    pub invite_id: String,
+    /// Group conversations the guest joins on sign-up
+    pub conversation_ids: Vec<String>,
+    pub expires_at: String,
+    /// None for unlimited
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+    pub created_by: String,
+    pub created_at: String,
+}
+
+/// Who besides the member (and user managers) can see a profile field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldVisibility {
+    Members,
+    Private,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProfileVisibility {
+    pub email: FieldVisibility,
+    pub avatar: FieldVisibility,
+    pub bio: FieldVisibility,
+    pub pronouns: FieldVisibility,
+    pub tags: FieldVisibility,
+}
+
+impl Default for ProfileVisibility {
+    fn default() -> Self {
+        Self {
+            email: FieldVisibility::Private,
+            avatar: FieldVisibility::Members,
+            bio: FieldVisibility::Members,
+            pronouns: FieldVisibility::Members,
+            tags: FieldVisibility::Members,
+        }
+    }
+}
+
+/// Member profile. The display name is always visible to other members;
+/// the remaining fields follow `visibility`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    pub user_id: String,
+    pub display_name: Option<String>,
+    /// When the avatar was last set; None without one. The image itself is
+    /// kept outside the member's files, see `StorageManager::avatar_path`.
+    pub avatar_updated_at: Option<String>,
+    pub bio: Option<String>,
+    pub pronouns: Option<String>,
+    pub tags: Vec<String>,
+    pub visibility: ProfileVisibility,
+    /// Contact preference: whether other members can start a DM
+    pub allow_direct_messages: bool,
+    /// Whether other members see when this member is online
+    pub show_presence: bool,
+    pub updated_at: Option<String>,
+}
+
+/// Background personal-data export. `status` is "pending", "ready" or "failed".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportJob {
+    pub job_id: String,
+    pub user_id: String,
+    pub status: String,
+    pub size_bytes: Option<u64>,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub completed_at: Option<String>,
+    /// The archive is deleted after this time
+    pub expires_at: Option<String>,
+}
+
+/// An email in the outbox. `status` is "pending", "sent" or "failed".
+/// The body is never serialized: it can hold verification links and
+/// password reset codes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingEmail {
+    pub email_id: String,
+    pub to_address: String,
+    pub subject: String,
+    #[serde(skip_serializing, default)]
+    pub body: String,
+    pub kind: String,
+    pub status: String,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: String,
+    pub created_at: String,
+    pub sent_at: Option<String>,
+}
+
+/// One row of the audit log. `actor_name` is captured when the event is
+/// recorded so entries stay readable after the actor is deleted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEvent {
+    pub event_id: String,
+    pub created_at: String,
+    pub actor_id: Option<String>,
+    pub actor_name: Option<String>,
+    pub action: String,
+    pub target: Option<String>,
+    pub ip: Option<String>,
+    pub details: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AuditFilter {
+    /// Actor user ID or username
+    pub actor: Option<String>,
+    /// Exact action, or a prefix ending in "." (e.g. "user.")
+    pub action: Option<String>,
+    pub target: Option<String>,
+    /// RFC 3339 bounds on the event time
+    pub since: Option<String>,
+    pub until: Option<String>,
+}
+
+pub const MAX_DISPLAY_NAME_LEN: usize = 64;
+/// Longest group or channel name
+pub const MAX_CONVERSATION_NAME_LEN: usize = 80;
+pub const MAX_BIO_LEN: usize = 1000;
+pub const MAX_PRONOUNS_LEN: usize = 32;
+pub const MAX_PROFILE_TAGS: usize = 10;
+pub const MAX_PROFILE_TAG_LEN: usize = 32;
+pub const MAX_AVATAR_BYTES: usize = 2 * 1024 * 1024;
+/// Avatars are scaled down to fit this many pixels square
+const AVATAR_SIZE: u32 = 512;
+/// Larger uploads are rejected before decoding
+const MAX_AVATAR_DIMENSION: u32 = 8192;
+/// Most emoji (grapheme clusters) in one reaction
+pub const MAX_REACTION_LEN: usize = 4;
+/// Byte cap on a reaction, which bounds long ZWJ or modifier sequences
+const MAX_REACTION_BYTES: usize = 64;
+pub const MAX_REACTIONS_PER_MESSAGE: u32 = 20;
+pub const MAX_FILE_DESCRIPTION_LEN: usize = 500;
+pub const MAX_SEARCH_QUERY_LEN: usize = 200;
+/// Shortest word a search looks for
+const MIN_SEARCH_TERM_LEN: usize = 2;
+
+/// Loopback, where cloudflared and tailscaled connect from
+pub const DEFAULT_TRUSTED_PROXIES: &[&str] = &["127.0.0.1/8", "::1/128"];
+
+/// Failed password attempts on an account, for lockout and backoff
+#[derive(Debug, Clone, Default)]
+pub struct LoginThrottle {
+    pub failed_logins: u32,
+    pub last_failed_at: Option<String>,
+    pub locked_until: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockedAccount {
+    pub user_id: String,
+    pub username: String,
+    pub failed_logins: u32,
+    pub locked_until: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub token_id: String,
+    pub user_id: String,
+    pub name: String,
+    pub scopes: Vec<String>,
+    pub expires_at: Option<String>,
+    pub last_used_at: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Passkey {
+    /// WebAuthn credential ID, base64url
+    pub credential_id: String,
+    pub user_id: String,
+    pub name: String,
+    /// COSE algorithm identifier (-8 EdDSA, -7 ES256, -257 RS256)
+    pub algorithm: i64,
+    pub created_at: String,
+    pub last_used_at: Option<String>,
+    #[serde(skip)]
+    pub public_key: Vec<u8>,
+    #[serde(skip)]
+    pub sign_count: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OidcClient {
+    pub client_id: String,
+    pub name: String,
+    pub redirect_uris: Vec<String>,
+    /// Confidential clients authenticate with a secret; public clients rely on PKCE alone
+    pub is_confidential: bool,
+    pub created_at: String,
+    #[serde(skip)]
+    pub client_secret_hash: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct OidcAuthCode {
+    pub client_id: String,
+    pub user_id: String,
+    pub redirect_uri: String,
+    pub scope: String,
+    pub nonce: Option<String>,
+    pub code_challenge: String,
+    pub auth_time: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -75,6 +325,7 @@ pub struct File {
     pub size_bytes: u64,
     pub is_public: bool,
     pub created_at: String,
+    pub description: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -85,6 +336,19 @@ pub struct Conversation {
     pub created_by: String,
     pub created_at: String,
     pub updated_at: String,
+    /// Channels only: just the creator and moderators can post
+    pub announcement_only: bool,
+    /// Channels only: new members join automatically
+    pub is_default: bool,
+}
+
+/// A channel as the directory lists it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelListing {
+    pub conversation: Conversation,
+    pub member_count: u32,
+    /// Whether the requesting user has joined
+    pub joined: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -92,6 +356,10 @@ pub struct ConversationMember {
     pub user_id: String,
     pub username: String,
     pub joined_at: String,
+    /// Read cursor: the last message this member has read, and its
+    /// `created_at`. Messages up to that time count as seen.
+    pub last_read_message_id: Option<String>,
+    pub last_read_at: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -99,6 +367,8 @@ pub struct ConversationWithMembers {
     pub conversation: Conversation,
     pub members: Vec<ConversationMember>,
     pub last_message: Option<Message>,
+    /// Messages from others after the caller's read cursor
+    pub unread_count: u32,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -118,6 +388,37 @@ pub struct Message {
     pub body: String,
     pub attachments: Vec<MessageAttachment>,
     pub created_at: String,
+    /// Set when the body was last edited
+    pub edited_at: Option<String>,
+    /// Set when the message was deleted; the body and attachments are gone
+    /// and only this tombstone remains
+    pub deleted_at: Option<String>,
+    pub reactions: Vec<ReactionCount>,
+    /// The thread this message replies to, if any
+    pub parent_id: Option<String>,
+    /// Replies in this message's thread, not counting deleted ones
+    pub reply_count: u32,
+    pub last_reply_at: Option<String>,
+    /// The sender's own ID for this message, used to match retries and
+    /// optimistic updates
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub client_id: Option<String>,
+}
+
+/// Everyone who reacted to a message with one emoji
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: u32,
+    pub user_ids: Vec<String>,
+}
+
+/// A previous version of an edited message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageEdit {
+    pub body: String,
+    /// When this version was replaced
+    pub edited_at: String,
 }
 
 pub struct StorageManager {
@@ -159,6 +460,142 @@ fn run_migrations(db: &Connection) -> Result<()> {
             created_at TEXT NOT NULL,
             updated_at TEXT NOT NULL
         );
+        CREATE TABLE IF NOT EXISTS api_tokens (
+            token_id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            token_hash TEXT NOT NULL UNIQUE,
+            scopes TEXT NOT NULL,
+            expires_at TEXT,
+            last_used_at TEXT,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_api_tokens_user_id ON api_tokens(user_id);
+        CREATE TABLE IF NOT EXISTS oidc_clients (
+            client_id TEXT PRIMARY KEY,
+            name TEXT NOT NULL,
+            client_secret_hash TEXT,
+            redirect_uris TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS oidc_auth_codes (
+            code_hash TEXT PRIMARY KEY,
+            client_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            redirect_uri TEXT NOT NULL,
+            scope TEXT NOT NULL,
+            nonce TEXT,
+            code_challenge TEXT NOT NULL,
+            auth_time TEXT NOT NULL,
+            expires_at TEXT NOT NULL,
+            FOREIGN KEY (client_id) REFERENCES oidc_clients(client_id) ON DELETE CASCADE,
+            FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS roles (
+            role_id TEXT PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            permissions TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS hub_settings (
+            key TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS passkeys (
+            credential_id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            name TEXT NOT NULL,
+            public_key BLOB NOT NULL,
+            algorithm INTEGER NOT NULL,
+            sign_count INTEGER NOT NULL DEFAULT 0,
+            created_at TEXT NOT NULL,
+            last_used_at TEXT,
+            FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_passkeys_user_id ON passkeys(user_id);
+        CREATE TABLE IF NOT EXISTS profiles (
+            user_id TEXT PRIMARY KEY,
+            display_name TEXT,
+            avatar_updated_at TEXT,
+            bio TEXT,
+            pronouns TEXT,
+            tags TEXT NOT NULL DEFAULT '[]',
+            visibility TEXT NOT NULL DEFAULT '{}',
+            allow_direct_messages INTEGER NOT NULL DEFAULT 1,
+            updated_at TEXT NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS export_jobs (
+            job_id TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            status TEXT NOT NULL,
+            size_bytes INTEGER,
+            error TEXT,
+            created_at TEXT NOT NULL,
+            completed_at TEXT,
+            expires_at TEXT,
+            FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_export_jobs_user_id ON export_jobs(user_id);
+        CREATE TABLE IF NOT EXISTS guest_invites (
+            invite_id TEXT PRIMARY KEY,
+            token_hash TEXT NOT NULL UNIQUE,
+            conversation_ids TEXT NOT NULL DEFAULT '[]',
+            expires_at TEXT NOT NULL,
+            max_uses INTEGER,
+            uses INTEGER NOT NULL DEFAULT 0,
+            created_by TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS email_outbox (
+            email_id TEXT PRIMARY KEY,
+            to_address TEXT NOT NULL,
+            subject TEXT NOT NULL,
+            body TEXT NOT NULL,
+            kind TEXT NOT NULL,
+            status TEXT NOT NULL DEFAULT 'pending',
+            attempts INTEGER NOT NULL DEFAULT 0,
+            last_error TEXT,
+            next_attempt_at TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            sent_at TEXT
+        );
+        CREATE INDEX IF NOT EXISTS idx_email_outbox_due ON email_outbox(status, next_attempt_at);
+        CREATE TABLE IF NOT EXISTS email_tokens (
+            token_hash TEXT PRIMARY KEY,
+            user_id TEXT NOT NULL,
+            purpose TEXT NOT NULL,
+            email TEXT NOT NULL,
+            expires_at TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+        );
+        CREATE TABLE IF NOT EXISTS user_events (
+            user_id TEXT NOT NULL,
+            seq INTEGER NOT NULL,
+            payload TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            PRIMARY KEY (user_id, seq),
+            FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_user_events_created ON user_events(created_at);
+        CREATE TABLE IF NOT EXISTS audit_log (
+            event_id TEXT PRIMARY KEY,
+            created_at TEXT NOT NULL,
+            actor_id TEXT,
+            actor_name TEXT,
+            action TEXT NOT NULL,
+            target TEXT,
+            ip TEXT,
+            details TEXT
+        );
+        CREATE INDEX IF NOT EXISTS idx_audit_log_created ON audit_log(created_at);
+        CREATE INDEX IF NOT EXISTS idx_audit_log_action ON audit_log(action);
+        CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+        BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END;
+        CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+        BEGIN SELECT RAISE(ABORT, 'audit_log is append-only'); END;
         CREATE TABLE IF NOT EXISTS spaces (
             space_id TEXT PRIMARY KEY,
             user_id TEXT NOT NULL,
@@ -209,6 +646,23 @@ fn run_migrations(db: &Connection) -> Result<()> {
             FOREIGN KEY (sender_id) REFERENCES users(user_id) ON DELETE CASCADE
         );
         CREATE INDEX IF NOT EXISTS idx_messages_conv ON messages(conversation_id, created_at);
+        CREATE TABLE IF NOT EXISTS message_edits (
+            edit_id TEXT PRIMARY KEY,
+            message_id TEXT NOT NULL,
+            body TEXT NOT NULL,
+            edited_at TEXT NOT NULL,
+            FOREIGN KEY (message_id) REFERENCES messages(message_id) ON DELETE CASCADE
+        );
+        CREATE INDEX IF NOT EXISTS idx_message_edits_msg ON message_edits(message_id);
+        CREATE TABLE IF NOT EXISTS message_reactions (
+            message_id TEXT NOT NULL,
+            user_id TEXT NOT NULL,
+            emoji TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            PRIMARY KEY (message_id, user_id, emoji),
+            FOREIGN KEY (message_id) REFERENCES messages(message_id) ON DELETE CASCADE,
+            FOREIGN KEY (user_id) REFERENCES users(user_id) ON DELETE CASCADE
+        );
         CREATE TABLE IF NOT EXISTS message_attachments (
             message_id TEXT NOT NULL,
             file_id TEXT NOT NULL,
@@ -219,19 +673,133 @@ fn run_migrations(db: &Connection) -> Result<()> {
         CREATE INDEX IF NOT EXISTS idx_msg_attach_file ON message_attachments(file_id);"
     ).context("Failed to run schema migrations")?;
 
-    // Safe column addition — only runs if column doesn't exist yet
-    let has_bg_mode: bool = db.prepare("PRAGMA table_info(node_config)")?
-        .query_map([], |row| row.get::<_, String>(1))?
-        .any(|col| col.as_deref() == Ok("background_mode"));
-    if !has_bg_mode {
+    add_column_if_missing(db, "node_config", "background_mode", "INTEGER NOT NULL DEFAULT 1")?;
+    // Require a passkey after the password at login
+    add_column_if_missing(db, "users", "passkey_required", "INTEGER NOT NULL DEFAULT 0")?;
+    // Roles replace the is_admin flag: existing admins become admins, the
+    // earliest of them the owner, and everyone else a member
+    if add_column_if_missing(db, "users", "role", "TEXT NOT NULL DEFAULT 'member'")? {
+        db.execute_batch(
+            "UPDATE users SET role = 'admin' WHERE is_admin = 1;
+             UPDATE users SET role = 'owner' WHERE user_id = (
+                 SELECT user_id FROM users WHERE is_admin = 1 ORDER BY created_at ASC LIMIT 1
+             );"
+        ).context("Failed to migrate admins to roles")?;
+    }
+    // Suspension keeps the account and its content but blocks sign-in
+    add_column_if_missing(db, "users", "suspended_at", "TEXT")?;
+    add_column_if_missing(db, "users", "suspension_reason", "TEXT")?;
+    add_column_if_missing(db, "users", "suspended_until", "TEXT")?;
+    // Per-account brute-force protection
+    add_column_if_missing(db, "users", "failed_logins", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(db, "users", "last_failed_login_at", "TEXT")?;
+    add_column_if_missing(db, "users", "locked_until", "TEXT")?;
+    // Session JWTs issued up to this time are no longer accepted
+    add_column_if_missing(db, "users", "sessions_revoked_at", "TEXT")?;
+    // Guest accounts: when they expire, and when their personal data was removed
+    add_column_if_missing(db, "users", "expires_at", "TEXT")?;
+    add_column_if_missing(db, "users", "anonymized_at", "TEXT")?;
+    // Imported accounts have no password until the member redeems a setup code
+    add_column_if_missing(db, "users", "setup_code_hash", "TEXT")?;
+    add_column_if_missing(db, "users", "setup_code_expires_at", "TEXT")?;
+    // Email verification, and opt-in message notifications by email
+    add_column_if_missing(db, "users", "email_verified_at", "TEXT")?;
+    add_column_if_missing(db, "users", "email_notifications", "INTEGER NOT NULL DEFAULT 0")?;
+    // Message editing and deletion (tombstones)
+    add_column_if_missing(db, "messages", "edited_at", "TEXT")?;
+    add_column_if_missing(db, "messages", "deleted_at", "TEXT")?;
+    // Threaded replies
+    add_column_if_missing(db, "messages", "parent_id", "TEXT")?;
+    db.execute_batch("CREATE INDEX IF NOT EXISTS idx_messages_parent ON messages(parent_id, created_at);")?;
+    // Client-generated message IDs, unique per sender
+    add_column_if_missing(db, "messages", "client_id", "TEXT")?;
+    db.execute_batch(
+        "CREATE UNIQUE INDEX IF NOT EXISTS idx_messages_client_id ON messages(sender_id, client_id)
+         WHERE client_id IS NOT NULL;"
+    )?;
+    // Channels: read-only announcement mode, and auto-join for new members
+    add_column_if_missing(db, "conversations", "announcement_only", "INTEGER NOT NULL DEFAULT 0")?;
+    add_column_if_missing(db, "conversations", "is_default", "INTEGER NOT NULL DEFAULT 0")?;
+    // Files can carry a short description
+    add_column_if_missing(db, "files", "description", "TEXT")?;
+    // Full-text search over message bodies and file names and descriptions.
+    // Triggers keep the indexes in sync; existing rows are indexed once.
+    let has_search_index = db.prepare("SELECT 1 FROM sqlite_master WHERE name = 'messages_fts'")?
+        .exists([])?;
+    db.execute_batch(
+        "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+             body, content='messages', content_rowid='rowid', tokenize='unicode61 remove_diacritics 2'
+         );
+         CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+             INSERT INTO messages_fts (rowid, body) VALUES (new.rowid, new.body);
+         END;
+         CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+             INSERT INTO messages_fts (messages_fts, rowid, body) VALUES ('delete', old.rowid, old.body);
+         END;
+         CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF body ON messages BEGIN
+             INSERT INTO messages_fts (messages_fts, rowid, body) VALUES ('delete', old.rowid, old.body);
+             INSERT INTO messages_fts (rowid, body) VALUES (new.rowid, new.body);
+         END;
+         CREATE VIRTUAL TABLE IF NOT EXISTS files_fts USING fts5(
+             file_name, description, content='files', content_rowid='rowid',
+             tokenize='unicode61 remove_diacritics 2'
+         );
+         CREATE TRIGGER IF NOT EXISTS files_fts_insert AFTER INSERT ON files BEGIN
+             INSERT INTO files_fts (rowid, file_name, description)
+             VALUES (new.rowid, new.file_name, new.description);
+         END;
+         CREATE TRIGGER IF NOT EXISTS files_fts_delete AFTER DELETE ON files BEGIN
+             INSERT INTO files_fts (files_fts, rowid, file_name, description)
+             VALUES ('delete', old.rowid, old.file_name, old.description);
+         END;
+         CREATE TRIGGER IF NOT EXISTS files_fts_update AFTER UPDATE OF file_name, description ON files BEGIN
+             INSERT INTO files_fts (files_fts, rowid, file_name, description)
+             VALUES ('delete', old.rowid, old.file_name, old.description);
+             INSERT INTO files_fts (rowid, file_name, description)
+             VALUES (new.rowid, new.file_name, new.description);
+         END;"
+    ).context("Failed to create search indexes")?;
+    if !has_search_index {
+        db.execute_batch(
+            "INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');
+             INSERT INTO files_fts (files_fts) VALUES ('rebuild');"
+        ).context("Failed to build search indexes")?;
+    }
+    // Presence: when the member was last connected, and whether others see it
+    add_column_if_missing(db, "users", "last_seen_at", "TEXT")?;
+    add_column_if_missing(db, "profiles", "show_presence", "INTEGER NOT NULL DEFAULT 1")?;
+    // Last realtime event sequence number handed out to each user
+    add_column_if_missing(db, "users", "event_seq", "INTEGER NOT NULL DEFAULT 0")?;
+    // Read cursors; existing history counts as read
+    add_column_if_missing(db, "conversation_members", "last_read_message_id", "TEXT")?;
+    if add_column_if_missing(db, "conversation_members", "last_read_at", "TEXT")? {
         db.execute_batch(
-            "ALTER TABLE node_config ADD COLUMN background_mode INTEGER NOT NULL DEFAULT 1;"
-        ).context("Failed to add background_mode column")?;
+            "UPDATE conversation_members SET
+                 last_read_at = (SELECT MAX(m.created_at) FROM messages m
+                                 WHERE m.conversation_id = conversation_members.conversation_id),
+                 last_read_message_id = (SELECT m.message_id FROM messages m
+                                         WHERE m.conversation_id = conversation_members.conversation_id
+                                         ORDER BY m.created_at DESC LIMIT 1);"
+        ).context("Failed to initialize read cursors")?;
     }
 
     Ok(())
 }
 
+/// Safe column addition — only runs if column doesn't exist yet.
+/// Returns true if the column was added.
+fn add_column_if_missing(db: &Connection, table: &str, column: &str, definition: &str) -> Result<bool> {
+    let exists: bool = db.prepare(&format!("PRAGMA table_info({})", table))?
+        .query_map([], |row| row.get::<_, String>(1))?
+        .any(|col| col.as_deref() == Ok(column));
+    if !exists {
+        db.execute_batch(
+            &format!("ALTER TABLE {} ADD COLUMN {} {};", table, column, definition)
+        ).with_context(|| format!("Failed to add {} column", column))?;
+    }
+    Ok(!exists)
+}
+
 impl StorageManager {
     pub fn initialize(install_path: &str) -> Result<Self> {
         let base = Path::new(install_path);
@@ -401,6 +969,18 @@ impl StorageManager {
         &self.db
     }
 
+    /// Run `f` in a transaction, so either all of its writes land or none do.
+    /// Inside an open transaction `f` simply joins it.
+    fn in_transaction<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        if !self.db.is_autocommit() {
+            return f();
+        }
+        let tx = self.db.unchecked_transaction().context("Failed to begin transaction")?;
+        let value = f()?;
+        tx.commit().context("Failed to commit transaction")?;
+        Ok(value)
+    }
+
     /// Safely relocate all Citinet data to a new path.
     /// Uses copy-verify-rename strategy: old data is never deleted, only renamed as backup.
     pub fn relocate(&mut self, new_path: &str, app_data_dir: &Path) -> Result<String> {
@@ -542,6 +1122,7 @@ impl StorageManager {
             size_bytes,
             is_public,
             created_at: now,
+            description: None,
         })
     }
 
@@ -550,41 +1131,23 @@ impl StorageManager {
         // If user specified, return their files + all public files
         let files = match requesting_user_id {
             Some(uid) => {
-                let mut stmt = self.db.prepare(
-                    "SELECT file_id, user_id, file_name, size_bytes, is_public, created_at
-                     FROM files
-                     WHERE user_id = ?1 OR is_public = 1
-                     ORDER BY created_at DESC"
-                )?;
-                let rows = stmt.query_map([uid], |row| {
-                    Ok(File {
-                        file_id: row.get(0)?,
-                        user_id: row.get(1)?,
-                        file_name: row.get(2)?,
-                        size_bytes: row.get(3)?,
-                        is_public: row.get::<_, i32>(4)? != 0,
-                        created_at: row.get(5)?,
-                    })
-                })?;
+                let mut stmt = self.db.prepare(&format!(
+                    "SELECT {} FROM files f
+                     WHERE f.user_id = ?1 OR f.is_public = 1
+                     ORDER BY f.created_at DESC",
+                    FILE_COLUMNS
+                ))?;
+                let rows = stmt.query_map([uid], file_from_row)?;
                 rows.collect::<Result<Vec<_>, _>>()?
             }
             None => {
-                let mut stmt = self.db.prepare(
-                    "SELECT file_id, user_id, file_name, size_bytes, is_public, created_at
-                     FROM files
-                     WHERE is_public = 1
-                     ORDER BY created_at DESC"
-                )?;
-                let rows = stmt.query_map([], |row| {
-                    Ok(File {
-                        file_id: row.get(0)?,
-                        user_id: row.get(1)?,
-                        file_name: row.get(2)?,
-                        size_bytes: row.get(3)?,
-                        is_public: row.get::<_, i32>(4)? != 0,
-                        created_at: row.get(5)?,
-                    })
-                })?;
+                let mut stmt = self.db.prepare(&format!(
+                    "SELECT {} FROM files f
+                     WHERE f.is_public = 1
+                     ORDER BY f.created_at DESC",
+                    FILE_COLUMNS
+                ))?;
+                let rows = stmt.query_map([], file_from_row)?;
                 rows.collect::<Result<Vec<_>, _>>()?
             }
         };
@@ -605,16 +1168,11 @@ impl StorageManager {
 
         let (file_id, owner_id) = file.ok_or_else(|| anyhow::anyhow!("File not found: {}", file_name))?;
 
-        // Check ownership
-        if owner_id != requesting_user_id {
-            // Check if requesting user is admin
-            let is_admin = self.get_user_by_id(requesting_user_id)?
-                .map(|u| u.is_admin)
-                .unwrap_or(false);
-            
-            if !is_admin {
-                anyhow::bail!("Permission denied: not the file owner");
-            }
+        // Check ownership — moderators can remove other members' files
+        if owner_id != requesting_user_id
+            && self.require_permission(requesting_user_id, roles::PERM_CONTENT_MODERATE).is_err()
+        {
+            anyhow::bail!("Permission denied: not the file owner");
         }
 
         // Delete from filesystem
@@ -642,17 +1200,48 @@ impl StorageManager {
 
         let (owner_id, is_public) = file.ok_or_else(|| anyhow::anyhow!("File not found: {}", file_name))?;
 
-        // Check permissions: owner can always read, others can read public files or files attached to their conversations
-        if owner_id != requesting_user_id && !is_public {
-            if !self.can_access_attached_file(requesting_user_id, file_name)? {
-                anyhow::bail!("Permission denied: file is private");
-            }
+        if !self.can_read_file(requesting_user_id, file_name, &owner_id, is_public)? {
+            anyhow::bail!("Permission denied: file is private");
         }
 
         let file_path = self.install_path.join("storage").join(file_name);
         fs::read(&file_path).context("Failed to read file")
     }
 
+    /// Owners can always read a file; others can read public files or
+    /// files attached to their conversations
+    fn can_read_file(&self, user_id: &str, file_name: &str, owner_id: &str, is_public: bool) -> Result<bool> {
+        Ok(owner_id == user_id || is_public || self.can_access_attached_file(user_id, file_name)?)
+    }
+
+    /// Set or clear (with an empty string) a file's description
+    pub fn update_file_description(&self, requesting_user_id: &str, file_name: &str, description: &str) -> Result<()> {
+        validate_filename(file_name)?;
+        let description = description.trim();
+        if description.chars().count() > MAX_FILE_DESCRIPTION_LEN {
+            anyhow::bail!("Description is too long (max {} characters)", MAX_FILE_DESCRIPTION_LEN);
+        }
+
+        let owner_id: String = self.db.query_row(
+            "SELECT user_id FROM files WHERE file_name = ?1",
+            [file_name],
+            |row| row.get(0),
+        ).optional()?.ok_or_else(|| anyhow::anyhow!("File not found: {}", file_name))?;
+
+        if owner_id != requesting_user_id
+            && self.require_permission(requesting_user_id, roles::PERM_CONTENT_MODERATE).is_err()
+        {
+            anyhow::bail!("Permission denied: not the file owner");
+        }
+
+        let description = (!description.is_empty()).then_some(description);
+        self.db.execute(
+            "UPDATE files SET description = ?1 WHERE file_name = ?2",
+            rusqlite::params![description, file_name],
+        )?;
+        Ok(())
+    }
+
     pub fn update_file_visibility(&self, requesting_user_id: &str, file_name: &str, is_public: bool) -> Result<()> {
         validate_filename(file_name)?;
 
@@ -665,13 +1254,10 @@ impl StorageManager {
 
         let (file_id, owner_id) = file.ok_or_else(|| anyhow::anyhow!("File not found: {}", file_name))?;
 
-        if owner_id != requesting_user_id {
-            let is_admin = self.get_user_by_id(requesting_user_id)?
-                .map(|u| u.is_admin)
-                .unwrap_or(false);
-            if !is_admin {
-                anyhow::bail!("Permission denied: not the file owner");
-            }
+        if owner_id != requesting_user_id
+            && self.require_permission(requesting_user_id, roles::PERM_CONTENT_MODERATE).is_err()
+        {
+            anyhow::bail!("Permission denied: not the file owner");
         }
 
         self.db.execute(
@@ -693,29 +1279,38 @@ impl StorageManager {
     ) -> Result<User> {
         // If user already exists, update their password and return them
         if let Ok(Some(existing)) = self.get_user_by_username(username) {
+            let role = self.role_for_legacy_flag(is_admin, Some(&existing.role))?;
             let now = Utc::now().to_rfc3339();
+            let email_verified_at = existing.email_verified_at.filter(|_| existing.email == email);
             self.db.execute(
-                "UPDATE users SET password_hash = ?1, email = ?2, is_admin = ?3, updated_at = ?4 WHERE user_id = ?5",
-                rusqlite::params![password_hash, email, is_admin as i32, now, existing.user_id],
+                "UPDATE users SET password_hash = ?1, email = ?2, is_admin = ?3, role = ?4, updated_at = ?5,
+                     email_verified_at = ?6
+                 WHERE user_id = ?7",
+                rusqlite::params![password_hash, email, is_admin as i32, role, now, email_verified_at, existing.user_id],
             ).context("Failed to update existing user")?;
             return Ok(User {
                 user_id: existing.user_id,
                 username: existing.username,
                 email: email.to_string(),
                 is_admin,
+                role,
                 created_at: existing.created_at,
                 updated_at: now,
+                suspension: existing.suspension,
+                expires_at: existing.expires_at,
+                email_verified_at,
             });
         }
 
         let user_id = Uuid::new_v4().to_string();
+        let role = self.role_for_legacy_flag(is_admin, None)?;
         let now = Utc::now().to_rfc3339();
 
         self.db.execute(
-            "INSERT INTO users (user_id, username, email, password_hash, is_admin, created_at, updated_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            "INSERT INTO users (user_id, username, email, password_hash, is_admin, role, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
             rusqlite::params![
-                user_id, username, email, password_hash, is_admin as i32, now, now
+                user_id, username, email, password_hash, is_admin as i32, role, now, now
             ],
         ).context("Failed to create user")?;
 
@@ -724,27 +1319,24 @@ impl StorageManager {
             username: username.to_string(),
             email: email.to_string(),
             is_admin,
+            role,
             created_at: now.clone(),
             updated_at: now,
+            suspension: None,
+            expires_at: None,
+            email_verified_at: None,
         })
     }
 
     pub fn get_user_by_username(&self, username: &str) -> Result<Option<User>> {
         let mut stmt = self.db.prepare(
-            "SELECT user_id, username, email, is_admin, created_at, updated_at
+            "SELECT user_id, username, email, is_admin, role, created_at, updated_at,
+                    suspended_at, suspension_reason, suspended_until, expires_at, email_verified_at
              FROM users WHERE username = ?1"
         ).context("Failed to prepare query")?;
 
-        let mut rows = stmt.query_map([username], |row| {
-            Ok(User {
-                user_id: row.get(0)?,
-                username: row.get(1)?,
-                email: row.get(2)?,
-                is_admin: row.get::<_, i32>(3)? != 0,
-                created_at: row.get(4)?,
-                updated_at: row.get(5)?,
-            })
-        }).context("Failed to query user")?;
+        let mut rows = stmt.query_map([username], user_from_row)
+            .context("Failed to query user")?;
 
         match rows.next() {
             Some(Ok(user)) => Ok(Some(user)),
@@ -755,20 +1347,13 @@ impl StorageManager {
 
     pub fn get_user_by_id(&self, user_id: &str) -> Result<Option<User>> {
         let mut stmt = self.db.prepare(
-            "SELECT user_id, username, email, is_admin, created_at, updated_at
+            "SELECT user_id, username, email, is_admin, role, created_at, updated_at,
+                    suspended_at, suspension_reason, suspended_until, expires_at, email_verified_at
              FROM users WHERE user_id = ?1"
         ).context("Failed to prepare query")?;
 
-        let mut rows = stmt.query_map([user_id], |row| {
-            Ok(User {
-                user_id: row.get(0)?,
-                username: row.get(1)?,
-                email: row.get(2)?,
-                is_admin: row.get::<_, i32>(3)? != 0,
-                created_at: row.get(4)?,
-                updated_at: row.get(5)?,
-            })
-        }).context("Failed to query user")?;
+        let mut rows = stmt.query_map([user_id], user_from_row)
+            .context("Failed to query user")?;
 
         match rows.next() {
             Some(Ok(user)) => Ok(Some(user)),
@@ -795,41 +1380,53 @@ impl StorageManager {
 
     pub fn list_users(&self) -> Result<Vec<User>> {
         let mut stmt = self.db.prepare(
-            "SELECT user_id, username, email, is_admin, created_at, updated_at
-             FROM users ORDER BY created_at DESC"
+            "SELECT user_id, username, email, is_admin, role, created_at, updated_at,
+                    suspended_at, suspension_reason, suspended_until, expires_at, email_verified_at
+             FROM users WHERE anonymized_at IS NULL ORDER BY created_at DESC"
         ).context("Failed to prepare query")?;
 
-        let users = stmt.query_map([], |row| {
-            Ok(User {
-                user_id: row.get(0)?,
-                username: row.get(1)?,
-                email: row.get(2)?,
-                is_admin: row.get::<_, i32>(3)? != 0,
-                created_at: row.get(4)?,
-                updated_at: row.get(5)?,
-            })
-        }).context("Failed to query users")?
+        let users = stmt.query_map([], user_from_row)
+            .context("Failed to query users")?
             .collect::<Result<Vec<_>, _>>()?;
 
         Ok(users)
     }
 
+    /// Page through users, optionally filtered by a username/email substring.
+    /// Returns the page and the total number of matches.
+    pub fn search_users(&self, query: Option<&str>, limit: u32, offset: u32) -> Result<(Vec<User>, u32)> {
+        let pattern = format!("%{}%", escape_like(query.unwrap_or("").trim()));
+
+        let total: u32 = self.db.query_row(
+            "SELECT COUNT(*) FROM users
+             WHERE anonymized_at IS NULL AND (username LIKE ?1 ESCAPE '\\' OR email LIKE ?1 ESCAPE '\\')",
+            [&pattern],
+            |row| row.get(0),
+        )?;
+
+        let mut stmt = self.db.prepare(
+            "SELECT user_id, username, email, is_admin, role, created_at, updated_at,
+                    suspended_at, suspension_reason, suspended_until, expires_at, email_verified_at
+             FROM users WHERE anonymized_at IS NULL AND (username LIKE ?1 ESCAPE '\\' OR email LIKE ?1 ESCAPE '\\')
+             ORDER BY created_at DESC LIMIT ?2 OFFSET ?3"
+        ).context("Failed to prepare query")?;
+
+        let users = stmt.query_map(rusqlite::params![pattern, limit, offset], user_from_row)
+            .context("Failed to query users")?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok((users, total))
+    }
+
     pub fn get_first_admin(&self) -> Result<Option<User>> {
         let mut stmt = self.db.prepare(
-            "SELECT user_id, username, email, is_admin, created_at, updated_at
+            "SELECT user_id, username, email, is_admin, role, created_at, updated_at,
+                    suspended_at, suspension_reason, suspended_until, expires_at, email_verified_at
              FROM users WHERE is_admin = 1 ORDER BY created_at ASC LIMIT 1"
         ).context("Failed to prepare query")?;
 
-        let mut rows = stmt.query_map([], |row| {
-            Ok(User {
-                user_id: row.get(0)?,
-                username: row.get(1)?,
-                email: row.get(2)?,
-                is_admin: row.get::<_, i32>(3)? != 0,
-                created_at: row.get(4)?,
-                updated_at: row.get(5)?,
-            })
-        }).context("Failed to query admin")?;
+        let mut rows = stmt.query_map([], user_from_row)
+            .context("Failed to query admin")?;
 
         match rows.next() {
             Some(Ok(user)) => Ok(Some(user)),
@@ -839,6 +1436,11 @@ impl StorageManager {
     }
 
     pub fn delete_user(&self, user_id: &str) -> Result<()> {
+        self.ensure_not_last_admin(user_id)?;
+        if self.is_owner(user_id)? && self.count_users_with_role(roles::ROLE_OWNER)? <= 1 {
+            anyhow::bail!("The hub must keep at least one owner");
+        }
+
         // Delete user's files from disk
         let files = self.list_files(Some(user_id))?;
         for f in &files {
@@ -847,82 +1449,1568 @@ impl StorageManager {
                 let _ = fs::remove_file(&file_path);
             }
         }
+        for job in self.list_export_jobs(user_id)? {
+            let _ = fs::remove_file(self.export_archive_path(&job.job_id));
+        }
+        self.remove_avatar_file(user_id);
         // Cascade deletes files and spaces via FK
         self.db.execute("DELETE FROM users WHERE user_id = ?1", [user_id])
             .context("Failed to delete user")?;
         Ok(())
     }
 
-    pub fn update_user_role(&self, user_id: &str, is_admin: bool) -> Result<()> {
-        let now = Utc::now().to_rfc3339();
+    /// Replace a user's password (admin reset). Also lifts any lockout.
+    pub fn set_password_hash(&self, user_id: &str, password_hash: &str) -> Result<()> {
         self.db.execute(
-            "UPDATE users SET is_admin = ?1, updated_at = ?2 WHERE user_id = ?3",
-            rusqlite::params![is_admin as i32, now, user_id],
-        ).context("Failed to update user role")?;
+            "UPDATE users SET password_hash = ?1, failed_logins = 0, last_failed_login_at = NULL,
+                 locked_until = NULL, updated_at = ?2
+             WHERE user_id = ?3",
+            rusqlite::params![password_hash, Utc::now().to_rfc3339(), user_id],
+        ).context("Failed to update password")?;
         Ok(())
     }
 
-    pub fn list_all_files(&self) -> Result<Vec<File>> {
-        // Admin-only method to list ALL files regardless of ownership
-        let mut stmt = self.db.prepare(
-            "SELECT file_id, user_id, file_name, size_bytes, is_public, created_at
-             FROM files
-             ORDER BY created_at DESC"
-        )?;
+    /// Replace a forgotten password: sets the new hash and signs the user out
+    /// everywhere, so whoever held the old password loses access too.
+    pub fn reset_password(&self, user_id: &str, password_hash: &str) -> Result<()> {
+        self.in_transaction(|| {
+            self.set_password_hash(user_id, password_hash)?;
+            self.revoke_sessions(user_id)
+        })
+    }
 
-        let files = stmt.query_map([], |row| {
-            Ok(File {
-                file_id: row.get(0)?,
-                user_id: row.get(1)?,
-                file_name: row.get(2)?,
-                size_bytes: row.get(3)?,
-                is_public: row.get::<_, i32>(4)? != 0,
-                created_at: row.get(5)?,
-            })
-        })?.collect::<Result<Vec<_>, _>>()?;
+    /// Sign the user out everywhere: session JWTs issued so far stop working
+    /// and personal access tokens are deleted.
+    pub fn revoke_sessions(&self, user_id: &str) -> Result<()> {
+        self.in_transaction(|| {
+            self.db.execute(
+                "UPDATE users SET sessions_revoked_at = ?1 WHERE user_id = ?2",
+                rusqlite::params![Utc::now().to_rfc3339(), user_id],
+            ).context("Failed to revoke sessions")?;
+            self.db.execute("DELETE FROM api_tokens WHERE user_id = ?1", [user_id])
+                .context("Failed to revoke API tokens")?;
+            Ok(())
+        })
+    }
 
-        Ok(files)
+    /// Whether a session JWT issued at `issued_at` (Unix seconds) was revoked
+    pub fn is_session_revoked(&self, user_id: &str, issued_at: i64) -> Result<bool> {
+        let revoked_at: Option<String> = self.db.query_row(
+            "SELECT sessions_revoked_at FROM users WHERE user_id = ?1",
+            [user_id],
+            |row| row.get(0),
+        ).optional()?.flatten();
+        Ok(revoked_at
+            .and_then(|t| chrono::DateTime::parse_from_rfc3339(&t).ok())
+            .is_some_and(|t| issued_at <= t.timestamp()))
     }
 
-    pub fn create_space(
-        &self,
-        user_id: &str,
-        name: &str,
-        storage_quota_gb: f64,
-        is_public: bool,
-    ) -> Result<Space> {
-        let space_id = Uuid::new_v4().to_string();
-        let now = Utc::now().to_rfc3339();
+    /// Swap in a rehash of the same password, unless the password changed
+    /// since `old_hash` was read
+    pub fn rehash_password(&self, user_id: &str, old_hash: &str, new_hash: &str) -> Result<()> {
+        self.db.execute(
+            "UPDATE users SET password_hash = ?1 WHERE user_id = ?2 AND password_hash = ?3",
+            [new_hash, user_id, old_hash],
+        ).context("Failed to update password hash")?;
+        Ok(())
+    }
 
+    // --- Suspension methods ---
+
+    pub fn suspend_user(&self, user_id: &str, reason: Option<&str>, until: Option<&str>) -> Result<()> {
+        let until = until.map(future_timestamp).transpose()?;
+        self.ensure_not_last_admin(user_id)?;
         self.db.execute(
-            "INSERT INTO spaces (space_id, user_id, name, storage_quota_gb, is_public, created_at, updated_at)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-            rusqlite::params![
-                space_id, user_id, name, storage_quota_gb, is_public as i32, now, now
-            ],
-        ).context("Failed to create space")?;
+            "UPDATE users SET suspended_at = ?1, suspension_reason = ?2, suspended_until = ?3 WHERE user_id = ?4",
+            rusqlite::params![Utc::now().to_rfc3339(), reason, until, user_id],
+        ).context("Failed to suspend user")?;
+        Ok(())
+    }
 
-        Ok(Space {
-            space_id,
-            user_id: user_id.to_string(),
-            name: name.to_string(),
-            storage_quota_gb,
-            is_public,
-            created_at: now.clone(),
-            updated_at: now,
-        })
+    pub fn unsuspend_user(&self, user_id: &str) -> Result<()> {
+        self.db.execute(
+            "UPDATE users SET suspended_at = NULL, suspension_reason = NULL, suspended_until = NULL WHERE user_id = ?1",
+            [user_id],
+        ).context("Failed to lift suspension")?;
+        Ok(())
     }
 
-    pub fn list_user_spaces(&self, user_id: &str) -> Result<Vec<Space>> {
-        let mut stmt = self.db.prepare(
-            "SELECT space_id, user_id, name, storage_quota_gb, is_public, created_at, updated_at
-             FROM spaces WHERE user_id = ?1 ORDER BY created_at DESC"
-        ).context("Failed to prepare query")?;
+    /// The user's suspension, if one is in effect. Expired suspensions read as None.
+    pub fn get_suspension(&self, user_id: &str) -> Result<Option<Suspension>> {
+        let suspension = self.db.query_row(
+            "SELECT suspended_at, suspension_reason, suspended_until FROM users WHERE user_id = ?1",
+            [user_id],
+            |row| suspension_from_row(row, 0),
+        );
+        match suspension {
+            Ok(s) => Ok(s),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
 
-        let spaces = stmt.query_map([user_id], |row| {
-            Ok(Space {
-                space_id: row.get(0)?,
-                user_id: row.get(1)?,
+    pub fn is_suspended(&self, user_id: &str) -> Result<bool> {
+        Ok(self.get_suspension(user_id)?.is_some())
+    }
+
+    /// Guard against locking everyone out of user management: the last active
+    /// user who can manage users can't be deleted, suspended or demoted.
+    fn ensure_not_last_admin(&self, user_id: &str) -> Result<()> {
+        let is_admin = self.get_user_by_id(user_id)?.is_some_and(|u| u.is_admin);
+        if !is_admin {
+            return Ok(());
+        }
+        let active_admins: u32 = self.db.query_row(
+            "SELECT COUNT(*) FROM users WHERE is_admin = 1
+               AND (suspended_at IS NULL OR (suspended_until IS NOT NULL AND suspended_until <= ?1))",
+            [Utc::now().to_rfc3339()],
+            |row| row.get(0),
+        )?;
+        if active_admins <= 1 {
+            anyhow::bail!("Cannot remove the last admin");
+        }
+        Ok(())
+    }
+
+    /// Legacy admin toggle: promotes to admin or demotes to member
+    pub fn update_user_role(&self, acting_user_id: &str, user_id: &str, is_admin: bool) -> Result<()> {
+        let role = if is_admin { roles::ROLE_ADMIN } else { roles::ROLE_MEMBER };
+        self.set_user_role(acting_user_id, user_id, role)
+    }
+
+    // --- Account setup codes ---
+
+    /// Create an account without a password, e.g. from a member import. The
+    /// member picks a password by redeeming the setup code before it expires.
+    pub fn create_pending_user(
+        &self,
+        acting_user_id: &str,
+        username: &str,
+        email: &str,
+        role_id: &str,
+        setup_code_hash: &str,
+        setup_code_expires_at: &str,
+    ) -> Result<User> {
+        let role = self.get_role(role_id)?
+            .ok_or_else(|| anyhow::anyhow!("Unknown role: {}", role_id))?;
+        if role.role_id == roles::ROLE_OWNER && !self.is_owner(acting_user_id)? {
+            anyhow::bail!("Permission denied: only an owner can grant the owner role");
+        }
+        self.ensure_can_grant(acting_user_id, &role.permissions)?;
+        if self.get_user_by_username(username)?.is_some() {
+            anyhow::bail!("Username is already taken");
+        }
+        let email_taken: bool = self.db.query_row(
+            "SELECT EXISTS(SELECT 1 FROM users WHERE email = ?1)",
+            [email],
+            |row| row.get(0),
+        )?;
+        if email_taken {
+            anyhow::bail!("Email is already in use");
+        }
+
+        let user_id = Uuid::new_v4().to_string();
+        let is_admin = role.permissions.iter().any(|p| p == roles::PERM_USERS_MANAGE);
+        let now = Utc::now().to_rfc3339();
+        self.db.execute(
+            "INSERT INTO users (user_id, username, email, password_hash, is_admin, role,
+                 setup_code_hash, setup_code_expires_at, created_at, updated_at)
+             VALUES (?1, ?2, ?3, '', ?4, ?5, ?6, ?7, ?8, ?9)",
+            rusqlite::params![
+                user_id, username, email, is_admin as i32, role.role_id,
+                setup_code_hash, setup_code_expires_at, now, now
+            ],
+        ).context("Failed to create user")?;
+
+        Ok(User {
+            user_id,
+            username: username.to_string(),
+            email: email.to_string(),
+            is_admin,
+            role: role.role_id,
+            created_at: now.clone(),
+            updated_at: now,
+            suspension: None,
+            expires_at: None,
+            email_verified_at: None,
+        })
+    }
+
+    /// Set the password of an account awaiting setup. Returns None unless the
+    /// code matches and hasn't expired; a code works only once.
+    pub fn complete_account_setup(&self, username: &str, setup_code_hash: &str, password_hash: &str) -> Result<Option<User>> {
+        let now = Utc::now().to_rfc3339();
+        let updated = self.db.execute(
+            "UPDATE users SET password_hash = ?1, setup_code_hash = NULL, setup_code_expires_at = NULL, updated_at = ?2
+             WHERE username = ?3 AND setup_code_hash = ?4 AND setup_code_expires_at > ?2",
+            rusqlite::params![password_hash, now, username, setup_code_hash],
+        ).context("Failed to complete account setup")?;
+        if updated == 0 {
+            return Ok(None);
+        }
+        self.get_user_by_username(username)
+    }
+
+    // --- Guest accounts ---
+
+    /// Create a guest account that expires at `expires_at` and is added to
+    /// the given group conversations. Guests have no email address.
+    pub fn create_guest(
+        &self,
+        username: &str,
+        password_hash: &str,
+        expires_at: &str,
+        conversation_ids: &[String],
+    ) -> Result<User> {
+        let expires_at = future_timestamp(expires_at)?;
+        self.ensure_group_conversations(conversation_ids)?;
+        if self.get_user_by_username(username)?.is_some() {
+            anyhow::bail!("Username is already taken");
+        }
+
+        let user_id = Uuid::new_v4().to_string();
+        let email = format!("{}@guest.invalid", user_id);
+        let now = Utc::now().to_rfc3339();
+        self.db.execute(
+            "INSERT INTO users (user_id, username, email, password_hash, is_admin, role, expires_at, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, 0, ?5, ?6, ?7, ?8)",
+            rusqlite::params![user_id, username, email, password_hash, roles::ROLE_GUEST, expires_at, now, now],
+        ).context("Failed to create guest")?;
+        for conversation_id in conversation_ids {
+            self.add_group_member(conversation_id, &user_id)?;
+        }
+
+        Ok(User {
+            user_id,
+            username: username.to_string(),
+            email,
+            is_admin: false,
+            role: roles::ROLE_GUEST.to_string(),
+            created_at: now.clone(),
+            updated_at: now,
+            suspension: None,
+            expires_at: Some(expires_at),
+            email_verified_at: None,
+        })
+    }
+
+    /// Whether the user is a guest whose account has run out
+    pub fn is_expired(&self, user_id: &str) -> Result<bool> {
+        let expires_at: Option<String> = self.db.query_row(
+            "SELECT expires_at FROM users WHERE user_id = ?1",
+            [user_id],
+            |row| row.get(0),
+        ).optional()?.flatten();
+        Ok(has_passed(expires_at.as_deref()))
+    }
+
+    pub fn create_guest_invite(
+        &self,
+        created_by: &str,
+        token_hash: &str,
+        conversation_ids: &[String],
+        expires_at: &str,
+        max_uses: Option<u32>,
+    ) -> Result<GuestInvite> {
+        let expires_at = future_timestamp(expires_at)?;
+        self.ensure_group_conversations(conversation_ids)?;
+
+        let invite = GuestInvite {
+            invite_id: Uuid::new_v4().to_string(),
+            conversation_ids: conversation_ids.to_vec(),
+            expires_at,
+            max_uses,
+            uses: 0,
+            created_by: created_by.to_string(),
+            created_at: Utc::now().to_rfc3339(),
+        };
+        self.db.execute(
+            "INSERT INTO guest_invites (invite_id, token_hash, conversation_ids, expires_at, max_uses, created_by, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![
+                invite.invite_id,
+                token_hash,
+                serde_json::to_string(&invite.conversation_ids)?,
+                invite.expires_at,
+                invite.max_uses,
+                invite.created_by,
+                invite.created_at,
+            ],
+        ).context("Failed to create guest invite")?;
+        Ok(invite)
+    }
+
+    /// Invites that haven't expired yet, newest first
+    pub fn list_guest_invites(&self) -> Result<Vec<GuestInvite>> {
+        let mut stmt = self.db.prepare(
+            "SELECT invite_id, conversation_ids, expires_at, max_uses, uses, created_by, created_at
+             FROM guest_invites WHERE expires_at > ?1 ORDER BY created_at DESC"
+        )?;
+        let invites = stmt.query_map([Utc::now().to_rfc3339()], guest_invite_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(invites)
+    }
+
+    /// Revoke an invite. Guests who already signed up keep their accounts.
+    pub fn delete_guest_invite(&self, invite_id: &str) -> Result<bool> {
+        let deleted = self.db.execute("DELETE FROM guest_invites WHERE invite_id = ?1", [invite_id])
+            .context("Failed to delete guest invite")?;
+        Ok(deleted > 0)
+    }
+
+    /// Count one use of an invite. Returns None if it doesn't exist, has
+    /// expired or has no uses left.
+    pub fn redeem_guest_invite(&self, token_hash: &str) -> Result<Option<GuestInvite>> {
+        let redeemed = self.db.execute(
+            "UPDATE guest_invites SET uses = uses + 1
+             WHERE token_hash = ?1 AND expires_at > ?2 AND (max_uses IS NULL OR uses < max_uses)",
+            [token_hash, &Utc::now().to_rfc3339()],
+        ).context("Failed to redeem guest invite")?;
+        if redeemed == 0 {
+            return Ok(None);
+        }
+        let invite = self.db.query_row(
+            "SELECT invite_id, conversation_ids, expires_at, max_uses, uses, created_by, created_at
+             FROM guest_invites WHERE token_hash = ?1",
+            [token_hash],
+            guest_invite_from_row,
+        )?;
+        Ok(Some(invite))
+    }
+
+    /// Redeem an invite and create the guest it admits, together: if the
+    /// account can't be created the invite use is not counted. Returns None
+    /// if the invite is invalid or used up.
+    pub fn sign_up_guest(&self, token_hash: &str, username: &str, password_hash: &str) -> Result<Option<(GuestInvite, User)>> {
+        self.in_transaction(|| {
+            let Some(invite) = self.redeem_guest_invite(token_hash)? else {
+                return Ok(None);
+            };
+            let guest = self.create_guest(username, password_hash, &invite.expires_at, &invite.conversation_ids)?;
+            Ok(Some((invite, guest)))
+        })
+    }
+
+    /// Anonymize guests past their expiry and drop expired invites. A
+    /// retired guest loses their tokens, passkeys, profile, files and
+    /// conversation memberships; the account row stays, renamed, so the
+    /// messages they sent still have a (now anonymous) sender.
+    /// Returns the IDs of the guests retired.
+    pub fn purge_expired_guests(&self) -> Result<Vec<String>> {
+        let now = Utc::now().to_rfc3339();
+        let expired: Vec<String> = self.db.prepare(
+            "SELECT user_id FROM users
+             WHERE expires_at IS NOT NULL AND expires_at <= ?1 AND anonymized_at IS NULL"
+        )?
+            .query_map([&now], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // One guest failing must not hold up the rest
+        let mut retired = Vec::with_capacity(expired.len());
+        for user_id in expired {
+            match self.anonymize_guest(&user_id) {
+                Ok(()) => retired.push(user_id),
+                Err(e) => log::error!("Failed to retire guest {}: {:#}", user_id, e),
+            }
+        }
+        self.db.execute("DELETE FROM guest_invites WHERE expires_at <= ?1", [&now])
+            .context("Failed to delete expired guest invites")?;
+        Ok(retired)
+    }
+
+    /// Strip a guest's data in one transaction; their files are removed from
+    /// disk only once it has committed.
+    fn anonymize_guest(&self, user_id: &str) -> Result<()> {
+        let own_files: Vec<File> = self.list_files(Some(user_id))?
+            .into_iter()
+            .filter(|f| f.user_id == user_id)
+            .collect();
+        let export_jobs = self.list_export_jobs(user_id)?;
+
+        self.in_transaction(|| self.clear_guest_rows(user_id))?;
+
+        for f in &own_files {
+            let _ = fs::remove_file(self.install_path.join("storage").join(&f.file_name));
+        }
+        for job in &export_jobs {
+            let _ = fs::remove_file(self.export_archive_path(&job.job_id));
+        }
+        self.remove_avatar_file(user_id);
+        Ok(())
+    }
+
+    fn clear_guest_rows(&self, user_id: &str) -> Result<()> {
+        for table in ["api_tokens", "passkeys", "oidc_auth_codes", "email_tokens", "user_events", "profiles", "export_jobs", "files", "spaces", "conversation_members"] {
+            self.db.execute(&format!("DELETE FROM {} WHERE user_id = ?1", table), [user_id])
+                .with_context(|| format!("Failed to clear guest {}", table))?;
+        }
+
+        let now = Utc::now().to_rfc3339();
+        self.db.execute(
+            "UPDATE users SET username = ?1, email = ?2, password_hash = '', failed_logins = 0,
+                 last_failed_login_at = NULL, locked_until = NULL, anonymized_at = ?3, updated_at = ?3
+             WHERE user_id = ?4",
+            rusqlite::params![
+                format!("former-guest-{}", user_id),
+                format!("{}@guest.invalid", user_id),
+                now,
+                user_id,
+            ],
+        ).context("Failed to anonymize guest")?;
+        Ok(())
+    }
+
+    fn ensure_group_conversations(&self, conversation_ids: &[String]) -> Result<()> {
+        for conversation_id in conversation_ids {
+            let kind: Option<String> = self.db.query_row(
+                "SELECT kind FROM conversations WHERE conversation_id = ?1",
+                [conversation_id],
+                |row| row.get(0),
+            ).optional()?;
+            if kind.as_deref() != Some("group") {
+                anyhow::bail!("Guests can only join existing group conversations");
+            }
+        }
+        Ok(())
+    }
+
+    // --- Roles and permissions ---
+
+    /// Role for accounts created with the legacy is_admin flag: the first
+    /// admin becomes the owner, later ones admins, everyone else a member.
+    fn role_for_legacy_flag(&self, is_admin: bool, existing_role: Option<&str>) -> Result<String> {
+        if !is_admin {
+            return Ok(roles::ROLE_MEMBER.to_string());
+        }
+        if existing_role == Some(roles::ROLE_OWNER) || self.count_users_with_role(roles::ROLE_OWNER)? == 0 {
+            Ok(roles::ROLE_OWNER.to_string())
+        } else {
+            Ok(roles::ROLE_ADMIN.to_string())
+        }
+    }
+
+    fn count_users_with_role(&self, role_id: &str) -> Result<u32> {
+        let count = self.db.query_row(
+            "SELECT COUNT(*) FROM users WHERE role = ?1",
+            [role_id],
+            |row| row.get(0),
+        )?;
+        Ok(count)
+    }
+
+    /// Built-in roles followed by custom roles
+    pub fn list_roles(&self) -> Result<Vec<Role>> {
+        let mut all: Vec<Role> = roles::BUILTIN_ROLES.iter()
+            .map(|id| builtin_role(id))
+            .collect();
+
+        let mut stmt = self.db.prepare(
+            "SELECT role_id, name, permissions FROM roles ORDER BY created_at ASC"
+        ).context("Failed to prepare query")?;
+        let custom = stmt.query_map([], role_from_row)
+            .context("Failed to query roles")?
+            .collect::<Result<Vec<_>, _>>()?;
+        all.extend(custom);
+
+        Ok(all)
+    }
+
+    pub fn get_role(&self, role_id: &str) -> Result<Option<Role>> {
+        if roles::is_builtin(role_id) {
+            return Ok(Some(builtin_role(role_id)));
+        }
+        let role = self.db.query_row(
+            "SELECT role_id, name, permissions FROM roles WHERE role_id = ?1",
+            [role_id],
+            role_from_row,
+        );
+        match role {
+            Ok(role) => Ok(Some(role)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn create_role(&self, acting_user_id: &str, name: &str, permissions: &[String]) -> Result<Role> {
+        validate_role(name, permissions)?;
+        self.ensure_can_grant(acting_user_id, permissions)?;
+        if self.list_roles()?.iter().any(|r| r.name.eq_ignore_ascii_case(name)) {
+            anyhow::bail!("A role named '{}' already exists", name);
+        }
+
+        let role_id = Uuid::new_v4().to_string();
+        self.db.execute(
+            "INSERT INTO roles (role_id, name, permissions, created_at) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![role_id, name, permissions.join(" "), Utc::now().to_rfc3339()],
+        ).context("Failed to create role")?;
+
+        Ok(Role {
+            role_id,
+            name: name.to_string(),
+            permissions: permissions.to_vec(),
+            is_builtin: false,
+        })
+    }
+
+    /// Rename a custom role or change its permissions. Built-in roles are fixed,
+    /// and the acting user must hold every permission of the role before and after.
+    pub fn update_role(&self, acting_user_id: &str, role_id: &str, name: &str, permissions: &[String]) -> Result<Role> {
+        if roles::is_builtin(role_id) {
+            anyhow::bail!("Built-in roles cannot be changed");
+        }
+        validate_role(name, permissions)?;
+        if let Some(existing) = self.get_role(role_id)? {
+            self.ensure_can_grant(acting_user_id, &existing.permissions)?;
+        }
+        self.ensure_can_grant(acting_user_id, permissions)?;
+        if self.list_roles()?.iter().any(|r| r.role_id != role_id && r.name.eq_ignore_ascii_case(name)) {
+            anyhow::bail!("A role named '{}' already exists", name);
+        }
+
+        let updated = self.db.execute(
+            "UPDATE roles SET name = ?1, permissions = ?2 WHERE role_id = ?3",
+            rusqlite::params![name, permissions.join(" "), role_id],
+        ).context("Failed to update role")?;
+        if updated == 0 {
+            anyhow::bail!("Role not found");
+        }
+        self.sync_admin_flag()?;
+
+        Ok(Role {
+            role_id: role_id.to_string(),
+            name: name.to_string(),
+            permissions: permissions.to_vec(),
+            is_builtin: false,
+        })
+    }
+
+    /// Delete a custom role. Fails while users still hold it.
+    pub fn delete_role(&self, role_id: &str) -> Result<bool> {
+        if roles::is_builtin(role_id) {
+            anyhow::bail!("Built-in roles cannot be deleted");
+        }
+        if self.count_users_with_role(role_id)? > 0 {
+            anyhow::bail!("Role is still assigned to users");
+        }
+        let deleted = self.db.execute("DELETE FROM roles WHERE role_id = ?1", [role_id])
+            .context("Failed to delete role")?;
+        Ok(deleted > 0)
+    }
+
+    /// Assign a role. Only an owner can grant or revoke the owner role, the
+    /// role can't carry permissions the acting user lacks, and the hub always
+    /// keeps at least one owner.
+    pub fn set_user_role(&self, acting_user_id: &str, user_id: &str, role_id: &str) -> Result<()> {
+        let user = self.get_user_by_id(user_id)?
+            .ok_or_else(|| anyhow::anyhow!("User not found"))?;
+        let role = self.get_role(role_id)?
+            .ok_or_else(|| anyhow::anyhow!("Unknown role: {}", role_id))?;
+
+        self.ensure_can_manage_user(acting_user_id, user_id)?;
+        if role.role_id == roles::ROLE_OWNER && !self.is_owner(acting_user_id)? {
+            anyhow::bail!("Permission denied: only an owner can grant the owner role");
+        }
+        self.ensure_can_grant(acting_user_id, &role.permissions)?;
+        if user.role == roles::ROLE_OWNER && role.role_id != roles::ROLE_OWNER
+            && self.count_users_with_role(roles::ROLE_OWNER)? <= 1
+        {
+            anyhow::bail!("The hub must keep at least one owner");
+        }
+        if !role.permissions.iter().any(|p| p == roles::PERM_USERS_MANAGE) {
+            self.ensure_not_last_admin(user_id)?;
+        }
+
+        self.db.execute(
+            "UPDATE users SET role = ?1, is_admin = ?2, updated_at = ?3 WHERE user_id = ?4",
+            rusqlite::params![
+                role.role_id,
+                role.permissions.iter().any(|p| p == roles::PERM_USERS_MANAGE) as i32,
+                Utc::now().to_rfc3339(),
+                user_id,
+            ],
+        ).context("Failed to update user role")?;
+        Ok(())
+    }
+
+    fn is_owner(&self, user_id: &str) -> Result<bool> {
+        Ok(self.get_user_by_id(user_id)?.is_some_and(|u| u.role == roles::ROLE_OWNER))
+    }
+
+    /// Users can only hand out permissions they hold themselves
+    pub fn ensure_can_grant(&self, acting_user_id: &str, permissions: &[String]) -> Result<()> {
+        let own = self.get_user_permissions(acting_user_id)?;
+        if let Some(missing) = permissions.iter().find(|p| !own.contains(p)) {
+            anyhow::bail!("Permission denied: cannot grant {} without holding it", missing);
+        }
+        Ok(())
+    }
+
+    /// Only an owner can change or remove another owner's account
+    pub fn ensure_can_manage_user(&self, acting_user_id: &str, user_id: &str) -> Result<()> {
+        if self.is_owner(user_id)? && !self.is_owner(acting_user_id)? {
+            anyhow::bail!("Permission denied: only an owner can manage an owner");
+        }
+        Ok(())
+    }
+
+    pub fn get_user_permissions(&self, user_id: &str) -> Result<Vec<String>> {
+        let role: Option<String> = self.db.query_row(
+            "SELECT role FROM users WHERE user_id = ?1",
+            [user_id],
+            |row| row.get(0),
+        ).ok();
+        match role {
+            Some(role_id) => Ok(self.get_role(&role_id)?.map(|r| r.permissions).unwrap_or_default()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Shared permission check for the hub API and Tauri commands
+    pub fn require_permission(&self, user_id: &str, permission: &str) -> Result<()> {
+        if self.get_user_permissions(user_id)?.iter().any(|p| p == permission) {
+            Ok(())
+        } else {
+            anyhow::bail!("Permission denied: {} required", permission)
+        }
+    }
+
+    /// Keep the legacy is_admin column (still exposed to clients and tokens)
+    /// in step with whether each user's role can manage users.
+    fn sync_admin_flag(&self) -> Result<()> {
+        let users: Vec<(String, String)> = self.db.prepare("SELECT user_id, role FROM users")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (user_id, role_id) in users {
+            let is_admin = self.get_role(&role_id)?
+                .is_some_and(|r| r.permissions.iter().any(|p| p == roles::PERM_USERS_MANAGE));
+            self.db.execute(
+                "UPDATE users SET is_admin = ?1 WHERE user_id = ?2",
+                rusqlite::params![is_admin as i32, user_id],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn set_last_seen(&self, user_id: &str, at: &str) -> Result<()> {
+        self.db.execute(
+            "UPDATE users SET last_seen_at = ?1 WHERE user_id = ?2",
+            [at, user_id],
+        ).context("Failed to update last seen")?;
+        Ok(())
+    }
+
+    /// When each member was last connected, for those who ever were
+    pub fn last_seen_times(&self) -> Result<HashMap<String, String>> {
+        let mut stmt = self.db.prepare(
+            "SELECT user_id, last_seen_at FROM users WHERE last_seen_at IS NOT NULL"
+        )?;
+        let times = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<HashMap<String, String>, _>>()?;
+        Ok(times)
+    }
+
+    // --- Profile methods ---
+
+    /// A member's profile; members who never edited theirs get the defaults.
+    pub fn get_profile(&self, user_id: &str) -> Result<Profile> {
+        let profile = self.db.query_row(
+            &format!("SELECT {} FROM profiles WHERE user_id = ?1", PROFILE_COLUMNS),
+            [user_id],
+            profile_from_row,
+        );
+        match profile {
+            Ok(p) => Ok(p),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(Profile {
+                user_id: user_id.to_string(),
+                display_name: None,
+                avatar_updated_at: None,
+                bio: None,
+                pronouns: None,
+                tags: Vec::new(),
+                visibility: ProfileVisibility::default(),
+                allow_direct_messages: true,
+                show_presence: true,
+                updated_at: None,
+            }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn save_profile(&self, profile: &Profile) -> Result<Profile> {
+        validate_profile(profile)?;
+        let now = Utc::now().to_rfc3339();
+        self.db.execute(
+            "INSERT INTO profiles (user_id, display_name, avatar_updated_at, bio, pronouns, tags, visibility,
+                                   allow_direct_messages, updated_at, show_presence)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+             ON CONFLICT(user_id) DO UPDATE SET
+                display_name = ?2, avatar_updated_at = ?3, bio = ?4, pronouns = ?5, tags = ?6,
+                visibility = ?7, allow_direct_messages = ?8, updated_at = ?9, show_presence = ?10",
+            rusqlite::params![
+                profile.user_id,
+                profile.display_name,
+                profile.avatar_updated_at,
+                profile.bio,
+                profile.pronouns,
+                serde_json::to_string(&profile.tags)?,
+                serde_json::to_string(&profile.visibility)?,
+                profile.allow_direct_messages as i32,
+                now,
+                profile.show_presence as i32,
+            ],
+        ).context("Failed to save profile")?;
+
+        Ok(Profile { updated_at: Some(now), ..profile.clone() })
+    }
+
+    /// Profiles of all members who have saved one, keyed by user ID
+    pub fn list_profiles(&self) -> Result<HashMap<String, Profile>> {
+        let mut stmt = self.db.prepare(&format!("SELECT {} FROM profiles", PROFILE_COLUMNS))?;
+        let profiles = stmt.query_map([], profile_from_row)?
+            .map(|p| p.map(|p| (p.user_id.clone(), p)))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        Ok(profiles)
+    }
+
+    /// Avatars live in their own directory, apart from the member's files, so
+    /// they don't show up in file listings or count towards storage.
+    pub fn avatar_path(&self, user_id: &str) -> PathBuf {
+        self.install_path.join("avatars").join(format!("{}.png", user_id))
+    }
+
+    /// Store a new avatar, replacing the previous one. The upload is decoded
+    /// and re-encoded, so only a clean PNG is ever served back.
+    pub fn set_avatar(&self, user_id: &str, data: &[u8]) -> Result<Profile> {
+        if data.len() > MAX_AVATAR_BYTES {
+            anyhow::bail!("Avatar is larger than {} bytes", MAX_AVATAR_BYTES);
+        }
+        let png = encode_avatar(data)?;
+
+        let path = self.avatar_path(user_id);
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).context("Failed to create avatars directory")?;
+        }
+        fs::write(&path, png).context("Failed to write avatar")?;
+
+        let mut profile = self.get_profile(user_id)?;
+        profile.avatar_updated_at = Some(Utc::now().to_rfc3339());
+        self.save_profile(&profile)
+    }
+
+    pub fn clear_avatar(&self, user_id: &str) -> Result<()> {
+        let mut profile = self.get_profile(user_id)?;
+        if profile.avatar_updated_at.take().is_some() {
+            self.save_profile(&profile)?;
+        }
+        self.remove_avatar_file(user_id);
+        Ok(())
+    }
+
+    fn remove_avatar_file(&self, user_id: &str) {
+        let path = self.avatar_path(user_id);
+        if path.exists() {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    /// The member's avatar as PNG bytes. Access is decided by the caller from
+    /// the profile visibility.
+    pub fn read_avatar(&self, user_id: &str) -> Result<Option<Vec<u8>>> {
+        if self.get_profile(user_id)?.avatar_updated_at.is_none() {
+            return Ok(None);
+        }
+        match fs::read(self.avatar_path(user_id)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context("Failed to read avatar"),
+        }
+    }
+
+    // --- Audit log methods ---
+
+    /// Append an event to the audit log. Failures are logged rather than
+    /// returned so auditing never blocks the action being audited.
+    pub fn audit(&self, actor_id: Option<&str>, action: &str, target: Option<&str>, ip: Option<&str>, details: Option<&str>) {
+        let actor_name = actor_id
+            .and_then(|id| self.get_user_by_id(id).ok().flatten())
+            .map(|u| u.username);
+        let result = self.db.execute(
+            "INSERT INTO audit_log (event_id, created_at, actor_id, actor_name, action, target, ip, details)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            rusqlite::params![
+                Uuid::new_v4().to_string(),
+                Utc::now().to_rfc3339(),
+                actor_id,
+                actor_name,
+                action,
+                target,
+                ip,
+                details,
+            ],
+        );
+        if let Err(e) = result {
+            log::error!("Failed to record audit event {}: {}", action, e);
+        }
+    }
+
+    /// Newest-first page of audit events matching the filter, and the total
+    /// number of matches.
+    pub fn list_audit_events(&self, filter: &AuditFilter, limit: u32, offset: u32) -> Result<(Vec<AuditEvent>, u32)> {
+        let mut clauses = Vec::new();
+        let mut params: Vec<String> = Vec::new();
+        let blank = |v: &Option<String>| v.as_deref().map(str::trim).filter(|v| !v.is_empty()).map(str::to_string);
+
+        if let Some(actor) = blank(&filter.actor) {
+            params.push(actor);
+            clauses.push(format!("(actor_id = ?{0} OR actor_name = ?{0})", params.len()));
+        }
+        if let Some(action) = blank(&filter.action) {
+            if action.ends_with('.') {
+                params.push(format!("{}%", escape_like(&action)));
+                clauses.push(format!("action LIKE ?{} ESCAPE '\\'", params.len()));
+            } else {
+                params.push(action);
+                clauses.push(format!("action = ?{}", params.len()));
+            }
+        }
+        if let Some(target) = blank(&filter.target) {
+            params.push(target);
+            clauses.push(format!("target = ?{}", params.len()));
+        }
+        if let Some(since) = blank(&filter.since) {
+            params.push(utc_timestamp(&since)?);
+            clauses.push(format!("created_at >= ?{}", params.len()));
+        }
+        if let Some(until) = blank(&filter.until) {
+            params.push(utc_timestamp(&until)?);
+            clauses.push(format!("created_at <= ?{}", params.len()));
+        }
+        let where_clause = if clauses.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", clauses.join(" AND "))
+        };
+
+        let total: u32 = self.db.query_row(
+            &format!("SELECT COUNT(*) FROM audit_log {}", where_clause),
+            rusqlite::params_from_iter(&params),
+            |row| row.get(0),
+        )?;
+
+        let sql = format!(
+            "SELECT event_id, created_at, actor_id, actor_name, action, target, ip, details
+             FROM audit_log {} ORDER BY created_at DESC LIMIT {} OFFSET {}",
+            where_clause, limit, offset,
+        );
+        let mut stmt = self.db.prepare(&sql).context("Failed to prepare query")?;
+        let events = stmt.query_map(rusqlite::params_from_iter(&params), |row| {
+            Ok(AuditEvent {
+                event_id: row.get(0)?,
+                created_at: row.get(1)?,
+                actor_id: row.get(2)?,
+                actor_name: row.get(3)?,
+                action: row.get(4)?,
+                target: row.get(5)?,
+                ip: row.get(6)?,
+                details: row.get(7)?,
+            })
+        })?.collect::<Result<Vec<_>, _>>()?;
+
+        Ok((events, total))
+    }
+
+    // --- Data export methods ---
+
+    /// Queue an export for the user. If one is already being built, that
+    /// job is returned instead of starting another.
+    pub fn create_export_job(&self, user_id: &str) -> Result<(ExportJob, bool)> {
+        self.purge_expired_exports()?;
+        if let Some(job) = self.list_export_jobs(user_id)?.into_iter().find(|j| j.status == "pending") {
+            return Ok((job, false));
+        }
+
+        let job = ExportJob {
+            job_id: Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            status: "pending".to_string(),
+            size_bytes: None,
+            error: None,
+            created_at: Utc::now().to_rfc3339(),
+            completed_at: None,
+            expires_at: None,
+        };
+        self.db.execute(
+            "INSERT INTO export_jobs (job_id, user_id, status, created_at) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![job.job_id, job.user_id, job.status, job.created_at],
+        ).context("Failed to create export job")?;
+        Ok((job, true))
+    }
+
+    pub fn list_export_jobs(&self, user_id: &str) -> Result<Vec<ExportJob>> {
+        let mut stmt = self.db.prepare(
+            "SELECT job_id, user_id, status, size_bytes, error, created_at, completed_at, expires_at
+             FROM export_jobs WHERE user_id = ?1 ORDER BY created_at DESC"
+        )?;
+        let jobs = stmt.query_map([user_id], export_job_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(jobs)
+    }
+
+    pub fn get_export_job(&self, user_id: &str, job_id: &str) -> Result<Option<ExportJob>> {
+        let job = self.db.query_row(
+            "SELECT job_id, user_id, status, size_bytes, error, created_at, completed_at, expires_at
+             FROM export_jobs WHERE job_id = ?1 AND user_id = ?2",
+            [job_id, user_id],
+            export_job_from_row,
+        );
+        match job {
+            Ok(j) => Ok(Some(j)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn finish_export_job(&self, job_id: &str, size_bytes: u64, expires_at: &str) -> Result<()> {
+        self.db.execute(
+            "UPDATE export_jobs SET status = 'ready', size_bytes = ?1, completed_at = ?2, expires_at = ?3
+             WHERE job_id = ?4",
+            rusqlite::params![size_bytes, Utc::now().to_rfc3339(), expires_at, job_id],
+        ).context("Failed to update export job")?;
+        Ok(())
+    }
+
+    pub fn fail_export_job(&self, job_id: &str, error: &str) -> Result<()> {
+        self.db.execute(
+            "UPDATE export_jobs SET status = 'failed', error = ?1, completed_at = ?2 WHERE job_id = ?3",
+            rusqlite::params![error, Utc::now().to_rfc3339(), job_id],
+        ).context("Failed to update export job")?;
+        Ok(())
+    }
+
+    /// Where a job's archive lives. Exports are kept outside `storage/` so
+    /// they never show up as member files.
+    pub fn export_archive_path(&self, job_id: &str) -> PathBuf {
+        self.install_path.join("exports").join(format!("{}.zip", job_id))
+    }
+
+    /// Delete expired archives, and fail jobs left pending by a restart.
+    pub fn purge_expired_exports(&self) -> Result<()> {
+        let now = Utc::now();
+        let expired: Vec<String> = self.db.prepare(
+            "SELECT job_id FROM export_jobs WHERE expires_at IS NOT NULL AND expires_at <= ?1"
+        )?.query_map([now.to_rfc3339()], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        for job_id in &expired {
+            let _ = fs::remove_file(self.export_archive_path(job_id));
+            self.db.execute("DELETE FROM export_jobs WHERE job_id = ?1", [job_id])?;
+        }
+
+        let stale = (now - chrono::Duration::hours(1)).to_rfc3339();
+        self.db.execute(
+            "UPDATE export_jobs SET status = 'failed', error = 'Interrupted', completed_at = ?1
+             WHERE status = 'pending' AND created_at < ?2",
+            rusqlite::params![now.to_rfc3339(), stale],
+        )?;
+        Ok(())
+    }
+
+    // --- Email methods ---
+
+    pub fn queue_email(&self, to_address: &str, subject: &str, body: &str, kind: &str) -> Result<String> {
+        let email_id = Uuid::new_v4().to_string();
+        let now = Utc::now().to_rfc3339();
+        self.db.execute(
+            "INSERT INTO email_outbox (email_id, to_address, subject, body, kind, next_attempt_at, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6)",
+            rusqlite::params![email_id, to_address, subject, body, kind, now],
+        ).context("Failed to queue email")?;
+        Ok(email_id)
+    }
+
+    /// Whether an email of this kind is still waiting to go to the address
+    pub fn has_pending_email(&self, to_address: &str, kind: &str) -> Result<bool> {
+        let pending = self.db.query_row(
+            "SELECT EXISTS(SELECT 1 FROM email_outbox WHERE to_address = ?1 AND kind = ?2 AND status = 'pending')",
+            [to_address, kind],
+            |row| row.get(0),
+        )?;
+        Ok(pending)
+    }
+
+    /// Pending emails whose next attempt is due, oldest first
+    pub fn due_emails(&self, limit: u32) -> Result<Vec<OutgoingEmail>> {
+        let mut stmt = self.db.prepare(
+            "SELECT email_id, to_address, subject, body, kind, status, attempts, last_error,
+                    next_attempt_at, created_at, sent_at
+             FROM email_outbox WHERE status = 'pending' AND next_attempt_at <= ?1
+             ORDER BY next_attempt_at ASC LIMIT ?2"
+        )?;
+        let emails = stmt.query_map(rusqlite::params![Utc::now().to_rfc3339(), limit], outgoing_email_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(emails)
+    }
+
+    /// Most recent outbox entries, for admins checking delivery
+    pub fn list_outbox(&self, limit: u32) -> Result<Vec<OutgoingEmail>> {
+        let mut stmt = self.db.prepare(
+            "SELECT email_id, to_address, subject, body, kind, status, attempts, last_error,
+                    next_attempt_at, created_at, sent_at
+             FROM email_outbox ORDER BY created_at DESC LIMIT ?1"
+        )?;
+        let emails = stmt.query_map([limit], outgoing_email_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(emails)
+    }
+
+    pub fn mark_email_sent(&self, email_id: &str) -> Result<()> {
+        self.db.execute(
+            "UPDATE email_outbox SET status = 'sent', attempts = attempts + 1, last_error = NULL, sent_at = ?1
+             WHERE email_id = ?2",
+            [&Utc::now().to_rfc3339(), email_id],
+        ).context("Failed to update email")?;
+        Ok(())
+    }
+
+    /// Record a failed attempt. The email is retried at `retry_at`, or
+    /// marked failed for good when that's None.
+    pub fn mark_email_failed(&self, email_id: &str, error: &str, retry_at: Option<&str>) -> Result<()> {
+        self.db.execute(
+            "UPDATE email_outbox SET attempts = attempts + 1, last_error = ?1,
+                 status = CASE WHEN ?2 IS NULL THEN 'failed' ELSE 'pending' END,
+                 next_attempt_at = COALESCE(?2, next_attempt_at)
+             WHERE email_id = ?3",
+            rusqlite::params![error, retry_at, email_id],
+        ).context("Failed to update email")?;
+        Ok(())
+    }
+
+    /// Drop sent and failed emails created before `before`
+    pub fn purge_outbox(&self, before: &str) -> Result<()> {
+        self.db.execute(
+            "DELETE FROM email_outbox WHERE status != 'pending' AND created_at < ?1",
+            [before],
+        ).context("Failed to purge email outbox")?;
+        Ok(())
+    }
+
+    /// Store a single-use token for a verification link or reset code,
+    /// replacing any earlier one the user had for the same purpose
+    pub fn create_email_token(&self, token_hash: &str, user_id: &str, purpose: &str, email: &str, expires_at: &str) -> Result<()> {
+        self.db.execute(
+            "DELETE FROM email_tokens WHERE user_id = ?1 AND purpose = ?2",
+            [user_id, purpose],
+        )?;
+        self.db.execute(
+            "INSERT INTO email_tokens (token_hash, user_id, purpose, email, expires_at, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            rusqlite::params![token_hash, user_id, purpose, email, expires_at, Utc::now().to_rfc3339()],
+        ).context("Failed to create email token")?;
+        Ok(())
+    }
+
+    /// Use up a token. Returns the user and the address it was sent to, or
+    /// None if it doesn't exist, is for another purpose or has expired.
+    pub fn consume_email_token(&self, token_hash: &str, purpose: &str) -> Result<Option<(String, String)>> {
+        let found: Option<(String, String, String)> = self.db.query_row(
+            "SELECT user_id, email, expires_at FROM email_tokens WHERE token_hash = ?1 AND purpose = ?2",
+            [token_hash, purpose],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        ).optional()?;
+        let Some((user_id, email, expires_at)) = found else {
+            return Ok(None);
+        };
+        self.db.execute("DELETE FROM email_tokens WHERE token_hash = ?1", [token_hash])?;
+        if has_passed(Some(&expires_at)) {
+            return Ok(None);
+        }
+        Ok(Some((user_id, email)))
+    }
+
+    /// Mark the user's email verified, provided it's still the address the
+    /// token was sent to. Returns whether it was.
+    pub fn mark_email_verified(&self, user_id: &str, email: &str) -> Result<bool> {
+        let updated = self.db.execute(
+            "UPDATE users SET email_verified_at = ?1 WHERE user_id = ?2 AND email = ?3",
+            [&Utc::now().to_rfc3339(), user_id, email],
+        ).context("Failed to verify email")?;
+        Ok(updated > 0)
+    }
+
+    /// Active accounts registered with an address (it isn't unique)
+    pub fn find_users_by_email(&self, email: &str) -> Result<Vec<User>> {
+        let mut stmt = self.db.prepare(
+            "SELECT user_id, username, email, is_admin, role, created_at, updated_at,
+                    suspended_at, suspension_reason, suspended_until, expires_at, email_verified_at
+             FROM users WHERE email = ?1 COLLATE NOCASE AND anonymized_at IS NULL"
+        )?;
+        let users = stmt.query_map([email], user_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(users)
+    }
+
+    pub fn get_email_notifications(&self, user_id: &str) -> Result<bool> {
+        let enabled: i32 = self.db.query_row(
+            "SELECT email_notifications FROM users WHERE user_id = ?1",
+            [user_id],
+            |row| row.get(0),
+        )?;
+        Ok(enabled != 0)
+    }
+
+    pub fn set_email_notifications(&self, user_id: &str, enabled: bool) -> Result<()> {
+        self.db.execute(
+            "UPDATE users SET email_notifications = ?1 WHERE user_id = ?2",
+            rusqlite::params![enabled as i32, user_id],
+        ).context("Failed to update notification settings")?;
+        Ok(())
+    }
+
+    /// Members of a conversation, other than the sender, who want message
+    /// notifications and have a verified address
+    pub fn notification_recipients(&self, conversation_id: &str, sender_id: &str) -> Result<Vec<User>> {
+        let mut stmt = self.db.prepare(
+            "SELECT u.user_id, u.username, u.email, u.is_admin, u.role, u.created_at, u.updated_at,
+                    u.suspended_at, u.suspension_reason, u.suspended_until, u.expires_at, u.email_verified_at
+             FROM conversation_members cm
+             JOIN users u ON u.user_id = cm.user_id
+             WHERE cm.conversation_id = ?1 AND u.user_id != ?2 AND u.email_notifications = 1
+               AND u.email_verified_at IS NOT NULL AND u.anonymized_at IS NULL"
+        )?;
+        let users = stmt.query_map([conversation_id, sender_id], user_from_row)?
+            .collect::<Result<Vec<User>, _>>()?
+            .into_iter()
+            .filter(|u| u.suspension.is_none() && !u.is_expired())
+            .collect();
+        Ok(users)
+    }
+
+    // --- Login throttling methods ---
+
+    pub fn get_login_throttle(&self, user_id: &str) -> Result<LoginThrottle> {
+        let throttle = self.db.query_row(
+            "SELECT failed_logins, last_failed_login_at, locked_until FROM users WHERE user_id = ?1",
+            [user_id],
+            |row| Ok(LoginThrottle {
+                failed_logins: row.get(0)?,
+                last_failed_at: row.get(1)?,
+                locked_until: row.get(2)?,
+            }),
+        ).ok();
+        Ok(throttle.unwrap_or_default())
+    }
+
+    /// Count a failed password attempt and return the new total
+    pub fn record_failed_login(&self, user_id: &str) -> Result<u32> {
+        self.db.execute(
+            "UPDATE users SET failed_logins = failed_logins + 1, last_failed_login_at = ?1 WHERE user_id = ?2",
+            rusqlite::params![Utc::now().to_rfc3339(), user_id],
+        ).context("Failed to record failed login")?;
+        let failed = self.db.query_row(
+            "SELECT failed_logins FROM users WHERE user_id = ?1",
+            [user_id],
+            |row| row.get(0),
+        )?;
+        Ok(failed)
+    }
+
+    pub fn lock_account(&self, user_id: &str, until: &str) -> Result<()> {
+        self.db.execute(
+            "UPDATE users SET locked_until = ?1 WHERE user_id = ?2",
+            rusqlite::params![until, user_id],
+        ).context("Failed to lock account")?;
+        Ok(())
+    }
+
+    /// Reset the failed-attempt counter and lift any lockout
+    pub fn clear_failed_logins(&self, user_id: &str) -> Result<()> {
+        self.db.execute(
+            "UPDATE users SET failed_logins = 0, last_failed_login_at = NULL, locked_until = NULL WHERE user_id = ?1",
+            [user_id],
+        ).context("Failed to clear failed logins")?;
+        Ok(())
+    }
+
+    pub fn list_locked_accounts(&self) -> Result<Vec<LockedAccount>> {
+        let mut stmt = self.db.prepare(
+            "SELECT user_id, username, failed_logins, locked_until
+             FROM users WHERE locked_until > ?1 ORDER BY locked_until DESC"
+        ).context("Failed to prepare query")?;
+
+        let accounts = stmt.query_map([Utc::now().to_rfc3339()], |row| {
+            Ok(LockedAccount {
+                user_id: row.get(0)?,
+                username: row.get(1)?,
+                failed_logins: row.get(2)?,
+                locked_until: row.get(3)?,
+            })
+        }).context("Failed to query locked accounts")?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(accounts)
+    }
+
+    // --- Hub settings ---
+
+    pub fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        let value = self.db.query_row(
+            "SELECT value FROM hub_settings WHERE key = ?1",
+            [key],
+            |row| row.get(0),
+        );
+        match value {
+            Ok(v) => Ok(Some(v)),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn set_setting(&self, key: &str, value: &str) -> Result<()> {
+        self.db.execute(
+            "INSERT INTO hub_settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+            rusqlite::params![key, value],
+        ).with_context(|| format!("Failed to save setting {}", key))?;
+        Ok(())
+    }
+
+    pub fn delete_setting(&self, key: &str) -> Result<()> {
+        self.db.execute("DELETE FROM hub_settings WHERE key = ?1", [key])
+            .with_context(|| format!("Failed to delete setting {}", key))?;
+        Ok(())
+    }
+
+    /// Addresses or CIDR ranges of reverse proxies allowed to set forwarding headers
+    pub fn get_trusted_proxies(&self) -> Result<Vec<String>> {
+        Ok(match self.get_setting("trusted_proxies")? {
+            Some(value) => value.lines().map(|l| l.to_string()).collect(),
+            None => DEFAULT_TRUSTED_PROXIES.iter().map(|p| p.to_string()).collect(),
+        })
+    }
+
+    pub fn set_trusted_proxies(&self, proxies: &[String]) -> Result<()> {
+        self.set_setting("trusted_proxies", &proxies.join("\n"))
+    }
+
+    // --- API token methods ---
+
+    pub fn create_api_token(
+        &self,
+        user_id: &str,
+        name: &str,
+        token_hash: &str,
+        scopes: &[String],
+        expires_at: Option<&str>,
+    ) -> Result<ApiToken> {
+        let token_id = Uuid::new_v4().to_string();
+        let now = Utc::now().to_rfc3339();
+
+        self.db.execute(
+            "INSERT INTO api_tokens (token_id, user_id, name, token_hash, scopes, expires_at, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![token_id, user_id, name, token_hash, scopes.join(" "), expires_at, now],
+        ).context("Failed to create API token")?;
+
+        Ok(ApiToken {
+            token_id,
+            user_id: user_id.to_string(),
+            name: name.to_string(),
+            scopes: scopes.to_vec(),
+            expires_at: expires_at.map(|s| s.to_string()),
+            last_used_at: None,
+            created_at: now,
+        })
+    }
+
+    pub fn list_api_tokens(&self, user_id: &str) -> Result<Vec<ApiToken>> {
+        let mut stmt = self.db.prepare(
+            "SELECT token_id, user_id, name, scopes, expires_at, last_used_at, created_at
+             FROM api_tokens WHERE user_id = ?1 ORDER BY created_at DESC"
+        ).context("Failed to prepare query")?;
+
+        let tokens = stmt.query_map([user_id], api_token_from_row)
+            .context("Failed to query API tokens")?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(tokens)
+    }
+
+    /// Look up an unexpired API token by hash and record that it was used.
+    pub fn use_api_token(&self, token_hash: &str) -> Result<Option<ApiToken>> {
+        let now = Utc::now().to_rfc3339();
+        let token = self.db.prepare(
+            "SELECT token_id, user_id, name, scopes, expires_at, last_used_at, created_at
+             FROM api_tokens
+             WHERE token_hash = ?1 AND (expires_at IS NULL OR expires_at > ?2)"
+        )?.query_row(rusqlite::params![token_hash, now], api_token_from_row).optional()?;
+
+        if let Some(token) = &token {
+            self.db.execute(
+                "UPDATE api_tokens SET last_used_at = ?1 WHERE token_id = ?2",
+                rusqlite::params![now, token.token_id],
+            ).context("Failed to update API token usage")?;
+        }
+
+        Ok(token)
+    }
+
+    /// Revoke one of a user's API tokens. Returns false if no such token exists.
+    pub fn delete_api_token(&self, user_id: &str, token_id: &str) -> Result<bool> {
+        let deleted = self.db.execute(
+            "DELETE FROM api_tokens WHERE token_id = ?1 AND user_id = ?2",
+            rusqlite::params![token_id, user_id],
+        ).context("Failed to delete API token")?;
+        Ok(deleted > 0)
+    }
+
+    // --- Passkey methods ---
+
+    pub fn create_passkey(
+        &self,
+        user_id: &str,
+        name: &str,
+        credential_id: &str,
+        public_key: &[u8],
+        algorithm: i64,
+        sign_count: u32,
+    ) -> Result<Passkey> {
+        let now = Utc::now().to_rfc3339();
+        self.db.execute(
+            "INSERT INTO passkeys (credential_id, user_id, name, public_key, algorithm, sign_count, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![credential_id, user_id, name, public_key, algorithm, sign_count, now],
+        ).context("Failed to save passkey")?;
+
+        Ok(Passkey {
+            credential_id: credential_id.to_string(),
+            user_id: user_id.to_string(),
+            name: name.to_string(),
+            algorithm,
+            created_at: now,
+            last_used_at: None,
+            public_key: public_key.to_vec(),
+            sign_count,
+        })
+    }
+
+    pub fn list_passkeys(&self, user_id: &str) -> Result<Vec<Passkey>> {
+        let mut stmt = self.db.prepare(
+            "SELECT credential_id, user_id, name, algorithm, created_at, last_used_at, public_key, sign_count
+             FROM passkeys WHERE user_id = ?1 ORDER BY created_at DESC"
+        ).context("Failed to prepare query")?;
+
+        let passkeys = stmt.query_map([user_id], passkey_from_row)
+            .context("Failed to query passkeys")?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(passkeys)
+    }
+
+    pub fn get_passkey(&self, credential_id: &str) -> Result<Option<Passkey>> {
+        let mut stmt = self.db.prepare(
+            "SELECT credential_id, user_id, name, algorithm, created_at, last_used_at, public_key, sign_count
+             FROM passkeys WHERE credential_id = ?1"
+        ).context("Failed to prepare query")?;
+
+        let mut rows = stmt.query_map([credential_id], passkey_from_row)
+            .context("Failed to query passkey")?;
+
+        match rows.next() {
+            Some(Ok(passkey)) => Ok(Some(passkey)),
+            Some(Err(e)) => Err(e.into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Record a successful sign-in with a passkey
+    pub fn update_passkey_usage(&self, credential_id: &str, sign_count: u32) -> Result<()> {
+        self.db.execute(
+            "UPDATE passkeys SET sign_count = ?1, last_used_at = ?2 WHERE credential_id = ?3",
+            rusqlite::params![sign_count, Utc::now().to_rfc3339(), credential_id],
+        ).context("Failed to update passkey")?;
+        Ok(())
+    }
+
+    /// Remove one of a user's passkeys. Returns false if no such passkey exists.
+    pub fn delete_passkey(&self, user_id: &str, credential_id: &str) -> Result<bool> {
+        let deleted = self.db.execute(
+            "DELETE FROM passkeys WHERE credential_id = ?1 AND user_id = ?2",
+            rusqlite::params![credential_id, user_id],
+        ).context("Failed to delete passkey")?;
+        Ok(deleted > 0)
+    }
+
+    /// Whether the user must confirm a password login with a passkey
+    pub fn is_passkey_required(&self, user_id: &str) -> Result<bool> {
+        let required: Option<bool> = self.db.query_row(
+            "SELECT passkey_required FROM users WHERE user_id = ?1",
+            [user_id],
+            |row| row.get(0),
+        ).optional()?;
+        Ok(required.unwrap_or(false))
+    }
+
+    pub fn set_passkey_required(&self, user_id: &str, required: bool) -> Result<()> {
+        self.db.execute(
+            "UPDATE users SET passkey_required = ?1, updated_at = ?2 WHERE user_id = ?3",
+            rusqlite::params![required, Utc::now().to_rfc3339(), user_id],
+        ).context("Failed to update passkey requirement")?;
+        Ok(())
+    }
+
+    // --- OIDC provider methods ---
+
+    pub fn create_oidc_client(
+        &self,
+        name: &str,
+        redirect_uris: &[String],
+        client_secret_hash: Option<&str>,
+    ) -> Result<OidcClient> {
+        let client_id = Uuid::new_v4().to_string();
+        let now = Utc::now().to_rfc3339();
+
+        self.db.execute(
+            "INSERT INTO oidc_clients (client_id, name, client_secret_hash, redirect_uris, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            rusqlite::params![client_id, name, client_secret_hash, redirect_uris.join("\n"), now],
+        ).context("Failed to create OIDC client")?;
+
+        Ok(OidcClient {
+            client_id,
+            name: name.to_string(),
+            redirect_uris: redirect_uris.to_vec(),
+            is_confidential: client_secret_hash.is_some(),
+            created_at: now,
+            client_secret_hash: client_secret_hash.map(|s| s.to_string()),
+        })
+    }
+
+    pub fn list_oidc_clients(&self) -> Result<Vec<OidcClient>> {
+        let mut stmt = self.db.prepare(
+            "SELECT client_id, name, client_secret_hash, redirect_uris, created_at
+             FROM oidc_clients ORDER BY created_at DESC"
+        ).context("Failed to prepare query")?;
+
+        let clients = stmt.query_map([], oidc_client_from_row)
+            .context("Failed to query OIDC clients")?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(clients)
+    }
+
+    pub fn get_oidc_client(&self, client_id: &str) -> Result<Option<OidcClient>> {
+        let mut stmt = self.db.prepare(
+            "SELECT client_id, name, client_secret_hash, redirect_uris, created_at
+             FROM oidc_clients WHERE client_id = ?1"
+        ).context("Failed to prepare query")?;
+
+        let mut rows = stmt.query_map([client_id], oidc_client_from_row)
+            .context("Failed to query OIDC client")?;
+
+        match rows.next() {
+            Some(Ok(client)) => Ok(Some(client)),
+            Some(Err(e)) => Err(e.into()),
+            None => Ok(None),
+        }
+    }
+
+    /// Delete a client registration; its outstanding authorization codes cascade.
+    pub fn delete_oidc_client(&self, client_id: &str) -> Result<bool> {
+        let deleted = self.db.execute(
+            "DELETE FROM oidc_clients WHERE client_id = ?1",
+            [client_id],
+        ).context("Failed to delete OIDC client")?;
+        Ok(deleted > 0)
+    }
+
+    pub fn create_oidc_auth_code(&self, code_hash: &str, code: &OidcAuthCode, expires_at: &str) -> Result<()> {
+        self.db.execute(
+            "INSERT INTO oidc_auth_codes
+                (code_hash, client_id, user_id, redirect_uri, scope, nonce, code_challenge, auth_time, expires_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            rusqlite::params![
+                code_hash, code.client_id, code.user_id, code.redirect_uri, code.scope,
+                code.nonce, code.code_challenge, code.auth_time, expires_at
+            ],
+        ).context("Failed to create authorization code")?;
+        Ok(())
+    }
+
+    /// Redeem an authorization code. Codes are single-use: the row is deleted
+    /// whether or not it is still valid, and expired codes are never returned.
+    pub fn take_oidc_auth_code(&self, code_hash: &str) -> Result<Option<OidcAuthCode>> {
+        let now = Utc::now().to_rfc3339();
+        let code = self.db.prepare(
+            "SELECT client_id, user_id, redirect_uri, scope, nonce, code_challenge, auth_time
+             FROM oidc_auth_codes WHERE code_hash = ?1 AND expires_at > ?2"
+        )?.query_row(rusqlite::params![code_hash, now], |row| {
+            Ok(OidcAuthCode {
+                client_id: row.get(0)?,
+                user_id: row.get(1)?,
+                redirect_uri: row.get(2)?,
+                scope: row.get(3)?,
+                nonce: row.get(4)?,
+                code_challenge: row.get(5)?,
+                auth_time: row.get(6)?,
+            })
+        }).optional()?;
+
+        self.db.execute(
+            "DELETE FROM oidc_auth_codes WHERE code_hash = ?1 OR expires_at <= ?2",
+            rusqlite::params![code_hash, now],
+        ).context("Failed to consume authorization code")?;
+
+        Ok(code)
+    }
+
+    pub fn list_all_files(&self) -> Result<Vec<File>> {
+        // Admin-only method to list ALL files regardless of ownership
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {} FROM files f ORDER BY f.created_at DESC",
+            FILE_COLUMNS
+        ))?;
+
+        let files = stmt.query_map([], file_from_row)?.collect::<Result<Vec<_>, _>>()?;
+
+        Ok(files)
+    }
+
+    pub fn create_space(
+        &self,
+        user_id: &str,
+        name: &str,
+        storage_quota_gb: f64,
+        is_public: bool,
+    ) -> Result<Space> {
+        let space_id = Uuid::new_v4().to_string();
+        let now = Utc::now().to_rfc3339();
+
+        self.db.execute(
+            "INSERT INTO spaces (space_id, user_id, name, storage_quota_gb, is_public, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![
+                space_id, user_id, name, storage_quota_gb, is_public as i32, now, now
+            ],
+        ).context("Failed to create space")?;
+
+        Ok(Space {
+            space_id,
+            user_id: user_id.to_string(),
+            name: name.to_string(),
+            storage_quota_gb,
+            is_public,
+            created_at: now.clone(),
+            updated_at: now,
+        })
+    }
+
+    pub fn list_user_spaces(&self, user_id: &str) -> Result<Vec<Space>> {
+        let mut stmt = self.db.prepare(
+            "SELECT space_id, user_id, name, storage_quota_gb, is_public, created_at, updated_at
+             FROM spaces WHERE user_id = ?1 ORDER BY created_at DESC"
+        ).context("Failed to prepare query")?;
+
+        let spaces = stmt.query_map([user_id], |row| {
+            Ok(Space {
+                space_id: row.get(0)?,
+                user_id: row.get(1)?,
                 name: row.get(2)?,
                 storage_quota_gb: row.get(3)?,
                 is_public: row.get::<_, i32>(4)? != 0,
@@ -937,54 +3025,55 @@ impl StorageManager {
 
     // --- Messaging methods ---
 
-    pub fn create_dm_conversation(&self, user_a_id: &str, user_b_id: &str) -> Result<Conversation> {
+    /// The DM between two users, created if there isn't one yet. The flag
+    /// is true if it was just created.
+    pub fn create_dm_conversation(&self, user_a_id: &str, user_b_id: &str) -> Result<(Conversation, bool)> {
+        if user_a_id == user_b_id {
+            anyhow::bail!("A DM needs two different users");
+        }
         // Check if a DM already exists between these two users
-        let existing = self.db.prepare(
-            "SELECT c.conversation_id, c.kind, c.name, c.created_by, c.created_at, c.updated_at
-             FROM conversations c
+        let existing = self.db.prepare(&format!(
+            "SELECT {} FROM conversations c
              JOIN conversation_members cm1 ON c.conversation_id = cm1.conversation_id
              JOIN conversation_members cm2 ON c.conversation_id = cm2.conversation_id
-             WHERE c.kind = 'dm' AND cm1.user_id = ?1 AND cm2.user_id = ?2"
-        )?.query_row(rusqlite::params![user_a_id, user_b_id], |row| {
-            Ok(Conversation {
-                conversation_id: row.get(0)?,
-                kind: row.get(1)?,
-                name: row.get(2)?,
-                created_by: row.get(3)?,
-                created_at: row.get(4)?,
-                updated_at: row.get(5)?,
-            })
-        }).ok();
+             WHERE c.kind = 'dm' AND cm1.user_id = ?1 AND cm2.user_id = ?2 AND cm1.user_id <> cm2.user_id",
+            CONVERSATION_COLUMNS
+        ))?.query_row(rusqlite::params![user_a_id, user_b_id], conversation_from_row).optional()?;
 
         if let Some(conv) = existing {
-            return Ok(conv);
+            return Ok((conv, false));
         }
 
         let conversation_id = Uuid::new_v4().to_string();
         let now = Utc::now().to_rfc3339();
 
-        self.db.execute(
-            "INSERT INTO conversations (conversation_id, kind, name, created_by, created_at, updated_at)
-             VALUES (?1, 'dm', NULL, ?2, ?3, ?4)",
-            rusqlite::params![conversation_id, user_a_id, now, now],
-        ).context("Failed to create conversation")?;
-
-        for uid in &[user_a_id, user_b_id] {
+        self.in_transaction(|| {
             self.db.execute(
-                "INSERT INTO conversation_members (conversation_id, user_id, joined_at)
-                 VALUES (?1, ?2, ?3)",
-                rusqlite::params![conversation_id, uid, now],
-            ).context("Failed to add conversation member")?;
-        }
+                "INSERT INTO conversations (conversation_id, kind, name, created_by, created_at, updated_at)
+                 VALUES (?1, 'dm', NULL, ?2, ?3, ?4)",
+                rusqlite::params![conversation_id, user_a_id, now, now],
+            ).context("Failed to create conversation")?;
 
-        Ok(Conversation {
+            for uid in &[user_a_id, user_b_id] {
+                self.db.execute(
+                    "INSERT INTO conversation_members (conversation_id, user_id, joined_at)
+                     VALUES (?1, ?2, ?3)",
+                    rusqlite::params![conversation_id, uid, now],
+                ).context("Failed to add conversation member")?;
+            }
+            Ok(())
+        })?;
+
+        Ok((Conversation {
             conversation_id,
             kind: "dm".to_string(),
             name: None,
             created_by: user_a_id.to_string(),
             created_at: now.clone(),
             updated_at: now,
-        })
+            announcement_only: false,
+            is_default: false,
+        }, true))
     }
 
     pub fn create_group_conversation(
@@ -993,6 +3082,7 @@ impl StorageManager {
         name: &str,
         member_ids: &[String],
     ) -> Result<Conversation> {
+        validate_conversation_name(name)?;
         let conversation_id = Uuid::new_v4().to_string();
         let now = Utc::now().to_rfc3339();
 
@@ -1027,28 +3117,140 @@ impl StorageManager {
             created_by: creator_id.to_string(),
             created_at: now.clone(),
             updated_at: now,
+            announcement_only: false,
+            is_default: false,
+        })
+    }
+
+    /// A public channel, listed in the directory for anyone to join
+    pub fn create_channel(
+        &self,
+        creator_id: &str,
+        name: &str,
+        announcement_only: bool,
+        is_default: bool,
+    ) -> Result<Conversation> {
+        validate_conversation_name(name)?;
+        let conversation_id = Uuid::new_v4().to_string();
+        let now = Utc::now().to_rfc3339();
+
+        self.db.execute(
+            "INSERT INTO conversations
+                 (conversation_id, kind, name, created_by, created_at, updated_at, announcement_only, is_default)
+             VALUES (?1, 'channel', ?2, ?3, ?4, ?4, ?5, ?6)",
+            rusqlite::params![conversation_id, name, creator_id, now, announcement_only as i32, is_default as i32],
+        ).context("Failed to create channel")?;
+        self.add_group_member(&conversation_id, creator_id)?;
+
+        Ok(Conversation {
+            conversation_id,
+            kind: "channel".to_string(),
+            name: Some(name.to_string()),
+            created_by: creator_id.to_string(),
+            created_at: now.clone(),
+            updated_at: now,
+            announcement_only,
+            is_default,
         })
     }
 
-    pub fn add_group_member(&self, conversation_id: &str, user_id: &str) -> Result<()> {
+    /// Every channel, by name, with whether the user has joined it
+    pub fn list_channels(&self, user_id: &str) -> Result<Vec<ChannelListing>> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {},
+                 (SELECT COUNT(*) FROM conversation_members cm WHERE cm.conversation_id = c.conversation_id),
+                 EXISTS (SELECT 1 FROM conversation_members cm
+                         WHERE cm.conversation_id = c.conversation_id AND cm.user_id = ?1)
+             FROM conversations c
+             WHERE c.kind = 'channel'
+             ORDER BY c.name COLLATE NOCASE ASC",
+            CONVERSATION_COLUMNS
+        ))?;
+        let channels = stmt.query_map([user_id], |row| {
+            Ok(ChannelListing {
+                conversation: conversation_from_row(row)?,
+                member_count: row.get(8)?,
+                joined: row.get(9)?,
+            })
+        })?.collect::<Result<Vec<_>, _>>()?;
+        Ok(channels)
+    }
+
+    /// Change a channel's settings. Making it a default channel only
+    /// affects members who join the hub later; existing members aren't added.
+    pub fn update_channel(
+        &self,
+        conversation_id: &str,
+        announcement_only: Option<bool>,
+        is_default: Option<bool>,
+    ) -> Result<()> {
+        let now = Utc::now().to_rfc3339();
+        self.db.execute(
+            "UPDATE conversations SET
+                 announcement_only = COALESCE(?1, announcement_only),
+                 is_default = COALESCE(?2, is_default),
+                 updated_at = ?3
+             WHERE conversation_id = ?4 AND kind = 'channel'",
+            rusqlite::params![announcement_only.map(|b| b as i32), is_default.map(|b| b as i32), now, conversation_id],
+        ).context("Failed to update channel")?;
+        Ok(())
+    }
+
+    /// Add a new member to every default channel
+    pub fn join_default_channels(&self, user_id: &str) -> Result<()> {
         let now = Utc::now().to_rfc3339();
         self.db.execute(
+            "INSERT OR IGNORE INTO conversation_members (conversation_id, user_id, joined_at)
+             SELECT conversation_id, ?1, ?2 FROM conversations WHERE kind = 'channel' AND is_default = 1",
+            [user_id, &now],
+        ).context("Failed to join default channels")?;
+        Ok(())
+    }
+
+    /// Returns false if the user was already a member
+    pub fn add_group_member(&self, conversation_id: &str, user_id: &str) -> Result<bool> {
+        let now = Utc::now().to_rfc3339();
+        let added = self.db.execute(
             "INSERT OR IGNORE INTO conversation_members (conversation_id, user_id, joined_at)
              VALUES (?1, ?2, ?3)",
             rusqlite::params![conversation_id, user_id, now],
         ).context("Failed to add group member")?;
-        Ok(())
+        Ok(added > 0)
     }
 
-    pub fn remove_group_member(&self, conversation_id: &str, user_id: &str) -> Result<()> {
-        self.db.execute(
+    /// Returns false if the user wasn't a member
+    pub fn remove_group_member(&self, conversation_id: &str, user_id: &str) -> Result<bool> {
+        let removed = self.db.execute(
             "DELETE FROM conversation_members WHERE conversation_id = ?1 AND user_id = ?2",
             rusqlite::params![conversation_id, user_id],
         ).context("Failed to remove group member")?;
-        Ok(())
+        Ok(removed > 0)
+    }
+
+    /// Everyone who shares at least one conversation with the user
+    pub fn conversation_peers(&self, user_id: &str) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare(
+            "SELECT DISTINCT cm2.user_id
+             FROM conversation_members cm1
+             JOIN conversation_members cm2 ON cm1.conversation_id = cm2.conversation_id
+             WHERE cm1.user_id = ?1 AND cm2.user_id != ?1"
+        )?;
+        let ids = stmt.query_map([user_id], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ids)
+    }
+
+    pub fn list_conversation_ids(&self, user_id: &str) -> Result<Vec<String>> {
+        let mut stmt = self.db.prepare(
+            "SELECT conversation_id FROM conversation_members WHERE user_id = ?1"
+        )?;
+        let ids = stmt.query_map([user_id], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(ids)
     }
 
     pub fn rename_conversation(&self, conversation_id: &str, name: &str) -> Result<()> {
+        validate_conversation_name(name)?;
         let now = Utc::now().to_rfc3339();
         self.db.execute(
             "UPDATE conversations SET name = ?1, updated_at = ?2 WHERE conversation_id = ?3",
@@ -1066,7 +3268,7 @@ impl StorageManager {
 
     pub fn get_conversation_members(&self, conversation_id: &str) -> Result<Vec<ConversationMember>> {
         let mut stmt = self.db.prepare(
-            "SELECT cm.user_id, u.username, cm.joined_at
+            "SELECT cm.user_id, u.username, cm.joined_at, cm.last_read_message_id, cm.last_read_at
              FROM conversation_members cm
              JOIN users u ON cm.user_id = u.user_id
              WHERE cm.conversation_id = ?1"
@@ -1077,104 +3279,182 @@ impl StorageManager {
                 user_id: row.get(0)?,
                 username: row.get(1)?,
                 joined_at: row.get(2)?,
+                last_read_message_id: row.get(3)?,
+                last_read_at: row.get(4)?,
             })
         })?.collect::<Result<Vec<_>, _>>()?;
 
         Ok(members)
     }
 
-    fn get_last_message(&self, conversation_id: &str) -> Result<Option<Message>> {
-        let mut stmt = self.db.prepare(
-            "SELECT m.message_id, m.conversation_id, m.sender_id, u.username, m.body, m.created_at
-             FROM messages m
-             JOIN users u ON m.sender_id = u.user_id
-             WHERE m.conversation_id = ?1
-             ORDER BY m.created_at DESC
-             LIMIT 1"
+    /// Move a member's read cursor up to `message_id`, or to the newest
+    /// message if none is given. The cursor never moves backwards; returns
+    /// the member with the new cursor, or None if it didn't move.
+    pub fn mark_conversation_read(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+        message_id: Option<&str>,
+    ) -> Result<Option<ConversationMember>> {
+        let target: Option<(String, String)> = match message_id {
+            Some(message_id) => self.db.query_row(
+                "SELECT message_id, created_at FROM messages WHERE message_id = ?1 AND conversation_id = ?2",
+                [message_id, conversation_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            ).optional()?,
+            None => self.db.query_row(
+                "SELECT message_id, created_at FROM messages WHERE conversation_id = ?1
+                 ORDER BY created_at DESC LIMIT 1",
+                [conversation_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            ).optional()?,
+        };
+        let Some((message_id, created_at)) = target else {
+            if message_id.is_some() {
+                anyhow::bail!("Message not found");
+            }
+            return Ok(None);
+        };
+
+        let moved = self.db.execute(
+            "UPDATE conversation_members SET last_read_message_id = ?1, last_read_at = ?2
+             WHERE conversation_id = ?3 AND user_id = ?4 AND (last_read_at IS NULL OR last_read_at < ?2)",
+            rusqlite::params![message_id, created_at, conversation_id, user_id],
         )?;
+        if moved == 0 {
+            return Ok(None);
+        }
 
-        let mut rows = stmt.query_map([conversation_id], |row| {
-            Ok((
-                row.get::<_, String>(0)?,
-                row.get::<_, String>(1)?,
-                row.get::<_, String>(2)?,
-                row.get::<_, String>(3)?,
-                row.get::<_, String>(4)?,
-                row.get::<_, String>(5)?,
-            ))
-        })?;
+        Ok(self.get_conversation_members(conversation_id)?
+            .into_iter()
+            .find(|m| m.user_id == user_id))
+    }
 
-        match rows.next() {
-            Some(Ok((message_id, conv_id, sender_id, username, body, created_at))) => {
-                let attachments = self.get_message_attachments(&message_id)?;
-                Ok(Some(Message {
-                    message_id,
-                    conversation_id: conv_id,
-                    sender_id,
-                    sender_username: username,
-                    body,
-                    attachments,
-                    created_at,
-                }))
-            }
-            Some(Err(e)) => Err(e.into()),
-            None => Ok(None),
-        }
+    /// Messages from others after the member's read cursor, or after they
+    /// joined if they haven't read anything yet
+    pub fn unread_count(&self, conversation_id: &str, user_id: &str) -> Result<u32> {
+        self.db.query_row(
+            "SELECT COUNT(*) FROM messages m
+             JOIN conversation_members cm ON cm.conversation_id = m.conversation_id AND cm.user_id = ?2
+             WHERE m.conversation_id = ?1 AND m.sender_id != ?2 AND m.deleted_at IS NULL
+               AND m.created_at > COALESCE(cm.last_read_at, cm.joined_at)",
+            [conversation_id, user_id],
+            |row| row.get(0),
+        ).context("Failed to count unread messages")
+    }
+
+    fn get_last_message(&self, conversation_id: &str) -> Result<Option<Message>> {
+        let message = self.db.query_row(
+            &format!(
+                "SELECT {} FROM messages m
+                 JOIN users u ON m.sender_id = u.user_id
+                 WHERE m.conversation_id = ?1
+                 ORDER BY m.created_at DESC
+                 LIMIT 1",
+                MESSAGE_COLUMNS
+            ),
+            [conversation_id],
+            message_from_row,
+        ).optional()?;
+        message.map(|m| self.with_details(m)).transpose()
     }
 
     pub fn list_conversations(&self, user_id: &str) -> Result<Vec<ConversationWithMembers>> {
-        let mut stmt = self.db.prepare(
-            "SELECT c.conversation_id, c.kind, c.name, c.created_by, c.created_at, c.updated_at
-             FROM conversations c
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {} FROM conversations c
              JOIN conversation_members cm ON c.conversation_id = cm.conversation_id
              WHERE cm.user_id = ?1
-             ORDER BY c.updated_at DESC"
-        )?;
+             ORDER BY c.updated_at DESC",
+            CONVERSATION_COLUMNS
+        ))?;
 
-        let convs: Vec<Conversation> = stmt.query_map([user_id], |row| {
-            Ok(Conversation {
-                conversation_id: row.get(0)?,
-                kind: row.get(1)?,
-                name: row.get(2)?,
-                created_by: row.get(3)?,
-                created_at: row.get(4)?,
-                updated_at: row.get(5)?,
-            })
-        })?.collect::<Result<Vec<_>, _>>()?;
+        let convs: Vec<Conversation> = stmt.query_map([user_id], conversation_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
 
-        let mut result = Vec::new();
-        for conv in convs {
-            let members = self.get_conversation_members(&conv.conversation_id)?;
-            let last_message = self.get_last_message(&conv.conversation_id)?;
-            result.push(ConversationWithMembers {
-                conversation: conv,
-                members,
-                last_message,
-            });
-        }
+        convs.into_iter().map(|conv| self.with_members(conv, user_id)).collect()
+    }
+
+    /// One of the user's conversations, as `list_conversations` shows it
+    pub fn get_member_conversation(&self, conversation_id: &str, user_id: &str) -> Result<Option<ConversationWithMembers>> {
+        let conv = self.db.query_row(
+            &format!(
+                "SELECT {} FROM conversations c
+                 JOIN conversation_members cm ON c.conversation_id = cm.conversation_id
+                 WHERE c.conversation_id = ?1 AND cm.user_id = ?2",
+                CONVERSATION_COLUMNS
+            ),
+            [conversation_id, user_id],
+            conversation_from_row,
+        ).optional()?;
+        conv.map(|conv| self.with_members(conv, user_id)).transpose()
+    }
 
-        Ok(result)
+    pub fn get_conversation(&self, conversation_id: &str) -> Result<Option<Conversation>> {
+        self.db.query_row(
+            &format!("SELECT {} FROM conversations c WHERE c.conversation_id = ?1", CONVERSATION_COLUMNS),
+            [conversation_id],
+            conversation_from_row,
+        ).optional().context("Failed to load conversation")
     }
 
+    fn with_members(&self, conv: Conversation, user_id: &str) -> Result<ConversationWithMembers> {
+        let members = self.get_conversation_members(&conv.conversation_id)?;
+        let last_message = self.get_last_message(&conv.conversation_id)?;
+        let unread_count = self.unread_count(&conv.conversation_id, user_id)?;
+        Ok(ConversationWithMembers {
+            conversation: conv,
+            members,
+            last_message,
+            unread_count,
+        })
+    }
+
+    /// Post a message, optionally as a reply in the thread of `parent_id`.
+    /// Threads are one level deep: replying to a reply joins its thread.
     pub fn create_message(
         &self,
         conversation_id: &str,
         sender_id: &str,
         body: &str,
         attachment_ids: &[String],
+        parent_id: Option<&str>,
+        client_id: Option<&str>,
     ) -> Result<Message> {
         let is_member = self.is_conversation_member(conversation_id, sender_id)?;
         if !is_member {
             anyhow::bail!("User is not a member of this conversation");
         }
 
+        // Threads are one level deep: a reply to a reply joins the thread of
+        // its root, which must itself still be there
+        let parent_id = match parent_id {
+            Some(parent_id) => {
+                let find = |id: &str| -> Result<Message> {
+                    let message = self.get_message(id)?
+                        .filter(|p| p.conversation_id == conversation_id)
+                        .ok_or_else(|| anyhow::anyhow!("Parent message not found"))?;
+                    if message.deleted_at.is_some() {
+                        anyhow::bail!("Can't reply to a deleted message");
+                    }
+                    Ok(message)
+                };
+                let parent = find(parent_id)?;
+                let root = match &parent.parent_id {
+                    Some(root_id) => find(root_id)?,
+                    None => parent,
+                };
+                Some(root.message_id)
+            }
+            None => None,
+        };
+
         let message_id = Uuid::new_v4().to_string();
         let now = Utc::now().to_rfc3339();
 
         self.db.execute(
-            "INSERT INTO messages (message_id, conversation_id, sender_id, body, created_at)
-             VALUES (?1, ?2, ?3, ?4, ?5)",
-            rusqlite::params![message_id, conversation_id, sender_id, body, now],
+            "INSERT INTO messages (message_id, conversation_id, sender_id, body, created_at, parent_id, client_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            rusqlite::params![message_id, conversation_id, sender_id, body, now, parent_id, client_id],
         ).context("Failed to create message")?;
 
         // Link attachments
@@ -1205,22 +3485,147 @@ impl StorageManager {
             "UPDATE conversations SET updated_at = ?1 WHERE conversation_id = ?2",
             rusqlite::params![now, conversation_id],
         )?;
+        // Senders have read their own message
+        self.db.execute(
+            "UPDATE conversation_members SET last_read_message_id = ?1, last_read_at = ?2
+             WHERE conversation_id = ?3 AND user_id = ?4",
+            rusqlite::params![message_id, now, conversation_id, sender_id],
+        )?;
 
         let username = self.get_user_by_id(sender_id)?
             .map(|u| u.username)
             .unwrap_or_default();
 
         Ok(Message {
-            message_id,
-            conversation_id: conversation_id.to_string(),
-            sender_id: sender_id.to_string(),
-            sender_username: username,
-            body: body.to_string(),
-            attachments,
-            created_at: now,
+            message_id,
+            conversation_id: conversation_id.to_string(),
+            sender_id: sender_id.to_string(),
+            sender_username: username,
+            body: body.to_string(),
+            attachments,
+            created_at: now,
+            edited_at: None,
+            deleted_at: None,
+            reactions: Vec::new(),
+            parent_id,
+            reply_count: 0,
+            last_reply_at: None,
+            client_id: client_id.map(str::to_string),
+        })
+    }
+
+    /// A message the sender already posted under this client ID
+    pub fn find_message_by_client_id(&self, sender_id: &str, client_id: &str) -> Result<Option<Message>> {
+        let message = self.db.query_row(
+            &format!(
+                "SELECT {} FROM messages m
+                 JOIN users u ON m.sender_id = u.user_id
+                 WHERE m.sender_id = ?1 AND m.client_id = ?2",
+                MESSAGE_COLUMNS
+            ),
+            [sender_id, client_id],
+            message_from_row,
+        ).optional()?;
+        message.map(|m| self.with_details(m)).transpose()
+    }
+
+    pub fn get_message(&self, message_id: &str) -> Result<Option<Message>> {
+        let message = self.db.query_row(
+            &format!(
+                "SELECT {} FROM messages m
+                 JOIN users u ON m.sender_id = u.user_id
+                 WHERE m.message_id = ?1",
+                MESSAGE_COLUMNS
+            ),
+            [message_id],
+            message_from_row,
+        ).optional()?;
+        message.map(|m| self.with_details(m)).transpose()
+    }
+
+    /// Replace a message's body, keeping the previous one in its edit
+    /// history. Only the sender can edit, and not once it's deleted.
+    pub fn edit_message(&self, message_id: &str, editor_id: &str, body: &str) -> Result<Message> {
+        let message = self.get_message(message_id)?
+            .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+        if message.sender_id != editor_id {
+            anyhow::bail!("Permission denied: only the sender can edit a message");
+        }
+        if message.deleted_at.is_some() {
+            anyhow::bail!("Deleted messages can't be edited");
+        }
+        if body.is_empty() && message.attachments.is_empty() {
+            anyhow::bail!("Message can't be empty");
+        }
+        if body == message.body {
+            return Ok(message);
+        }
+
+        let now = Utc::now().to_rfc3339();
+        self.in_transaction(|| {
+            self.db.execute(
+                "INSERT INTO message_edits (edit_id, message_id, body, edited_at) VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![Uuid::new_v4().to_string(), message_id, message.body, now],
+            ).context("Failed to record message edit")?;
+            self.db.execute(
+                "UPDATE messages SET body = ?1, edited_at = ?2 WHERE message_id = ?3",
+                rusqlite::params![body, now, message_id],
+            ).context("Failed to edit message")?;
+            Ok(())
+        })?;
+
+        Ok(Message {
+            body: body.to_string(),
+            edited_at: Some(now),
+            ..message
+        })
+    }
+
+    /// Turn a message into a tombstone: the body, attachments and edit
+    /// history are removed, and the row stays so the conversation keeps
+    /// its shape. Deleting twice is a no-op. Permission is up to the caller.
+    pub fn delete_message(&self, message_id: &str) -> Result<Message> {
+        let message = self.get_message(message_id)?
+            .ok_or_else(|| anyhow::anyhow!("Message not found"))?;
+        if message.deleted_at.is_some() {
+            return Ok(message);
+        }
+
+        let now = Utc::now().to_rfc3339();
+        self.in_transaction(|| {
+            self.db.execute("DELETE FROM message_attachments WHERE message_id = ?1", [message_id])?;
+            self.db.execute("DELETE FROM message_edits WHERE message_id = ?1", [message_id])?;
+            self.db.execute("DELETE FROM message_reactions WHERE message_id = ?1", [message_id])?;
+            self.db.execute(
+                "UPDATE messages SET body = '', deleted_at = ?1 WHERE message_id = ?2",
+                [&now, message_id],
+            ).context("Failed to delete message")?;
+            Ok(())
+        })?;
+
+        Ok(Message {
+            body: String::new(),
+            attachments: Vec::new(),
+            reactions: Vec::new(),
+            deleted_at: Some(now),
+            ..message
         })
     }
 
+    /// Earlier versions of a message, oldest first
+    pub fn list_message_edits(&self, message_id: &str) -> Result<Vec<MessageEdit>> {
+        let mut stmt = self.db.prepare(
+            "SELECT body, edited_at FROM message_edits WHERE message_id = ?1 ORDER BY edited_at ASC"
+        )?;
+        let edits = stmt.query_map([message_id], |row| {
+            Ok(MessageEdit {
+                body: row.get(0)?,
+                edited_at: row.get(1)?,
+            })
+        })?.collect::<Result<Vec<_>, _>>()?;
+        Ok(edits)
+    }
+
     fn get_message_attachments(&self, message_id: &str) -> Result<Vec<MessageAttachment>> {
         let mut stmt = self.db.prepare(
             "SELECT f.file_id, f.file_name, f.size_bytes
@@ -1253,80 +3658,333 @@ impl StorageManager {
         Ok(exists)
     }
 
+    /// The conversation's main timeline, newest first. Thread replies are
+    /// left out; their parents carry a reply count instead.
     pub fn list_messages(
         &self,
         conversation_id: &str,
         limit: u32,
         before: Option<&str>,
     ) -> Result<Vec<Message>> {
-        let raw_rows: Vec<(String, String, String, String, String, String)> = if let Some(cursor) = before {
-            let mut stmt = self.db.prepare(
-                "SELECT m.message_id, m.conversation_id, m.sender_id, u.username, m.body, m.created_at
-                 FROM messages m
-                 JOIN users u ON m.sender_id = u.user_id
-                 WHERE m.conversation_id = ?1 AND m.created_at < ?2
-                 ORDER BY m.created_at DESC
-                 LIMIT ?3"
-            )?;
-            let result = stmt.query_map(rusqlite::params![conversation_id, cursor, limit], |row| {
-                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
-            })?.collect::<Result<Vec<_>, _>>()?;
-            result
-        } else {
-            let mut stmt = self.db.prepare(
-                "SELECT m.message_id, m.conversation_id, m.sender_id, u.username, m.body, m.created_at
-                 FROM messages m
-                 JOIN users u ON m.sender_id = u.user_id
-                 WHERE m.conversation_id = ?1
-                 ORDER BY m.created_at DESC
-                 LIMIT ?2"
-            )?;
-            let result = stmt.query_map(rusqlite::params![conversation_id, limit], |row| {
-                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?))
-            })?.collect::<Result<Vec<_>, _>>()?;
-            result
-        };
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {} FROM messages m
+             JOIN users u ON m.sender_id = u.user_id
+             WHERE m.conversation_id = ?1 AND m.parent_id IS NULL AND (?2 IS NULL OR m.created_at < ?2)
+             ORDER BY m.created_at DESC
+             LIMIT ?3",
+            MESSAGE_COLUMNS
+        ))?;
+        let rows = stmt.query_map(rusqlite::params![conversation_id, before, limit], message_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
 
-        let mut messages = Vec::with_capacity(raw_rows.len());
-        for (message_id, conv_id, sender_id, username, body, created_at) in raw_rows {
-            let attachments = self.get_message_attachments(&message_id)?;
-            messages.push(Message {
-                message_id,
-                conversation_id: conv_id,
-                sender_id,
-                sender_username: username,
-                body,
-                attachments,
-                created_at,
-            });
+        self.with_details_all(rows)
+    }
+
+    /// Replies in a thread, newest first
+    pub fn list_thread(&self, parent_id: &str, limit: u32, before: Option<&str>) -> Result<Vec<Message>> {
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {} FROM messages m
+             JOIN users u ON m.sender_id = u.user_id
+             WHERE m.parent_id = ?1 AND (?2 IS NULL OR m.created_at < ?2)
+             ORDER BY m.created_at DESC
+             LIMIT ?3",
+            MESSAGE_COLUMNS
+        ))?;
+        let rows = stmt.query_map(rusqlite::params![parent_id, before, limit], message_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.with_details_all(rows)
+    }
+
+    /// A page of every message in a conversation, thread replies included,
+    /// oldest first. Pass the last message of the previous page as `after`.
+    pub fn list_all_messages(&self, conversation_id: &str, after: Option<&Message>, limit: i64) -> Result<Vec<Message>> {
+        let (after_created, after_id) = after
+            .map(|m| (m.created_at.as_str(), m.message_id.as_str()))
+            .unwrap_or(("", ""));
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {} FROM messages m
+             JOIN users u ON m.sender_id = u.user_id
+             WHERE m.conversation_id = ?1
+               AND (m.created_at > ?2 OR (m.created_at = ?2 AND m.message_id > ?3))
+             ORDER BY m.created_at ASC, m.message_id ASC
+             LIMIT ?4",
+            MESSAGE_COLUMNS
+        ))?;
+        let rows = stmt.query_map(rusqlite::params![conversation_id, after_created, after_id, limit], message_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        self.with_details_all(rows)
+    }
+
+    fn with_details(&self, mut message: Message) -> Result<Message> {
+        message.attachments = self.get_message_attachments(&message.message_id)?;
+        message.reactions = self.list_reactions(&message.message_id)?;
+        Ok(message)
+    }
+
+    /// `with_details` for a page of messages, with one query for all their
+    /// attachments and one for all their reactions
+    fn with_details_all(&self, mut messages: Vec<Message>) -> Result<Vec<Message>> {
+        if messages.is_empty() {
+            return Ok(messages);
+        }
+        let ids: Vec<&str> = messages.iter().map(|m| m.message_id.as_str()).collect();
+        let placeholders = (1..=ids.len()).map(|i| format!("?{}", i)).collect::<Vec<_>>().join(", ");
+
+        let mut attachments: HashMap<String, Vec<MessageAttachment>> = HashMap::new();
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT ma.message_id, f.file_id, f.file_name, f.size_bytes
+             FROM message_attachments ma
+             JOIN files f ON ma.file_id = f.file_id
+             WHERE ma.message_id IN ({})",
+            placeholders
+        ))?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(&ids), |row| {
+            let file_name: String = row.get(2)?;
+            Ok((row.get::<_, String>(0)?, MessageAttachment {
+                file_id: row.get(1)?,
+                mime_type: mime_from_ext(&file_name),
+                file_name,
+                size_bytes: row.get(3)?,
+            }))
+        })?;
+        for row in rows {
+            let (message_id, attachment) = row?;
+            attachments.entry(message_id).or_default().push(attachment);
+        }
+
+        let mut reactions: HashMap<String, Vec<(String, String)>> = HashMap::new();
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT message_id, emoji, user_id FROM message_reactions
+             WHERE message_id IN ({}) ORDER BY created_at ASC",
+            placeholders
+        ))?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(&ids), |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?))
+        })?;
+        for row in rows {
+            let (message_id, emoji, user_id) = row?;
+            reactions.entry(message_id).or_default().push((emoji, user_id));
+        }
+
+        for message in &mut messages {
+            message.attachments = attachments.remove(&message.message_id).unwrap_or_default();
+            message.reactions = group_reactions(reactions.remove(&message.message_id).unwrap_or_default());
         }
         Ok(messages)
     }
 
+    /// React to a message. Returns false if the user had already reacted
+    /// with this emoji.
+    pub fn add_reaction(&self, message_id: &str, user_id: &str, emoji: &str) -> Result<bool> {
+        validate_reaction(emoji)?;
+        let already: bool = self.db.query_row(
+            "SELECT COUNT(*) > 0 FROM message_reactions WHERE message_id = ?1 AND user_id = ?2 AND emoji = ?3",
+            [message_id, user_id, emoji],
+            |row| row.get(0),
+        )?;
+        if already {
+            return Ok(false);
+        }
+        let distinct: u32 = self.db.query_row(
+            "SELECT COUNT(DISTINCT emoji) FROM message_reactions WHERE message_id = ?1 AND emoji != ?2",
+            [message_id, emoji],
+            |row| row.get(0),
+        )?;
+        if distinct >= MAX_REACTIONS_PER_MESSAGE {
+            anyhow::bail!("A message can have at most {} different reactions", MAX_REACTIONS_PER_MESSAGE);
+        }
+
+        self.db.execute(
+            "INSERT INTO message_reactions (message_id, user_id, emoji, created_at) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![message_id, user_id, emoji, Utc::now().to_rfc3339()],
+        ).context("Failed to add reaction")?;
+        Ok(true)
+    }
+
+    /// Returns false if there was no such reaction
+    pub fn remove_reaction(&self, message_id: &str, user_id: &str, emoji: &str) -> Result<bool> {
+        let removed = self.db.execute(
+            "DELETE FROM message_reactions WHERE message_id = ?1 AND user_id = ?2 AND emoji = ?3",
+            [message_id, user_id, emoji],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// Reactions grouped by emoji, in the order each emoji was first used
+    pub fn list_reactions(&self, message_id: &str) -> Result<Vec<ReactionCount>> {
+        let mut stmt = self.db.prepare(
+            "SELECT emoji, user_id FROM message_reactions WHERE message_id = ?1 ORDER BY created_at ASC"
+        )?;
+        let rows = stmt.query_map([message_id], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(group_reactions(rows))
+    }
+
+    /// Log a realtime event for each recipient under their next sequence
+    /// number. Returns the number each recipient got; unknown users are
+    /// skipped.
+    pub fn record_event(&self, user_ids: &[String], payload: &str) -> Result<HashMap<String, u64>> {
+        let now = Utc::now().to_rfc3339();
+        self.in_transaction(|| {
+            let mut seqs = HashMap::new();
+            for user_id in user_ids {
+                if seqs.contains_key(user_id) {
+                    continue;
+                }
+                let seq: Option<u64> = self.db.query_row(
+                    "UPDATE users SET event_seq = event_seq + 1 WHERE user_id = ?1 RETURNING event_seq",
+                    [user_id],
+                    |row| row.get(0),
+                ).optional()?;
+                let Some(seq) = seq else { continue };
+                self.db.execute(
+                    "INSERT INTO user_events (user_id, seq, payload, created_at) VALUES (?1, ?2, ?3, ?4)",
+                    rusqlite::params![user_id, seq, payload, now],
+                ).context("Failed to record event")?;
+                seqs.insert(user_id.clone(), seq);
+            }
+            Ok(seqs)
+        })
+    }
+
+    /// The last sequence number handed out to the user
+    pub fn event_seq(&self, user_id: &str) -> Result<u64> {
+        self.db.query_row(
+            "SELECT event_seq FROM users WHERE user_id = ?1",
+            [user_id],
+            |row| row.get(0),
+        ).optional()?.ok_or_else(|| anyhow::anyhow!("User not found"))
+    }
+
+    /// The user's events after `since`, oldest first, as (seq, payload).
+    /// None if some of them are gone or there are more than `limit`, in
+    /// which case the client has to resync from scratch.
+    pub fn events_since(&self, user_id: &str, since: u64, limit: u32) -> Result<Option<Vec<(u64, String)>>> {
+        let latest = self.event_seq(user_id)?;
+        if since > latest || latest - since > u64::from(limit) {
+            return Ok(None);
+        }
+        let mut stmt = self.db.prepare(
+            "SELECT seq, payload FROM user_events WHERE user_id = ?1 AND seq > ?2 ORDER BY seq ASC"
+        )?;
+        let events = stmt.query_map(rusqlite::params![user_id, since], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<(u64, String)>, _>>()?;
+        // Sequence numbers are handed out without gaps, so anything missing
+        // has been purged
+        if events.len() as u64 != latest - since {
+            return Ok(None);
+        }
+        Ok(Some(events))
+    }
+
+    /// Drop realtime events created before `before`
+    pub fn purge_user_events(&self, before: &str) -> Result<()> {
+        self.db.execute("DELETE FROM user_events WHERE created_at < ?1", [before])
+            .context("Failed to purge events")?;
+        Ok(())
+    }
+
+    // --- Search ---
+
+    /// Messages matching `query` in conversations the user belongs to, best
+    /// match first. Deleted messages are left out.
+    pub fn search_messages(&self, user_id: &str, query: &str, limit: usize) -> Result<Vec<Message>> {
+        let Some(query) = fts_query(query)? else {
+            return Ok(Vec::new());
+        };
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {} FROM messages_fts
+             JOIN messages m ON m.rowid = messages_fts.rowid
+             JOIN users u ON m.sender_id = u.user_id
+             JOIN conversation_members cm ON cm.conversation_id = m.conversation_id AND cm.user_id = ?2
+             WHERE messages_fts MATCH ?1 AND m.deleted_at IS NULL
+             ORDER BY messages_fts.rank
+             LIMIT ?3",
+            MESSAGE_COLUMNS
+        ))?;
+        let messages = stmt.query_map(rusqlite::params![query, user_id, limit as i64], message_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        self.with_details_all(messages)
+    }
+
+    /// Files matching `query` by name or description that the user can
+    /// read, best match first
+    pub fn search_files(&self, user_id: &str, query: &str, limit: usize) -> Result<Vec<File>> {
+        let Some(query) = fts_query(query)? else {
+            return Ok(Vec::new());
+        };
+        // Same rule as `can_read_file`: owned, public, or attached in one of
+        // the user's conversations
+        let mut stmt = self.db.prepare(&format!(
+            "SELECT {} FROM files_fts
+             JOIN files f ON f.rowid = files_fts.rowid
+             WHERE files_fts MATCH ?1
+               AND (f.user_id = ?2 OR f.is_public OR EXISTS (
+                   SELECT 1 FROM message_attachments ma
+                   JOIN messages m ON ma.message_id = m.message_id
+                   JOIN conversation_members cm ON m.conversation_id = cm.conversation_id
+                   WHERE ma.file_id = f.file_id AND cm.user_id = ?2
+               ))
+             ORDER BY files_fts.rank
+             LIMIT ?3",
+            FILE_COLUMNS
+        ))?;
+        let files = stmt.query_map(rusqlite::params![query, user_id, limit as i64], file_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(files)
+    }
+
     /// Factory reset: wipe all data from the database and delete the storage directory.
     /// The database file itself is preserved so the app can reinitialize on next launch.
-    /// The wizard runs fresh because node_config will be empty.
+    /// The wizard runs fresh because node_config will be empty. The audit log
+    /// is kept on purpose: the reset itself is audited, and the history is
+    /// the only record of who wiped the hub and what happened before. Entries
+    /// store actor names, so they stay readable after the users are gone.
     pub fn factory_reset(&mut self) -> Result<()> {
         // Wipe all tables — order matters for foreign key constraints
         self.db.execute_batch(
             "PRAGMA foreign_keys = OFF;
              DELETE FROM messages;
              DELETE FROM message_attachments;
+             DELETE FROM message_edits;
+             DELETE FROM message_reactions;
              DELETE FROM conversation_members;
              DELETE FROM conversations;
              DELETE FROM files;
              DELETE FROM spaces;
+             DELETE FROM api_tokens;
+             DELETE FROM passkeys;
+             DELETE FROM profiles;
+             DELETE FROM export_jobs;
+             DELETE FROM guest_invites;
+             DELETE FROM email_tokens;
+             DELETE FROM user_events;
+             DELETE FROM email_outbox;
+             DELETE FROM hub_settings;
+             DELETE FROM roles;
+             DELETE FROM oidc_auth_codes;
+             DELETE FROM oidc_clients;
              DELETE FROM users;
              DELETE FROM tunnel_config;
              DELETE FROM node_config;
              PRAGMA foreign_keys = ON;"
         ).context("Failed to wipe database")?;
 
-        // Delete all uploaded files from disk
+        // Delete all uploaded files, avatars and export archives from disk
         let storage_dir = self.install_path.join("storage");
         if storage_dir.exists() {
             let _ = fs::remove_dir_all(&storage_dir);
         }
+        let avatars_dir = self.install_path.join("avatars");
+        if avatars_dir.exists() {
+            let _ = fs::remove_dir_all(&avatars_dir);
+        }
+        let exports_dir = self.install_path.join("exports");
+        if exports_dir.exists() {
+            let _ = fs::remove_dir_all(&exports_dir);
+        }
 
         Ok(())
     }
@@ -1356,6 +4014,377 @@ fn mime_from_ext(name: &str) -> String {
     }
 }
 
+fn api_token_from_row(row: &rusqlite::Row) -> rusqlite::Result<ApiToken> {
+    let scopes: String = row.get(3)?;
+    Ok(ApiToken {
+        token_id: row.get(0)?,
+        user_id: row.get(1)?,
+        name: row.get(2)?,
+        scopes: scopes.split_whitespace().map(|s| s.to_string()).collect(),
+        expires_at: row.get(4)?,
+        last_used_at: row.get(5)?,
+        created_at: row.get(6)?,
+    })
+}
+
+fn builtin_role(role_id: &str) -> Role {
+    let mut name = role_id.to_string();
+    name[..1].make_ascii_uppercase();
+    Role {
+        role_id: role_id.to_string(),
+        name,
+        permissions: roles::builtin_permissions(role_id).unwrap_or_default()
+            .iter().map(|p| p.to_string()).collect(),
+        is_builtin: true,
+    }
+}
+
+fn role_from_row(row: &rusqlite::Row) -> rusqlite::Result<Role> {
+    let permissions: String = row.get(2)?;
+    Ok(Role {
+        role_id: row.get(0)?,
+        name: row.get(1)?,
+        permissions: permissions.split_whitespace().map(|p| p.to_string()).collect(),
+        is_builtin: false,
+    })
+}
+
+fn validate_role(name: &str, permissions: &[String]) -> Result<()> {
+    if name.trim().is_empty() || name.len() > 64 {
+        anyhow::bail!("Role name must be 1-64 characters");
+    }
+    if let Some(invalid) = permissions.iter().find(|p| !roles::is_valid_permission(p)) {
+        anyhow::bail!("Unknown permission: {}", invalid);
+    }
+    Ok(())
+}
+
+fn user_from_row(row: &rusqlite::Row) -> rusqlite::Result<User> {
+    Ok(User {
+        user_id: row.get(0)?,
+        username: row.get(1)?,
+        email: row.get(2)?,
+        is_admin: row.get::<_, i32>(3)? != 0,
+        role: row.get(4)?,
+        created_at: row.get(5)?,
+        updated_at: row.get(6)?,
+        suspension: suspension_from_row(row, 7)?,
+        expires_at: row.get(10)?,
+        email_verified_at: row.get(11)?,
+    })
+}
+
+/// Suspension stored in the three columns starting at `idx`. A suspension
+/// whose end has passed reads as None and needs no cleanup.
+fn suspension_from_row(row: &rusqlite::Row, idx: usize) -> rusqlite::Result<Option<Suspension>> {
+    let Some(suspended_at) = row.get::<_, Option<String>>(idx)? else {
+        return Ok(None);
+    };
+    let suspended_until: Option<String> = row.get(idx + 2)?;
+    if has_passed(suspended_until.as_deref()) {
+        return Ok(None);
+    }
+    Ok(Some(Suspension {
+        reason: row.get(idx + 1)?,
+        suspended_at,
+        suspended_until,
+    }))
+}
+
+fn guest_invite_from_row(row: &rusqlite::Row) -> rusqlite::Result<GuestInvite> {
+    let conversation_ids: String = row.get(1)?;
+    Ok(GuestInvite {
+        invite_id: row.get(0)?,
+        conversation_ids: serde_json::from_str(&conversation_ids).unwrap_or_default(),
+        expires_at: row.get(2)?,
+        max_uses: row.get(3)?,
+        uses: row.get(4)?,
+        created_by: row.get(5)?,
+        created_at: row.get(6)?,
+    })
+}
+
+/// Escape `%`, `_` and the escape character itself for a `LIKE ... ESCAPE '\'`
+fn escape_like(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | '%' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Whether an optional RFC 3339 deadline has been reached
+fn has_passed(deadline: Option<&str>) -> bool {
+    deadline
+        .and_then(|d| chrono::DateTime::parse_from_rfc3339(d).ok())
+        .is_some_and(|d| d <= Utc::now())
+}
+
+/// Normalize a caller-supplied RFC 3339 timestamp to UTC, so it compares
+/// correctly as a string against stored timestamps.
+pub fn utc_timestamp(value: &str) -> Result<String> {
+    let at = chrono::DateTime::parse_from_rfc3339(value.trim())
+        .context("Expected an RFC 3339 timestamp")?;
+    Ok(at.with_timezone(&Utc).to_rfc3339())
+}
+
+/// Normalize a caller-supplied expiry to UTC and reject one already in
+/// the past.
+fn future_timestamp(value: &str) -> Result<String> {
+    let at = chrono::DateTime::parse_from_rfc3339(value.trim())
+        .context("Expiry must be an RFC 3339 timestamp")?
+        .with_timezone(&Utc);
+    if at <= Utc::now() {
+        anyhow::bail!("Expiry must be in the future");
+    }
+    Ok(at.to_rfc3339())
+}
+
+/// Columns read by `conversation_from_row`, from `conversations c`
+const CONVERSATION_COLUMNS: &str =
+    "c.conversation_id, c.kind, c.name, c.created_by, c.created_at, c.updated_at, c.announcement_only, c.is_default";
+
+fn conversation_from_row(row: &rusqlite::Row) -> rusqlite::Result<Conversation> {
+    Ok(Conversation {
+        conversation_id: row.get(0)?,
+        kind: row.get(1)?,
+        name: row.get(2)?,
+        created_by: row.get(3)?,
+        created_at: row.get(4)?,
+        updated_at: row.get(5)?,
+        announcement_only: row.get::<_, i32>(6)? != 0,
+        is_default: row.get::<_, i32>(7)? != 0,
+    })
+}
+
+/// Columns read by `message_from_row`, from `messages m JOIN users u`
+const MESSAGE_COLUMNS: &str =
+    "m.message_id, m.conversation_id, m.sender_id, u.username, m.body, m.created_at, m.edited_at, m.deleted_at,
+     m.parent_id,
+     (SELECT COUNT(*) FROM messages r WHERE r.parent_id = m.message_id AND r.deleted_at IS NULL),
+     (SELECT MAX(r.created_at) FROM messages r WHERE r.parent_id = m.message_id AND r.deleted_at IS NULL),
+     m.client_id";
+
+/// Columns read by `file_from_row`, from `files f`
+const FILE_COLUMNS: &str =
+    "f.file_id, f.user_id, f.file_name, f.size_bytes, f.is_public, f.created_at, f.description";
+
+fn file_from_row(row: &rusqlite::Row) -> rusqlite::Result<File> {
+    Ok(File {
+        file_id: row.get(0)?,
+        user_id: row.get(1)?,
+        file_name: row.get(2)?,
+        size_bytes: row.get(3)?,
+        is_public: row.get::<_, i32>(4)? != 0,
+        created_at: row.get(5)?,
+        description: row.get(6)?,
+    })
+}
+
+/// A message without its attachments and reactions, which are loaded separately
+fn message_from_row(row: &rusqlite::Row) -> rusqlite::Result<Message> {
+    Ok(Message {
+        message_id: row.get(0)?,
+        conversation_id: row.get(1)?,
+        sender_id: row.get(2)?,
+        sender_username: row.get(3)?,
+        body: row.get(4)?,
+        attachments: Vec::new(),
+        created_at: row.get(5)?,
+        edited_at: row.get(6)?,
+        deleted_at: row.get(7)?,
+        reactions: Vec::new(),
+        parent_id: row.get(8)?,
+        reply_count: row.get(9)?,
+        last_reply_at: row.get(10)?,
+        client_id: row.get(11)?,
+    })
+}
+
+fn outgoing_email_from_row(row: &rusqlite::Row) -> rusqlite::Result<OutgoingEmail> {
+    Ok(OutgoingEmail {
+        email_id: row.get(0)?,
+        to_address: row.get(1)?,
+        subject: row.get(2)?,
+        body: row.get(3)?,
+        kind: row.get(4)?,
+        status: row.get(5)?,
+        attempts: row.get(6)?,
+        last_error: row.get(7)?,
+        next_attempt_at: row.get(8)?,
+        created_at: row.get(9)?,
+        sent_at: row.get(10)?,
+    })
+}
+
+fn export_job_from_row(row: &rusqlite::Row) -> rusqlite::Result<ExportJob> {
+    Ok(ExportJob {
+        job_id: row.get(0)?,
+        user_id: row.get(1)?,
+        status: row.get(2)?,
+        size_bytes: row.get(3)?,
+        error: row.get(4)?,
+        created_at: row.get(5)?,
+        completed_at: row.get(6)?,
+        expires_at: row.get(7)?,
+    })
+}
+
+/// Decode an avatar upload, whatever type it claims to be, and re-encode it as
+/// a PNG scaled to fit `AVATAR_SIZE`. This drops metadata and anything that
+/// isn't a PNG, JPEG, GIF or WebP image.
+fn encode_avatar(data: &[u8]) -> Result<Vec<u8>> {
+    use image::ImageFormat;
+
+    let format = image::guess_format(data).context("Avatar is not an image")?;
+    if !matches!(format, ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP) {
+        anyhow::bail!("Avatar must be a PNG, JPEG, GIF or WebP image");
+    }
+    let mut reader = image::ImageReader::with_format(std::io::Cursor::new(data), format);
+    let mut limits = image::Limits::default();
+    limits.max_image_width = Some(MAX_AVATAR_DIMENSION);
+    limits.max_image_height = Some(MAX_AVATAR_DIMENSION);
+    reader.limits(limits);
+    let mut avatar = reader.decode().context("Avatar image could not be decoded")?;
+    if avatar.width() > AVATAR_SIZE || avatar.height() > AVATAR_SIZE {
+        avatar = avatar.thumbnail(AVATAR_SIZE, AVATAR_SIZE);
+    }
+
+    let mut png = Vec::new();
+    avatar.write_to(&mut std::io::Cursor::new(&mut png), ImageFormat::Png)
+        .context("Failed to encode avatar")?;
+    Ok(png)
+}
+
+/// Columns read by `profile_from_row`
+const PROFILE_COLUMNS: &str =
+    "user_id, display_name, avatar_updated_at, bio, pronouns, tags, visibility,
+     allow_direct_messages, updated_at, show_presence";
+
+fn profile_from_row(row: &rusqlite::Row) -> rusqlite::Result<Profile> {
+    let tags: String = row.get(5)?;
+    let visibility: String = row.get(6)?;
+    Ok(Profile {
+        user_id: row.get(0)?,
+        display_name: row.get(1)?,
+        avatar_updated_at: row.get(2)?,
+        bio: row.get(3)?,
+        pronouns: row.get(4)?,
+        tags: serde_json::from_str(&tags).unwrap_or_default(),
+        visibility: serde_json::from_str(&visibility).unwrap_or_default(),
+        allow_direct_messages: row.get::<_, i32>(7)? != 0,
+        updated_at: row.get(8)?,
+        show_presence: row.get::<_, i32>(9)? != 0,
+    })
+}
+
+/// Group (emoji, user ID) rows, in reaction order, by emoji
+fn group_reactions(rows: Vec<(String, String)>) -> Vec<ReactionCount> {
+    let mut reactions: Vec<ReactionCount> = Vec::new();
+    for (emoji, user_id) in rows {
+        match reactions.iter_mut().find(|r| r.emoji == emoji) {
+            Some(r) => {
+                r.count += 1;
+                r.user_ids.push(user_id);
+            }
+            None => reactions.push(ReactionCount { emoji, count: 1, user_ids: vec![user_id] }),
+        }
+    }
+    reactions
+}
+
+/// A reaction is a short run of emoji, counted in grapheme clusters so a
+/// ZWJ sequence or flag is one. Each cluster must be pictographic: no
+/// letters, digits (other than keycaps), punctuation or whitespace.
+fn validate_reaction(emoji: &str) -> Result<()> {
+    let clusters: Vec<&str> = emoji.graphemes(true).collect();
+    if clusters.is_empty() || clusters.len() > MAX_REACTION_LEN || emoji.len() > MAX_REACTION_BYTES {
+        anyhow::bail!("Reactions must be 1 to {} emoji", MAX_REACTION_LEN);
+    }
+    let is_emoji = |cluster: &str| {
+        let keycap = cluster.ends_with('\u{20E3}');
+        let base = cluster.chars().next().unwrap_or(' ');
+        !cluster.chars().any(|c| c.is_whitespace() || c.is_control())
+            && (keycap || (!base.is_ascii() && !base.is_alphanumeric()))
+    };
+    if !clusters.iter().all(|c| is_emoji(c)) {
+        anyhow::bail!("Reactions must be emoji");
+    }
+    Ok(())
+}
+
+fn validate_profile(profile: &Profile) -> Result<()> {
+    let too_long = |value: &Option<String>, max: usize| value.as_ref().is_some_and(|v| v.chars().count() > max);
+    if too_long(&profile.display_name, MAX_DISPLAY_NAME_LEN) {
+        anyhow::bail!("Display name must be at most {} characters", MAX_DISPLAY_NAME_LEN);
+    }
+    if too_long(&profile.bio, MAX_BIO_LEN) {
+        anyhow::bail!("Bio must be at most {} characters", MAX_BIO_LEN);
+    }
+    if too_long(&profile.pronouns, MAX_PRONOUNS_LEN) {
+        anyhow::bail!("Pronouns must be at most {} characters", MAX_PRONOUNS_LEN);
+    }
+    if profile.tags.len() > MAX_PROFILE_TAGS {
+        anyhow::bail!("At most {} tags are allowed", MAX_PROFILE_TAGS);
+    }
+    if profile.tags.iter().any(|t| t.is_empty() || t.chars().count() > MAX_PROFILE_TAG_LEN) {
+        anyhow::bail!("Tags must be 1 to {} characters", MAX_PROFILE_TAG_LEN);
+    }
+    Ok(())
+}
+
+fn passkey_from_row(row: &rusqlite::Row) -> rusqlite::Result<Passkey> {
+    Ok(Passkey {
+        credential_id: row.get(0)?,
+        user_id: row.get(1)?,
+        name: row.get(2)?,
+        algorithm: row.get(3)?,
+        created_at: row.get(4)?,
+        last_used_at: row.get(5)?,
+        public_key: row.get(6)?,
+        sign_count: row.get(7)?,
+    })
+}
+
+fn oidc_client_from_row(row: &rusqlite::Row) -> rusqlite::Result<OidcClient> {
+    let client_secret_hash: Option<String> = row.get(2)?;
+    let redirect_uris: String = row.get(3)?;
+    Ok(OidcClient {
+        client_id: row.get(0)?,
+        name: row.get(1)?,
+        redirect_uris: redirect_uris.lines().map(|s| s.to_string()).collect(),
+        is_confidential: client_secret_hash.is_some(),
+        created_at: row.get(4)?,
+        client_secret_hash,
+    })
+}
+
+/// Turn free text into an FTS5 query in which every word must match, as a
+/// prefix. Words shorter than `MIN_SEARCH_TERM_LEN` are skipped, since as
+/// prefixes they'd match nearly everything. Returns None if there are no
+/// words to search for.
+fn fts_query(query: &str) -> Result<Option<String>> {
+    if query.chars().count() > MAX_SEARCH_QUERY_LEN {
+        anyhow::bail!("Search query is too long (max {} characters)", MAX_SEARCH_QUERY_LEN);
+    }
+    let terms: Vec<String> = query
+        .split_whitespace()
+        .filter(|term| term.chars().filter(|c| c.is_alphanumeric()).count() >= MIN_SEARCH_TERM_LEN)
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect();
+    Ok((!terms.is_empty()).then(|| terms.join(" ")))
+}
+
+fn validate_conversation_name(name: &str) -> Result<()> {
+    if name.chars().count() > MAX_CONVERSATION_NAME_LEN {
+        anyhow::bail!("Name is too long (max {} characters)", MAX_CONVERSATION_NAME_LEN);
+    }
+    Ok(())
+}
+
 fn validate_filename(name: &str) -> Result<()> {
     if name.is_empty() || name.contains("..") || name.contains('/') || name.contains('\\') {
         anyhow::bail!("Invalid filename: {}", name);
@@ -1405,3 +4434,617 @@ fn walk_dir_size(path: &Path) -> Result<(u64, u64)> {
 
     Ok((total_size, file_count))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fresh hub in a temporary directory, removed again on drop
+    struct TestHub {
+        sm: StorageManager,
+        dir: PathBuf,
+    }
+
+    impl std::ops::Deref for TestHub {
+        type Target = StorageManager;
+        fn deref(&self) -> &StorageManager {
+            &self.sm
+        }
+    }
+
+    impl Drop for TestHub {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn hub() -> TestHub {
+        let dir = std::env::temp_dir().join(format!("citinet-test-{}", Uuid::new_v4()));
+        let sm = StorageManager::initialize(dir.to_str().unwrap()).unwrap();
+        TestHub { sm, dir }
+    }
+
+    fn add_user(sm: &StorageManager, username: &str, is_admin: bool) -> User {
+        sm.create_user(username, &format!("{}@example.org", username), "hash", is_admin).unwrap()
+    }
+
+    #[test]
+    fn test_api_tokens() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        let scopes = vec!["files:read".to_string()];
+
+        let token = hub.create_api_token(&alice.user_id, "backup", "hash-1", &scopes, None).unwrap();
+        let used = hub.use_api_token("hash-1").unwrap().unwrap();
+        assert_eq!(used.token_id, token.token_id);
+        assert_eq!(used.scopes, scopes);
+        assert!(hub.list_api_tokens(&alice.user_id).unwrap()[0].last_used_at.is_some());
+        assert!(hub.use_api_token("unknown").unwrap().is_none());
+
+        let past = (Utc::now() - chrono::Duration::days(1)).to_rfc3339();
+        hub.create_api_token(&alice.user_id, "old", "hash-2", &scopes, Some(&past)).unwrap();
+        assert!(hub.use_api_token("hash-2").unwrap().is_none());
+
+        assert!(hub.delete_api_token(&alice.user_id, &token.token_id).unwrap());
+        assert!(!hub.delete_api_token(&alice.user_id, &token.token_id).unwrap());
+        assert!(hub.use_api_token("hash-1").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_oidc_auth_codes_are_single_use() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        let redirect = vec!["https://app.example.org/callback".to_string()];
+        let client = hub.create_oidc_client("App", &redirect, None).unwrap();
+        let code = OidcAuthCode {
+            client_id: client.client_id.clone(),
+            user_id: alice.user_id.clone(),
+            redirect_uri: redirect[0].clone(),
+            scope: "openid email".to_string(),
+            nonce: Some("n-1".to_string()),
+            code_challenge: "challenge".to_string(),
+            auth_time: Utc::now().to_rfc3339(),
+        };
+
+        let future = (Utc::now() + chrono::Duration::minutes(5)).to_rfc3339();
+        hub.create_oidc_auth_code("code-1", &code, &future).unwrap();
+        let taken = hub.take_oidc_auth_code("code-1").unwrap().unwrap();
+        assert_eq!(taken.user_id, alice.user_id);
+        assert_eq!(taken.nonce.as_deref(), Some("n-1"));
+        assert!(hub.take_oidc_auth_code("code-1").unwrap().is_none());
+
+        let past = (Utc::now() - chrono::Duration::minutes(1)).to_rfc3339();
+        hub.create_oidc_auth_code("code-2", &code, &past).unwrap();
+        assert!(hub.take_oidc_auth_code("code-2").unwrap().is_none());
+    }
+
+    #[test]
+    fn test_passkey_required() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        assert!(!hub.is_passkey_required(&alice.user_id).unwrap());
+        hub.set_passkey_required(&alice.user_id, true).unwrap();
+        assert!(hub.is_passkey_required(&alice.user_id).unwrap());
+        assert!(!hub.is_passkey_required("unknown").unwrap());
+    }
+
+    #[test]
+    fn test_login_throttle_and_lockout() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        assert_eq!(hub.record_failed_login(&alice.user_id).unwrap(), 1);
+        assert_eq!(hub.record_failed_login(&alice.user_id).unwrap(), 2);
+        let throttle = hub.get_login_throttle(&alice.user_id).unwrap();
+        assert_eq!(throttle.failed_logins, 2);
+        assert!(throttle.last_failed_at.is_some());
+        assert!(hub.list_locked_accounts().unwrap().is_empty());
+
+        let until = (Utc::now() + chrono::Duration::minutes(15)).to_rfc3339();
+        hub.lock_account(&alice.user_id, &until).unwrap();
+        let locked = hub.list_locked_accounts().unwrap();
+        assert_eq!(locked.len(), 1);
+        assert_eq!(locked[0].failed_logins, 2);
+
+        hub.clear_failed_logins(&alice.user_id).unwrap();
+        let throttle = hub.get_login_throttle(&alice.user_id).unwrap();
+        assert_eq!(throttle.failed_logins, 0);
+        assert!(throttle.locked_until.is_none());
+        assert!(hub.list_locked_accounts().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_trusted_proxies() {
+        let hub = hub();
+        assert_eq!(hub.get_trusted_proxies().unwrap().len(), DEFAULT_TRUSTED_PROXIES.len());
+        let proxies = vec!["10.0.0.0/8".to_string(), "192.168.1.2".to_string()];
+        hub.set_trusted_proxies(&proxies).unwrap();
+        assert_eq!(hub.get_trusted_proxies().unwrap(), proxies);
+    }
+
+    fn perms(list: &[&str]) -> Vec<String> {
+        list.iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn test_roles_grant_only_held_permissions() {
+        let hub = hub();
+        let owner = add_user(&hub, "owner", true);
+        let bob = add_user(&hub, "bob", false);
+        let carol = add_user(&hub, "carol", false);
+        assert_eq!(owner.role, roles::ROLE_OWNER);
+
+        let manager = hub.create_role(&owner.user_id, "User manager", &perms(&[
+            roles::PERM_USERS_MANAGE, roles::PERM_MEMBERS_VIEW, roles::PERM_FILES_READ,
+            roles::PERM_FILES_UPLOAD, roles::PERM_MESSAGES_SEND,
+        ])).unwrap();
+        hub.set_user_role(&owner.user_id, &bob.user_id, &manager.role_id).unwrap();
+        assert!(hub.get_user_by_id(&bob.user_id).unwrap().unwrap().is_admin);
+
+        // Roles within bob's own permissions can be granted, wider ones can't
+        hub.set_user_role(&bob.user_id, &carol.user_id, roles::ROLE_GUEST).unwrap();
+        hub.set_user_role(&bob.user_id, &carol.user_id, roles::ROLE_MEMBER).unwrap();
+        assert!(hub.set_user_role(&bob.user_id, &carol.user_id, roles::ROLE_MODERATOR).is_err());
+        assert!(hub.set_user_role(&bob.user_id, &carol.user_id, roles::ROLE_ADMIN).is_err());
+        assert!(hub.set_user_role(&bob.user_id, &carol.user_id, roles::ROLE_OWNER).is_err());
+        assert_eq!(hub.get_user_by_id(&carol.user_id).unwrap().unwrap().role, roles::ROLE_MEMBER);
+
+        assert!(hub.create_role(&bob.user_id, "Hub admin", &perms(&[roles::PERM_HUB_MANAGE])).is_err());
+        let viewer = hub.create_role(&bob.user_id, "Viewer", &perms(&[roles::PERM_MEMBERS_VIEW])).unwrap();
+        assert!(hub.update_role(&bob.user_id, &viewer.role_id, "Viewer", &perms(&[roles::PERM_CONTENT_MODERATE])).is_err());
+        hub.update_role(&bob.user_id, &viewer.role_id, "Readers", &perms(&[roles::PERM_FILES_READ])).unwrap();
+
+        // Nor can bob touch a role that already holds more than he does
+        let mods = hub.create_role(&owner.user_id, "Mods", &perms(&[roles::PERM_CONTENT_MODERATE])).unwrap();
+        assert!(hub.update_role(&bob.user_id, &mods.role_id, "Mods", &perms(&[])).is_err());
+        assert_eq!(hub.get_role(&mods.role_id).unwrap().unwrap().permissions, perms(&[roles::PERM_CONTENT_MODERATE]));
+    }
+
+    #[test]
+    fn test_reset_password_revokes_sessions() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        let scopes = vec!["files:read".to_string()];
+        hub.create_api_token(&alice.user_id, "sync", "hash-1", &scopes, None).unwrap();
+        hub.lock_account(&alice.user_id, &(Utc::now() + chrono::Duration::minutes(5)).to_rfc3339()).unwrap();
+        let issued = Utc::now().timestamp() - 10;
+        assert!(!hub.is_session_revoked(&alice.user_id, issued).unwrap());
+
+        hub.reset_password(&alice.user_id, "new-hash").unwrap();
+        assert_eq!(hub.get_password_hash("alice").unwrap().as_deref(), Some("new-hash"));
+        assert!(hub.is_session_revoked(&alice.user_id, issued).unwrap());
+        assert!(!hub.is_session_revoked(&alice.user_id, Utc::now().timestamp() + 10).unwrap());
+        assert!(hub.use_api_token("hash-1").unwrap().is_none());
+        assert!(hub.get_login_throttle(&alice.user_id).unwrap().locked_until.is_none());
+    }
+
+    #[test]
+    fn test_search_users_escapes_wildcards() {
+        let hub = hub();
+        add_user(&hub, "alice_smith", false);
+        add_user(&hub, "alicexsmith", false);
+        add_user(&hub, "bob", false);
+
+        let (users, total) = hub.search_users(Some("alice_"), 10, 0).unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(users[0].username, "alice_smith");
+        assert_eq!(hub.search_users(Some("%"), 10, 0).unwrap().1, 0);
+        assert_eq!(hub.search_users(Some("ALICE"), 10, 0).unwrap().1, 2);
+        let (page, total) = hub.search_users(None, 2, 0).unwrap();
+        assert_eq!((page.len(), total), (2, 3));
+    }
+
+    #[test]
+    fn test_suspension() {
+        let hub = hub();
+        let owner = add_user(&hub, "owner", true);
+        let alice = add_user(&hub, "alice", false);
+        assert!(hub.suspend_user(&owner.user_id, None, None).is_err());
+
+        let until = (Utc::now() + chrono::Duration::days(1)).with_timezone(&chrono::FixedOffset::east_opt(3600).unwrap());
+        hub.suspend_user(&alice.user_id, Some("spam"), Some(&until.to_rfc3339())).unwrap();
+        let suspension = hub.get_suspension(&alice.user_id).unwrap().unwrap();
+        assert_eq!(suspension.reason.as_deref(), Some("spam"));
+        assert!(suspension.suspended_until.unwrap().ends_with("+00:00"));
+        assert!(hub.get_user_by_id(&alice.user_id).unwrap().unwrap().suspension.is_some());
+        assert!(hub.suspend_user(&alice.user_id, None, Some("2000-01-01T00:00:00Z")).is_err());
+
+        hub.unsuspend_user(&alice.user_id).unwrap();
+        assert!(!hub.is_suspended(&alice.user_id).unwrap());
+
+        // A suspension whose end has passed no longer applies
+        hub.suspend_user(&alice.user_id, None, None).unwrap();
+        assert!(hub.is_suspended(&alice.user_id).unwrap());
+        hub.db().execute(
+            "UPDATE users SET suspended_until = '2000-01-01T00:00:00+00:00' WHERE user_id = ?1",
+            [&alice.user_id],
+        ).unwrap();
+        assert!(!hub.is_suspended(&alice.user_id).unwrap());
+    }
+
+    fn image_bytes(width: u32, height: u32, format: image::ImageFormat) -> Vec<u8> {
+        let mut out = Vec::new();
+        image::DynamicImage::new_rgb8(width, height)
+            .write_to(&mut std::io::Cursor::new(&mut out), format)
+            .unwrap();
+        out
+    }
+
+    #[test]
+    fn test_avatars_are_reencoded_and_kept_apart_from_files() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        assert!(hub.set_avatar(&alice.user_id, b"<svg onload=alert(1)>").is_err());
+        assert!(hub.set_avatar(&alice.user_id, b"\x89PNG\r\n\x1a\nnot really").is_err());
+
+        let profile = hub.set_avatar(&alice.user_id, &image_bytes(1024, 600, image::ImageFormat::Jpeg)).unwrap();
+        assert!(profile.avatar_updated_at.is_some());
+        let stored = hub.read_avatar(&alice.user_id).unwrap().unwrap();
+        assert_eq!(image::guess_format(&stored).unwrap(), image::ImageFormat::Png);
+        let decoded = image::load_from_memory(&stored).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (512, 300));
+
+        assert!(hub.list_files(Some(&alice.user_id)).unwrap().is_empty());
+        assert_eq!(hub.get_storage_status().unwrap().file_count, 0);
+        assert!(hub.list_profiles().unwrap().contains_key(&alice.user_id));
+
+        hub.clear_avatar(&alice.user_id).unwrap();
+        assert!(hub.read_avatar(&alice.user_id).unwrap().is_none());
+        assert!(!hub.avatar_path(&alice.user_id).exists());
+    }
+
+    #[test]
+    fn test_list_all_messages_pages() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        let conv = hub.create_group_conversation(&alice.user_id, "Team", &[]).unwrap();
+        let sent: Vec<String> = (0..5)
+            .map(|i| hub.create_message(&conv.conversation_id, &alice.user_id, &format!("m{}", i), &[], None, None).unwrap().message_id)
+            .collect();
+
+        let mut read = Vec::new();
+        let mut pages = 0;
+        loop {
+            let page = hub.list_all_messages(&conv.conversation_id, read.last(), 2).unwrap();
+            pages += 1;
+            let done = page.len() < 2;
+            read.extend(page);
+            if done {
+                break;
+            }
+        }
+        assert_eq!(pages, 3);
+        assert_eq!(read.iter().map(|m| m.message_id.clone()).collect::<Vec<_>>(), sent);
+    }
+
+    #[test]
+    fn test_export_jobs() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+
+        let (job, created) = hub.create_export_job(&alice.user_id).unwrap();
+        assert!(created);
+        let (again, created) = hub.create_export_job(&alice.user_id).unwrap();
+        assert!(!created);
+        assert_eq!(again.job_id, job.job_id);
+
+        // Ready jobs are deleted along with their archive once they expire
+        let archive = hub.export_archive_path(&job.job_id);
+        fs::create_dir_all(archive.parent().unwrap()).unwrap();
+        fs::write(&archive, b"zip").unwrap();
+        let past = (Utc::now() - chrono::Duration::minutes(1)).to_rfc3339();
+        hub.finish_export_job(&job.job_id, 3, &past).unwrap();
+        assert_eq!(hub.get_export_job(&alice.user_id, &job.job_id).unwrap().unwrap().status, "ready");
+        hub.purge_expired_exports().unwrap();
+        assert!(hub.get_export_job(&alice.user_id, &job.job_id).unwrap().is_none());
+        assert!(!archive.exists());
+
+        let (job, _) = hub.create_export_job(&alice.user_id).unwrap();
+        hub.fail_export_job(&job.job_id, "Export failed").unwrap();
+        let failed = hub.get_export_job(&alice.user_id, &job.job_id).unwrap().unwrap();
+        assert_eq!(failed.status, "failed");
+        assert!(hub.create_export_job(&alice.user_id).unwrap().1);
+    }
+
+    #[test]
+    fn test_audit_filters() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", true);
+        hub.audit(Some(&alice.user_id), "user.role", None, None, None);
+        hub.audit(Some(&alice.user_id), "user_x.role", None, None, None);
+        hub.audit(Some(&alice.user_id), "userxx.role", None, None, None);
+        let filter = |action: &str, since: Option<String>, until: Option<String>| AuditFilter {
+            action: Some(action.to_string()),
+            since,
+            until,
+            ..Default::default()
+        };
+
+        // `_` in a prefix is matched literally
+        let (events, total) = hub.list_audit_events(&filter("user_x.", None, None), 10, 0).unwrap();
+        assert_eq!(total, 1);
+        assert_eq!(events[0].action, "user_x.role");
+        assert_eq!(events[0].actor_name.as_deref(), Some("alice"));
+
+        // Bounds with an offset are compared in UTC
+        let east = |hours: i64| (Utc::now() + chrono::Duration::hours(hours))
+            .with_timezone(&chrono::FixedOffset::east_opt(5 * 3600).unwrap())
+            .to_rfc3339();
+        assert_eq!(hub.list_audit_events(&filter("user.", Some(east(-1)), Some(east(1))), 10, 0).unwrap().1, 1);
+        assert_eq!(hub.list_audit_events(&filter("user.", Some(east(1)), None), 10, 0).unwrap().1, 0);
+        assert!(hub.list_audit_events(&filter("user.", Some("yesterday".into()), None), 10, 0).is_err());
+    }
+
+    #[test]
+    fn test_guest_sign_up_and_retirement() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", true);
+        let conv = hub.create_group_conversation(&alice.user_id, "Event", &[]).unwrap();
+        let in_an_hour = (Utc::now() + chrono::Duration::hours(1)).to_rfc3339();
+        let conversations = vec![conv.conversation_id.clone()];
+        hub.create_guest_invite(&alice.user_id, "invite-hash", &conversations, &in_an_hour, Some(1)).unwrap();
+
+        // A failed sign-up doesn't use up the invite
+        assert!(hub.sign_up_guest("invite-hash", "alice", "hash").is_err());
+        assert!(hub.sign_up_guest("wrong-hash", "visitor", "hash").unwrap().is_none());
+        let (_, guest) = hub.sign_up_guest("invite-hash", "visitor", "hash").unwrap().unwrap();
+        assert!(hub.is_conversation_member(&conv.conversation_id, &guest.user_id).unwrap());
+        assert!(hub.sign_up_guest("invite-hash", "visitor2", "hash").unwrap().is_none());
+
+        // Expire two guests; both are retired under distinct names
+        let other = hub.create_guest("visitor3", "hash", &in_an_hour, &[]).unwrap();
+        let past = (Utc::now() - chrono::Duration::minutes(1)).to_rfc3339();
+        hub.db.execute("UPDATE users SET expires_at = ?1", [&past]).unwrap();
+        hub.db.execute("UPDATE users SET expires_at = NULL WHERE user_id = ?1", [&alice.user_id]).unwrap();
+        let mut retired = hub.purge_expired_guests().unwrap();
+        retired.sort();
+        let mut expected = vec![guest.user_id.clone(), other.user_id.clone()];
+        expected.sort();
+        assert_eq!(retired, expected);
+
+        let renamed = hub.get_user_by_id(&guest.user_id).unwrap().unwrap();
+        assert_eq!(renamed.username, format!("former-guest-{}", guest.user_id));
+        assert!(!hub.is_conversation_member(&conv.conversation_id, &guest.user_id).unwrap());
+        assert!(hub.purge_expired_guests().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_edit_and_delete_message() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        let bob = add_user(&hub, "bob", false);
+        let conv = hub.create_group_conversation(&alice.user_id, "Team", std::slice::from_ref(&bob.user_id)).unwrap();
+        let message = hub.create_message(&conv.conversation_id, &alice.user_id, "first", &[], None, None).unwrap();
+
+        assert!(hub.edit_message(&message.message_id, &bob.user_id, "hijack").is_err());
+        let edited = hub.edit_message(&message.message_id, &alice.user_id, "second").unwrap();
+        assert_eq!(edited.body, "second");
+        assert!(edited.edited_at.is_some());
+        let history = hub.list_message_edits(&message.message_id).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].body, "first");
+
+        hub.add_reaction(&message.message_id, &bob.user_id, "👍").unwrap();
+        let deleted = hub.delete_message(&message.message_id).unwrap();
+        assert!(deleted.body.is_empty() && deleted.deleted_at.is_some());
+        assert!(hub.list_message_edits(&message.message_id).unwrap().is_empty());
+        let stored = hub.get_message(&message.message_id).unwrap().unwrap();
+        assert!(stored.reactions.is_empty());
+        assert!(hub.edit_message(&message.message_id, &alice.user_id, "third").is_err());
+        assert_eq!(hub.delete_message(&message.message_id).unwrap().deleted_at, deleted.deleted_at);
+    }
+
+    #[test]
+    fn test_validate_reaction() {
+        for ok in ["👍", "🎉🎉", "👨‍👩‍👧‍👦", "🇳🇱", "👍🏽", "1️⃣", "❤️"] {
+            assert!(validate_reaction(ok).is_ok(), "{} should be allowed", ok);
+        }
+        for bad in ["", "a", "ok", "é", "中", "👍 ", "👍\n", "!", "👍👍👍👍👍"] {
+            assert!(validate_reaction(bad).is_err(), "{:?} should be refused", bad);
+        }
+        assert!(validate_reaction(&"👨‍👩‍👧‍👦".repeat(3)).is_err());
+    }
+
+    #[test]
+    fn test_list_messages_includes_reactions() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        let bob = add_user(&hub, "bob", false);
+        let conv = hub.create_group_conversation(&alice.user_id, "Team", std::slice::from_ref(&bob.user_id)).unwrap();
+        let first = hub.create_message(&conv.conversation_id, &alice.user_id, "first", &[], None, None).unwrap();
+        let second = hub.create_message(&conv.conversation_id, &bob.user_id, "second", &[], None, None).unwrap();
+        hub.add_reaction(&first.message_id, &alice.user_id, "👍").unwrap();
+        hub.add_reaction(&first.message_id, &bob.user_id, "👍").unwrap();
+        hub.add_reaction(&second.message_id, &alice.user_id, "🎉").unwrap();
+
+        let listed = hub.list_messages(&conv.conversation_id, 10, None).unwrap();
+        for message in &listed {
+            assert_eq!(message.reactions, hub.list_reactions(&message.message_id).unwrap());
+        }
+        let first = listed.iter().find(|m| m.message_id == first.message_id).unwrap();
+        assert_eq!(first.reactions.len(), 1);
+        assert_eq!(first.reactions[0].count, 2);
+    }
+
+    #[test]
+    fn test_replies_attach_to_the_thread_root() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        let conv = hub.create_group_conversation(&alice.user_id, "Team", &[]).unwrap();
+        let other = hub.create_group_conversation(&alice.user_id, "Other", &[]).unwrap();
+        let send = |parent: Option<&str>| hub.create_message(&conv.conversation_id, &alice.user_id, "hi", &[], parent, None);
+
+        let root = send(None).unwrap();
+        let reply = send(Some(&root.message_id)).unwrap();
+        let nested = send(Some(&reply.message_id)).unwrap();
+        assert_eq!(nested.parent_id.as_deref(), Some(root.message_id.as_str()));
+        assert!(hub.create_message(&other.conversation_id, &alice.user_id, "hi", &[], Some(&root.message_id), None).is_err());
+
+        // Once the root is gone, replying through one of its replies fails too
+        hub.delete_message(&root.message_id).unwrap();
+        assert!(send(Some(&root.message_id)).is_err());
+        assert!(send(Some(&reply.message_id)).is_err());
+    }
+
+    #[test]
+    fn test_read_cursors_and_unread_counts() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        let bob = add_user(&hub, "bob", false);
+        let conv = hub.create_group_conversation(&alice.user_id, "Team", std::slice::from_ref(&bob.user_id)).unwrap();
+        let id = conv.conversation_id.as_str();
+        let sent: Vec<Message> = (0..3)
+            .map(|i| hub.create_message(id, &alice.user_id, &format!("m{}", i), &[], None, None).unwrap())
+            .collect();
+
+        // Senders have read their own messages
+        assert_eq!(hub.unread_count(id, &alice.user_id).unwrap(), 0);
+        assert_eq!(hub.unread_count(id, &bob.user_id).unwrap(), 3);
+
+        let member = hub.mark_conversation_read(id, &bob.user_id, Some(&sent[1].message_id)).unwrap().unwrap();
+        assert_eq!(member.last_read_message_id.as_deref(), Some(sent[1].message_id.as_str()));
+        assert_eq!(hub.unread_count(id, &bob.user_id).unwrap(), 1);
+
+        // The cursor never moves backwards
+        assert!(hub.mark_conversation_read(id, &bob.user_id, Some(&sent[0].message_id)).unwrap().is_none());
+        assert!(hub.mark_conversation_read(id, &bob.user_id, Some("missing")).is_err());
+
+        // Deleted messages don't count as unread
+        hub.delete_message(&sent[2].message_id).unwrap();
+        assert_eq!(hub.unread_count(id, &bob.user_id).unwrap(), 0);
+        hub.create_message(id, &alice.user_id, "m3", &[], None, None).unwrap();
+        let listed = hub.list_conversations(&bob.user_id).unwrap();
+        assert_eq!(listed[0].unread_count, 1);
+
+        assert!(hub.mark_conversation_read(id, &bob.user_id, None).unwrap().is_some());
+        assert_eq!(hub.list_conversations(&bob.user_id).unwrap()[0].unread_count, 0);
+    }
+
+    #[test]
+    fn test_events_since() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        let bob = add_user(&hub, "bob", false);
+        let recipients = vec![alice.user_id.clone(), bob.user_id.clone(), alice.user_id.clone(), "missing".to_string()];
+        let seqs = hub.record_event(&recipients, "one").unwrap();
+        assert_eq!(seqs.len(), 2);
+        hub.record_event(std::slice::from_ref(&alice.user_id), "two").unwrap();
+        hub.record_event(std::slice::from_ref(&alice.user_id), "three").unwrap();
+        assert_eq!(hub.event_seq(&alice.user_id).unwrap(), 3);
+        assert_eq!(hub.event_seq(&bob.user_id).unwrap(), 1);
+
+        let events = hub.events_since(&alice.user_id, 1, 10).unwrap().unwrap();
+        assert_eq!(events, vec![(2, "two".to_string()), (3, "three".to_string())]);
+        assert_eq!(hub.events_since(&alice.user_id, 3, 10).unwrap(), Some(Vec::new()));
+
+        // Too many to replay, or a sequence number from the future
+        assert!(hub.events_since(&alice.user_id, 0, 2).unwrap().is_none());
+        assert!(hub.events_since(&alice.user_id, 4, 10).unwrap().is_none());
+
+        // Purged events can't be replayed
+        hub.purge_user_events(&(Utc::now() + chrono::Duration::seconds(1)).to_rfc3339()).unwrap();
+        assert!(hub.events_since(&alice.user_id, 1, 10).unwrap().is_none());
+        assert_eq!(hub.events_since(&alice.user_id, 3, 10).unwrap(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_fts_query() {
+        assert_eq!(fts_query("hello wor").unwrap().as_deref(), Some("\"hello\"* \"wor\"*"));
+        // Quotes are escaped and operators are searched as words
+        assert_eq!(fts_query("say \"hi\" OR").unwrap().as_deref(), Some("\"say\"* \"\"\"hi\"\"\"* \"OR\"*"));
+        // Short words and punctuation are skipped
+        assert_eq!(fts_query("a cat -").unwrap().as_deref(), Some("\"cat\"*"));
+        assert!(fts_query("a b - ").unwrap().is_none());
+        assert!(fts_query(&"x".repeat(MAX_SEARCH_QUERY_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn test_search_visibility() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        let bob = add_user(&hub, "bob", false);
+        let carol = add_user(&hub, "carol", false);
+        let dave = add_user(&hub, "dave", false);
+        let team = hub.create_group_conversation(&alice.user_id, "Team", std::slice::from_ref(&bob.user_id)).unwrap();
+        let other = hub.create_group_conversation(&carol.user_id, "Other", std::slice::from_ref(&dave.user_id)).unwrap();
+
+        for body in ["budget one", "budget two", "budget three"] {
+            hub.create_message(&team.conversation_id, &alice.user_id, body, &[], None, None).unwrap();
+        }
+        let deleted = hub.create_message(&team.conversation_id, &alice.user_id, "budget gone", &[], None, None).unwrap();
+        hub.delete_message(&deleted.message_id).unwrap();
+        hub.create_message(&other.conversation_id, &carol.user_id, "budget elsewhere", &[], None, None).unwrap();
+
+        let found = hub.search_messages(&bob.user_id, "budg", 10).unwrap();
+        assert_eq!(found.len(), 3);
+        assert!(found.iter().all(|m| m.conversation_id == team.conversation_id));
+        assert_eq!(hub.search_messages(&bob.user_id, "budget", 2).unwrap().len(), 2);
+        assert_eq!(hub.search_messages(&dave.user_id, "budget", 10).unwrap().len(), 1);
+
+        hub.upload_file(&alice.user_id, "budget-private.txt", b"x", false).unwrap();
+        hub.upload_file(&alice.user_id, "budget-public.txt", b"x", true).unwrap();
+        let shared = hub.upload_file(&alice.user_id, "budget-shared.txt", b"x", false).unwrap();
+        hub.create_message(&team.conversation_id, &alice.user_id, "", std::slice::from_ref(&shared.file_id), None, None).unwrap();
+
+        let names = |user: &User| {
+            let mut names: Vec<String> = hub.search_files(&user.user_id, "budget", 10).unwrap()
+                .into_iter().map(|f| f.file_name).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(names(&alice), vec!["budget-private.txt", "budget-public.txt", "budget-shared.txt"]);
+        assert_eq!(names(&bob), vec!["budget-public.txt", "budget-shared.txt"]);
+        assert_eq!(names(&carol), vec!["budget-public.txt"]);
+        assert_eq!(hub.search_files(&alice.user_id, "budget", 1).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_channels() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        let bob = add_user(&hub, "bob", false);
+        let news = hub.create_channel(&alice.user_id, "news", true, true).unwrap();
+        let chat = hub.create_channel(&bob.user_id, "Chat", false, false).unwrap();
+        assert!(hub.is_conversation_member(&news.conversation_id, &alice.user_id).unwrap());
+        assert!(hub.create_channel(&alice.user_id, &"x".repeat(MAX_CONVERSATION_NAME_LEN + 1), false, false).is_err());
+        assert!(hub.rename_conversation(&chat.conversation_id, &"x".repeat(MAX_CONVERSATION_NAME_LEN + 1)).is_err());
+
+        // Listed by name, whatever the case, with counts and the caller's membership
+        let listing = hub.list_channels(&bob.user_id).unwrap();
+        let summary: Vec<(&str, u32, bool)> = listing.iter()
+            .map(|c| (c.conversation.name.as_deref().unwrap(), c.member_count, c.joined))
+            .collect();
+        assert_eq!(summary, vec![("Chat", 1, true), ("news", 1, false)]);
+        assert!(listing[1].conversation.announcement_only && listing[1].conversation.is_default);
+
+        // Only new members join default channels
+        let carol = add_user(&hub, "carol", false);
+        hub.join_default_channels(&carol.user_id).unwrap();
+        hub.join_default_channels(&carol.user_id).unwrap();
+        assert!(hub.is_conversation_member(&news.conversation_id, &carol.user_id).unwrap());
+        assert!(!hub.is_conversation_member(&chat.conversation_id, &carol.user_id).unwrap());
+        hub.update_channel(&chat.conversation_id, None, Some(true)).unwrap();
+        assert!(!hub.is_conversation_member(&chat.conversation_id, &alice.user_id).unwrap());
+        assert_eq!(hub.list_channels(&carol.user_id).unwrap()[1].member_count, 2);
+    }
+
+    #[test]
+    fn test_dm_conversations() {
+        let hub = hub();
+        let alice = add_user(&hub, "alice", false);
+        let bob = add_user(&hub, "bob", false);
+        let carol = add_user(&hub, "carol", false);
+
+        let (dm, created) = hub.create_dm_conversation(&alice.user_id, &bob.user_id).unwrap();
+        assert!(created);
+        let (again, created) = hub.create_dm_conversation(&bob.user_id, &alice.user_id).unwrap();
+        assert!(!created);
+        assert_eq!(again.conversation_id, dm.conversation_id);
+
+        // A DM with yourself isn't one of your DMs with someone else
+        assert!(hub.create_dm_conversation(&alice.user_id, &alice.user_id).is_err());
+        let (other, created) = hub.create_dm_conversation(&alice.user_id, &carol.user_id).unwrap();
+        assert!(created);
+        assert_ne!(other.conversation_id, dm.conversation_id);
+        assert!(hub.create_dm_conversation(&alice.user_id, "missing").is_err());
+    }
+}
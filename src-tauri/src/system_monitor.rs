@@ -140,7 +140,7 @@ impl SystemMonitor {
         // Detect Raspberry Pi by checking for ARM architecture and specific CPU info
         let is_raspberry_pi = cfg!(target_arch = "aarch64") || cfg!(target_arch = "arm") ||
             sys.cpus().first()
-                .and_then(|cpu| Some(cpu.brand().to_lowercase().contains("arm")))
+                .map(|cpu| cpu.brand().to_lowercase().contains("arm"))
                 .unwrap_or(false);
 
         Ok(HardwareInfo {
@@ -276,7 +276,7 @@ impl TailscaleManager {
                 let stderr_pipe = child.stderr.take();
                 let stderr_thread = std::thread::spawn(move || {
                     if let Some(pipe) = stderr_pipe {
-                        for line in BufReader::new(pipe).lines().flatten() {
+                        for line in BufReader::new(pipe).lines().map_while(Result::ok) {
                             log::info!("tailscale login stderr: {}", line);
                             if let Some(url) = TailscaleManager::find_url_in_line(&line) {
                                 let _ = tx2.send(url);
@@ -288,7 +288,7 @@ impl TailscaleManager {
 
                 // Stdout in this thread (parallel with stderr sub-thread)
                 if let Some(pipe) = child.stdout.take() {
-                    for line in BufReader::new(pipe).lines().flatten() {
+                    for line in BufReader::new(pipe).lines().map_while(Result::ok) {
                         log::info!("tailscale login stdout: {}", line);
                         if let Some(url) = TailscaleManager::find_url_in_line(&line) {
                             let _ = tx.send(url);
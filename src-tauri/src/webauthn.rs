@@ -0,0 +1,479 @@
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ciborium::Value as Cbor;
+use ring::signature::{self, UnparsedPublicKey};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a registration or sign-in ceremony stays open
+const CEREMONY_TTL: Duration = Duration::from_secs(300);
+/// Open ceremonies kept at most; the oldest is dropped to make room
+const MAX_PENDING_CEREMONIES: usize = 1000;
+
+// COSE algorithm identifiers we accept, in order of preference
+const COSE_EDDSA: i64 = -8;
+const COSE_ES256: i64 = -7;
+const COSE_RS256: i64 = -257;
+const SUPPORTED_ALGORITHMS: &[i64] = &[COSE_EDDSA, COSE_ES256, COSE_RS256];
+
+// Authenticator data flags
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_USER_VERIFIED: u8 = 0x04;
+const FLAG_ATTESTED_DATA: u8 = 0x40;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Ceremony {
+    /// Adding a passkey to a signed-in account
+    Register { user_id: String },
+    /// Signing in. `user_id` is set when the account is already known
+    /// (username hint or password as first factor) and restricts which
+    /// credentials are accepted.
+    Authenticate { user_id: Option<String> },
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingCeremony {
+    pub ceremony: Ceremony,
+    pub challenge: Vec<u8>,
+    pub rp_id: String,
+    started_at: Instant,
+}
+
+/// In-memory store of open ceremonies, keyed by a random ceremony ID that the
+/// client echoes back with the authenticator response. Entries are single use.
+#[derive(Clone, Default)]
+pub struct ChallengeStore {
+    pending: Arc<Mutex<HashMap<String, PendingCeremony>>>,
+}
+
+impl ChallengeStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open a ceremony and return its ID and the challenge bytes
+    pub fn start(&self, ceremony: Ceremony, rp_id: &str) -> Result<(String, Vec<u8>)> {
+        let mut challenge = vec![0u8; 32];
+        getrandom::fill(&mut challenge).context("Failed to generate challenge")?;
+        let mut id = [0u8; 16];
+        getrandom::fill(&mut id).context("Failed to generate ceremony ID")?;
+        let id = hex::encode(id);
+
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.retain(|_, c| c.started_at.elapsed() < CEREMONY_TTL);
+        while pending.len() >= MAX_PENDING_CEREMONIES {
+            let oldest = pending.iter()
+                .min_by_key(|(_, c)| c.started_at)
+                .map(|(id, _)| id.clone());
+            match oldest {
+                Some(oldest) => pending.remove(&oldest),
+                None => break,
+            };
+        }
+        pending.insert(id.clone(), PendingCeremony {
+            ceremony,
+            challenge: challenge.clone(),
+            rp_id: rp_id.to_string(),
+            started_at: Instant::now(),
+        });
+        Ok((id, challenge))
+    }
+
+    /// Remove and return an open ceremony, if it exists and hasn't expired
+    pub fn take(&self, id: &str) -> Option<PendingCeremony> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.remove(id).filter(|c| c.started_at.elapsed() < CEREMONY_TTL)
+    }
+}
+
+// --- Client responses (as serialized by the browser / @simplewebauthn-style helpers) ---
+
+#[derive(Debug, Deserialize)]
+pub struct RegistrationCredential {
+    /// Credential ID, base64url
+    pub id: String,
+    pub response: AttestationResponse,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AttestationResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "attestationObject")]
+    pub attestation_object: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuthenticationCredential {
+    /// Credential ID, base64url
+    pub id: String,
+    pub response: AssertionResponse,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AssertionResponse {
+    #[serde(rename = "clientDataJSON")]
+    pub client_data_json: String,
+    #[serde(rename = "authenticatorData")]
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+    origin: String,
+}
+
+/// A newly registered credential, ready to be stored
+#[derive(Debug)]
+pub struct VerifiedCredential {
+    /// Credential ID, base64url
+    pub credential_id: String,
+    /// COSE_Key encoding of the credential public key
+    pub public_key: Vec<u8>,
+    pub algorithm: i64,
+    pub sign_count: u32,
+}
+
+struct AuthenticatorData<'a> {
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+    /// Remainder after the fixed header (attested credential data, extensions)
+    rest: &'a [u8],
+}
+
+pub fn b64url(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Decode base64url, tolerating padding some clients leave in
+pub fn b64url_decode(s: &str) -> Result<Vec<u8>> {
+    URL_SAFE_NO_PAD.decode(s.trim_end_matches('=')).context("Invalid base64url")
+}
+
+/// `publicKey` options for `navigator.credentials.create()`
+pub fn creation_options(
+    challenge: &[u8],
+    rp_id: &str,
+    rp_name: &str,
+    user_id: &str,
+    username: &str,
+    exclude_credentials: &[String],
+) -> Value {
+    json!({
+        "challenge": b64url(challenge),
+        "rp": { "id": rp_id, "name": rp_name },
+        "user": {
+            "id": b64url(user_id.as_bytes()),
+            "name": username,
+            "displayName": username,
+        },
+        "pubKeyCredParams": SUPPORTED_ALGORITHMS.iter()
+            .map(|alg| json!({ "type": "public-key", "alg": alg }))
+            .collect::<Vec<_>>(),
+        "timeout": CEREMONY_TTL.as_millis() as u64,
+        "attestation": "none",
+        "authenticatorSelection": {
+            "residentKey": "preferred",
+            "userVerification": "required",
+        },
+        "excludeCredentials": exclude_credentials.iter()
+            .map(|id| json!({ "type": "public-key", "id": id }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// `publicKey` options for `navigator.credentials.get()`. An empty
+/// `allow_credentials` lets the authenticator offer discoverable passkeys.
+pub fn request_options(challenge: &[u8], rp_id: &str, allow_credentials: &[String]) -> Value {
+    json!({
+        "challenge": b64url(challenge),
+        "rpId": rp_id,
+        "timeout": CEREMONY_TTL.as_millis() as u64,
+        "userVerification": "required",
+        "allowCredentials": allow_credentials.iter()
+            .map(|id| json!({ "type": "public-key", "id": id }))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// Verify a registration response (attestation format is not checked; we
+/// request `attestation: "none"`) and extract the credential public key.
+pub fn verify_registration(
+    credential: &RegistrationCredential,
+    challenge: &[u8],
+    rp_id: &str,
+) -> Result<VerifiedCredential> {
+    let client_data = b64url_decode(&credential.response.client_data_json)?;
+    check_client_data(&client_data, "webauthn.create", challenge, rp_id)?;
+
+    let attestation = b64url_decode(&credential.response.attestation_object)?;
+    let attestation: Cbor = ciborium::de::from_reader(attestation.as_slice())
+        .context("Invalid attestation object")?;
+    let auth_data = attestation.as_map()
+        .and_then(|m| m.iter().find(|(k, _)| k.as_text() == Some("authData")))
+        .and_then(|(_, v)| v.as_bytes())
+        .ok_or_else(|| anyhow!("Attestation object has no authData"))?;
+
+    let auth_data = parse_authenticator_data(auth_data)?;
+    check_authenticator_data(&auth_data, rp_id)?;
+    if auth_data.flags & FLAG_ATTESTED_DATA == 0 {
+        bail!("Authenticator did not return a credential");
+    }
+
+    // Attested credential data: AAGUID (16), credential ID length (2), ID, COSE key
+    let rest = auth_data.rest;
+    if rest.len() < 18 {
+        bail!("Attested credential data is truncated");
+    }
+    let id_len = u16::from_be_bytes([rest[16], rest[17]]) as usize;
+    let credential_id = rest.get(18..18 + id_len)
+        .ok_or_else(|| anyhow!("Credential ID is truncated"))?;
+    if b64url(credential_id) != credential.id.trim_end_matches('=') {
+        bail!("Credential ID mismatch");
+    }
+
+    let mut key_bytes = &rest[18 + id_len..];
+    let before = key_bytes.len();
+    let key: Cbor = ciborium::de::from_reader(&mut key_bytes).context("Invalid credential public key")?;
+    let public_key = rest[18 + id_len..18 + id_len + (before - key_bytes.len())].to_vec();
+
+    let algorithm = cose_int(&key, 3).ok_or_else(|| anyhow!("Public key has no algorithm"))?;
+    if !SUPPORTED_ALGORITHMS.contains(&algorithm) {
+        bail!("Unsupported key algorithm {}", algorithm);
+    }
+
+    Ok(VerifiedCredential {
+        credential_id: b64url(credential_id),
+        public_key,
+        algorithm,
+        sign_count: auth_data.sign_count,
+    })
+}
+
+/// Verify a sign-in assertion against a stored credential and return the
+/// authenticator's new signature counter.
+pub fn verify_authentication(
+    credential: &AuthenticationCredential,
+    challenge: &[u8],
+    rp_id: &str,
+    public_key: &[u8],
+    stored_sign_count: u32,
+) -> Result<u32> {
+    let client_data = b64url_decode(&credential.response.client_data_json)?;
+    check_client_data(&client_data, "webauthn.get", challenge, rp_id)?;
+
+    let raw_auth_data = b64url_decode(&credential.response.authenticator_data)?;
+    let auth_data = parse_authenticator_data(&raw_auth_data)?;
+    check_authenticator_data(&auth_data, rp_id)?;
+
+    let mut signed = raw_auth_data.clone();
+    signed.extend_from_slice(&Sha256::digest(&client_data));
+    let signature = b64url_decode(&credential.response.signature)?;
+    verify_signature(public_key, &signed, &signature)?;
+
+    // A counter that doesn't advance suggests a cloned authenticator.
+    // Authenticators that don't keep a counter always report zero.
+    if (auth_data.sign_count != 0 || stored_sign_count != 0) && auth_data.sign_count <= stored_sign_count {
+        bail!("Signature counter did not increase");
+    }
+
+    Ok(auth_data.sign_count)
+}
+
+fn check_client_data(raw: &[u8], expected_type: &str, challenge: &[u8], rp_id: &str) -> Result<()> {
+    let client_data: ClientData = serde_json::from_slice(raw).context("Invalid clientDataJSON")?;
+    if client_data.kind != expected_type {
+        bail!("Unexpected ceremony type {}", client_data.kind);
+    }
+    if b64url_decode(&client_data.challenge)? != challenge {
+        bail!("Challenge mismatch");
+    }
+    if !origin_matches(&client_data.origin, rp_id) {
+        bail!("Origin {} is not valid for {}", client_data.origin, rp_id);
+    }
+    Ok(())
+}
+
+/// The origin must be the RP ID or a subdomain of it, over HTTPS
+/// (plain HTTP is only allowed for localhost, as in browsers).
+fn origin_matches(origin: &str, rp_id: &str) -> bool {
+    let Ok(url) = reqwest::Url::parse(origin) else { return false };
+    let Some(host) = url.host_str() else { return false };
+    let host_ok = host == rp_id || host.ends_with(&format!(".{}", rp_id));
+    let scheme_ok = url.scheme() == "https" || (url.scheme() == "http" && host == "localhost");
+    host_ok && scheme_ok
+}
+
+fn parse_authenticator_data(data: &[u8]) -> Result<AuthenticatorData<'_>> {
+    if data.len() < 37 {
+        bail!("Authenticator data is truncated");
+    }
+    Ok(AuthenticatorData {
+        rp_id_hash: &data[..32],
+        flags: data[32],
+        sign_count: u32::from_be_bytes([data[33], data[34], data[35], data[36]]),
+        rest: &data[37..],
+    })
+}
+
+fn check_authenticator_data(auth_data: &AuthenticatorData, rp_id: &str) -> Result<()> {
+    if auth_data.rp_id_hash != Sha256::digest(rp_id.as_bytes()).as_slice() {
+        bail!("RP ID hash mismatch");
+    }
+    if auth_data.flags & FLAG_USER_PRESENT == 0 {
+        bail!("User presence not confirmed");
+    }
+    if auth_data.flags & FLAG_USER_VERIFIED == 0 {
+        bail!("User verification not performed");
+    }
+    Ok(())
+}
+
+fn cose_field(key: &Cbor, label: i64) -> Option<&Cbor> {
+    key.as_map()?.iter()
+        .find(|(k, _)| k.as_integer().map(i128::from) == Some(label as i128))
+        .map(|(_, v)| v)
+}
+
+fn cose_int(key: &Cbor, label: i64) -> Option<i64> {
+    cose_field(key, label)?.as_integer().and_then(|i| i64::try_from(i).ok())
+}
+
+fn cose_bytes(key: &Cbor, label: i64) -> Result<&[u8]> {
+    cose_field(key, label)
+        .and_then(|v| v.as_bytes())
+        .map(|b| b.as_slice())
+        .ok_or_else(|| anyhow!("Public key is missing parameter {}", label))
+}
+
+/// Verify a signature with a COSE_Key-encoded public key
+fn verify_signature(cose_key: &[u8], message: &[u8], sig: &[u8]) -> Result<()> {
+    let key: Cbor = ciborium::de::from_reader(cose_key).context("Invalid stored public key")?;
+    let verified = match cose_int(&key, 3) {
+        Some(COSE_EDDSA) => {
+            UnparsedPublicKey::new(&signature::ED25519, cose_bytes(&key, -2)?)
+                .verify(message, sig)
+        }
+        Some(COSE_ES256) => {
+            let mut point = vec![0x04];
+            point.extend_from_slice(cose_bytes(&key, -2)?);
+            point.extend_from_slice(cose_bytes(&key, -3)?);
+            UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_ASN1, point)
+                .verify(message, sig)
+        }
+        Some(COSE_RS256) => {
+            signature::RsaPublicKeyComponents { n: cose_bytes(&key, -1)?, e: cose_bytes(&key, -2)? }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, sig)
+        }
+        other => bail!("Unsupported key algorithm {:?}", other),
+    };
+    verified.map_err(|_| anyhow!("Invalid signature"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn cbor(value: &Cbor) -> Vec<u8> {
+        let mut out = Vec::new();
+        ciborium::ser::into_writer(value, &mut out).unwrap();
+        out
+    }
+
+    fn client_data(kind: &str, challenge: &[u8]) -> String {
+        b64url(json!({
+            "type": kind,
+            "challenge": b64url(challenge),
+            "origin": "https://hub.example.org",
+        }).to_string().as_bytes())
+    }
+
+    #[test]
+    fn test_passkey_registration_and_sign_in() {
+        let rp_id = "example.org";
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let credential_id = [7u8; 16];
+
+        let cose_key = cbor(&Cbor::Map(vec![
+            (Cbor::from(1), Cbor::from(1)),
+            (Cbor::from(3), Cbor::from(COSE_EDDSA)),
+            (Cbor::from(-1), Cbor::from(6)),
+            (Cbor::from(-2), Cbor::Bytes(key_pair.public_key().as_ref().to_vec())),
+        ]));
+        let mut auth_data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        auth_data.push(FLAG_USER_PRESENT | FLAG_USER_VERIFIED | FLAG_ATTESTED_DATA);
+        auth_data.extend_from_slice(&0u32.to_be_bytes());
+        auth_data.extend_from_slice(&[0u8; 16]);
+        auth_data.extend_from_slice(&(credential_id.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(&credential_id);
+        auth_data.extend_from_slice(&cose_key);
+        let attestation = cbor(&Cbor::Map(vec![
+            (Cbor::from("fmt"), Cbor::from("none")),
+            (Cbor::from("attStmt"), Cbor::Map(vec![])),
+            (Cbor::from("authData"), Cbor::Bytes(auth_data)),
+        ]));
+
+        let challenge = [1u8; 32];
+        let registration = RegistrationCredential {
+            id: b64url(&credential_id),
+            response: AttestationResponse {
+                client_data_json: client_data("webauthn.create", &challenge),
+                attestation_object: b64url(&attestation),
+            },
+        };
+        let verified = verify_registration(&registration, &challenge, rp_id).unwrap();
+        assert_eq!(verified.algorithm, COSE_EDDSA);
+        assert_eq!(verified.public_key, cose_key);
+        assert!(verify_registration(&registration, &[2u8; 32], rp_id).is_err());
+        assert!(verify_registration(&registration, &challenge, "other.org").is_err());
+
+        // Sign in with the registered credential
+        let challenge = [3u8; 32];
+        let client_data_json = client_data("webauthn.get", &challenge);
+        let mut auth_data = Sha256::digest(rp_id.as_bytes()).to_vec();
+        auth_data.push(FLAG_USER_PRESENT | FLAG_USER_VERIFIED);
+        auth_data.extend_from_slice(&5u32.to_be_bytes());
+        let mut signed = auth_data.clone();
+        signed.extend_from_slice(&Sha256::digest(b64url_decode(&client_data_json).unwrap()));
+        let assertion = AuthenticationCredential {
+            id: b64url(&credential_id),
+            response: AssertionResponse {
+                client_data_json,
+                authenticator_data: b64url(&auth_data),
+                signature: b64url(key_pair.sign(&signed).as_ref()),
+            },
+        };
+        assert_eq!(verify_authentication(&assertion, &challenge, rp_id, &verified.public_key, 0).unwrap(), 5);
+        // Replayed counter is rejected
+        assert!(verify_authentication(&assertion, &challenge, rp_id, &verified.public_key, 5).is_err());
+    }
+
+    #[test]
+    fn test_challenge_store_is_bounded() {
+        let store = ChallengeStore::new();
+        let ceremony = Ceremony::Authenticate { user_id: None };
+        let (first, _) = store.start(ceremony.clone(), "example.org").unwrap();
+        let ids: Vec<String> = (0..MAX_PENDING_CEREMONIES)
+            .map(|_| store.start(ceremony.clone(), "example.org").unwrap().0)
+            .collect();
+
+        assert_eq!(store.pending.lock().unwrap().len(), MAX_PENDING_CEREMONIES);
+        assert!(store.take(&first).is_none());
+        let taken = store.take(&ids[0]).unwrap();
+        assert_eq!(taken.rp_id, "example.org");
+        assert!(store.take(&ids[0]).is_none());
+    }
+}
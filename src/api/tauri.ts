@@ -80,8 +80,144 @@ export interface User {
   username: string;
   email: string;
   is_admin: boolean;
+  /** Built-in role ("owner", "admin", "moderator", "member", "guest") or a custom role ID */
+  role: string;
   created_at: string;
   updated_at: string;
+  /** Present while the user is suspended */
+  suspension: Suspension | null;
+  /** Guest accounts stop working at this time */
+  expires_at: string | null;
+  /** When the member confirmed their email address */
+  email_verified_at: string | null;
+}
+
+export interface ImportReport {
+  created: {
+    row: number;
+    user_id: string;
+    username: string;
+    email: string;
+    role: string;
+    /** One-time code the member uses to choose a password */
+    setup_code: string;
+  }[];
+  errors: { row: number; username: string | null; error: string }[];
+  setup_codes_expire_at: string;
+}
+
+export interface GuestInvite {
+  invite_id: string;
+  conversation_ids: string[];
+  expires_at: string;
+  max_uses: number | null;
+  uses: number;
+  created_by: string;
+  created_at: string;
+}
+
+export interface Suspension {
+  reason: string | null;
+  suspended_at: string;
+  /** RFC 3339 end of the suspension; null when indefinite */
+  suspended_until: string | null;
+}
+
+export interface Role {
+  role_id: string;
+  name: string;
+  permissions: string[];
+  is_builtin: boolean;
+}
+
+export interface LockedAccount {
+  user_id: string;
+  username: string;
+  failed_logins: number;
+  locked_until: string;
+}
+
+export interface AuditEvent {
+  event_id: string;
+  created_at: string;
+  actor_id: string | null;
+  actor_name: string | null;
+  action: string;
+  target: string | null;
+  ip: string | null;
+  details: string | null;
+}
+
+/** A trailing "." on `action` matches every action with that prefix */
+export interface AuditFilter {
+  actor?: string;
+  action?: string;
+  target?: string;
+  since?: string;
+  until?: string;
+}
+
+export interface SmtpConfig {
+  host: string;
+  port: number;
+  username: string | null;
+  /** Write-only: omit to keep the stored password, "" to clear it */
+  password?: string;
+  tls: "none" | "starttls" | "tls";
+  from_address: string;
+  from_name: string | null;
+  /** Address members reach the hub at, for links in emails; defaults to the tunnel hostname */
+  public_url: string | null;
+}
+
+/** SmtpConfig as returned by the hub; the password is never sent back */
+export type SmtpSettings = Omit<SmtpConfig, "password"> & { has_password: boolean };
+
+export interface OutgoingEmail {
+  email_id: string;
+  to_address: string;
+  subject: string;
+  /** "verify", "password_reset" or "notification" */
+  kind: string;
+  /** "pending", "sent" or "failed" */
+  status: string;
+  attempts: number;
+  last_error: string | null;
+  next_attempt_at: string;
+  created_at: string;
+  sent_at: string | null;
+}
+
+export interface SigningKeyInfo {
+  kid: string;
+  /** "HS256", "EdDSA" or "ES256" */
+  algorithm: string;
+  active: boolean;
+  created_at: string;
+  retired_at: string | null;
+  /** When a retired key stops verifying tokens */
+  expires_at: string | null;
+}
+
+/** Argon2id cost used for new password hashes */
+export interface PasswordHashParams {
+  memory_kib: number;
+  iterations: number;
+  parallelism: number;
+}
+
+export interface OidcClient {
+  client_id: string;
+  name: string;
+  redirect_uris: string[];
+  /** Confidential clients authenticate to the token endpoint with a secret */
+  is_confidential: boolean;
+  created_at: string;
+}
+
+export interface NewOidcClient extends OidcClient {
+  /** Only returned at creation time; null for public (PKCE-only) clients */
+  client_secret: string | null;
 }
 
 // --- Tunnel types ---
@@ -204,10 +340,169 @@ export class CitinetAPI {
     return await invoke("delete_user", { userId });
   }
 
+  static async suspendUser(userId: string, reason?: string, until?: string): Promise<void> {
+    return await invoke("suspend_user", { userId, reason, until });
+  }
+
+  static async unsuspendUser(userId: string): Promise<void> {
+    return await invoke("unsuspend_user", { userId });
+  }
+
+  /** Create accounts from a `username,email,role` CSV */
+  static async importMembers(csv: string): Promise<ImportReport> {
+    return await invoke<ImportReport>("import_members", { csv });
+  }
+
+  /** The member list as CSV */
+  static async exportMembers(): Promise<string> {
+    return await invoke<string>("export_members");
+  }
+
+  /** Create a guest account; the temporary password is returned only once */
+  static async createGuest(
+    username: string,
+    expiresAt: string,
+    conversationIds: string[] = [],
+  ): Promise<User & { temporary_password: string }> {
+    return await invoke("create_guest", { username, expiresAt, conversationIds });
+  }
+
+  static async listGuestInvites(): Promise<GuestInvite[]> {
+    return await invoke<GuestInvite[]>("list_guest_invites");
+  }
+
+  /** Create a guest invite; the token is returned only once */
+  static async createGuestInvite(
+    expiresAt: string,
+    conversationIds: string[] = [],
+    maxUses?: number,
+  ): Promise<GuestInvite & { token: string }> {
+    return await invoke("create_guest_invite", { expiresAt, conversationIds, maxUses });
+  }
+
+  static async deleteGuestInvite(inviteId: string): Promise<void> {
+    return await invoke("delete_guest_invite", { inviteId });
+  }
+
+  static async logoutUser(): Promise<void> {
+    return await invoke("logout_user");
+  }
+
   static async updateUserRole(userId: string, isAdmin: boolean): Promise<void> {
     return await invoke("update_user_role", { userId, isAdmin });
   }
 
+  static async setUserRole(userId: string, role: string): Promise<void> {
+    return await invoke("set_user_role", { userId, role });
+  }
+
+  // --- Roles ---
+
+  static async listRoles(): Promise<Role[]> {
+    return await invoke<Role[]>("list_roles");
+  }
+
+  static async createRole(name: string, permissions: string[]): Promise<Role> {
+    return await invoke<Role>("create_role", { name, permissions });
+  }
+
+  static async updateRole(roleId: string, name: string, permissions: string[]): Promise<Role> {
+    return await invoke<Role>("update_role", { roleId, name, permissions });
+  }
+
+  static async deleteRole(roleId: string): Promise<void> {
+    return await invoke("delete_role", { roleId });
+  }
+
+  static async listLockedAccounts(): Promise<LockedAccount[]> {
+    return await invoke<LockedAccount[]>("list_locked_accounts");
+  }
+
+  static async unlockUser(userId: string): Promise<void> {
+    return await invoke("unlock_user", { userId });
+  }
+
+  static async listAuditEvents(
+    filter: AuditFilter = {},
+    limit?: number,
+    offset?: number,
+  ): Promise<{ events: AuditEvent[]; total: number }> {
+    return await invoke("list_audit_events", { filter, limit, offset });
+  }
+
+  /** Addresses or CIDR ranges allowed to set X-Forwarded-For / CF-Connecting-IP */
+  static async getTrustedProxies(): Promise<string[]> {
+    return await invoke<string[]>("get_trusted_proxies");
+  }
+
+  static async setTrustedProxies(proxies: string[]): Promise<void> {
+    return await invoke("set_trusted_proxies", { proxies });
+  }
+
+  // --- Outgoing email ---
+
+  /** null while outgoing email is off */
+  static async getSmtpConfig(): Promise<SmtpSettings | null> {
+    return await invoke<SmtpSettings | null>("get_smtp_config");
+  }
+
+  static async setSmtpConfig(config: SmtpConfig): Promise<SmtpSettings> {
+    return await invoke<SmtpSettings>("set_smtp_config", { config });
+  }
+
+  static async clearSmtpConfig(): Promise<void> {
+    return await invoke("clear_smtp_config");
+  }
+
+  /** Sends immediately with the saved settings; rejects with the SMTP error */
+  static async sendTestEmail(to: string): Promise<void> {
+    return await invoke("send_test_email", { to });
+  }
+
+  static async listEmailOutbox(): Promise<OutgoingEmail[]> {
+    return await invoke<OutgoingEmail[]>("list_email_outbox");
+  }
+
+  // --- Signing keys ---
+
+  static async listSigningKeys(): Promise<SigningKeyInfo[]> {
+    return await invoke<SigningKeyInfo[]>("list_signing_keys");
+  }
+
+  /** Omitting graceHours keeps the old key valid for the full token lifetime. */
+  static async rotateSigningKey(algorithm: string, graceHours?: number): Promise<SigningKeyInfo> {
+    return await invoke<SigningKeyInfo>("rotate_signing_key", { algorithm, graceHours });
+  }
+
+  // --- Password hashing ---
+
+  static async getPasswordHashParams(): Promise<PasswordHashParams> {
+    return await invoke<PasswordHashParams>("get_password_hash_params");
+  }
+
+  /** Existing passwords are rehashed with the new parameters as members sign in */
+  static async setPasswordHashParams(params: PasswordHashParams): Promise<void> {
+    return await invoke("set_password_hash_params", { params });
+  }
+
+  // --- OIDC clients ---
+
+  static async listOidcClients(): Promise<OidcClient[]> {
+    return await invoke<OidcClient[]>("list_oidc_clients");
+  }
+
+  static async createOidcClient(
+    name: string,
+    redirectUris: string[],
+    confidential: boolean,
+  ): Promise<NewOidcClient> {
+    return await invoke<NewOidcClient>("create_oidc_client", { name, redirectUris, confidential });
+  }
+
+  static async deleteOidcClient(clientId: string): Promise<void> {
+    return await invoke("delete_oidc_client", { clientId });
+  }
+
   // --- File operations ---
 
   static async listFiles(): Promise<FileInfo[]> {
@@ -1,10 +1,12 @@
 import { useState, useEffect, useCallback } from "react";
 import { Card } from "../ui/Card";
-import { CitinetAPI, TailscaleStatus, TunnelStatus, User } from "../../api/tauri";
+import {
+  AuditEvent, CitinetAPI, GuestInvite, ImportReport, OutgoingEmail, SmtpConfig, SmtpSettings, TailscaleStatus, TunnelStatus, User,
+} from "../../api/tauri";
 import { useConfigStore } from "../../stores/configStore";
 import {
   Globe, Link, Loader2, CheckCircle2, AlertCircle, Copy, Check,
-  Users, Shield, ShieldOff, Trash2, Share2, Mail, BookOpen,
+  Users, Shield, ShieldOff, Trash2, Share2, Mail, BookOpen, Ban, Undo2, ScrollText, Ticket, Upload, Download,
 } from "lucide-react";
 
 // --- Tunnel Section ---
@@ -672,10 +674,22 @@ function TunnelSection() {
 
 // --- Users Section ---
 
+function downloadText(text: string, fileName: string, type: string) {
+  const url = URL.createObjectURL(new Blob([text], { type }));
+  const a = document.createElement("a");
+  a.href = url;
+  a.download = fileName;
+  document.body.appendChild(a);
+  a.click();
+  document.body.removeChild(a);
+  URL.revokeObjectURL(url);
+}
+
 function UsersSection() {
   const [users, setUsers] = useState<User[]>([]);
   const [loading, setLoading] = useState(true);
   const [error, setError] = useState<string | null>(null);
+  const [importReport, setImportReport] = useState<ImportReport | null>(null);
 
   const refresh = useCallback(() => {
     CitinetAPI.listUsers()
@@ -694,6 +708,52 @@ function UsersSection() {
     }
   };
 
+  const handleToggleSuspend = async (user: User) => {
+    try {
+      if (user.suspension) {
+        await CitinetAPI.unsuspendUser(user.user_id);
+      } else {
+        const reason = prompt(`Suspend "${user.username}"? Their files and messages are kept.\nReason (optional):`);
+        if (reason === null) return;
+        await CitinetAPI.suspendUser(user.user_id, reason || undefined);
+      }
+      refresh();
+    } catch (e) {
+      setError(e instanceof Error ? e.message : String(e));
+    }
+  };
+
+  const handleImport = async (e: React.ChangeEvent<HTMLInputElement>) => {
+    const file = e.target.files?.[0];
+    e.target.value = "";
+    if (!file) return;
+    try {
+      const report = await CitinetAPI.importMembers(await file.text());
+      setImportReport(report);
+      refresh();
+    } catch (err) {
+      setError(err instanceof Error ? err.message : String(err));
+    }
+  };
+
+  const handleExport = async () => {
+    try {
+      const csv = await CitinetAPI.exportMembers();
+      downloadText(csv, "members.csv", "text/csv");
+    } catch (e) {
+      setError(e instanceof Error ? e.message : String(e));
+    }
+  };
+
+  // Setup codes are only shown once, so offer them as a file to hand out
+  const handleSaveSetupCodes = () => {
+    if (!importReport) return;
+    const lines = ["username,email,setup_code"].concat(
+      importReport.created.map((m) => `${m.username},${m.email},${m.setup_code}`),
+    );
+    downloadText(lines.join("\n") + "\n", "setup-codes.csv", "text/csv");
+  };
+
   const handleDelete = async (user: User) => {
     if (!confirm(`Remove user "${user.username}"? Their files will be deleted.`)) return;
     try {
@@ -721,8 +781,51 @@ function UsersSection() {
         <Users className="w-5 h-5 text-primary-500" />
         <h3 className="text-sm font-medium text-[var(--text-primary)]">Users</h3>
         <span className="text-xs text-[var(--text-muted)] ml-auto">{users.length} total</span>
+        <label
+          className="p-1.5 rounded-md hover:bg-surface-100 dark:hover:bg-surface-800 transition-colors cursor-pointer"
+          title="Import members from CSV (username, email, role)"
+        >
+          <Upload className="w-4 h-4 text-[var(--text-muted)]" />
+          <input type="file" accept=".csv,text/csv" onChange={handleImport} className="hidden" />
+        </label>
+        <button
+          onClick={handleExport}
+          className="p-1.5 rounded-md hover:bg-surface-100 dark:hover:bg-surface-800 transition-colors"
+          title="Export members as CSV"
+        >
+          <Download className="w-4 h-4 text-[var(--text-muted)]" />
+        </button>
       </div>
 
+      {importReport && (
+        <div className="p-3 rounded-lg bg-primary-500/10 border border-primary-500/30 mb-3 space-y-2">
+          <div className="flex items-center gap-2">
+            <p className="text-sm text-[var(--text-primary)] flex-1">
+              Imported {importReport.created.length} member{importReport.created.length === 1 ? "" : "s"}
+              {importReport.errors.length > 0 && `, ${importReport.errors.length} row${importReport.errors.length === 1 ? "" : "s"} skipped`}
+            </p>
+            {importReport.created.length > 0 && (
+              <button onClick={handleSaveSetupCodes} className="text-xs text-primary-500 hover:underline">
+                Save setup codes
+              </button>
+            )}
+            <button onClick={() => setImportReport(null)} className="text-xs text-[var(--text-muted)] hover:underline">
+              Dismiss
+            </button>
+          </div>
+          {importReport.created.length > 0 && (
+            <p className="text-xs text-[var(--text-muted)]">
+              Setup codes are shown only now and expire {new Date(importReport.setup_codes_expire_at).toLocaleDateString()}.
+            </p>
+          )}
+          {importReport.errors.map((e) => (
+            <p key={e.row} className="text-xs text-red-500">
+              Row {e.row}{e.username ? ` (${e.username})` : ""}: {e.error}
+            </p>
+          ))}
+        </div>
+      )}
+
       {error && (
         <div className="flex items-start gap-2 p-3 rounded-lg bg-red-500/10 border border-red-500/30 mb-3">
           <AlertCircle className="w-4 h-4 text-red-500 mt-0.5 shrink-0" />
@@ -751,6 +854,21 @@ function UsersSection() {
                       Admin
                     </span>
                   )}
+                  {user.expires_at && (
+                    <span className="text-[10px] px-1.5 py-0.5 rounded bg-primary-500/10 text-primary-500 font-medium shrink-0">
+                      Guest until {new Date(user.expires_at).toLocaleDateString()}
+                    </span>
+                  )}
+                  {user.suspension && (
+                    <span
+                      className="text-[10px] px-1.5 py-0.5 rounded bg-red-500/10 text-red-500 font-medium shrink-0"
+                      title={user.suspension.reason ?? undefined}
+                    >
+                      {user.suspension.suspended_until
+                        ? `Suspended until ${new Date(user.suspension.suspended_until).toLocaleDateString()}`
+                        : "Suspended"}
+                    </span>
+                  )}
                 </div>
                 <span className="text-xs text-[var(--text-muted)] truncate block">{user.email}</span>
               </div>
@@ -766,6 +884,17 @@ function UsersSection() {
                     <Shield className="w-4 h-4 text-[var(--text-muted)]" />
                   )}
                 </button>
+                <button
+                  onClick={() => handleToggleSuspend(user)}
+                  className="p-1.5 rounded-md hover:bg-surface-100 dark:hover:bg-surface-800 transition-colors"
+                  title={user.suspension ? "Lift suspension" : "Suspend user"}
+                >
+                  {user.suspension ? (
+                    <Undo2 className="w-4 h-4 text-[var(--text-muted)]" />
+                  ) : (
+                    <Ban className="w-4 h-4 text-[var(--text-muted)]" />
+                  )}
+                </button>
                 <button
                   onClick={() => handleDelete(user)}
                   className="p-1.5 rounded-md hover:bg-red-500/10 transition-colors"
@@ -782,6 +911,467 @@ function UsersSection() {
   );
 }
 
+// --- Guest Invites Section ---
+
+function GuestInvitesSection() {
+  const [invites, setInvites] = useState<GuestInvite[]>([]);
+  const [expiresAt, setExpiresAt] = useState("");
+  const [maxUses, setMaxUses] = useState("");
+  const [newToken, setNewToken] = useState<string | null>(null);
+  const [copied, setCopied] = useState(false);
+  const [error, setError] = useState<string | null>(null);
+
+  const refresh = useCallback(() => {
+    CitinetAPI.listGuestInvites()
+      .then(setInvites)
+      .catch((e) => setError(String(e)));
+  }, []);
+
+  useEffect(() => { refresh(); }, [refresh]);
+
+  const handleCreate = async () => {
+    if (!expiresAt) return;
+    try {
+      const invite = await CitinetAPI.createGuestInvite(
+        new Date(expiresAt).toISOString(),
+        [],
+        maxUses ? Number(maxUses) : undefined,
+      );
+      setNewToken(invite.token);
+      setExpiresAt("");
+      setMaxUses("");
+      setError(null);
+      refresh();
+    } catch (e) {
+      setError(e instanceof Error ? e.message : String(e));
+    }
+  };
+
+  const handleDelete = async (invite: GuestInvite) => {
+    if (!confirm("Revoke this invite? Guests who already joined keep access until it expires.")) return;
+    try {
+      await CitinetAPI.deleteGuestInvite(invite.invite_id);
+      refresh();
+    } catch (e) {
+      setError(e instanceof Error ? e.message : String(e));
+    }
+  };
+
+  return (
+    <Card>
+      <div className="flex items-center gap-2 mb-4">
+        <Ticket className="w-5 h-5 text-primary-500" />
+        <h3 className="text-sm font-medium text-[var(--text-primary)]">Guest Invites</h3>
+      </div>
+      <p className="text-xs text-[var(--text-muted)] mb-3">
+        Visitors who sign up with an invite get a guest account that can read public files.
+        Guest accounts expire with the invite and are then anonymized.
+      </p>
+
+      <div className="flex gap-2 mb-3">
+        <input
+          type="datetime-local"
+          value={expiresAt}
+          onChange={(e) => setExpiresAt(e.target.value)}
+          className="flex-1 px-3 py-2 text-sm rounded-lg border border-[var(--border-color)] bg-[var(--bg-primary)] text-[var(--text-primary)]"
+        />
+        <input
+          type="number"
+          min={1}
+          value={maxUses}
+          onChange={(e) => setMaxUses(e.target.value)}
+          placeholder="Max uses"
+          className="w-28 px-3 py-2 text-sm rounded-lg border border-[var(--border-color)] bg-[var(--bg-primary)] text-[var(--text-primary)]"
+        />
+        <button
+          onClick={handleCreate}
+          disabled={!expiresAt}
+          className="px-3 py-2 text-sm rounded-lg bg-primary-500 text-white hover:bg-primary-600 disabled:opacity-50 transition-colors"
+        >
+          Create
+        </button>
+      </div>
+
+      {newToken && (
+        <div className="flex items-center gap-2 p-3 rounded-lg bg-primary-500/10 border border-primary-500/30 mb-3">
+          <code className="text-xs text-[var(--text-primary)] truncate flex-1">{newToken}</code>
+          <button
+            onClick={() => { navigator.clipboard.writeText(newToken); setCopied(true); setTimeout(() => setCopied(false), 2000); }}
+            className="p-1.5 rounded-md hover:bg-surface-100 dark:hover:bg-surface-800 transition-colors"
+            title="Copy invite"
+          >
+            {copied ? (
+              <Check className="w-4 h-4 text-green-500" />
+            ) : (
+              <Copy className="w-4 h-4 text-[var(--text-muted)]" />
+            )}
+          </button>
+        </div>
+      )}
+
+      {error && (
+        <div className="flex items-start gap-2 p-3 rounded-lg bg-red-500/10 border border-red-500/30 mb-3">
+          <AlertCircle className="w-4 h-4 text-red-500 mt-0.5 shrink-0" />
+          <p className="text-sm text-red-500">{error}</p>
+        </div>
+      )}
+
+      {invites.length === 0 ? (
+        <p className="text-sm text-[var(--text-muted)] text-center py-2">No active invites</p>
+      ) : (
+        <div className="divide-y divide-[var(--border-color)]">
+          {invites.map((invite) => (
+            <div key={invite.invite_id} className="flex items-center gap-3 py-2 first:pt-0 last:pb-0">
+              <div className="flex-1 min-w-0">
+                <span className="text-sm text-[var(--text-primary)] block">
+                  Until {new Date(invite.expires_at).toLocaleString()}
+                </span>
+                <span className="text-xs text-[var(--text-muted)]">
+                  {invite.max_uses ? `${invite.uses} of ${invite.max_uses} used` : `${invite.uses} used`}
+                </span>
+              </div>
+              <button
+                onClick={() => handleDelete(invite)}
+                className="p-1.5 rounded-md hover:bg-red-500/10 transition-colors"
+                title="Revoke invite"
+              >
+                <Trash2 className="w-4 h-4 text-red-500" />
+              </button>
+            </div>
+          ))}
+        </div>
+      )}
+    </Card>
+  );
+}
+
+// --- Email Section ---
+
+const EMPTY_SMTP: SmtpConfig = {
+  host: "",
+  port: 587,
+  username: null,
+  tls: "starttls",
+  from_address: "",
+  from_name: null,
+  public_url: null,
+};
+
+function EmailSection() {
+  const [settings, setSettings] = useState<SmtpSettings | null>(null);
+  const [form, setForm] = useState<SmtpConfig>(EMPTY_SMTP);
+  const [password, setPassword] = useState("");
+  const [testTo, setTestTo] = useState("");
+  const [outbox, setOutbox] = useState<OutgoingEmail[]>([]);
+  const [busy, setBusy] = useState(false);
+  const [notice, setNotice] = useState<string | null>(null);
+  const [error, setError] = useState<string | null>(null);
+
+  const refresh = useCallback(() => {
+    CitinetAPI.getSmtpConfig()
+      .then((s) => {
+        setSettings(s);
+        if (s) {
+          setForm({
+            host: s.host,
+            port: s.port,
+            username: s.username,
+            tls: s.tls,
+            from_address: s.from_address,
+            from_name: s.from_name,
+            public_url: s.public_url,
+          });
+        }
+      })
+      .catch((e) => setError(String(e)));
+    CitinetAPI.listEmailOutbox()
+      .then(setOutbox)
+      .catch((e) => setError(String(e)));
+  }, []);
+
+  useEffect(() => { refresh(); }, [refresh]);
+
+  const run = async (action: () => Promise<void>, done: string) => {
+    setBusy(true);
+    setError(null);
+    setNotice(null);
+    try {
+      await action();
+      setNotice(done);
+      refresh();
+    } catch (e) {
+      setError(e instanceof Error ? e.message : String(e));
+    } finally {
+      setBusy(false);
+    }
+  };
+
+  const handleSave = () => run(async () => {
+    // An empty password field keeps the stored one
+    await CitinetAPI.setSmtpConfig({ ...form, password: password || undefined });
+    setPassword("");
+  }, "Email settings saved");
+
+  const handleDisable = () => {
+    if (!confirm("Turn off outgoing email? Verification, password reset and notifications stop until it's configured again.")) return;
+    run(async () => {
+      await CitinetAPI.clearSmtpConfig();
+      setForm(EMPTY_SMTP);
+    }, "Outgoing email turned off");
+  };
+
+  const handleTest = () => run(() => CitinetAPI.sendTestEmail(testTo), `Test email sent to ${testTo}`);
+
+  const input = "px-3 py-2 text-sm rounded-lg border border-[var(--border-color)] bg-[var(--bg-primary)] text-[var(--text-primary)]";
+
+  return (
+    <Card>
+      <div className="flex items-center gap-2 mb-4">
+        <Mail className="w-5 h-5 text-primary-500" />
+        <h3 className="text-sm font-medium text-[var(--text-primary)]">Outgoing Email</h3>
+        <span className="text-xs text-[var(--text-muted)] ml-auto">{settings ? "On" : "Off"}</span>
+      </div>
+      <p className="text-xs text-[var(--text-muted)] mb-3">
+        Used to verify members' addresses, send password reset codes and, for members who opt in,
+        notify them of new messages. Failed deliveries are retried for several hours.
+      </p>
+
+      <div className="grid grid-cols-2 gap-2 mb-3">
+        <input
+          type="text"
+          value={form.host}
+          onChange={(e) => setForm({ ...form, host: e.target.value })}
+          placeholder="SMTP host"
+          className={input}
+        />
+        <div className="flex gap-2">
+          <input
+            type="number"
+            min={1}
+            max={65535}
+            value={form.port}
+            onChange={(e) => setForm({ ...form, port: Number(e.target.value) })}
+            className={`w-24 ${input}`}
+          />
+          <select
+            value={form.tls}
+            onChange={(e) => setForm({ ...form, tls: e.target.value as SmtpConfig["tls"] })}
+            className={`flex-1 ${input}`}
+          >
+            <option value="starttls">STARTTLS</option>
+            <option value="tls">TLS</option>
+            <option value="none">None</option>
+          </select>
+        </div>
+        <input
+          type="text"
+          value={form.username ?? ""}
+          onChange={(e) => setForm({ ...form, username: e.target.value || null })}
+          placeholder="Username"
+          className={input}
+        />
+        <input
+          type="password"
+          value={password}
+          onChange={(e) => setPassword(e.target.value)}
+          placeholder={settings?.has_password ? "Password (unchanged)" : "Password"}
+          className={input}
+        />
+        <input
+          type="email"
+          value={form.from_address}
+          onChange={(e) => setForm({ ...form, from_address: e.target.value })}
+          placeholder="From address"
+          className={input}
+        />
+        <input
+          type="text"
+          value={form.from_name ?? ""}
+          onChange={(e) => setForm({ ...form, from_name: e.target.value || null })}
+          placeholder="From name"
+          className={input}
+        />
+        <input
+          type="url"
+          value={form.public_url ?? ""}
+          onChange={(e) => setForm({ ...form, public_url: e.target.value || null })}
+          placeholder="Public URL for links, e.g. https://hub.example.org (defaults to the tunnel)"
+          className={`col-span-2 ${input}`}
+        />
+      </div>
+
+      <div className="flex gap-2 mb-3">
+        <button
+          onClick={handleSave}
+          disabled={busy || !form.host || !form.from_address}
+          className="px-3 py-2 text-sm rounded-lg bg-primary-500 text-white hover:bg-primary-600 disabled:opacity-50 transition-colors"
+        >
+          Save
+        </button>
+        {settings && (
+          <button
+            onClick={handleDisable}
+            disabled={busy}
+            className="px-3 py-2 text-sm rounded-lg border border-[var(--border-color)] text-red-500 hover:bg-red-500/10 disabled:opacity-50 transition-colors"
+          >
+            Turn off
+          </button>
+        )}
+      </div>
+
+      {settings && (
+        <div className="flex gap-2 mb-3">
+          <input
+            type="email"
+            value={testTo}
+            onChange={(e) => setTestTo(e.target.value)}
+            placeholder="Send a test email to..."
+            className={`flex-1 ${input}`}
+          />
+          <button
+            onClick={handleTest}
+            disabled={busy || !testTo}
+            className="px-3 py-2 text-sm rounded-lg border border-[var(--border-color)] text-[var(--text-primary)] hover:bg-surface-100 dark:hover:bg-surface-800 disabled:opacity-50 transition-colors"
+          >
+            {busy ? <Loader2 className="w-4 h-4 animate-spin" /> : "Send test"}
+          </button>
+        </div>
+      )}
+
+      {notice && (
+        <div className="flex items-start gap-2 p-3 rounded-lg bg-green-500/10 border border-green-500/30 mb-3">
+          <CheckCircle2 className="w-4 h-4 text-green-500 mt-0.5 shrink-0" />
+          <p className="text-sm text-green-500">{notice}</p>
+        </div>
+      )}
+
+      {error && (
+        <div className="flex items-start gap-2 p-3 rounded-lg bg-red-500/10 border border-red-500/30 mb-3">
+          <AlertCircle className="w-4 h-4 text-red-500 mt-0.5 shrink-0" />
+          <p className="text-sm text-red-500">{error}</p>
+        </div>
+      )}
+
+      {outbox.length > 0 && (
+        <div className="divide-y divide-[var(--border-color)] max-h-60 overflow-y-auto">
+          {outbox.map((email) => (
+            <div key={email.email_id} className="py-2 first:pt-0 last:pb-0">
+              <div className="flex items-center gap-2">
+                <span
+                  className={`text-xs px-1.5 py-0.5 rounded shrink-0 ${
+                    email.status === "sent"
+                      ? "bg-green-500/10 text-green-500"
+                      : email.status === "failed"
+                        ? "bg-red-500/10 text-red-500"
+                        : "bg-primary-500/10 text-primary-500"
+                  }`}
+                >
+                  {email.status}
+                </span>
+                <span className="text-sm text-[var(--text-primary)] truncate">{email.to_address}</span>
+                <span className="text-xs text-[var(--text-muted)] ml-auto shrink-0">
+                  {new Date(email.created_at).toLocaleString()}
+                </span>
+              </div>
+              <p className="text-xs text-[var(--text-muted)] truncate mt-0.5">
+                {[email.subject, email.attempts > 1 ? `${email.attempts} attempts` : null, email.last_error]
+                  .filter(Boolean)
+                  .join(" · ")}
+              </p>
+            </div>
+          ))}
+        </div>
+      )}
+    </Card>
+  );
+}
+
+// --- Audit Log Section ---
+
+function AuditLogSection() {
+  const [events, setEvents] = useState<AuditEvent[]>([]);
+  const [total, setTotal] = useState(0);
+  const [actor, setActor] = useState("");
+  const [action, setAction] = useState("");
+  const [loading, setLoading] = useState(true);
+  const [error, setError] = useState<string | null>(null);
+
+  const refresh = useCallback(() => {
+    setLoading(true);
+    CitinetAPI.listAuditEvents({ actor: actor || undefined, action: action || undefined }, 50)
+      .then((r) => { setEvents(r.events); setTotal(r.total); setError(null); setLoading(false); })
+      .catch((e) => { setError(String(e)); setLoading(false); });
+  }, [actor, action]);
+
+  useEffect(() => { refresh(); }, [refresh]);
+
+  return (
+    <Card>
+      <div className="flex items-center gap-2 mb-4">
+        <ScrollText className="w-5 h-5 text-primary-500" />
+        <h3 className="text-sm font-medium text-[var(--text-primary)]">Audit Log</h3>
+        <span className="text-xs text-[var(--text-muted)] ml-auto">{total} events</span>
+      </div>
+
+      <div className="flex gap-2 mb-3">
+        <input
+          type="text"
+          value={actor}
+          onChange={(e) => setActor(e.target.value)}
+          placeholder="Actor"
+          className="flex-1 px-3 py-2 text-sm rounded-lg border border-[var(--border-color)] bg-[var(--bg-primary)] text-[var(--text-primary)]"
+        />
+        <input
+          type="text"
+          value={action}
+          onChange={(e) => setAction(e.target.value)}
+          placeholder="Action (e.g. user.)"
+          className="flex-1 px-3 py-2 text-sm rounded-lg border border-[var(--border-color)] bg-[var(--bg-primary)] text-[var(--text-primary)]"
+        />
+      </div>
+
+      {error && (
+        <div className="flex items-start gap-2 p-3 rounded-lg bg-red-500/10 border border-red-500/30 mb-3">
+          <AlertCircle className="w-4 h-4 text-red-500 mt-0.5 shrink-0" />
+          <p className="text-sm text-red-500">{error}</p>
+        </div>
+      )}
+
+      {loading ? (
+        <div className="flex items-center gap-2">
+          <Loader2 className="w-4 h-4 animate-spin text-primary-500" />
+          <span className="text-sm text-[var(--text-secondary)]">Loading events...</span>
+        </div>
+      ) : events.length === 0 ? (
+        <p className="text-sm text-[var(--text-muted)] text-center py-4">No matching events</p>
+      ) : (
+        <div className="divide-y divide-[var(--border-color)] max-h-80 overflow-y-auto">
+          {events.map((event) => (
+            <div key={event.event_id} className="py-2 first:pt-0 last:pb-0">
+              <div className="flex items-center gap-2">
+                <span className="text-xs font-mono px-1.5 py-0.5 rounded bg-primary-500/10 text-primary-500 shrink-0">
+                  {event.action}
+                </span>
+                <span className="text-sm text-[var(--text-primary)] truncate">
+                  {event.actor_name ?? event.actor_id ?? "system"}
+                </span>
+                <span className="text-xs text-[var(--text-muted)] ml-auto shrink-0">
+                  {new Date(event.created_at).toLocaleString()}
+                </span>
+              </div>
+              {(event.target || event.details || event.ip) && (
+                <p className="text-xs text-[var(--text-muted)] truncate mt-0.5">
+                  {[event.target, event.details, event.ip].filter(Boolean).join(" · ")}
+                </p>
+              )}
+            </div>
+          ))}
+        </div>
+      )}
+    </Card>
+  );
+}
+
 // --- Registry Section ---
 
 function RegistrySection() {
@@ -919,6 +1509,9 @@ export function AdminPanel() {
     <div className="max-w-2xl space-y-6">
       <h2 className="text-xl font-bold text-[var(--text-primary)]">Admin Panel</h2>
       <UsersSection />
+      <GuestInvitesSection />
+      <EmailSection />
+      <AuditLogSection />
       <TunnelSection />
       <RegistrySection />
     </div>
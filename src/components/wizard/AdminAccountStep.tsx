@@ -3,6 +3,9 @@ import { useWizardStore } from "../../stores/wizardStore";
 import { Button } from "../ui/Button";
 import { Mail, Lock, User as UserIcon, AlertCircle } from "lucide-react";
 
+/** Matches the hub's minimum; it also rejects common and repeated-character passwords */
+const MIN_PASSWORD_LENGTH = 10;
+
 export function AdminAccountStep() {
   const { nextStep, prevStep } = useWizardStore();
   const [username, setUsername] = useState("");
@@ -14,7 +17,7 @@ export function AdminAccountStep() {
   const isValid = 
     username.length >= 3 &&
     email.includes("@") &&
-    password.length >= 8 &&
+    password.length >= MIN_PASSWORD_LENGTH &&
     password === confirmPassword;
 
   const handleContinue = () => {
@@ -93,8 +96,8 @@ export function AdminAccountStep() {
             className="w-full px-3 py-2 rounded-lg border border-[var(--border-color)] bg-[var(--bg-primary)] text-[var(--text-primary)]"
             autoComplete="new-password"
           />
-          {password && password.length < 8 && (
-            <p className="text-xs text-red-500 mt-1">Password must be at least 8 characters</p>
+          {password && password.length < MIN_PASSWORD_LENGTH && (
+            <p className="text-xs text-red-500 mt-1">Password must be at least {MIN_PASSWORD_LENGTH} characters</p>
           )}
         </div>
 
@@ -1,5 +1,5 @@
 import { create } from "zustand";
-import type { User } from "../api/tauri";
+import { CitinetAPI, type User } from "../api/tauri";
 
 interface AuthState {
   currentUser: User | null;
@@ -17,6 +17,7 @@ export const useAuthStore = create<AuthState>((set) => ({
     set({ currentUser: user });
   },
   logout: () => {
+    CitinetAPI.logoutUser().catch(() => {});
     localStorage.removeItem("citinet-auth-user");
     localStorage.setItem("citinet-phase", "login");
     set({ currentUser: null });
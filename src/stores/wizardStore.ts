@@ -1,4 +1,5 @@
 import { create } from "zustand";
+import type { User } from "../api/tauri";
 
 interface WizardState {
   currentStep: number;
@@ -16,7 +17,7 @@ interface WizardState {
   adminUsername: string;
   adminEmail: string;
   adminPassword: string;
-  createdUser: { user_id: string; username: string; email: string; is_admin: boolean; created_at: string; updated_at: string } | null;
+  createdUser: User | null;
   setStep: (step: number) => void;
   nextStep: () => void;
   prevStep: () => void;